@@ -1,3 +1,1863 @@
+use std::collections::VecDeque;
+
+use crate::audio::*;
+use crate::cartridge::TvSystem;
+use crate::expansion::ExpansionAudio;
+use crate::savestate::*;
+
+// length counter load values, indexed by the top 5 bits of $4003/$4007/$400B/$400F
+pub const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+pub const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+pub const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// noise timer periods in CPU cycles
+pub const NOISE_PERIODS_NTSC: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+pub const NOISE_PERIODS_PAL: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+
+// DMC timer periods in CPU cycles
+pub const DMC_RATES_NTSC: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+pub const DMC_RATES_PAL: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+
+// frame sequencer step positions in CPU cycles after the sequence (re)starts, the last
+// entry is where it wraps back to 0
+const NTSC_FOUR_STEP: [u32; 6] = [7457, 14913, 22371, 29828, 29829, 29830];
+const NTSC_FIVE_STEP: [u32; 6] = [7457, 14913, 22371, 29829, 37281, 37282];
+const PAL_FOUR_STEP: [u32; 6] = [8313, 16627, 24939, 33252, 33253, 33254];
+const PAL_FIVE_STEP: [u32; 6] = [8313, 16627, 24939, 33253, 41565, 41566];
+
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    period: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            looping: false,
+            constant: false,
+            period: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    // --LC VVVV of $4000/$4004/$400C
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant = data & 0b0001_0000 != 0;
+        self.period = data & 0b0000_1111;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+            return;
+        }
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.period;
+        if self.decay > 0 {
+            self.decay -= 1;
+        } else if self.looping {
+            self.decay = 15;
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        if self.constant { self.period } else { self.decay }
+    }
+}
+
+pub struct LengthCounter {
+    enabled: bool,
+    halted: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> Self {
+        LengthCounter {
+            enabled: false,
+            halted: false,
+            counter: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halted && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn value(&self) -> u8 {
+        self.counter
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PulseChannel {
+    One,
+    Two,
+}
+
+pub struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+impl Sweep {
+    pub fn new() -> Self {
+        Sweep {
+            enabled: false,
+            period: 0,
+            negate: false,
+            shift: 0,
+            divider: 0,
+            reload: false,
+        }
+    }
+
+    // EPPP NSSS of $4001/$4005
+    pub fn write(&mut self, data: u8) {
+        self.enabled = data & 0b1000_0000 != 0;
+        self.period = (data >> 4) & 0b111;
+        self.negate = data & 0b0000_1000 != 0;
+        self.shift = data & 0b111;
+        self.reload = true;
+    }
+}
+
+pub struct Pulse {
+    channel: PulseChannel,
+    duty: u8,
+    duty_step: u8,
+    timer_period: u16,
+    timer: u16,
+    pub envelope: Envelope,
+    pub sweep: Sweep,
+    pub length: LengthCounter,
+}
+
+impl Pulse {
+    pub fn new(channel: PulseChannel) -> Self {
+        Pulse {
+            channel: channel,
+            duty: 0,
+            duty_step: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            sweep: Sweep::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    // register 0-3 relative to the channel's base ($4000 or $4004)
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.length.set_halted(data & 0b0010_0000 != 0);
+                self.envelope.write(data);
+            }
+            1 => self.sweep.write(data),
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | (((data & 0b111) as u16) << 8);
+                self.length.load(data >> 3);
+                self.duty_step = 0;
+                self.envelope.restart();
+            }
+            _ => panic!("pulse has no register {}", register),
+        }
+    }
+
+    // pulse timers run at half the CPU clock
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_step = (self.duty_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+        self.clock_sweep();
+    }
+
+    // pulse 1 negates with one's complement, pulse 2 with two's complement
+    pub fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep.shift;
+        if !self.sweep.negate {
+            return self.timer_period + change;
+        }
+        match self.channel {
+            PulseChannel::One => self.timer_period.saturating_sub(change + 1),
+            PulseChannel::Two => self.timer_period.saturating_sub(change),
+        }
+    }
+
+    // the channel is silenced whenever the period is too low or the sweep would overflow,
+    // even with the sweep unit disabled
+    pub fn sweep_muting(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.divider == 0 && self.sweep.enabled && self.sweep.shift > 0 && !self.sweep_muting() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    pub fn timer_period(&self) -> u16 {
+        self.timer_period
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.sweep_muting() || DUTY_TABLE[self.duty as usize][self.duty_step as usize] == 0 {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+}
+
+pub struct Triangle {
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    pub length: LengthCounter,
+    // periods below 2 produce tones far above hearing that only pop on real speakers,
+    // so by default the sequencer just holds its position
+    pub silence_ultrasonic: bool,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            timer_period: 0,
+            timer: 0,
+            step: 0,
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+            length: LengthCounter::new(),
+            silence_ultrasonic: true,
+        }
+    }
+
+    // register 0-3 relative to $4008 ($4009 is unused)
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length.set_halted(self.control);
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            1 => {}
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | (((data & 0b111) as u16) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+            _ => panic!("triangle has no register {}", register),
+        }
+    }
+
+    // the triangle timer runs at the full CPU clock
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period;
+        if !self.length.active() || self.linear_counter == 0 {
+            return;
+        }
+        if self.silence_ultrasonic && self.timer_period < 2 {
+            return;
+        }
+        self.step = (self.step + 1) % 32;
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn linear_counter(&self) -> u8 {
+        self.linear_counter
+    }
+
+    pub fn timer_period(&self) -> u16 {
+        self.timer_period
+    }
+
+    // the triangle has no volume control, a halted sequencer keeps putting out its last step
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.step as usize]
+    }
+}
+
+pub struct Noise {
+    periods: &'static [u16; 16],
+    timer_period: u16,
+    timer: u16,
+    short_mode: bool,
+    shift_register: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(tv_system: TvSystem) -> Self {
+        Noise {
+            periods: match tv_system {
+                TvSystem::NTSC => &NOISE_PERIODS_NTSC,
+                TvSystem::PAL => &NOISE_PERIODS_PAL,
+            },
+            timer_period: NOISE_PERIODS_NTSC[0] - 1,
+            timer: 0,
+            short_mode: false,
+            shift_register: 1,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    // register 0-3 relative to $400C ($400D is unused)
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.length.set_halted(data & 0b0010_0000 != 0);
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0b1000_0000 != 0;
+                self.timer_period = self.periods[(data & 0b1111) as usize] - 1;
+            }
+            3 => {
+                self.length.load(data >> 3);
+                self.envelope.restart();
+            }
+            _ => panic!("noise has no register {}", register),
+        }
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period;
+        // 15-bit LFSR, short mode taps bit 6 instead of bit 1 for a 93 step loop
+        let tap = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.shift_register & 1) ^ ((self.shift_register >> tap) & 1);
+        self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn timer_period(&self) -> u16 {
+        self.timer_period + 1
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift_register & 1 == 1 {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+}
+
+pub struct Dmc {
+    rates: &'static [u16; 16],
+    irq_enabled: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    output_level: u8,
+    pub irq_flag: bool,
+}
+
+impl Dmc {
+    pub fn new(tv_system: TvSystem) -> Self {
+        let rates = match tv_system {
+            TvSystem::NTSC => &DMC_RATES_NTSC,
+            TvSystem::PAL => &DMC_RATES_PAL,
+        };
+        Dmc {
+            rates: rates,
+            irq_enabled: false,
+            looping: false,
+            timer_period: rates[0] - 1,
+            timer: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            output_level: 0,
+            irq_flag: false,
+        }
+    }
+
+    // register 0-3 relative to $4010
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                self.looping = data & 0b0100_0000 != 0;
+                self.timer_period = self.rates[(data & 0b1111) as usize] - 1;
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+            }
+            1 => self.output_level = data & 0b0111_1111,
+            2 => self.sample_address = 0xC000 | ((data as u16) << 6),
+            3 => self.sample_length = ((data as u16) << 4) | 1,
+            _ => panic!("dmc has no register {}", register),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // address the memory reader wants fetched, the bus does the read and stalls the CPU
+    pub fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            return Some(self.current_address);
+        }
+        None
+    }
+
+    pub fn load_sample(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period;
+
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn timer_period(&self) -> u16 {
+        self.timer_period + 1
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+pub struct FrameCounter {
+    tv_system: TvSystem,
+    cycles: u32,
+    five_step: bool,
+    irq_inhibit: bool,
+    pub irq_flag: bool,
+    // a $4017 write takes effect 3 or 4 CPU cycles later: (cycles left, new mode)
+    pending_write: Option<(u8, bool)>,
+}
+
+impl FrameCounter {
+    pub fn new(tv_system: TvSystem) -> Self {
+        FrameCounter {
+            tv_system: tv_system,
+            cycles: 0,
+            five_step: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            pending_write: None,
+        }
+    }
+
+    // MI-- ---- of $4017, odd_cycle is whether the write lands between APU cycles
+    pub fn write(&mut self, data: u8, odd_cycle: bool) {
+        self.irq_inhibit = data & 0b0100_0000 != 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        let delay = if odd_cycle { 4 } else { 3 };
+        self.pending_write = Some((delay, data & 0b1000_0000 != 0));
+    }
+
+    pub fn five_step(&self) -> bool {
+        self.five_step
+    }
+
+    fn steps(&self) -> &'static [u32; 6] {
+        match (self.tv_system, self.five_step) {
+            (TvSystem::NTSC, false) => &NTSC_FOUR_STEP,
+            (TvSystem::NTSC, true) => &NTSC_FIVE_STEP,
+            (TvSystem::PAL, false) => &PAL_FOUR_STEP,
+            (TvSystem::PAL, true) => &PAL_FIVE_STEP,
+        }
+    }
+
+    fn set_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+    }
+
+    // returns (quarter frame, half frame) clocks for this CPU cycle
+    pub fn step(&mut self) -> (bool, bool) {
+        if let Some((delay, five_step)) = self.pending_write {
+            if delay <= 1 {
+                self.pending_write = None;
+                self.cycles = 0;
+                self.five_step = five_step;
+                // switching to 5-step mode clocks everything right away
+                if five_step {
+                    return (true, true);
+                }
+                return (false, false);
+            }
+            self.pending_write = Some((delay - 1, five_step));
+        }
+
+        self.cycles += 1;
+        let step = self.steps().iter().position(|&c| c == self.cycles);
+        match (step, self.five_step) {
+            (Some(0), _) | (Some(2), _) => (true, false),
+            (Some(1), _) => (true, true),
+            (Some(3), false) => {
+                self.set_irq();
+                (false, false)
+            }
+            (Some(3), true) => (false, false),
+            (Some(4), false) => {
+                self.set_irq();
+                (true, true)
+            }
+            (Some(4), true) => (true, true),
+            (Some(5), five_step) => {
+                if !five_step {
+                    self.set_irq();
+                }
+                self.cycles = 0;
+                (false, false)
+            }
+            _ => (false, false),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    // cartridge channels, numbered across all chips in the order they were added
+    Expansion(usize),
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::Dmc];
+
+    pub fn index(&self) -> usize {
+        match self {
+            Channel::Pulse1 => 0,
+            Channel::Pulse2 => 1,
+            Channel::Triangle => 2,
+            Channel::Noise => 3,
+            Channel::Dmc => 4,
+            Channel::Expansion(n) => Channel::ALL.len() + n,
+        }
+    }
+
+    // the chips name their own channels, see Apu::channel_name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Expansion(_) => "expansion",
+            _ => CHANNEL_NAMES[self.index()],
+        }
+    }
+}
+
+// a snapshot of one channel for debuggers and the visualizer
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelState {
+    pub channel: Channel,
+    pub name: String,
+    // timer period in CPU cycles (noise and DMC) or timer reload value (tone channels)
+    pub period: u16,
+    pub frequency: f64,
+    // only the tone channels play notes
+    pub note: Option<String>,
+    // 0-15, the DMC reports its 0-127 output level and expansion channels their own range
+    pub volume: u8,
+    pub max_volume: u8,
+    // the range of the levels in the channel's waveform
+    pub max_level: u8,
+    pub active: bool,
+    pub muted: bool,
+    pub solo: bool,
+}
+
+// "A4" for 440 Hz, nearest equal-tempered note
+pub fn note_name(frequency: f64) -> Option<String> {
+    const NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+    if frequency < 20.0 || frequency > 20000.0 {
+        return None;
+    }
+    let midi = (69.0 + 12.0 * (frequency / 440.0).log2()).round() as i32;
+    Some(format!("{}{}", NAMES[(midi % 12) as usize], midi / 12 - 1))
+}
+
+// recent raw DAC levels per channel for oscilloscopes, only kept while enabled
+pub const SCOPE_LENGTH: usize = 512;
+const SCOPE_DECIMATION: u64 = 64;
+
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    frame_counter: FrameCounter,
+    cycles: u64,
+    pub audio: AudioPipeline,
+    tv_system: TvSystem,
+    // sound chips on the cartridge, mixed in with the 2A03 channels
+    expansion: Vec<Box<dyn ExpansionAudio>>,
+    muted: Vec<bool>,
+    solo: Vec<bool>,
+    audible: Vec<bool>,
+    scope: Option<Vec<VecDeque<u8>>>,
+}
+
+impl Apu {
+    pub fn new(tv_system: TvSystem) -> Self {
+        Apu {
+            pulse1: Pulse::new(PulseChannel::One),
+            pulse2: Pulse::new(PulseChannel::Two),
+            triangle: Triangle::new(),
+            noise: Noise::new(tv_system),
+            dmc: Dmc::new(tv_system),
+            frame_counter: FrameCounter::new(tv_system),
+            cycles: 0,
+            audio: AudioPipeline::new(cpu_clock(tv_system), DEFAULT_SAMPLE_RATE),
+            tv_system: tv_system,
+            expansion: vec![],
+            muted: vec![false; 5],
+            solo: vec![false; 5],
+            audible: vec![true; 5],
+            scope: None,
+        }
+    }
+
+    // mutes and solos carry over while the channel count stays the same
+    pub fn set_expansion(&mut self, chips: Vec<Box<dyn ExpansionAudio>>) {
+        self.expansion = chips;
+        let count = self.channels().len();
+        if count != self.muted.len() {
+            self.muted = vec![false; count];
+            self.solo = vec![false; count];
+            self.audible = vec![true; count];
+            if self.scope.is_some() {
+                self.set_scope(true);
+            }
+        }
+    }
+
+    // a reset silences the channels as a $4015 write of 0 does; at power-on they start
+    // over completely. Either way the audio pipeline, mutes and expansion chips stay
+    pub fn reset(&mut self, power: bool) {
+        if power {
+            self.pulse1 = Pulse::new(PulseChannel::One);
+            self.pulse2 = Pulse::new(PulseChannel::Two);
+            self.triangle = Triangle::new();
+            self.noise = Noise::new(self.tv_system);
+            self.dmc = Dmc::new(self.tv_system);
+            self.frame_counter = FrameCounter::new(self.tv_system);
+        } else {
+            self.write_register(0x4015, 0);
+        }
+    }
+
+    pub fn expansion(&self) -> &[Box<dyn ExpansionAudio>] {
+        &self.expansion
+    }
+
+    pub fn expansion_handles(&self, addr: u16) -> bool {
+        self.expansion.iter().any(|chip| chip.handles(addr))
+    }
+
+    // every chip that decodes the address sees the write
+    pub fn expansion_write(&mut self, addr: u16, data: u8) {
+        for chip in self.expansion.iter_mut().filter(|chip| chip.handles(addr)) {
+            chip.write(addr, data);
+        }
+    }
+
+    pub fn expansion_read(&mut self, addr: u16) -> Option<u8> {
+        self.expansion.iter_mut().filter(|chip| chip.handles(addr)).find_map(|chip| chip.read(addr))
+    }
+
+    // the 2A03 channels followed by the expansion channels
+    pub fn channels(&self) -> Vec<Channel> {
+        let count: usize = self.expansion.iter().map(|chip| chip.channel_count()).sum();
+        Channel::ALL.iter().cloned().chain((0..count).map(Channel::Expansion)).collect()
+    }
+
+    // the chip and its own channel number
+    fn expansion_channel(&self, n: usize) -> (&dyn ExpansionAudio, usize) {
+        let mut n = n;
+        for chip in self.expansion.iter() {
+            let count = chip.channel_count();
+            if n < count {
+                return (chip.as_ref(), n);
+            }
+            n -= count;
+        }
+        panic!("no expansion channel {}", n)
+    }
+
+    pub fn channel_name(&self, channel: Channel) -> String {
+        match channel {
+            Channel::Expansion(n) => {
+                let (chip, index) = self.expansion_channel(n);
+                chip.channel_names()[index].clone()
+            }
+            _ => channel.name().to_string(),
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write_register(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write_register(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write_register(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write_register(addr - 0x400C, data),
+            0x4010..=0x4013 => self.dmc.write_register(addr - 0x4010, data),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0b0001 != 0);
+                self.pulse2.length.set_enabled(data & 0b0010 != 0);
+                self.triangle.length.set_enabled(data & 0b0100 != 0);
+                self.noise.length.set_enabled(data & 0b1000 != 0);
+                self.dmc.set_enabled(data & 0b1_0000 != 0);
+            }
+            0x4017 => self.frame_counter.write(data, self.cycles % 2 == 1),
+            _ => {}
+        }
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length.active() {
+            status |= 0b01;
+        }
+        if self.pulse2.length.active() {
+            status |= 0b10;
+        }
+        if self.triangle.length.active() {
+            status |= 0b0100;
+        }
+        if self.noise.length.active() {
+            status |= 0b1000;
+        }
+        if self.dmc.active() {
+            status |= 0b1_0000;
+        }
+        if self.frame_counter.irq_flag {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq_flag {
+            status |= 0b1000_0000;
+        }
+        // reading the status acknowledges the frame interrupt
+        self.frame_counter.irq_flag = false;
+        status
+    }
+
+    pub fn irq(&self) -> bool {
+        self.dmc.irq_flag || self.frame_counter.irq_flag
+    }
+
+    pub fn tick(&mut self, cpu_cycles: u8) {
+        for _ in 0..cpu_cycles {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        if self.cycles % 2 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+
+        for chip in self.expansion.iter_mut() {
+            chip.clock();
+        }
+
+        let (quarter, half) = self.frame_counter.step();
+        if quarter {
+            self.pulse1.clock_quarter_frame();
+            self.pulse2.clock_quarter_frame();
+            self.triangle.clock_quarter_frame();
+            self.noise.clock_quarter_frame();
+        }
+        if half {
+            self.pulse1.clock_half_frame();
+            self.pulse2.clock_half_frame();
+            self.triangle.clock_half_frame();
+            self.noise.clock_half_frame();
+        }
+        if self.audio.channel_streams() {
+            self.audio.add_channel_levels(&self.channel_outputs());
+        }
+        self.audio.add_level(self.output());
+        if self.cycles % SCOPE_DECIMATION == 0 {
+            self.sample_scope();
+        }
+        self.cycles += 1;
+    }
+
+    fn raw_outputs(&self) -> [u8; 5] {
+        [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ]
+    }
+
+    fn expansion_levels(&self) -> Vec<u8> {
+        let mut levels = vec![];
+        for chip in self.expansion.iter() {
+            for channel in 0..chip.channel_count() {
+                levels.push(chip.level(channel));
+            }
+        }
+        levels
+    }
+
+    // what the expansion channels add to the mix, optionally leaving out muted ones
+    fn expansion_output(&self, audible_only: bool) -> f32 {
+        let mut sum = 0.0;
+        let mut index = Channel::ALL.len();
+        for chip in self.expansion.iter() {
+            for channel in 0..chip.channel_count() {
+                if !audible_only || self.audible[index] {
+                    sum += chip.output(channel);
+                }
+                index += 1;
+            }
+        }
+        sum
+    }
+
+    fn sample_scope(&mut self) {
+        if self.scope.is_none() {
+            return;
+        }
+        let mut levels = self.raw_outputs().to_vec();
+        levels.extend(self.expansion_levels());
+        if let Some(scope) = self.scope.as_mut() {
+            for (samples, level) in scope.iter_mut().zip(levels.iter()) {
+                if samples.len() == SCOPE_LENGTH {
+                    samples.pop_front();
+                }
+                samples.push_back(*level);
+            }
+        }
+    }
+
+    pub fn set_scope(&mut self, enabled: bool) {
+        let count = self.channels().len();
+        self.scope = if enabled { Some(vec![VecDeque::with_capacity(SCOPE_LENGTH); count]) } else { None };
+    }
+
+    // oldest first, empty while the scope is off
+    pub fn waveform(&self, channel: Channel) -> Vec<u8> {
+        match &self.scope {
+            Some(scope) => scope[channel.index()].iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel.index()] = muted;
+        self.update_audible();
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel.index()]
+    }
+
+    // while any channel is soloed only soloed channels are heard
+    pub fn set_solo(&mut self, channel: Channel, solo: bool) {
+        self.solo[channel.index()] = solo;
+        self.update_audible();
+    }
+
+    pub fn is_solo(&self, channel: Channel) -> bool {
+        self.solo[channel.index()]
+    }
+
+    pub fn audible(&self, channel: Channel) -> bool {
+        self.audible[channel.index()]
+    }
+
+    fn update_audible(&mut self) {
+        let any_solo = self.solo.iter().any(|s| *s);
+        for i in 0..self.audible.len() {
+            self.audible[i] = !self.muted[i] && (!any_solo || self.solo[i]);
+        }
+    }
+
+    pub fn channel_state(&self, channel: Channel) -> ChannelState {
+        let clock = cpu_clock(self.tv_system);
+        if let Channel::Expansion(n) = channel {
+            let (chip, index) = self.expansion_channel(n);
+            let info = chip.info(index, clock);
+            return ChannelState {
+                channel: channel,
+                name: self.channel_name(channel),
+                period: info.period,
+                frequency: info.frequency,
+                note: if info.active { note_name(info.frequency) } else { None },
+                volume: info.volume,
+                max_volume: info.max_volume,
+                max_level: chip.max_level(index),
+                active: info.active,
+                muted: self.is_muted(channel),
+                solo: self.is_solo(channel),
+            };
+        }
+        let (period, frequency, volume, active) = match channel {
+            Channel::Pulse1 | Channel::Pulse2 => {
+                let pulse = if channel == Channel::Pulse1 { &self.pulse1 } else { &self.pulse2 };
+                let volume = pulse.envelope.volume();
+                let active = pulse.length.active() && !pulse.sweep_muting() && volume > 0;
+                (pulse.timer_period(), clock / (16.0 * (pulse.timer_period() as f64 + 1.0)), volume, active)
+            }
+            Channel::Triangle => {
+                let active = self.triangle.length.active() && self.triangle.linear_counter() > 0;
+                let volume = if active { 15 } else { 0 };
+                (self.triangle.timer_period(), clock / (32.0 * (self.triangle.timer_period() as f64 + 1.0)), volume, active)
+            }
+            Channel::Noise => {
+                let volume = self.noise.envelope.volume();
+                let active = self.noise.length.active() && volume > 0;
+                (self.noise.timer_period(), clock / self.noise.timer_period() as f64, volume, active)
+            }
+            Channel::Dmc => {
+                let active = self.dmc.active();
+                (self.dmc.timer_period(), clock / self.dmc.timer_period() as f64, self.dmc.output(), active)
+            }
+            Channel::Expansion(_) => unreachable!(),
+        };
+        let note = match channel {
+            Channel::Noise | Channel::Dmc => None,
+            _ if !active => None,
+            _ => note_name(frequency),
+        };
+        let max = if channel == Channel::Dmc { 127 } else { 15 };
+        ChannelState {
+            channel: channel,
+            name: self.channel_name(channel),
+            period: period,
+            frequency: frequency,
+            note: note,
+            volume: volume,
+            max_volume: max,
+            max_level: max,
+            active: active,
+            muted: self.is_muted(channel),
+            solo: self.is_solo(channel),
+        }
+    }
+
+    // what each channel contributes when it goes through the mixer on its own, with
+    // all expansion channels together in the last slot
+    pub fn channel_outputs(&self) -> [f32; 6] {
+        [
+            mix(self.pulse1.output(), 0, 0, 0, 0),
+            mix(0, self.pulse2.output(), 0, 0, 0),
+            mix(0, 0, self.triangle.output(), 0, 0),
+            mix(0, 0, 0, self.noise.output(), 0),
+            mix(0, 0, 0, 0, self.dmc.output()),
+            self.expansion_output(false),
+        ]
+    }
+
+    // the mixed DAC level right now, without muted channels
+    pub fn output(&self) -> f32 {
+        let mut levels = self.raw_outputs();
+        for i in 0..5 {
+            if !self.audible[i] {
+                levels[i] = 0;
+            }
+        }
+        mix(levels[0], levels[1], levels[2], levels[3], levels[4]) + self.expansion_output(true)
+    }
+}
+
+// the 2A03's channels and frame counter, then the expansion chips
+impl Savestate for Envelope {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bool(&mut self.start);
+        s.bool(&mut self.looping);
+        s.bool(&mut self.constant);
+        s.u8(&mut self.period);
+        s.u8(&mut self.divider);
+        s.u8(&mut self.decay);
+    }
+}
+
+impl Savestate for LengthCounter {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bool(&mut self.enabled);
+        s.bool(&mut self.halted);
+        s.u8(&mut self.counter);
+    }
+}
+
+impl Savestate for Sweep {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bool(&mut self.enabled);
+        s.u8(&mut self.period);
+        s.bool(&mut self.negate);
+        s.u8(&mut self.shift);
+        s.u8(&mut self.divider);
+        s.bool(&mut self.reload);
+    }
+}
+
+impl Savestate for Pulse {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.u8(&mut self.duty);
+        s.u8(&mut self.duty_step);
+        s.u16(&mut self.timer_period);
+        s.u16(&mut self.timer);
+        self.envelope.state(s);
+        self.sweep.state(s);
+        self.length.state(s);
+    }
+}
+
+impl Savestate for Triangle {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.u16(&mut self.timer_period);
+        s.u16(&mut self.timer);
+        s.u8(&mut self.step);
+        s.bool(&mut self.control);
+        s.u8(&mut self.linear_reload_value);
+        s.u8(&mut self.linear_counter);
+        s.bool(&mut self.linear_reload);
+        self.length.state(s);
+    }
+}
+
+impl Savestate for Noise {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.u16(&mut self.timer_period);
+        s.u16(&mut self.timer);
+        s.bool(&mut self.short_mode);
+        s.u16(&mut self.shift_register);
+        self.envelope.state(s);
+        self.length.state(s);
+    }
+}
+
+impl Savestate for Dmc {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bool(&mut self.irq_enabled);
+        s.bool(&mut self.looping);
+        s.u16(&mut self.timer_period);
+        s.u16(&mut self.timer);
+        s.u16(&mut self.sample_address);
+        s.u16(&mut self.sample_length);
+        s.u16(&mut self.current_address);
+        s.u16(&mut self.bytes_remaining);
+        s.option_u8(&mut self.sample_buffer);
+        s.u8(&mut self.shift_register);
+        s.u8(&mut self.bits_remaining);
+        s.bool(&mut self.silence);
+        s.u8(&mut self.output_level);
+        s.bool(&mut self.irq_flag);
+    }
+}
+
+impl Savestate for FrameCounter {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.u32(&mut self.cycles);
+        s.bool(&mut self.five_step);
+        s.bool(&mut self.irq_inhibit);
+        s.bool(&mut self.irq_flag);
+        let (mut pending, mut delay, mut five_step) = match self.pending_write {
+            Some((delay, five_step)) => (true, delay, five_step),
+            None => (false, 0, false),
+        };
+        s.bool(&mut pending);
+        s.u8(&mut delay);
+        s.bool(&mut five_step);
+        self.pending_write = if pending { Some((delay, five_step)) } else { None };
+    }
+}
+
+impl Savestate for Apu {
+    fn state(&mut self, s: &mut StateBuffer) {
+        self.pulse1.state(s);
+        self.pulse2.state(s);
+        self.triangle.state(s);
+        self.noise.state(s);
+        self.dmc.state(s);
+        self.frame_counter.state(s);
+        s.u64(&mut self.cycles);
+        // the chips come with the cartridge, so only what's in them changes
+        for chip in self.expansion.iter_mut() {
+            chip.state(s);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn enabled_apu() -> Apu {
+        let mut apu = Apu::new(TvSystem::NTSC);
+        apu.write_register(0x4015, 0b1111);
+        apu
+    }
+
+    #[test]
+    fn test_length_counter_load_and_status() {
+        let mut apu = enabled_apu();
+        apu.write_register(0x4003, 0b0000_1000); // index 1 -> 254
+        assert_eq!(apu.pulse1.length.value(), 254);
+        assert_eq!(apu.read_status() & 0b11, 0b01);
+
+        apu.write_register(0x4015, 0b10);
+        assert_eq!(apu.read_status() & 0b11, 0);
+
+        // loads are ignored while the channel is disabled
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.pulse1.length.value(), 0);
+    }
+
+    #[test]
+    fn test_length_counter_clocks_on_half_frames() {
+        let mut apu = enabled_apu();
+        apu.write_register(0x4000, 0);
+        apu.write_register(0x4003, 0b0001_1000); // index 3 -> 2
+        for _ in 0..14913 {
+            apu.tick(1);
+        }
+        assert_eq!(apu.pulse1.length.value(), 1);
+        for _ in 14913..29829 {
+            apu.tick(1);
+        }
+        assert_eq!(apu.pulse1.length.value(), 0);
+        assert_eq!(apu.read_status() & 1, 0);
+    }
+
+    #[test]
+    fn test_length_counter_halt() {
+        let mut apu = enabled_apu();
+        apu.write_register(0x4000, 0b0010_0000);
+        apu.write_register(0x4003, 0b0001_1000);
+        for _ in 0..29830 * 2 {
+            apu.tick(1);
+        }
+        assert_eq!(apu.pulse1.length.value(), 2);
+    }
+
+    #[test]
+    fn test_envelope_decay() {
+        let mut envelope = Envelope::new();
+        envelope.write(0b0000_0001); // period 1, decaying
+        envelope.restart();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+        envelope.clock();
+        assert_eq!(envelope.volume(), 14);
+        for _ in 0..28 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume(), 0);
+        envelope.clock();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 0);
+    }
+
+    #[test]
+    fn test_envelope_loop_and_constant() {
+        let mut envelope = Envelope::new();
+        envelope.write(0b0010_0000); // loop, period 0
+        envelope.restart();
+        envelope.clock();
+        for _ in 0..15 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume(), 0);
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+
+        envelope.write(0b0001_0111);
+        assert_eq!(envelope.volume(), 7);
+    }
+
+    #[test]
+    fn test_sweep_negate_differs_between_pulses() {
+        let mut apu = enabled_apu();
+        for base in [0x4000u16, 0x4004] {
+            apu.write_register(base + 1, 0b1000_1001); // enabled, negate, shift 1
+            apu.write_register(base + 2, 0x00);
+            apu.write_register(base + 3, 0x01); // period 0x100
+        }
+        assert_eq!(apu.pulse1.sweep_target(), 0x100 - 0x80 - 1);
+        assert_eq!(apu.pulse2.sweep_target(), 0x100 - 0x80);
+    }
+
+    #[test]
+    fn test_sweep_updates_period() {
+        let mut pulse = Pulse::new(PulseChannel::Two);
+        pulse.length.set_enabled(true);
+        pulse.write_register(1, 0b1000_0001); // enabled, period 0, shift 1
+        pulse.write_register(2, 0x00);
+        pulse.write_register(3, 0x01);
+        pulse.clock_half_frame();
+        assert_eq!(pulse.timer_period(), 0x180);
+    }
+
+    #[test]
+    fn test_sweep_muting() {
+        let mut pulse = Pulse::new(PulseChannel::One);
+        pulse.length.set_enabled(true);
+        pulse.write_register(0, 0b1001_1111);
+        pulse.write_register(2, 0x07);
+        pulse.write_register(3, 0x08);
+        assert!(pulse.sweep_muting());
+
+        // an overflowing target mutes even with the sweep unit disabled
+        pulse.write_register(1, 0b0000_0000);
+        pulse.write_register(2, 0xFF);
+        pulse.write_register(3, 0x0F);
+        assert!(pulse.sweep_muting());
+        assert_eq!(pulse.output(), 0);
+
+        pulse.write_register(1, 0b0000_1000);
+        assert!(!pulse.sweep_muting());
+    }
+
+    #[test]
+    fn test_duty_sequence_output() {
+        let mut pulse = Pulse::new(PulseChannel::One);
+        pulse.length.set_enabled(true);
+        pulse.write_register(0, 0b1011_1010); // duty 2, constant volume 10
+        pulse.write_register(1, 0b0000_1000);
+        pulse.write_register(2, 8);
+        pulse.write_register(3, 0b0000_1000);
+
+        let mut wave = vec![];
+        for _ in 0..8 {
+            wave.push(pulse.output());
+            for _ in 0..=8 {
+                pulse.clock_timer();
+            }
+        }
+        assert_eq!(wave, vec![0, 10, 10, 10, 10, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_timer_runs_every_other_cpu_cycle() {
+        let mut apu = enabled_apu();
+        apu.write_register(0x4000, 0b0101_1111);
+        apu.write_register(0x4001, 0b0000_1000);
+        apu.write_register(0x4002, 8);
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.pulse1.output(), 0);
+        // period 8 -> the sequencer advances every 9 APU cycles = 18 CPU cycles
+        apu.tick(2);
+        assert_eq!(apu.pulse1.output(), 15);
+        apu.tick(18);
+        assert_eq!(apu.pulse1.output(), 15);
+        apu.tick(18);
+        assert_eq!(apu.pulse1.output(), 0);
+    }
+
+    #[test]
+    fn test_triangle_and_noise_status_bits() {
+        let mut apu = enabled_apu();
+        apu.write_register(0x400B, 0b0000_1000);
+        apu.write_register(0x400F, 0b0000_1000);
+        assert_eq!(apu.read_status() & 0b1100, 0b1100);
+        apu.write_register(0x4015, 0b0011);
+        assert_eq!(apu.read_status() & 0b1100, 0);
+    }
+
+    fn playing_triangle(period: u16) -> Triangle {
+        let mut triangle = Triangle::new();
+        triangle.length.set_enabled(true);
+        triangle.write_register(0, 0b0111_1111);
+        triangle.write_register(2, (period & 0xFF) as u8);
+        triangle.write_register(3, 0b0000_1000 | (period >> 8) as u8);
+        triangle.clock_quarter_frame();
+        triangle
+    }
+
+    #[test]
+    fn test_triangle_sequence() {
+        let mut triangle = playing_triangle(3);
+        let mut wave = vec![];
+        for _ in 0..32 {
+            wave.push(triangle.output());
+            for _ in 0..4 {
+                triangle.clock_timer();
+            }
+        }
+        let mut expected = vec![15];
+        expected.extend((0..15).rev());
+        expected.extend(0..16);
+        assert_eq!(wave, expected);
+    }
+
+    #[test]
+    fn test_triangle_linear_counter() {
+        let mut triangle = playing_triangle(3);
+        triangle.write_register(0, 0b0000_0010); // control off, reload 2
+        triangle.write_register(3, 0b0000_1000);
+        triangle.clock_quarter_frame();
+        assert_eq!(triangle.linear_counter(), 2);
+        triangle.clock_quarter_frame();
+        triangle.clock_quarter_frame();
+        assert_eq!(triangle.linear_counter(), 0);
+        // with the counter out the sequencer stops
+        let before = triangle.output();
+        for _ in 0..16 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), before);
+    }
+
+    #[test]
+    fn test_triangle_control_keeps_reloading() {
+        let mut triangle = playing_triangle(3);
+        triangle.write_register(0, 0b1000_0101);
+        triangle.write_register(3, 0b0000_1000);
+        for _ in 0..5 {
+            triangle.clock_quarter_frame();
+        }
+        assert_eq!(triangle.linear_counter(), 5);
+    }
+
+    #[test]
+    fn test_triangle_ultrasonic_silencing() {
+        let mut triangle = playing_triangle(1);
+        for _ in 0..10 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), 15);
+
+        triangle.silence_ultrasonic = false;
+        for _ in 0..2 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), 14);
+    }
+
+    fn lfsr_loop_length(short_mode: bool) -> usize {
+        let mut noise = Noise::new(TvSystem::NTSC);
+        noise.write_register(2, if short_mode { 0x80 } else { 0 });
+        let start = noise.shift_register;
+        let mut steps = 0;
+        loop {
+            for _ in 0..noise.timer_period() {
+                noise.clock_timer();
+            }
+            steps += 1;
+            if noise.shift_register == start {
+                return steps;
+            }
+        }
+    }
+
+    #[test]
+    fn test_noise_lfsr_periods() {
+        assert_eq!(lfsr_loop_length(false), 32767);
+        assert_eq!(lfsr_loop_length(true), 93);
+    }
+
+    #[test]
+    fn test_noise_period_tables() {
+        let mut ntsc = Noise::new(TvSystem::NTSC);
+        let mut pal = Noise::new(TvSystem::PAL);
+        ntsc.write_register(2, 0x0F);
+        pal.write_register(2, 0x0F);
+        assert_eq!(ntsc.timer_period(), 4068);
+        assert_eq!(pal.timer_period(), 3778);
+        pal.write_register(2, 0x02);
+        assert_eq!(pal.timer_period(), 14);
+    }
+
+    #[test]
+    fn test_noise_output_uses_envelope() {
+        let mut apu = enabled_apu();
+        apu.write_register(0x400C, 0b0001_1001);
+        apu.write_register(0x400E, 0);
+        apu.write_register(0x400F, 0b0000_1000);
+        let mut seen = [false; 16];
+        for _ in 0..2000 {
+            apu.tick(1);
+            seen[apu.noise.output() as usize] = true;
+        }
+        assert!(seen[0] && seen[9]);
+        assert_eq!(seen.iter().filter(|s| **s).count(), 2);
+    }
+
+    fn dmc_with_sample(length: u8, control: u8) -> Dmc {
+        let mut dmc = Dmc::new(TvSystem::NTSC);
+        dmc.write_register(0, control);
+        dmc.write_register(2, 0x10); // $C400
+        dmc.write_register(3, length);
+        dmc.set_enabled(true);
+        dmc
+    }
+
+    #[test]
+    fn test_dmc_sample_address_and_length() {
+        let mut dmc = dmc_with_sample(1, 0);
+        assert_eq!(dmc.dma_request(), Some(0xC400));
+        for i in 0..17 {
+            assert_eq!(dmc.dma_request(), Some(0xC400 + i));
+            dmc.load_sample(0);
+            assert_eq!(dmc.dma_request(), None);
+            dmc.sample_buffer = None;
+        }
+        assert!(!dmc.active());
+        assert_eq!(dmc.dma_request(), None);
+    }
+
+    #[test]
+    fn test_dmc_address_wraps_to_8000() {
+        let mut dmc = Dmc::new(TvSystem::NTSC);
+        dmc.write_register(2, 0xFF); // $FFC0
+        dmc.write_register(3, 4); // 65 bytes
+        dmc.set_enabled(true);
+        for _ in 0..0x40 {
+            dmc.load_sample(0);
+            dmc.sample_buffer = None;
+        }
+        assert_eq!(dmc.dma_request(), Some(0x8000));
+    }
+
+    #[test]
+    fn test_dmc_irq_and_loop() {
+        let mut dmc = dmc_with_sample(0, 0b1000_0000);
+        dmc.load_sample(0);
+        assert!(dmc.irq_flag);
+        dmc.set_enabled(true);
+        assert!(!dmc.irq_flag);
+
+        let mut looping = dmc_with_sample(0, 0b1100_0000);
+        looping.load_sample(0);
+        assert!(!looping.irq_flag);
+        assert!(looping.active());
+    }
+
+    #[test]
+    fn test_dmc_irq_disable_clears_flag() {
+        let mut dmc = dmc_with_sample(0, 0b1000_0000);
+        dmc.load_sample(0);
+        dmc.write_register(0, 0);
+        assert!(!dmc.irq_flag);
+    }
+
+    #[test]
+    fn test_dmc_output_unit() {
+        let mut dmc = dmc_with_sample(0, 0x0F);
+        dmc.write_register(1, 64);
+        dmc.load_sample(0b0000_0101);
+        let period = dmc.timer_period();
+        // the first 8 clocks play out the silent initial shift register
+        for _ in 0..8 * period {
+            dmc.clock_timer();
+        }
+        assert_eq!(dmc.output(), 64);
+        let mut levels = vec![];
+        for _ in 0..8 {
+            for _ in 0..period {
+                dmc.clock_timer();
+            }
+            levels.push(dmc.output());
+        }
+        assert_eq!(levels, vec![66, 64, 66, 64, 62, 60, 58, 56]);
+    }
+
+    #[test]
+    fn test_dmc_status_bits() {
+        let mut apu = enabled_apu();
+        apu.write_register(0x4010, 0b1000_0000);
+        apu.write_register(0x4013, 0);
+        apu.write_register(0x4015, 0b1_0000);
+        assert_eq!(apu.read_status() & 0b1_0000, 0b1_0000);
+        apu.dmc.load_sample(0);
+        assert_eq!(apu.read_status() & 0b1001_0000, 0b1000_0000);
+        assert!(apu.irq());
+        apu.write_register(0x4015, 0);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_dmc_rate_tables() {
+        let mut ntsc = Dmc::new(TvSystem::NTSC);
+        let mut pal = Dmc::new(TvSystem::PAL);
+        ntsc.write_register(0, 0x0F);
+        pal.write_register(0, 0x0F);
+        assert_eq!(ntsc.timer_period(), 54);
+        assert_eq!(pal.timer_period(), 50);
+    }
+
+    #[test]
+    fn test_note_names() {
+        assert_eq!(note_name(440.0), Some("A4".to_string()));
+        assert_eq!(note_name(261.63), Some("C4".to_string()));
+        assert_eq!(note_name(277.0), Some("C#4".to_string()));
+        assert_eq!(note_name(5.0), None);
+    }
+
+    fn playing_pulse() -> Apu {
+        let mut apu = enabled_apu();
+        apu.write_register(0x4000, 0b1011_1111); // 50% duty, constant volume 15
+        apu.write_register(0x4002, 253);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu
+    }
+
+    #[test]
+    fn test_channel_state_reports_note() {
+        let apu = playing_pulse();
+        let state = apu.channel_state(Channel::Pulse1);
+        assert_eq!(state.period, 253);
+        assert!((state.frequency - 440.4).abs() < 0.1);
+        assert_eq!(state.note, Some("A4".to_string()));
+        assert_eq!(state.volume, 15);
+        assert!(state.active);
+        assert!(!apu.channel_state(Channel::Pulse2).active);
+        assert_eq!(apu.channel_state(Channel::Noise).note, None);
+    }
+
+    fn peak_output(apu: &mut Apu) -> f32 {
+        let mut peak: f32 = 0.0;
+        for _ in 0..2000 {
+            apu.tick(1);
+            peak = peak.max(apu.output());
+        }
+        peak
+    }
+
+    #[test]
+    fn test_mute_and_solo() {
+        let mut apu = playing_pulse();
+        assert!(peak_output(&mut apu) > 0.0);
+
+        // only the idle triangle's held step is left
+        apu.set_muted(Channel::Pulse1, true);
+        assert_eq!(peak_output(&mut apu), mix(0, 0, 15, 0, 0));
+        apu.set_muted(Channel::Pulse1, false);
+
+        // soloing another channel silences everything else
+        apu.set_solo(Channel::Noise, true);
+        assert!(!apu.audible(Channel::Pulse1));
+        assert_eq!(peak_output(&mut apu), 0.0);
+        apu.set_solo(Channel::Pulse1, true);
+        assert!(peak_output(&mut apu) > 0.0);
+        assert!(apu.channel_state(Channel::Pulse1).solo);
+    }
+
+    #[test]
+    fn test_scope_keeps_recent_levels() {
+        let mut apu = playing_pulse();
+        assert!(apu.waveform(Channel::Pulse1).is_empty());
+        apu.set_scope(true);
+        apu.tick(200);
+        assert_eq!(apu.waveform(Channel::Pulse1).len(), 4);
+        for _ in 0..1000 {
+            apu.tick(100);
+        }
+        let wave = apu.waveform(Channel::Pulse1);
+        assert_eq!(wave.len(), SCOPE_LENGTH);
+        assert!(wave.contains(&15) && wave.contains(&0));
+        // muting doesn't hide a channel from the scope
+        apu.set_muted(Channel::Pulse1, true);
+        apu.tick(255);
+        assert!(apu.waveform(Channel::Pulse1).iter().any(|l| *l == 15));
+    }
+
+    // writes $4017 on an even cycle and returns the cycle the sequence restarts on
+    fn write_frame_counter(apu: &mut Apu, data: u8) {
+        if apu.cycles % 2 == 1 {
+            apu.tick(1);
+        }
+        apu.write_register(0x4017, data);
+        apu.tick(3);
+    }
+
+    fn cycles_until_irq(apu: &mut Apu, limit: u32) -> Option<u32> {
+        for cycle in 1..=limit {
+            apu.tick(1);
+            if apu.irq() {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_frame_irq_four_step() {
+        let mut apu = enabled_apu();
+        write_frame_counter(&mut apu, 0);
+        assert_eq!(cycles_until_irq(&mut apu, 40000), Some(29828));
+        assert_eq!(apu.read_status() & 0b0100_0000, 0b0100_0000);
+        assert!(!apu.irq());
+        // the flag is raised again on the next two cycles
+        apu.tick(1);
+        assert!(apu.irq());
+        apu.read_status();
+        apu.tick(1);
+        assert!(apu.irq());
+        apu.read_status();
+        apu.tick(1);
+        assert!(!apu.irq());
+        // and then once per sequence
+        assert_eq!(cycles_until_irq(&mut apu, 40000), Some(29830 - 3));
+    }
+
+    #[test]
+    fn test_frame_irq_inhibit() {
+        let mut apu = enabled_apu();
+        write_frame_counter(&mut apu, 0);
+        cycles_until_irq(&mut apu, 40000);
+        assert!(apu.irq());
+        apu.write_register(0x4017, 0b0100_0000);
+        assert!(!apu.irq());
+        assert_eq!(cycles_until_irq(&mut apu, 70000), None);
+    }
+
+    #[test]
+    fn test_five_step_has_no_irq() {
+        let mut apu = enabled_apu();
+        write_frame_counter(&mut apu, 0b1000_0000);
+        assert_eq!(cycles_until_irq(&mut apu, 80000), None);
+    }
+
+    #[test]
+    fn test_five_step_clocks_immediately() {
+        let mut apu = enabled_apu();
+        apu.write_register(0x4003, 0b0001_1000); // length 2
+        write_frame_counter(&mut apu, 0b1000_0000);
+        assert_eq!(apu.pulse1.length.value(), 1);
+
+        let mut apu = enabled_apu();
+        apu.write_register(0x4003, 0b0001_1000);
+        write_frame_counter(&mut apu, 0);
+        assert_eq!(apu.pulse1.length.value(), 2);
+    }
+
+    fn half_frame_cycles(data: u8) -> Vec<u32> {
+        let mut apu = enabled_apu();
+        apu.write_register(0x4000, 0);
+        apu.write_register(0x4003, 0b1111_1000); // length 30
+        write_frame_counter(&mut apu, data);
+        let mut clocks = vec![];
+        let mut length = apu.pulse1.length.value();
+        for cycle in 1..=45000 {
+            apu.tick(1);
+            if apu.pulse1.length.value() != length {
+                length = apu.pulse1.length.value();
+                clocks.push(cycle);
+            }
+        }
+        clocks
+    }
+
+    #[test]
+    fn test_length_clock_timing() {
+        assert_eq!(half_frame_cycles(0), vec![14913, 29829, 29830 + 14913]);
+        assert_eq!(half_frame_cycles(0b1000_0000)[0..2], [14913, 37281]);
+    }
+
+    #[test]
+    fn test_frame_counter_write_jitter() {
+        let mut even = enabled_apu();
+        even.write_register(0x4017, 0);
+        let even_irq = cycles_until_irq(&mut even, 40000).unwrap();
+
+        let mut odd = enabled_apu();
+        odd.tick(1);
+        odd.write_register(0x4017, 0);
+        let odd_irq = cycles_until_irq(&mut odd, 40000).unwrap();
+
+        assert_eq!(even_irq, 29828 + 3);
+        assert_eq!(odd_irq, 29828 + 4);
+    }
+
+    #[test]
+    fn test_pal_frame_irq() {
+        let mut apu = Apu::new(TvSystem::PAL);
+        write_frame_counter(&mut apu, 0);
+        assert_eq!(cycles_until_irq(&mut apu, 40000), Some(33252));
+    }
+}
+
 
 
 mod testingTime {
@@ -0,0 +1,550 @@
+use std::f64::consts::PI;
+
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+use lazy_static::lazy_static;
+
+use crate::cartridge::TvSystem;
+use crate::wav::*;
+
+pub const NTSC_CPU_CLOCK: f64 = 1789772.7272;
+pub const PAL_CPU_CLOCK: f64 = 1662607.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// the three RC stages between the DACs and the output jack
+const HIGH_PASS_1_HZ: f64 = 90.0;
+const HIGH_PASS_2_HZ: f64 = 440.0;
+const LOW_PASS_HZ: f64 = 14000.0;
+
+pub fn cpu_clock(tv_system: TvSystem) -> f64 {
+    match tv_system {
+        TvSystem::NTSC => NTSC_CPU_CLOCK,
+        TvSystem::PAL => PAL_CPU_CLOCK,
+    }
+}
+
+// anything that takes the mono output, in -1.0..1.0 at sample_rate()
+pub trait AudioSink {
+    fn sample_rate(&self) -> u32;
+    fn push_samples(&mut self, samples: &[f32]);
+
+    // samples still waiting to be played, for sinks backed by a real device
+    fn queued_samples(&self) -> Option<usize> {
+        None
+    }
+}
+
+// keeps everything in memory, for tests
+#[cfg(test)]
+pub struct BufferSink {
+    pub sample_rate: u32,
+    pub samples: Vec<f32>,
+}
+
+#[cfg(test)]
+impl BufferSink {
+    pub fn new(sample_rate: u32) -> Self {
+        BufferSink { sample_rate: sample_rate, samples: vec![] }
+    }
+}
+
+#[cfg(test)]
+impl AudioSink for BufferSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push_samples(&mut self, samples: &[f32]) {
+        self.samples.extend_from_slice(samples);
+    }
+}
+
+// SDL audio queue, the device pulls from it on its own thread
+pub struct QueueSink {
+    pub queue: AudioQueue<f32>,
+}
+
+impl QueueSink {
+    pub fn open(audio: &AudioSubsystem, sample_rate: u32) -> Result<QueueSink, String> {
+        let spec = AudioSpecDesired {
+            freq: Some(sample_rate as i32),
+            channels: Some(1),
+            samples: Some(1024),
+        };
+        let queue = audio.open_queue::<f32, _>(None, &spec)?;
+        queue.resume();
+        Ok(QueueSink { queue: queue })
+    }
+}
+
+impl AudioSink for QueueSink {
+    fn sample_rate(&self) -> u32 {
+        self.queue.spec().freq as u32
+    }
+
+    fn push_samples(&mut self, samples: &[f32]) {
+        self.queue.queue(samples);
+    }
+
+    fn queued_samples(&self) -> Option<usize> {
+        Some(self.queue.size() as usize / std::mem::size_of::<f32>())
+    }
+}
+
+lazy_static! {
+    // output = 95.52 / (8128 / (pulse1 + pulse2) + 100)
+    static ref PULSE_TABLE: [f32; 31] = {
+        let mut table = [0.0; 31];
+        for n in 1..31 {
+            table[n] = (95.52 / (8128.0 / n as f64 + 100.0)) as f32;
+        }
+        table
+    };
+    // output = 163.67 / (24329 / (3 * triangle + 2 * noise + dmc) + 100)
+    static ref TND_TABLE: [f32; 203] = {
+        let mut table = [0.0; 203];
+        for n in 1..203 {
+            table[n] = (163.67 / (24329.0 / n as f64 + 100.0)) as f32;
+        }
+        table
+    };
+}
+
+// the nonlinear DACs, channel levels in, 0.0..~1.0 out
+pub fn mix(pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+    let pulse = PULSE_TABLE[(pulse1 + pulse2) as usize];
+    let tnd = TND_TABLE[3 * triangle as usize + 2 * noise as usize + dmc as usize];
+    pulse + tnd
+}
+
+// first order RC high pass
+pub struct HighPass {
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl HighPass {
+    pub fn new(cutoff: f64, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f64;
+        HighPass { alpha: (rc / (rc + dt)) as f32, prev_in: 0.0, prev_out: 0.0 }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.prev_out = self.alpha * (self.prev_out + input - self.prev_in);
+        self.prev_in = input;
+        self.prev_out
+    }
+}
+
+// first order RC low pass
+pub struct LowPass {
+    alpha: f32,
+    prev_out: f32,
+}
+
+impl LowPass {
+    pub fn new(cutoff: f64, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f64;
+        LowPass { alpha: (dt / (rc + dt)) as f32, prev_out: 0.0 }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.prev_out += self.alpha * (input - self.prev_out);
+        self.prev_out
+    }
+}
+
+const BLIP_TAPS: usize = 16;
+const BLIP_PHASES: usize = 64;
+// fraction of the output rate the steps are band limited to, a bit under Nyquist
+const BLIP_CUTOFF: f64 = 0.45;
+
+// windowed sinc impulse for every fractional sample position, each row sums to 1 so a
+// step of height d adds up to exactly d once the buffer is integrated
+fn blip_kernel() -> Vec<[f32; BLIP_TAPS]> {
+    (0..BLIP_PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / BLIP_PHASES as f64;
+            let mut row = [0.0f64; BLIP_TAPS];
+            for k in 0..BLIP_TAPS {
+                let t = k as f64 - offset - (BLIP_TAPS / 2 - 1) as f64;
+                let x = 2.0 * BLIP_CUTOFF * t;
+                let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                // blackman window over the whole kernel
+                let w = (t + BLIP_TAPS as f64 / 2.0) / BLIP_TAPS as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                row[k] = sinc * window.max(0.0);
+            }
+            let sum: f64 = row.iter().sum();
+            let mut out = [0.0f32; BLIP_TAPS];
+            for k in 0..BLIP_TAPS {
+                out[k] = (row[k] / sum) as f32;
+            }
+            out
+        })
+        .collect()
+}
+
+// band-limited step synthesis: level changes at CPU clock times go in as deltas, output
+// samples come out by integrating, so nothing above the output Nyquist aliases back in
+pub struct BlipBuffer {
+    clock_rate: f64,
+    sample_rate: u32,
+    factor: f64,
+    kernel: Vec<[f32; BLIP_TAPS]>,
+    buffer: Vec<f32>,
+    // output position of clock 0 of the current frame, in samples
+    offset: f64,
+    integrator: f32,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Self {
+        BlipBuffer {
+            clock_rate: clock_rate,
+            sample_rate: sample_rate,
+            factor: sample_rate as f64 / clock_rate,
+            kernel: blip_kernel(),
+            buffer: vec![0.0; BLIP_TAPS],
+            offset: 0.0,
+            integrator: 0.0,
+        }
+    }
+
+    // bends the output rate by `ratio` without touching what is already buffered,
+    // for dynamic rate control
+    pub fn set_rate_adjust(&mut self, ratio: f64) {
+        self.factor = self.sample_rate as f64 * ratio / self.clock_rate;
+    }
+
+    pub fn add_delta(&mut self, clock: u64, delta: f32) {
+        let pos = self.offset + clock as f64 * self.factor;
+        let index = pos as usize;
+        let phase = ((pos - index as f64) * BLIP_PHASES as f64) as usize;
+        if self.buffer.len() < index + BLIP_TAPS {
+            self.buffer.resize(index + BLIP_TAPS, 0.0);
+        }
+        let row = &self.kernel[phase.min(BLIP_PHASES - 1)];
+        for k in 0..BLIP_TAPS {
+            self.buffer[index + k] += delta * row[k];
+        }
+    }
+
+    // closes the current frame after `clocks` CPU cycles, the next frame starts at clock 0
+    pub fn end_frame(&mut self, clocks: u64) {
+        self.offset += clocks as f64 * self.factor;
+        let needed = self.offset as usize + BLIP_TAPS;
+        if self.buffer.len() < needed {
+            self.buffer.resize(needed, 0.0);
+        }
+    }
+
+    pub fn samples_available(&self) -> usize {
+        self.offset as usize
+    }
+
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let count = self.samples_available();
+        for sample in self.buffer.drain(..count) {
+            self.integrator += sample;
+            out.push(self.integrator);
+        }
+        self.offset -= count as f64;
+    }
+}
+
+// per-channel recordings put all cartridge channels in one "expansion" stream
+pub const CHANNEL_NAMES: [&str; 6] = ["pulse1", "pulse2", "triangle", "noise", "dmc", "expansion"];
+
+// one level signal resampled and run through the output stage filters
+struct Stream {
+    blip: BlipBuffer,
+    high_pass_1: HighPass,
+    high_pass_2: HighPass,
+    low_pass: LowPass,
+    level: f32,
+    samples: Vec<f32>,
+}
+
+impl Stream {
+    fn new(clock_rate: f64, sample_rate: u32) -> Self {
+        Stream {
+            blip: BlipBuffer::new(clock_rate, sample_rate),
+            high_pass_1: HighPass::new(HIGH_PASS_1_HZ, sample_rate),
+            high_pass_2: HighPass::new(HIGH_PASS_2_HZ, sample_rate),
+            low_pass: LowPass::new(LOW_PASS_HZ, sample_rate),
+            level: 0.0,
+            samples: vec![],
+        }
+    }
+
+    fn add_level(&mut self, clock: u64, level: f32) {
+        if level != self.level {
+            self.blip.add_delta(clock, level - self.level);
+            self.level = level;
+        }
+    }
+
+    fn end_frame(&mut self, clocks: u64) {
+        self.blip.end_frame(clocks);
+        let mut raw = vec![];
+        self.blip.read_samples(&mut raw);
+        for sample in raw {
+            let sample = self.high_pass_1.process(sample);
+            let sample = self.high_pass_2.process(sample);
+            self.samples.push(self.low_pass.process(sample));
+        }
+    }
+}
+
+// mixed APU level in, filtered samples at the output rate out. Per-channel streams are
+// only run while something (a per-channel recording) asks for them.
+pub struct AudioPipeline {
+    clock_rate: f64,
+    sample_rate: u32,
+    mixed: Stream,
+    channels: Option<Vec<Stream>>,
+    clock: u64,
+    recorder: Option<WavRecorder>,
+}
+
+impl AudioPipeline {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Self {
+        AudioPipeline {
+            clock_rate: clock_rate,
+            sample_rate: sample_rate,
+            mixed: Stream::new(clock_rate, sample_rate),
+            channels: None,
+            clock: 0,
+            recorder: None,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_rate_adjust(&mut self, ratio: f64) {
+        self.mixed.blip.set_rate_adjust(ratio);
+        for stream in self.channels.iter_mut().flatten() {
+            stream.blip.set_rate_adjust(ratio);
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            self.mixed = Stream::new(self.clock_rate, sample_rate);
+            self.set_channel_streams(self.channels.is_some());
+        }
+    }
+
+    pub fn set_channel_streams(&mut self, enabled: bool) {
+        self.channels = if enabled {
+            Some(CHANNEL_NAMES.iter().map(|_| Stream::new(self.clock_rate, self.sample_rate)).collect())
+        } else {
+            None
+        };
+    }
+
+    pub fn channel_streams(&self) -> bool {
+        self.channels.is_some()
+    }
+
+    // each channel's level through the mixer on its own, before add_level for the cycle
+    pub fn add_channel_levels(&mut self, levels: &[f32; 6]) {
+        if let Some(channels) = self.channels.as_mut() {
+            for (stream, level) in channels.iter_mut().zip(levels.iter()) {
+                stream.add_level(self.clock, *level);
+            }
+        }
+    }
+
+    // the mixed level for one CPU cycle
+    pub fn add_level(&mut self, level: f32) {
+        self.mixed.add_level(self.clock, level);
+        self.clock += 1;
+    }
+
+    // resamples and filters everything since the last call
+    pub fn end_frame(&mut self) {
+        let start = self.mixed.samples.len();
+        self.mixed.end_frame(self.clock);
+        for stream in self.channels.iter_mut().flatten() {
+            stream.end_frame(self.clock);
+        }
+        self.clock = 0;
+
+        let channels: Vec<Vec<f32>> = self
+            .channels
+            .iter_mut()
+            .flatten()
+            .map(|stream| std::mem::replace(&mut stream.samples, vec![]))
+            .collect();
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.write(&self.mixed.samples[start..], &channels) {
+                println!("audio recording stopped: {}", e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    pub fn drain(&mut self, sink: &mut dyn AudioSink) {
+        sink.push_samples(&self.mixed.samples);
+        self.mixed.samples.clear();
+    }
+
+    pub fn discard(&mut self) {
+        self.mixed.samples.clear();
+    }
+
+    pub fn start_recording(&mut self, path: &str, per_channel: bool) -> Result<(), String> {
+        self.recorder = Some(WavRecorder::start(path, self.sample_rate, per_channel)?);
+        if per_channel {
+            self.set_channel_streams(true);
+        }
+        Ok(())
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // returns how many samples went into the mixed file
+    pub fn stop_recording(&mut self) -> Result<usize, String> {
+        let recorder = self.recorder.take().ok_or("not recording".to_string())?;
+        if recorder.per_channel() {
+            self.set_channel_streams(false);
+        }
+        recorder.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mixer_tables() {
+        assert_eq!(mix(0, 0, 0, 0, 0), 0.0);
+        assert!((mix(15, 15, 0, 0, 0) - 0.2585).abs() < 0.001);
+        assert!((mix(0, 0, 15, 15, 127) - 0.7415).abs() < 0.001);
+        // the DACs compress: two pulses at 15 are less than twice one pulse
+        assert!(mix(15, 15, 0, 0, 0) < 2.0 * mix(15, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_kernel_rows_sum_to_one() {
+        for row in blip_kernel() {
+            let sum: f32 = row.iter().sum();
+            assert!((sum - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_blip_step_settles() {
+        let mut blip = BlipBuffer::new(NTSC_CPU_CLOCK, 44100);
+        blip.add_delta(1000, 0.5);
+        blip.end_frame(29780);
+        let mut out = vec![];
+        blip.read_samples(&mut out);
+        assert_eq!(out.len(), 733);
+        assert_eq!(out[0], 0.0);
+        assert!((out[100] - 0.5).abs() < 1e-4);
+        // band limiting rings around the edge (Gibbs) but doesn't blow up
+        assert!(out.iter().all(|s| *s > -0.07 && *s < 0.57));
+    }
+
+    #[test]
+    fn test_sample_count_tracks_clock() {
+        let mut pipeline = AudioPipeline::new(NTSC_CPU_CLOCK, 48000);
+        let mut sink = BufferSink::new(48000);
+        for _ in 0..60 {
+            for _ in 0..29830 {
+                pipeline.add_level(0.0);
+            }
+            pipeline.end_frame();
+            pipeline.drain(&mut sink);
+        }
+        let expected = 60.0 * 29830.0 * 48000.0 / NTSC_CPU_CLOCK;
+        assert!((sink.samples.len() as f64 - expected).abs() <= 1.0);
+    }
+
+    #[test]
+    fn test_rate_adjust_bends_sample_count() {
+        let mut pipeline = AudioPipeline::new(NTSC_CPU_CLOCK, 44100);
+        let mut sink = BufferSink::new(44100);
+        pipeline.set_rate_adjust(1.005);
+        for _ in 0..NTSC_CPU_CLOCK as usize {
+            pipeline.add_level(0.0);
+        }
+        pipeline.end_frame();
+        pipeline.drain(&mut sink);
+        assert!((sink.samples.len() as i32 - 44320).abs() <= 1, "{}", sink.samples.len());
+    }
+
+    #[test]
+    fn test_recording_per_channel() {
+        let path = std::env::temp_dir().join(format!("nes-audio-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut pipeline = AudioPipeline::new(NTSC_CPU_CLOCK, 44100);
+        pipeline.start_recording(path, true).unwrap();
+        assert!(pipeline.channel_streams());
+        for cycle in 0..29780 {
+            let level = if cycle % 2000 < 1000 { 0.2 } else { 0.0 };
+            pipeline.add_channel_levels(&[0.0, 0.0, level, 0.0, 0.0, 0.0]);
+            pipeline.add_level(level);
+        }
+        pipeline.end_frame();
+        assert_eq!(pipeline.stop_recording().unwrap(), 733);
+        assert!(!pipeline.channel_streams());
+
+        // the triangle file carries the whole signal, the silent pulse file nothing
+        let mixed = std::fs::read(path).unwrap();
+        let triangle = std::fs::read(channel_path(path, "triangle")).unwrap();
+        let pulse = std::fs::read(channel_path(path, "pulse1")).unwrap();
+        assert_eq!(mixed, triangle);
+        assert!(pulse[44..].iter().all(|b| *b == 0));
+        std::fs::remove_file(path).unwrap();
+        for name in CHANNEL_NAMES.iter() {
+            std::fs::remove_file(channel_path(path, name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_high_pass_removes_dc() {
+        let mut pipeline = AudioPipeline::new(NTSC_CPU_CLOCK, 44100);
+        let mut sink = BufferSink::new(44100);
+        for _ in 0..NTSC_CPU_CLOCK as usize / 4 {
+            pipeline.add_level(0.5);
+        }
+        pipeline.end_frame();
+        pipeline.drain(&mut sink);
+        assert!(sink.samples[20] > 0.1);
+        assert!(sink.samples.last().unwrap().abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_square_wave_frequency() {
+        let mut pipeline = AudioPipeline::new(NTSC_CPU_CLOCK, 44100);
+        let mut sink = BufferSink::new(44100);
+        // 1 kHz square wave
+        let half_period = (NTSC_CPU_CLOCK / 2000.0) as usize;
+        for cycle in 0..NTSC_CPU_CLOCK as usize / 2 {
+            pipeline.add_level(if (cycle / half_period) % 2 == 0 { 0.3 } else { 0.0 });
+        }
+        pipeline.end_frame();
+        pipeline.drain(&mut sink);
+        // skip the ringing at the very first edge, then measure the spacing of rising edges
+        let rising: Vec<usize> = sink.samples.windows(2).enumerate()
+            .skip(20)
+            .filter(|(_, w)| w[0] < 0.0 && w[1] >= 0.0)
+            .map(|(i, _)| i)
+            .collect();
+        let period = (rising[rising.len() - 1] - rising[0]) as f64 / (rising.len() - 1) as f64;
+        assert!((period - 44.1).abs() < 0.1, "period {}", period);
+    }
+}
@@ -0,0 +1,562 @@
+use sdl2::controller::{Axis, Button};
+use sdl2::keyboard::Keycode;
+
+use crate::config::*;
+use crate::joypad::JoypadButtons;
+use crate::pad_input::PadButton;
+use crate::playback::Hotkey;
+
+pub const PLAYERS: usize = 4;
+// how far a stick or trigger has to travel, as a fraction of its range, to count as pressed
+pub const DEFAULT_AXIS_THRESHOLD: f32 = 0.5;
+pub const DEFAULT_BINDINGS_PATH: &str = "input.toml";
+
+// the pad's buttons in the order the config file and the rebinding screen list them
+pub const BUTTONS: [(&str, PadButton); 10] = [
+    ("up", PadButton::Normal(JoypadButtons::UP)),
+    ("down", PadButton::Normal(JoypadButtons::DOWN)),
+    ("left", PadButton::Normal(JoypadButtons::LEFT)),
+    ("right", PadButton::Normal(JoypadButtons::RIGHT)),
+    ("select", PadButton::Normal(JoypadButtons::SELECT)),
+    ("start", PadButton::Normal(JoypadButtons::START)),
+    ("b", PadButton::Normal(JoypadButtons::BUTTON_B)),
+    ("a", PadButton::Normal(JoypadButtons::BUTTON_A)),
+    ("turbo_b", PadButton::Turbo(JoypadButtons::BUTTON_B)),
+    ("turbo_a", PadButton::Turbo(JoypadButtons::BUTTON_A)),
+];
+
+// the [hotkeys] section's entries
+pub const HOTKEYS: [(&str, Hotkey); 27] = [
+    ("pause", Hotkey::Pause),
+    ("frame_advance", Hotkey::FrameAdvance),
+    ("fast_forward", Hotkey::FastForward),
+    ("slow_motion", Hotkey::SlowMotion),
+    ("rewind", Hotkey::Rewind),
+    ("soft_reset", Hotkey::SoftReset),
+    ("power_cycle", Hotkey::PowerCycle),
+    ("rebind", Hotkey::Rebind),
+    ("record_audio", Hotkey::RecordAudio),
+    ("record_video", Hotkey::RecordVideo),
+    ("screenshot", Hotkey::Screenshot),
+    ("palette", Hotkey::Palette),
+    ("ntsc", Hotkey::Ntsc),
+    ("overscan", Hotkey::Overscan),
+    ("aspect", Hotkey::Aspect),
+    ("visualizer", Hotkey::Visualizer),
+    ("filter", Hotkey::Filter),
+    ("channel1", Hotkey::Channel(0)),
+    ("channel2", Hotkey::Channel(1)),
+    ("channel3", Hotkey::Channel(2)),
+    ("channel4", Hotkey::Channel(3)),
+    ("channel5", Hotkey::Channel(4)),
+    ("channel6", Hotkey::Channel(5)),
+    ("channel7", Hotkey::Channel(6)),
+    ("channel8", Hotkey::Channel(7)),
+    ("channel9", Hotkey::Channel(8)),
+    ("channel10", Hotkey::Channel(9)),
+];
+// kept for quitting, and for backing out of the rebinding screen
+const QUIT_KEY: Keycode = Keycode::Escape;
+
+// keys without a single-character name, named the way SDL names them
+const NAMED_KEYS: [(&str, Keycode); 49] = [
+    ("Up", Keycode::Up), ("Down", Keycode::Down), ("Left", Keycode::Left), ("Right", Keycode::Right),
+    ("Space", Keycode::Space), ("Return", Keycode::Return), ("Backspace", Keycode::Backspace),
+    ("Tab", Keycode::Tab), ("Escape", Keycode::Escape),
+    ("Left Shift", Keycode::LShift), ("Right Shift", Keycode::RShift),
+    ("Left Ctrl", Keycode::LCtrl), ("Right Ctrl", Keycode::RCtrl),
+    ("Left Alt", Keycode::LAlt), ("Right Alt", Keycode::RAlt),
+    ("Insert", Keycode::Insert), ("Delete", Keycode::Delete), ("Home", Keycode::Home), ("End", Keycode::End),
+    ("PageUp", Keycode::PageUp), ("PageDown", Keycode::PageDown),
+    ("F1", Keycode::F1), ("F2", Keycode::F2), ("F3", Keycode::F3), ("F4", Keycode::F4),
+    ("F5", Keycode::F5), ("F6", Keycode::F6), ("F7", Keycode::F7), ("F8", Keycode::F8),
+    ("F9", Keycode::F9), ("F10", Keycode::F10), ("F11", Keycode::F11), ("F12", Keycode::F12),
+    ("Keypad 0", Keycode::Kp0), ("Keypad 1", Keycode::Kp1), ("Keypad 2", Keycode::Kp2), ("Keypad 3", Keycode::Kp3),
+    ("Keypad 4", Keycode::Kp4), ("Keypad 5", Keycode::Kp5), ("Keypad 6", Keycode::Kp6), ("Keypad 7", Keycode::Kp7),
+    ("Keypad 8", Keycode::Kp8), ("Keypad 9", Keycode::Kp9),
+    ("Keypad +", Keycode::KpPlus), ("Keypad -", Keycode::KpMinus), ("Keypad *", Keycode::KpMultiply),
+    ("Keypad /", Keycode::KpDivide), ("Keypad Enter", Keycode::KpEnter), ("Keypad .", Keycode::KpPeriod),
+];
+
+// SDL's game controller mapping names
+const CONTROLLER_BUTTONS: [(&str, Button); 15] = [
+    ("a", Button::A), ("b", Button::B), ("x", Button::X), ("y", Button::Y),
+    ("back", Button::Back), ("guide", Button::Guide), ("start", Button::Start),
+    ("leftstick", Button::LeftStick), ("rightstick", Button::RightStick),
+    ("leftshoulder", Button::LeftShoulder), ("rightshoulder", Button::RightShoulder),
+    ("dpup", Button::DPadUp), ("dpdown", Button::DPadDown), ("dpleft", Button::DPadLeft), ("dpright", Button::DPadRight),
+];
+
+const CONTROLLER_AXES: [(&str, Axis); 6] = [
+    ("leftx", Axis::LeftX), ("lefty", Axis::LeftY), ("rightx", Axis::RightX), ("righty", Axis::RightY),
+    ("lefttrigger", Axis::TriggerLeft), ("righttrigger", Axis::TriggerRight),
+];
+
+// printable keys are named by their character: letters, digits and US punctuation
+fn is_character_key(key: Keycode) -> bool {
+    let code = key as i32;
+    code < 0x80 && (code as u8 as char).is_ascii_graphic()
+}
+
+pub fn key_name(key: Keycode) -> Option<String> {
+    if is_character_key(key) {
+        return Some((key as i32 as u8 as char).to_ascii_uppercase().to_string());
+    }
+    NAMED_KEYS.iter().find(|(_, k)| *k == key).map(|(name, _)| name.to_string())
+}
+
+pub fn key_from_name(name: &str) -> Option<Keycode> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Keycode::from_i32(c.to_ascii_lowercase() as i32).filter(|key| is_character_key(*key));
+    }
+    NAMED_KEYS.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, key)| *key)
+}
+
+// one host input that presses a pad button: a key, a controller button, or a controller
+// axis pushed past the threshold in one direction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    Key(Keycode),
+    Button(Button),
+    // true for the positive direction
+    Axis(Axis, bool),
+}
+
+impl Binding {
+    // "key:Left Shift", "button:dpup" or "axis:lefty-"
+    pub fn parse(text: &str) -> Result<Binding, String> {
+        let bad = || format!("bad binding {}", text);
+        let (kind, name) = text.split_at(text.find(':').ok_or_else(bad)?);
+        let name = &name[1..];
+        match kind {
+            "key" => key_from_name(name).map(Binding::Key).ok_or_else(bad),
+            "button" => CONTROLLER_BUTTONS.iter().find(|(n, _)| *n == name).map(|(_, b)| Binding::Button(*b)).ok_or_else(bad),
+            "axis" => {
+                let positive = match name.chars().last() {
+                    Some('+') => true,
+                    Some('-') => false,
+                    _ => return Err(bad()),
+                };
+                let axis = &name[..name.len() - 1];
+                CONTROLLER_AXES.iter().find(|(n, _)| *n == axis).map(|(_, a)| Binding::Axis(*a, positive)).ok_or_else(bad)
+            }
+            _ => Err(bad()),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Binding::Key(key) => format!("key:{}", key_name(*key).unwrap_or("?".to_string())),
+            Binding::Button(button) => {
+                format!("button:{}", CONTROLLER_BUTTONS.iter().find(|(_, b)| b == button).unwrap().0)
+            }
+            Binding::Axis(axis, positive) => format!(
+                "axis:{}{}",
+                CONTROLLER_AXES.iter().find(|(_, a)| a == axis).unwrap().0,
+                if *positive { "+" } else { "-" }
+            ),
+        }
+    }
+
+    pub fn is_key(&self) -> bool {
+        matches!(self, Binding::Key(_))
+    }
+}
+
+pub struct PlayerBindings {
+    // one list for each entry of BUTTONS
+    pub buttons: Vec<Vec<Binding>>,
+    // the connected gamepad this player reads, counting from 0 in the order they were plugged in
+    pub gamepad: Option<usize>,
+    pub axis_threshold: f32,
+}
+
+impl PlayerBindings {
+    fn new(gamepad: Option<usize>) -> Self {
+        PlayerBindings { buttons: vec![vec![]; BUTTONS.len()], gamepad: gamepad, axis_threshold: DEFAULT_AXIS_THRESHOLD }
+    }
+
+    // gives the binding to one button, taking it off any other
+    pub fn bind(&mut self, button: usize, binding: Binding) {
+        for bindings in self.buttons.iter_mut() {
+            bindings.retain(|b| *b != binding);
+        }
+        self.buttons[button].push(binding);
+    }
+}
+
+pub struct InputBindings {
+    pub players: Vec<PlayerBindings>,
+    // one list for each entry of HOTKEYS, from any gamepad
+    pub hotkeys: Vec<Vec<Binding>>,
+}
+
+impl InputBindings {
+    // a keyboard block per player, and each player on the gamepad of the same number
+    pub fn defaults() -> Self {
+        // up, down, left, right, select, start, B, A, then turbo B and A where there are
+        // keys to spare that aren't the emulator's own
+        let layouts = [
+            vec![Keycode::Up, Keycode::Down, Keycode::Left, Keycode::Right, Keycode::Space, Keycode::Return, Keycode::Z, Keycode::X, Keycode::C, Keycode::D],
+            vec![Keycode::I, Keycode::K, Keycode::J, Keycode::L, Keycode::RShift, Keycode::Backspace, Keycode::U, Keycode::Y, Keycode::M, Keycode::B],
+            vec![Keycode::Kp8, Keycode::Kp2, Keycode::Kp4, Keycode::Kp6, Keycode::KpPlus, Keycode::KpEnter, Keycode::Kp0, Keycode::KpPeriod, Keycode::Kp1, Keycode::Kp3],
+            vec![Keycode::W, Keycode::S, Keycode::Q, Keycode::E, Keycode::G, Keycode::H, Keycode::R, Keycode::T],
+        ];
+        let pad = [
+            vec![Binding::Button(Button::DPadUp), Binding::Axis(Axis::LeftY, false)],
+            vec![Binding::Button(Button::DPadDown), Binding::Axis(Axis::LeftY, true)],
+            vec![Binding::Button(Button::DPadLeft), Binding::Axis(Axis::LeftX, false)],
+            vec![Binding::Button(Button::DPadRight), Binding::Axis(Axis::LeftX, true)],
+            vec![Binding::Button(Button::Back)],
+            vec![Binding::Button(Button::Start)],
+            // the NES's B sits left of A, like the bottom and right face buttons, with
+            // turbo on the two above them
+            vec![Binding::Button(Button::A)],
+            vec![Binding::Button(Button::B)],
+            vec![Binding::Button(Button::X)],
+            vec![Binding::Button(Button::Y)],
+        ];
+        let players = layouts
+            .iter()
+            .enumerate()
+            .map(|(player, keys)| {
+                let mut bindings = PlayerBindings::new(Some(player));
+                for (button, key) in keys.iter().enumerate() {
+                    bindings.buttons[button].push(Binding::Key(*key));
+                }
+                for (button, pad_bindings) in pad.iter().enumerate() {
+                    bindings.buttons[button].extend(pad_bindings.iter().cloned());
+                }
+                bindings
+            })
+            .collect();
+        // in HOTKEYS order
+        let hotkeys = [
+            Keycode::F5, Keycode::F6, Keycode::Tab, Keycode::F7, Keycode::Backquote,
+            Keycode::F1, Keycode::F2, Keycode::F9, Keycode::F10, Keycode::F11, Keycode::F12,
+            Keycode::P, Keycode::N, Keycode::O, Keycode::A, Keycode::V, Keycode::F,
+            Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4, Keycode::Num5,
+            Keycode::Num6, Keycode::Num7, Keycode::Num8, Keycode::Num9, Keycode::Num0,
+        ];
+        InputBindings { players: players, hotkeys: hotkeys.iter().map(|key| vec![Binding::Key(*key)]).collect() }
+    }
+
+    // sections [player1] to [player4] and [hotkeys]; whatever a section leaves out keeps
+    // its default
+    pub fn parse(text: &str) -> Result<InputBindings, String> {
+        let config = Config::parse(text)?;
+        let mut bindings = InputBindings::defaults();
+        for entry in config.entries.iter() {
+            let error = |message: String| format!("line {}: {}", entry.line, message);
+            if entry.section == "hotkeys" {
+                let hotkey = HOTKEYS.iter().position(|(name, _)| *name == entry.key).ok_or_else(|| error(format!("unknown hotkey {}", entry.key)))?;
+                bindings.hotkeys[hotkey] = binding_list(entry).map_err(error)?;
+                continue;
+            }
+            let player = entry
+                .section
+                .strip_prefix("player")
+                .and_then(|n| n.parse::<usize>().ok())
+                .filter(|n| (1..=PLAYERS).contains(n))
+                .ok_or_else(|| error(format!("unknown section [{}]", entry.section)))?;
+            let player = &mut bindings.players[player - 1];
+            match entry.key.as_str() {
+                "gamepad" => {
+                    let number = entry.value.as_number().filter(|n| *n >= 0.0 && n.fract() == 0.0);
+                    let number = number.ok_or_else(|| error("gamepad should be a number, 0 for none".to_string()))?;
+                    player.gamepad = if number == 0.0 { None } else { Some(number as usize - 1) };
+                }
+                "axis_threshold" => {
+                    let threshold = entry.value.as_number().filter(|t| *t > 0.0 && *t < 1.0);
+                    player.axis_threshold = threshold.ok_or_else(|| error("axis_threshold should be between 0 and 1".to_string()))? as f32;
+                }
+                key => {
+                    let button = BUTTONS.iter().position(|(name, _)| *name == key).ok_or_else(|| error(format!("unknown button {}", key)))?;
+                    player.buttons[button] = binding_list(entry).map_err(error)?;
+                }
+            }
+        }
+        bindings.check()?;
+        Ok(bindings)
+    }
+
+    // a binding is either a hotkey or pad buttons, never both or two hotkeys, since the
+    // hotkey would swallow the other. Escape is neither
+    fn check(&self) -> Result<(), String> {
+        let pad_bindings = self.players.iter().enumerate().flat_map(|(i, player)| {
+            player.buttons.iter().enumerate().flat_map(move |(button, bindings)| bindings.iter().map(move |binding| (i, button, binding)))
+        });
+        for (i, button, binding) in pad_bindings {
+            let name = format!("player {} {}", i + 1, BUTTONS[button].0);
+            if *binding == Binding::Key(QUIT_KEY) {
+                return Err(format!("{} can't be bound to {}, it quits", name, binding.name()));
+            }
+            if let Some(hotkey) = self.hotkey_name(binding) {
+                return Err(format!("{} is bound to both the {} hotkey and {}", binding.name(), hotkey, name));
+            }
+        }
+        for (hotkey, bindings) in self.hotkeys.iter().enumerate() {
+            for binding in bindings.iter() {
+                if *binding == Binding::Key(QUIT_KEY) {
+                    return Err(format!("the {} hotkey can't be bound to {}, it quits", HOTKEYS[hotkey].0, binding.name()));
+                }
+                if let Some(other) = self.hotkeys[hotkey + 1..].iter().position(|others| others.contains(binding)) {
+                    let other = HOTKEYS[hotkey + 1 + other].0;
+                    return Err(format!("{} is bound to both the {} and {} hotkeys", binding.name(), HOTKEYS[hotkey].0, other));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the hotkey a binding already belongs to, which the rebinding screen won't take
+    pub fn hotkey_name(&self, binding: &Binding) -> Option<&'static str> {
+        HOTKEYS.iter().zip(self.hotkeys.iter()).find(|(_, bindings)| bindings.contains(binding)).map(|((name, _), _)| *name)
+    }
+
+    // the defaults when the file doesn't exist yet
+    pub fn load(path: &str) -> Result<InputBindings, String> {
+        if !std::path::Path::new(path).exists() {
+            return Ok(InputBindings::defaults());
+        }
+        let text = std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        InputBindings::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn to_config(&self) -> String {
+        let mut text = String::from("# pad bindings: key:<name>, button:<SDL controller button> or axis:<SDL controller axis>+/-\n");
+        for (i, player) in self.players.iter().enumerate() {
+            text.push_str(&format!("\n[player{}]\n", i + 1));
+            text.push_str(&format!("gamepad = {}\n", player.gamepad.map_or(0, |pad| pad + 1)));
+            text.push_str(&format!("axis_threshold = {}\n", player.axis_threshold));
+            for (button, (name, _)) in BUTTONS.iter().enumerate() {
+                let bindings: Vec<String> = player.buttons[button].iter().map(|b| quote(&b.name())).collect();
+                text.push_str(&format!("{} = [{}]\n", name, bindings.join(", ")));
+            }
+        }
+        text.push_str("\n[hotkeys]\n");
+        for (hotkey, (name, _)) in HOTKEYS.iter().enumerate() {
+            let bindings: Vec<String> = self.hotkeys[hotkey].iter().map(|b| quote(&b.name())).collect();
+            text.push_str(&format!("{} = [{}]\n", name, bindings.join(", ")));
+        }
+        text
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        std::fs::write(path, self.to_config()).map_err(|e| format!("could not write {}: {}", path, e))
+    }
+
+    // every player button bound to the key
+    pub fn key(&self, key: Keycode) -> Vec<(usize, PadButton)> {
+        self.matching(|_, binding| *binding == Binding::Key(key))
+    }
+
+    pub fn button(&self, gamepad: usize, button: Button) -> Vec<(usize, PadButton)> {
+        self.matching(|player, binding| player.gamepad == Some(gamepad) && *binding == Binding::Button(button))
+    }
+
+    // each player button bound to either direction of the axis, and whether it's now held
+    pub fn axis(&self, gamepad: usize, axis: Axis, value: i16) -> Vec<(usize, PadButton, bool)> {
+        let mut changes = vec![];
+        for (i, player) in self.players.iter().enumerate() {
+            if player.gamepad != Some(gamepad) {
+                continue;
+            }
+            let position = value as f32 / i16::MAX as f32;
+            for (button, bindings) in player.buttons.iter().enumerate() {
+                for binding in bindings.iter() {
+                    if let Binding::Axis(a, positive) = binding {
+                        if *a == axis {
+                            let held = if *positive { position > player.axis_threshold } else { position < -player.axis_threshold };
+                            changes.push((i, BUTTONS[button].1, held));
+                        }
+                    }
+                }
+            }
+        }
+        changes
+    }
+
+    // every button a gamepad could be holding, to let go of when it's unplugged
+    pub fn gamepad_buttons(&self, gamepad: usize) -> Vec<(usize, PadButton)> {
+        self.matching(|player, binding| player.gamepad == Some(gamepad) && !binding.is_key())
+    }
+
+    pub fn key_hotkeys(&self, key: Keycode) -> Vec<Hotkey> {
+        self.hotkeys_matching(|binding| *binding == Binding::Key(key))
+    }
+
+    pub fn button_hotkeys(&self, button: Button) -> Vec<Hotkey> {
+        self.hotkeys_matching(|binding| *binding == Binding::Button(button))
+    }
+
+    // each hotkey bound to either direction of the axis, and whether it's now held
+    pub fn axis_hotkeys(&self, axis: Axis, value: i16) -> Vec<(Hotkey, bool)> {
+        let position = value as f32 / i16::MAX as f32;
+        let mut changes = vec![];
+        for (hotkey, bindings) in self.hotkeys.iter().enumerate() {
+            for binding in bindings.iter() {
+                if let Binding::Axis(a, positive) = binding {
+                    if *a == axis {
+                        let held = if *positive { position > DEFAULT_AXIS_THRESHOLD } else { position < -DEFAULT_AXIS_THRESHOLD };
+                        changes.push((HOTKEYS[hotkey].1, held));
+                    }
+                }
+            }
+        }
+        changes
+    }
+
+    fn hotkeys_matching(&self, matches: impl Fn(&Binding) -> bool) -> Vec<Hotkey> {
+        HOTKEYS
+            .iter()
+            .zip(self.hotkeys.iter())
+            .filter(|(_, bindings)| bindings.iter().any(|binding| matches(binding)))
+            .map(|((_, hotkey), _)| *hotkey)
+            .collect()
+    }
+
+    fn matching(&self, matches: impl Fn(&PlayerBindings, &Binding) -> bool) -> Vec<(usize, PadButton)> {
+        let mut found = vec![];
+        for (i, player) in self.players.iter().enumerate() {
+            for (button, bindings) in player.buttons.iter().enumerate() {
+                if bindings.iter().any(|binding| matches(player, binding)) {
+                    found.push((i, BUTTONS[button].1));
+                }
+            }
+        }
+        found
+    }
+}
+
+fn binding_list(entry: &ConfigEntry) -> Result<Vec<Binding>, String> {
+    let names = entry.value.as_text_list().ok_or_else(|| format!("{} should be a list of bindings", entry.key))?;
+    names.iter().map(|name| Binding::parse(name)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_binding_names_round_trip() {
+        for text in ["key:Up", "key:Left Shift", "key:Keypad 8", "key:X", "key:7", "key:/", "button:dpup", "axis:lefty-", "axis:righttrigger+"].iter() {
+            assert_eq!(Binding::parse(text).unwrap().name(), *text);
+        }
+        assert_eq!(Binding::parse("key:x"), Ok(Binding::Key(Keycode::X)));
+        assert_eq!(Binding::parse("key:left shift"), Ok(Binding::Key(Keycode::LShift)));
+        for text in ["Up", "key:Hyper", "button:z", "axis:leftx", "axis:middle+", "mouse:left"].iter() {
+            assert!(Binding::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_defaults() {
+        let bindings = InputBindings::defaults();
+        assert_eq!(bindings.key(Keycode::X), vec![(0, PadButton::Normal(JoypadButtons::BUTTON_A))]);
+        assert_eq!(bindings.key(Keycode::I), vec![(1, PadButton::Normal(JoypadButtons::UP))]);
+        assert_eq!(bindings.key(Keycode::F5), vec![]);
+        assert_eq!(bindings.button(1, Button::Start), vec![(1, PadButton::Normal(JoypadButtons::START))]);
+        assert_eq!(bindings.key(Keycode::C), vec![(0, PadButton::Turbo(JoypadButtons::BUTTON_B))]);
+        assert_eq!(bindings.button(0, Button::Y), vec![(0, PadButton::Turbo(JoypadButtons::BUTTON_A))]);
+    }
+
+    #[test]
+    fn test_parse_overrides_defaults() {
+        let bindings = InputBindings::parse(
+            "[player1]\n\
+             a = [\"key:Left Ctrl\", \"button:rightshoulder\"]\n\
+             gamepad = 2\n\
+             [player2]\n\
+             gamepad = 0\n\
+             axis_threshold = 0.25\n",
+        )
+        .unwrap();
+        assert_eq!(bindings.key(Keycode::LCtrl), vec![(0, PadButton::Normal(JoypadButtons::BUTTON_A))]);
+        assert_eq!(bindings.key(Keycode::X), vec![]);
+        assert_eq!(bindings.key(Keycode::Z), vec![(0, PadButton::Normal(JoypadButtons::BUTTON_B))]);
+        assert_eq!(bindings.button(1, Button::RightShoulder), vec![(0, PadButton::Normal(JoypadButtons::BUTTON_A))]);
+        assert_eq!(bindings.players[1].gamepad, None);
+        assert_eq!(bindings.players[1].axis_threshold, 0.25);
+    }
+
+    #[test]
+    fn test_hotkeys() {
+        let defaults = InputBindings::defaults();
+        assert_eq!(defaults.key_hotkeys(Keycode::Tab), vec![Hotkey::FastForward]);
+        assert_eq!(defaults.key_hotkeys(Keycode::X), vec![]);
+        assert_eq!(defaults.key_hotkeys(Keycode::Num0), vec![Hotkey::Channel(9)]);
+        assert!(defaults.check().is_ok());
+        let bindings = InputBindings::parse(
+            "[hotkeys]\n\
+             pause = \"key:F3\"\n\
+             fast_forward = [\"key:Tab\", \"axis:righttrigger+\"]\n\
+             rewind = [\"button:leftshoulder\"]\n",
+        )
+        .unwrap();
+        assert_eq!(bindings.key_hotkeys(Keycode::F3), vec![Hotkey::Pause]);
+        assert_eq!(bindings.key_hotkeys(Keycode::F5), vec![]);
+        assert_eq!(bindings.key_hotkeys(Keycode::F6), vec![Hotkey::FrameAdvance]);
+        assert_eq!(bindings.button_hotkeys(Button::LeftShoulder), vec![Hotkey::Rewind]);
+        assert_eq!(bindings.axis_hotkeys(Axis::TriggerRight, 30000), vec![(Hotkey::FastForward, true)]);
+        assert_eq!(bindings.axis_hotkeys(Axis::TriggerRight, 1000), vec![(Hotkey::FastForward, false)]);
+        assert!(InputBindings::parse("[hotkeys]\nturbo = []").err().unwrap().contains("turbo"));
+        assert!(InputBindings::parse("[hotkeys]\npause = 5").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(InputBindings::parse("[player5]\nup = []").err().unwrap().contains("[player5]"));
+        assert!(InputBindings::parse("[player1]\njump = []").err().unwrap().contains("jump"));
+        assert!(InputBindings::parse("[player1]\nup = [\"key:Nope\"]").err().unwrap().starts_with("line 2"));
+        assert!(InputBindings::parse("[player1]\naxis_threshold = 1.5").is_err());
+        assert!(InputBindings::parse("[player1]\ngamepad = 1.5").is_err());
+    }
+
+    #[test]
+    fn test_conflicts_rejected() {
+        let error = InputBindings::parse("[player1]\na = [\"key:N\"]").err().unwrap();
+        assert!(error.contains("ntsc") && error.contains("player 1 a"), "{}", error);
+        let error = InputBindings::parse("[hotkeys]\npause = [\"key:Tab\"]").err().unwrap();
+        assert!(error.contains("pause") && error.contains("fast_forward"), "{}", error);
+        assert!(InputBindings::parse("[player2]\nstart = [\"key:Escape\"]").is_err());
+        assert!(InputBindings::parse("[hotkeys]\nrewind = [\"key:Escape\"]").is_err());
+        // moving a hotkey frees its old key for the pad
+        let bindings = InputBindings::parse("[hotkeys]\nntsc = [\"key:F3\"]\n[player1]\na = [\"key:N\"]").unwrap();
+        assert_eq!(bindings.key(Keycode::N), vec![(0, PadButton::Normal(JoypadButtons::BUTTON_A))]);
+    }
+
+    #[test]
+    fn test_config_round_trips() {
+        let mut bindings = InputBindings::defaults();
+        bindings.hotkeys[0] = vec![Binding::Button(Button::Guide)];
+        bindings.players[2].gamepad = None;
+        bindings.players[3].axis_threshold = 0.75;
+        bindings.players[0].bind(7, Binding::Key(Keycode::Z));
+        let text = bindings.to_config();
+        let loaded = InputBindings::parse(&text).unwrap();
+        assert_eq!(loaded.to_config(), text);
+        assert_eq!(loaded.key(Keycode::Z), vec![(0, PadButton::Normal(JoypadButtons::BUTTON_A))]);
+        assert_eq!(loaded.players[2].gamepad, None);
+    }
+
+    #[test]
+    fn test_axis_threshold() {
+        let bindings = InputBindings::defaults();
+        let held = |value: i16| -> Vec<(usize, PadButton, bool)> { bindings.axis(0, Axis::LeftY, value) };
+        assert_eq!(held(-20000), vec![(0, PadButton::Normal(JoypadButtons::UP), true), (0, PadButton::Normal(JoypadButtons::DOWN), false)]);
+        assert_eq!(held(-10000), vec![(0, PadButton::Normal(JoypadButtons::UP), false), (0, PadButton::Normal(JoypadButtons::DOWN), false)]);
+        assert_eq!(held(32767), vec![(0, PadButton::Normal(JoypadButtons::UP), false), (0, PadButton::Normal(JoypadButtons::DOWN), true)]);
+        assert!(bindings.axis(3, Axis::RightX, 32767).is_empty());
+    }
+
+    #[test]
+    fn test_gamepad_buttons() {
+        let bindings = InputBindings::defaults();
+        let held = bindings.gamepad_buttons(2);
+        assert_eq!(held.len(), 10);
+        assert!(held.iter().all(|(player, _)| *player == 2));
+        assert!(bindings.gamepad_buttons(4).is_empty());
+    }
+}
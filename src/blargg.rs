@@ -0,0 +1,175 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::bus::*;
+use crate::cartridge::Rom;
+use crate::cpu::*;
+
+// blargg's test ROMs report through PRG RAM: $6000 holds the status, $6001-$6003 the
+// DE B0 61 signature once the report is valid, and zero-terminated text follows from $6004
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const STATUS_RUNNING: u8 = 0x80;
+// the ROM wants the reset button pressed, no sooner than 100ms after it asked
+const STATUS_NEEDS_RESET: u8 = 0x81;
+const RESET_DELAY_FRAMES: usize = 10;
+// the slowest of ppu_vbl_nmi and apu_test finish well inside a minute
+pub const DEFAULT_TIMEOUT_FRAMES: usize = 60 * 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestReport {
+    // 0 passed, anything else below $80 is the number of the failed check
+    pub status: u8,
+    pub text: String,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.status == 0
+    }
+
+    // the text on one line, as the ROM would have printed it on screen
+    pub fn summary(&self) -> String {
+        self.text.split('\n').map(|line| line.trim()).filter(|line| !line.is_empty()).collect::<Vec<_>>().join(" / ")
+    }
+}
+
+// the report as it stands, None until the signature is there
+pub fn read_report(prg_ram: &[u8]) -> Option<TestReport> {
+    if prg_ram[1..4] != SIGNATURE {
+        return None;
+    }
+    let text = prg_ram[4..].iter().take_while(|c| **c != 0).map(|c| *c as char).collect();
+    Some(TestReport { status: prg_ram[0], text: text })
+}
+
+// runs the ROM without a window until it reports a result, pressing reset whenever it asks
+pub fn run_test_rom(rom: Rom, timeout_frames: usize) -> Result<TestReport, String> {
+    let frame_count = Rc::new(Cell::new(0));
+    let counted = frame_count.clone();
+    let bus = Bus::new(rom, move |_, _, _| {
+        counted.set(counted.get() + 1);
+    });
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+
+    let mut last_frame = 0;
+    let mut reset_at = None;
+    let mut result = None;
+    cpu.execute(|cpu| {
+        let frame = frame_count.get();
+        if frame == last_frame {
+            return;
+        }
+        last_frame = frame;
+        match read_report(cpu.bus.prg_ram()) {
+            Some(report) if report.status == STATUS_NEEDS_RESET => {
+                if frame >= *reset_at.get_or_insert(frame + RESET_DELAY_FRAMES) {
+                    cpu.bus.request_reset(ResetKind::Soft);
+                    reset_at = None;
+                }
+            }
+            Some(report) if report.status < STATUS_RUNNING => {
+                result = Some(report);
+                cpu.stop();
+            }
+            _ => reset_at = None,
+        }
+        if frame >= timeout_frames {
+            cpu.stop();
+        }
+    });
+    result.ok_or_else(|| {
+        let text = read_report(cpu.bus.prg_ram()).map(|report| report.summary()).unwrap_or_default();
+        if text.is_empty() {
+            format!("no result after {} frames", timeout_frames)
+        } else {
+            format!("no result after {} frames: {}", timeout_frames, text)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    // sets the status to running and writes the signature, the test's own code follows at $8014
+    fn reporting(body: &[u8]) -> Vec<u8> {
+        let mut program = vec![
+            0xA9, 0x80, 0x8D, 0x00, 0x60, // LDA #$80; STA $6000
+            0xA9, 0xDE, 0x8D, 0x01, 0x60, // LDA #$DE; STA $6001
+            0xA9, 0xB0, 0x8D, 0x02, 0x60, // LDA #$B0; STA $6002
+            0xA9, 0x61, 0x8D, 0x03, 0x60, // LDA #$61; STA $6003
+        ];
+        program.extend_from_slice(body);
+        program
+    }
+
+    #[test]
+    fn test_read_report() {
+        let mut ram = vec![0; 0x2000];
+        ram[0] = 3;
+        ram[4..10].copy_from_slice(b"\nabc\n\n");
+        assert_eq!(read_report(&ram), None);
+        ram[1..4].copy_from_slice(&SIGNATURE);
+        let report = read_report(&ram).unwrap();
+        assert_eq!(report, TestReport { status: 3, text: "\nabc\n\n".to_string() });
+        assert!(!report.passed());
+        assert_eq!(report.summary(), "abc");
+    }
+
+    #[test]
+    fn test_passing_rom() {
+        let program = reporting(&[
+            0xA9, 0x6F, 0x8D, 0x04, 0x60, // LDA #'o'; STA $6004
+            0xA9, 0x6B, 0x8D, 0x05, 0x60, // LDA #'k'; STA $6005
+            0xA9, 0x00, 0x8D, 0x06, 0x60, // LDA #0; STA $6006
+            0x8D, 0x00, 0x60, // STA $6000
+            0x4C, 0x26, 0x80, // JMP $8026
+        ]);
+        let report = run_test_rom(test_rom(program), 60).unwrap();
+        assert!(report.passed());
+        assert_eq!(report.text, "ok");
+    }
+
+    #[test]
+    fn test_reset_request_is_answered() {
+        let program = reporting(&[
+            0xAD, 0x10, 0x60, // LDA $6010
+            0xD0, 0x0B, // BNE $8024
+            0xEE, 0x10, 0x60, // INC $6010
+            0xA9, 0x81, 0x8D, 0x00, 0x60, // LDA #$81; STA $6000
+            0x4C, 0x21, 0x80, // JMP $8021
+            // back from the reset, fail check 2
+            0xA9, 0x72, 0x8D, 0x04, 0x60, // LDA #'r'; STA $6004
+            0xA9, 0x02, 0x8D, 0x00, 0x60, // LDA #2; STA $6000
+            0x4C, 0x2E, 0x80, // JMP $802E
+        ]);
+        let report = run_test_rom(test_rom(program), 60).unwrap();
+        assert_eq!(report.status, 2);
+        assert_eq!(report.text, "r");
+    }
+
+    // the frame counter's IRQ flag in the style of apu_test: set once a 4-step sequence
+    // ends, cleared by the $4015 read that saw it
+    #[test]
+    fn test_frame_irq_rom() {
+        let program = reporting(&[
+            0xA9, 0x00, 0x8D, 0x17, 0x40, // LDA #0; STA $4017
+            0xAD, 0x15, 0x40, // LDA $4015
+            0x29, 0x40, // AND #$40
+            0xF0, 0xF9, // BEQ $8019
+            0xAD, 0x15, 0x40, // LDA $4015
+            0x29, 0x40, // AND #$40
+            0x8D, 0x00, 0x60, // STA $6000
+            0x4C, 0x28, 0x80, // JMP $8028
+        ]);
+        assert!(run_test_rom(test_rom(program), 60).unwrap().passed());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let program = reporting(&[0x4C, 0x14, 0x80]); // JMP $8014
+        assert_eq!(run_test_rom(test_rom(program), 30), Err("no result after 30 frames".to_string()));
+    }
+}
@@ -5,6 +5,14 @@ use crate::ppu::*;
 use crate::render::*;
 use crate::frame::*;
 use crate::joypad::*;
+use crate::apu::*;
+use crate::audio::*;
+use crate::pacing::*;
+use crate::nsf::NsfMemory;
+use crate::controller::*;
+use crate::zapper::LightSense;
+use crate::movie::MovieSession;
+use crate::savestate::{Savestate, StateBuffer};
 
 //  _______________ $10000  _______________
 // | PRG-ROM       |       |               |
@@ -35,19 +43,62 @@ use crate::joypad::*;
 // |_______________| $0000 |_______________|
 pub struct Bus <'call>{
 	cpu_vram: [u8; 2048],
+	// work RAM at $6000-$7FFF, which blargg's test ROMs report through too
+	prg_ram: Vec<u8>,
 	prg_rom: Vec<u8>,
     ppu: ppu,
     cycles: usize,
-    gameloop_callback: Box<dyn FnMut(&ppu, &mut Joypad) + 'call>,
-    joypad: Joypad,
+    gameloop_callback: Box<dyn FnMut(&ppu, &mut Apu, &mut ControllerPorts) + 'call>,
+    pub ports: ControllerPorts,
+    // the last value on the data bus, undriven bits read back as this
+    open_bus: u8,
+    light: LightSense,
+    pub apu: Apu,
+    stall_cycles: usize,
+    oam_dma_cycles: usize,
+    controller_read: Option<u16>,
+    // cycles of the current instruction already run to line the PPU up with a $2002 read
+    caught_up: u8,
+    audio_sink: Option<Box<dyn AudioSink + 'call>>,
+    pacer: Option<FramePacer>,
+    // set while playing an NSF, it then owns $4020-$FFFF
+    nsf: Option<NsfMemory>,
+    // a movie recording or playing, stepped at each frame boundary
+    pub movie: Option<MovieSession>,
+    // asked for by the host, done at the next frame boundary so a movie can record it
+    requested_reset: Option<ResetKind>,
+    // for the CPU to carry out
+    pending_reset: Option<ResetKind>,
+    // set at each frame boundary until the CPU takes it, for rewind snapshots
+    frame_ended: bool,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetKind {
+    // the console's reset button
+    Soft,
+    // switched off and on again
+    Power,
+}
+
+// cycles the CPU is halted for a DMC sample fetch, fewer when it lands inside an OAM DMA
+const DMC_DMA_STALL: usize = 4;
+const DMC_DMA_STALL_DURING_OAM_DMA: usize = 2;
+const OAM_DMA_STALL: usize = 513;
+const PRG_RAM_SIZE: usize = 0x2000;
+
 impl <'a>Bus<'a> {
 	pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
     where
-        F: FnMut(&ppu, &mut Joypad) + 'call,
+        F: FnMut(&ppu, &mut Apu, &mut ControllerPorts) + 'call,
     {
+        let mut apu = Apu::new(rom.tv_system);
+        apu.set_expansion(rom.expansion_audio());
         let ppu = ppu::new(rom.chr_rom, rom.screen_mirroring);
+        let mut ports = ControllerPorts::new();
+        if let Some(setup) = InputSetup::from_header(rom.input_device) {
+            ports.connect(setup);
+        }
 
         // for i in 0..rom.prg_rom.len(){
         //     println!("{:x}", rom.prg_rom[i]);
@@ -56,11 +107,26 @@ impl <'a>Bus<'a> {
 
         Bus {
             cpu_vram: [0; 2048],
+            prg_ram: vec![0; PRG_RAM_SIZE],
             prg_rom: rom.prg_rom,
             ppu: ppu,
             cycles: 0,
             gameloop_callback: Box::from(gameloop_callback),
-            joypad: Joypad::new(),
+            ports: ports,
+            open_bus: 0,
+            light: LightSense::new(),
+            apu: apu,
+            stall_cycles: 0,
+            oam_dma_cycles: 0,
+            controller_read: None,
+            caught_up: 0,
+            audio_sink: None,
+            pacer: None,
+            nsf: None,
+            movie: None,
+            requested_reset: None,
+            pending_reset: None,
+            frame_ended: false,
         }
     }
 
@@ -74,13 +140,150 @@ impl <'a>Bus<'a> {
     }
 
     pub fn tick(&mut self, ticks: u8){
-        self.cycles += ticks as usize;
-        let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(ticks * 3);
-        let nmi_after = self.ppu.nmi_interrupt.is_some();
-        if(!nmi_before && nmi_after){
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad);
+        let ticks = ticks.saturating_sub(std::mem::take(&mut self.caught_up));
+        for i in 0..ticks {
+            // the controller read happens on the last cycle of the instruction
+            let controller_read = if i + 1 == ticks { self.controller_read } else { None };
+            self.tick_cycle(controller_read);
+        }
+        self.controller_read = None;
+
+        // the CPU sits halted while DMA runs, everything else keeps going
+        while self.stall_cycles > 0 {
+            self.stall_cycles -= 1;
+            self.tick_cycle(None);
+        }
+    }
+
+    // runs the first cycles of an instruction ahead of one of its reads, tick then only
+    // runs the rest
+    pub fn catch_up(&mut self, cycles: u8) {
+        while self.caught_up < cycles {
+            self.caught_up += 1;
+            self.tick_cycle(None);
+        }
+    }
+
+    fn tick_cycle(&mut self, controller_read: Option<u16>){
+        self.cycles += 1;
+        self.apu.tick(1);
+        if self.oam_dma_cycles > 0 {
+            self.oam_dma_cycles -= 1;
+        }
+
+        if let Some(addr) = self.apu.dmc.dma_request() {
+            let sample = self.memory_read(addr);
+            self.apu.dmc.load_sample(sample);
+            self.stall_cycles += if self.oam_dma_cycles > 0 { DMC_DMA_STALL_DURING_OAM_DMA } else { DMC_DMA_STALL };
+            // the halted CPU keeps repeating its read, clocking the controller an extra time
+            if let Some(port) = controller_read {
+                self.memory_read(port);
+            }
         }
+
+        let events = self.ppu.tick(3);
+        if events.contains(PpuEvents::FRAME_STARTED) {
+            self.light.invalidate();
+        }
+        // everything once a frame happens as vblank starts, games that leave NMI off or
+        // poll $2002 included
+        if events.contains(PpuEvents::FRAME_COMPLETE) {
+            self.flush_audio();
+            (self.gameloop_callback)(&self.ppu, &mut self.apu, &mut self.ports);
+            self.ports.frame();
+            let requested = self.requested_reset.take();
+            self.pending_reset = match self.movie.as_mut() {
+                Some(movie) => movie.frame(&mut self.ports, requested),
+                None => requested,
+            };
+            self.frame_ended = true;
+        }
+    }
+
+    pub fn request_reset(&mut self, kind: ResetKind) {
+        self.requested_reset = Some(kind);
+    }
+
+    pub fn take_reset(&mut self) -> Option<ResetKind> {
+        self.pending_reset.take()
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    pub fn take_frame_end(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ended)
+    }
+
+    // the bus's side of a reset; the CPU sees to its registers
+    pub fn reset(&mut self, kind: ResetKind) {
+        self.stall_cycles = 0;
+        self.oam_dma_cycles = 0;
+        self.controller_read = None;
+        self.caught_up = 0;
+        self.light.invalidate();
+        match kind {
+            ResetKind::Soft => {
+                self.ppu.reset();
+                self.apu.reset(false);
+            }
+            ResetKind::Power => {
+                self.cpu_vram = [0; 2048];
+                self.open_bus = 0;
+                // CHR RAM comes up cleared, PRG RAM is battery backed on the carts that have it
+                let chr_rom = if self.ppu.chr_ram { vec![] } else { std::mem::take(&mut self.ppu.chr_rom) };
+                self.ppu = ppu::new(chr_rom, self.ppu.mirroring);
+                self.apu.reset(true);
+            }
+        }
+    }
+
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink + 'a>) {
+        self.apu.audio.set_sample_rate(sink.sample_rate());
+        self.audio_sink = Some(sink);
+    }
+
+    // without a pacer (tests, headless runs) frames go as fast as the host allows
+    pub fn set_frame_pacer(&mut self, pacer: FramePacer) {
+        self.pacer = Some(pacer);
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        if let Some(pacer) = self.pacer.as_mut() {
+            pacer.speed = speed;
+        }
+    }
+
+    pub fn load_nsf(&mut self, memory: NsfMemory) {
+        self.nsf = Some(memory);
+    }
+
+    pub fn reset_nsf(&mut self) {
+        if let Some(memory) = self.nsf.as_mut() {
+            memory.reset();
+        }
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    // hands the frame's samples to the sink, once per frame at vblank
+    pub fn flush_audio(&mut self) {
+        self.apu.audio.end_frame();
+        let muted = self.pacer.as_ref().map_or(false, |pacer| pacer.mutes_audio());
+        match self.audio_sink.as_mut() {
+            Some(sink) if !muted => self.apu.audio.drain(sink.as_mut()),
+            _ => self.apu.audio.discard(),
+        }
+        if let Some(pacer) = self.pacer.as_mut() {
+            pacer.end_frame(self.audio_sink.as_deref(), &mut self.apu.audio);
+        }
+    }
+
+    pub fn poll_irq_status(&self) -> bool{
+        return self.apu.irq();
     }
 
     pub fn poll_nmi_status(&mut self) -> Option<u8>{
@@ -88,6 +291,26 @@ impl <'a>Bus<'a> {
     }
 }
 
+// the cartridge is all ROM and the controllers follow the host, so neither is kept; an
+// NSF's RAM and banks are
+impl Savestate for Bus<'_> {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bytes(&mut self.cpu_vram);
+        s.bytes(&mut self.prg_ram);
+        s.usize(&mut self.cycles);
+        s.u8(&mut self.open_bus);
+        s.usize(&mut self.stall_cycles);
+        s.usize(&mut self.oam_dma_cycles);
+        s.option_u16(&mut self.controller_read);
+        self.ppu.state(s);
+        self.apu.state(s);
+        if let Some(nsf) = self.nsf.as_mut() {
+            nsf.state(s);
+        }
+        self.light.invalidate();
+    }
+}
+
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF; // 0x1FFF
 const PPU_REGISTERS: u16 = 0x2000;
@@ -95,7 +318,26 @@ const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF; // 0x3FFF
 
 impl Mem for Bus<'_> {
    fn memory_read(&mut self, addr: u16) -> u8 {
+       let data = self.read_data_bus(addr);
+       self.open_bus = data;
+       data
+   }
+
+   fn memory_write(&mut self, addr: u16, data: u8) {
+       self.open_bus = data;
+       self.write_data_bus(addr, data);
+   }
+}
+
+impl Bus<'_> {
+   fn read_data_bus(&mut self, addr: u16) -> u8 {
     //println!("{:x}", addr);
+       // readable expansion audio registers shadow whatever else is there
+       if addr >= 0x4020 {
+           if let Some(data) = self.apu.expansion_read(addr) {
+               return data;
+           }
+       }
        match addr {
         
            RAM ..= RAM_MIRRORS_END => {
@@ -123,28 +365,35 @@ impl Mem for Bus<'_> {
                 self.memory_read(mirror_down_addr)
             }
 
-            0x4016 => {
-                return self.joypad.read_joypad();
-            }
+            0x4015 => self.apu.read_status(),
 
-            0x4017 => {
-                0
+            0x4016 | 0x4017 => {
+                self.controller_read = Some(addr);
+                if self.ports.needs_light() {
+                    self.light.update(&self.ppu);
+                    self.ports.sense_light(&self.light);
+                }
+                self.ports.read((addr - 0x4016) as usize, self.open_bus)
             }
 
-            0x4020..=0x6000 => {
+            0x4020..=0xFFFF if self.nsf.is_some() => self.nsf.as_ref().unwrap().read(addr),
+
+            0x4020..=0x5FFF => {
                 todo!("expansion rom");
             }
 
+            0x6000..=0x7FFF => self.prg_ram[addr as usize - 0x6000],
+
 			0x8000..= 0xFFFF => self.read_prg_rom(addr),
 
            _ => {
                println!("Ignoring mem access at {:x}", addr);
-               0
+               self.open_bus
            }
        }
    }
 
-   fn memory_write(&mut self, addr: u16, data: u8) {
+   fn write_data_bus(&mut self, addr: u16, data: u8) {
        match addr {
 
            RAM ..= RAM_MIRRORS_END => {
@@ -185,15 +434,15 @@ impl Mem for Bus<'_> {
            }
 
            0x4000..=0x4013 | 0x4015 => {
-            //ignore APU 
+            self.apu.write_register(addr, data);
         }
 
         0x4016 => {
-            self.joypad.write_joypad(data);
+            self.ports.write(data);
         }
 
         0x4017 => {
-            // ignore joypad 2
+            self.apu.write_register(addr, data);
         }
 
            0x2008 ..= PPU_REGISTERS_MIRRORS_END => {
@@ -210,9 +459,18 @@ impl Mem for Bus<'_> {
                 }
 
                 self.ppu.write_oam_dma(&buffer);
+                // one extra alignment cycle when the DMA starts on an odd cycle
+                self.stall_cycles += OAM_DMA_STALL + self.cycles % 2;
+                self.oam_dma_cycles = self.stall_cycles;
 
            }
 
+            0x4020..=0xFFFF if self.apu.expansion_handles(addr) => self.apu.expansion_write(addr, data),
+
+            0x4020..=0xFFFF if self.nsf.is_some() => self.nsf.as_mut().unwrap().write(addr, data),
+
+            0x6000..=0x7FFF => self.prg_ram[addr as usize - 0x6000] = data,
+
             0x8000..=0xFFFF => {
 						//panic!("Attempt to write to Cartridge ROM space")
 			}
@@ -236,4 +494,229 @@ mod test {
     //     bus.memory_write(0x01, 0x55);
     //     assert_eq!(bus.memory_read(0x01), 0x55);
     // }
+
+    fn test_bus<'a>() -> Bus<'a> {
+        Bus::new(test::test_rom(vec![]), |_, _, _| {})
+    }
+
+    fn start_dmc_sample(bus: &mut Bus) {
+        bus.memory_write(0x4010, 0x00);
+        bus.memory_write(0x4012, 0x00);
+        bus.memory_write(0x4013, 0x00);
+        bus.memory_write(0x4015, 0b1_0000);
+    }
+
+    struct SharedSink(std::rc::Rc<std::cell::RefCell<Vec<f32>>>);
+
+    impl AudioSink for SharedSink {
+        fn sample_rate(&self) -> u32 {
+            48000
+        }
+
+        fn push_samples(&mut self, samples: &[f32]) {
+            self.0.borrow_mut().extend_from_slice(samples);
+        }
+    }
+
+    #[test]
+    fn test_audio_flushed_every_frame() {
+        let samples = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let mut bus = test_bus();
+        bus.set_audio_sink(Box::new(SharedSink(samples.clone())));
+        bus.memory_write(0x2000, 0b1000_0000);
+        for _ in 0..29781 * 3 / 100 {
+            bus.tick(100);
+        }
+        // at least two vblanks went by, each handing over about a frame of samples
+        let count = samples.borrow().len();
+        assert!(count >= 48000 / 60 * 2 - 2 && count <= 48000 / 60 * 3 + 2, "{} samples", count);
+        assert_eq!(bus.apu.audio.sample_rate(), 48000);
+    }
+
+    #[test]
+    fn test_frames_end_with_nmi_off() {
+        let samples = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let frames = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counted = frames.clone();
+        let mut bus = Bus::new(test::test_rom(vec![]), move |_, _, _| counted.set(counted.get() + 1));
+        bus.set_audio_sink(Box::new(SharedSink(samples.clone())));
+        // a game polling $2002 for vblank with NMI left off
+        for _ in 0..29781 * 3 / 100 {
+            bus.tick(100);
+            bus.memory_read(0x2002);
+        }
+        assert!(bus.take_frame_end());
+        assert_eq!(frames.get(), 3);
+        let count = samples.borrow().len();
+        assert!(count >= 48000 / 60 * 2 - 2 && count <= 48000 / 60 * 3 + 2, "{} samples", count);
+    }
+
+    #[test]
+    fn test_paced_with_nmi_off() {
+        let mut bus = test_bus();
+        bus.set_frame_pacer(FramePacer::new(TvSystem::NTSC, 48000));
+        let start = std::time::Instant::now();
+        // four vblanks, none of them with an NMI
+        for _ in 0..29781 * 4 / 100 {
+            bus.tick(100);
+        }
+        assert!(start.elapsed() >= std::time::Duration::from_millis(45), "{:?}", start.elapsed());
+    }
+
+    // runs LDA $2002 with NMI on, its read landing on the given dot, and returns what it
+    // read and whether the NMI came
+    fn read_status_on(scanline: u16, dot: usize) -> (u8, bool) {
+        let mut cpu = crate::cpu::CPU::new(Bus::new(test::test_rom(vec![0xAD, 0x02, 0x20]), |_, _, _| {}));
+        cpu.bus.memory_write(0x2000, 0b1000_0000);
+        // the read is on the instruction's fourth cycle, three cycles' dots after it starts
+        let start = scanline as usize * 341 + dot - 9;
+        while cpu.bus.ppu.scanline() as usize * 341 + cpu.bus.ppu.dot() != start {
+            cpu.bus.ppu.tick(1);
+        }
+        cpu.program_counter = 0x8000;
+        let mut instructions = 0;
+        cpu.execute(|cpu| {
+            instructions += 1;
+            if instructions == 2 {
+                cpu.stop();
+            }
+        });
+        // an NMI is taken ahead of the next instruction, away from $8003
+        (cpu.register_a & 0x80, cpu.program_counter != 0x8003)
+    }
+
+    #[test]
+    fn test_status_read_races_vblank() {
+        assert_eq!(read_status_on(240, 340), (0, true));
+        // a dot early the flag is never set and there's no NMI
+        assert_eq!(read_status_on(241, 0), (0, false));
+        // on the dot or the one after, the flag is seen but the NMI is lost
+        assert_eq!(read_status_on(241, 1), (0x80, false));
+        assert_eq!(read_status_on(241, 2), (0x80, false));
+        assert_eq!(read_status_on(241, 3), (0x80, true));
+    }
+
+    #[test]
+    fn test_mapper_24_mixes_in_vrc6() {
+        let mut rom = test::test_rom(vec![]);
+        rom.mapper = 24;
+        let mut bus = Bus::new(rom, |_, _, _| {});
+        assert_eq!(bus.apu.expansion()[0].name(), "VRC6");
+        assert_eq!(bus.apu.channels().len(), 8);
+        // VRC6 pulse 1 at full volume, the 2A03 stays silent
+        bus.memory_write(0x9000, 0b0111_1111);
+        bus.memory_write(0x9001, 0x20);
+        bus.memory_write(0x9002, 0b1000_0000);
+        let mut levels = vec![];
+        for _ in 0..2000 {
+            bus.tick(1);
+            levels.push(bus.apu.output());
+        }
+        assert!(levels.iter().any(|level| *level > 0.0));
+        assert!(levels.iter().any(|level| *level != levels[0]));
+        assert_eq!(bus.apu.channel_state(Channel::Expansion(0)).volume, 15);
+    }
+
+    #[test]
+    fn test_mapper_5_expansion_registers() {
+        let mut rom = test::test_rom(vec![]);
+        rom.mapper = 5;
+        let mut bus = Bus::new(rom, |_, _, _| {});
+        bus.memory_write(0x5015, 0b01);
+        bus.memory_write(0x5000, 0b0011_1111);
+        bus.memory_write(0x5003, 0b0000_1000);
+        assert_eq!(bus.memory_read(0x5015), 0b01);
+        // MMC5 pulse 1 is expansion channel 0
+        assert_eq!(bus.apu.channel_state(Channel::Expansion(0)).volume, 15);
+    }
+
+    #[test]
+    fn test_dmc_fetch_stalls_cpu() {
+        let mut bus = test_bus();
+        start_dmc_sample(&mut bus);
+        bus.tick(2);
+        assert_eq!(bus.cycles, 2 + DMC_DMA_STALL);
+        assert_eq!(bus.apu.dmc.dma_request(), None);
+    }
+
+    #[test]
+    fn test_oam_dma_stall() {
+        let mut bus = test_bus();
+        bus.memory_write(0x4014, 0x02);
+        bus.tick(0);
+        assert_eq!(bus.cycles, 513);
+
+        // starting on an odd cycle costs one more
+        bus.memory_write(0x4014, 0x02);
+        bus.tick(0);
+        assert_eq!(bus.cycles, 513 + 514);
+    }
+
+    #[test]
+    fn test_dmc_fetch_during_oam_dma() {
+        let mut bus = test_bus();
+        bus.memory_write(0x4014, 0x02);
+        start_dmc_sample(&mut bus);
+        bus.tick(0);
+        assert_eq!(bus.cycles, 513 + DMC_DMA_STALL_DURING_OAM_DMA);
+    }
+
+    fn strobe_with_a_and_b(bus: &mut Bus) {
+        let joypad = bus.ports.joypad(0).unwrap();
+        joypad.set_button_pressed_status(JoypadButtons::BUTTON_A, true);
+        joypad.set_button_pressed_status(JoypadButtons::BUTTON_B, true);
+        bus.memory_write(0x4016, 1);
+        bus.memory_write(0x4016, 0);
+    }
+
+    #[test]
+    fn test_controller_reads_without_dmc() {
+        let mut bus = test_bus();
+        strobe_with_a_and_b(&mut bus);
+        assert_eq!(bus.memory_read(0x4016), 1);
+        bus.tick(4);
+        assert_eq!(bus.memory_read(0x4016), 1);
+        bus.tick(4);
+        assert_eq!(bus.memory_read(0x4016), 0);
+    }
+
+    #[test]
+    fn test_second_controller_and_open_bus() {
+        let mut bus = test_bus();
+        bus.ports.joypad(1).unwrap().set_button_pressed_status(JoypadButtons::BUTTON_A, true);
+        bus.memory_write(0x4016, 1);
+        bus.memory_write(0x4016, 0);
+        // as after fetching the operand of LDA $4017
+        bus.open_bus = 0x40;
+        assert_eq!(bus.memory_read(0x4017), 0x41);
+        assert_eq!(bus.memory_read(0x4017), 0x40);
+        assert_eq!(bus.memory_read(0x4016), 0x40);
+    }
+
+    #[test]
+    fn test_zapper_senses_the_screen() {
+        let mut bus = test_bus();
+        bus.ports.connect(InputSetup::Zapper);
+        bus.ppu.palette_table = [0x30; 32];
+        bus.ports.zapper().unwrap().aim = Some((128, 90));
+        // the beam hasn't reached the aim point yet
+        assert_eq!(bus.memory_read(0x4017), 0b0000_1000);
+        while bus.ppu.scanline() < 95 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.memory_read(0x4017), 0);
+        bus.ports.zapper().unwrap().trigger = true;
+        assert_eq!(bus.memory_read(0x4017), 0b0001_0000);
+    }
+
+    #[test]
+    fn test_dmc_fetch_on_controller_read_drops_a_bit() {
+        let mut bus = test_bus();
+        strobe_with_a_and_b(&mut bus);
+        start_dmc_sample(&mut bus);
+        assert_eq!(bus.memory_read(0x4016), 1);
+        bus.tick(1);
+        // B got clocked out by the repeated read
+        assert_eq!(bus.memory_read(0x4016), 0);
+    }
 }
\ No newline at end of file
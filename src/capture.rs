@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::cartridge::{frame_rate_ratio, TvSystem};
+use crate::display::*;
+use crate::filters::*;
+
+// 24-bit uncompressed BMP, rows bottom-up and padded to 4 bytes
+pub fn encode_bmp(image: &Image) -> Vec<u8> {
+    let row_size = (image.width * 3 + 3) & !3;
+    let pixel_bytes = row_size * image.height;
+    let file_size = 54 + pixel_bytes;
+
+    let mut out = Vec::with_capacity(file_size);
+    out.extend_from_slice(b"BM");
+    out.extend_from_slice(&(file_size as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&54u32.to_le_bytes());
+    out.extend_from_slice(&40u32.to_le_bytes());
+    out.extend_from_slice(&(image.width as i32).to_le_bytes());
+    out.extend_from_slice(&(image.height as i32).to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&24u16.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(pixel_bytes as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 16]);
+
+    for y in (0..image.height).rev() {
+        for x in 0..image.width {
+            let (r, g, b) = image.get(x as isize, y as isize);
+            out.extend_from_slice(&[b, g, r]);
+        }
+        out.resize(out.len() + row_size - image.width * 3, 0);
+    }
+    out
+}
+
+// saves what the display settings would show and returns the crop region that was used
+pub fn save_screenshot(path: &str, frame: &Image, settings: &DisplaySettings) -> Result<CropRegion, String> {
+    let image = settings.present(frame);
+    std::fs::write(path, encode_bmp(&image)).map_err(|e| format!("could not write {}: {}", path, e))?;
+    Ok(settings.crop_region())
+}
+
+// YUV4MPEG2 stream with 4:4:4 chroma; the crop region travels in an X comment and the
+// pixel aspect in the A field, so encoders (and our own tooling) can pick both up
+pub struct VideoRecorder {
+    out: BufWriter<File>,
+    settings: DisplaySettings,
+    pub frames: usize,
+}
+
+impl VideoRecorder {
+    pub fn start(path: &str, settings: DisplaySettings, tv_system: TvSystem) -> Result<VideoRecorder, String> {
+        let file = File::create(path).map_err(|e| format!("could not create {}: {}", path, e))?;
+        let mut out = BufWriter::new(file);
+        out.write_all(y4m_header(&settings, tv_system).as_bytes()).map_err(|e| e.to_string())?;
+        Ok(VideoRecorder { out: out, settings: settings, frames: 0 })
+    }
+
+    pub fn write_frame(&mut self, frame: &Image) -> Result<(), String> {
+        let image = self.settings.crop(frame);
+        self.out.write_all(&y4m_frame(&image)).map_err(|e| e.to_string())?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<usize, String> {
+        self.out.flush().map_err(|e| e.to_string())?;
+        Ok(self.frames)
+    }
+}
+
+pub fn y4m_header(settings: &DisplaySettings, tv_system: TvSystem) -> String {
+    let region = settings.crop_region();
+    let rate = frame_rate_ratio(tv_system);
+    let aspect = if settings.aspect_correction { PIXEL_ASPECT } else { (1, 1) };
+    format!(
+        "YUV4MPEG2 W{} H{} F{}:{} Ip A{}:{} C444 XCROP={}\n",
+        region.width, region.height, rate.0, rate.1, aspect.0, aspect.1, region
+    )
+}
+
+// BT.601 studio range planes, Y then Cb then Cr
+pub fn y4m_frame(image: &Image) -> Vec<u8> {
+    let pixels = image.width * image.height;
+    let mut out = Vec::with_capacity(6 + pixels * 3);
+    out.extend_from_slice(b"FRAME\n");
+    let mut u = Vec::with_capacity(pixels);
+    let mut v = Vec::with_capacity(pixels);
+    for px in image.data.chunks_exact(3) {
+        let (r, g, b) = (px[0] as f32, px[1] as f32, px[2] as f32);
+        out.push((16.0 + 0.257 * r + 0.504 * g + 0.098 * b).round() as u8);
+        u.push((128.0 - 0.148 * r - 0.291 * g + 0.439 * b).round() as u8);
+        v.push((128.0 + 0.439 * r - 0.368 * g - 0.071 * b).round() as u8);
+    }
+    out.extend(u);
+    out.extend(v);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bmp_layout() {
+        let mut image = Image::new(2, 2);
+        image.set(0, 0, (1, 2, 3));
+        image.set(1, 1, (4, 5, 6));
+        let bmp = encode_bmp(&image);
+        // two rows of 6 bytes padded to 8
+        assert_eq!(bmp.len(), 54 + 16);
+        assert_eq!(&bmp[0..2], b"BM");
+        assert_eq!(u32::from_le_bytes([bmp[2], bmp[3], bmp[4], bmp[5]]), 70);
+        assert_eq!(i32::from_le_bytes([bmp[18], bmp[19], bmp[20], bmp[21]]), 2);
+        // bottom row first, stored as BGR
+        assert_eq!(&bmp[54..60], &[0, 0, 0, 6, 5, 4]);
+        assert_eq!(&bmp[62..68], &[3, 2, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_y4m_header_reports_crop_and_aspect() {
+        let mut settings = DisplaySettings::new();
+        settings.overscan = Overscan::NTSC;
+        settings.aspect_correction = true;
+        assert_eq!(y4m_header(&settings, TvSystem::NTSC), "YUV4MPEG2 W256 H224 F39375000:655171 Ip A8:7 C444 XCROP=0,8,256,224\n");
+        settings.aspect_correction = false;
+        assert_eq!(y4m_header(&settings, TvSystem::PAL), "YUV4MPEG2 W256 H224 F50007:1000 Ip A1:1 C444 XCROP=0,8,256,224\n");
+    }
+
+    #[test]
+    fn test_y4m_frame_planes() {
+        let mut image = Image::new(2, 1);
+        image.set(0, 0, (255, 255, 255));
+        let frame = y4m_frame(&image);
+        assert_eq!(&frame[0..6], b"FRAME\n");
+        assert_eq!(&frame[6..8], &[235, 16]);
+        assert_eq!(&frame[8..12], &[128, 128, 128, 128]);
+    }
+}
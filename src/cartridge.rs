@@ -1,21 +1,54 @@
+use crate::expansion::ExpansionAudio;
+use crate::fds::Fds;
+use crate::mmc5_audio::Mmc5Audio;
+use crate::n163::N163;
+use crate::sunsoft5b::Sunsoft5B;
+use crate::vrc6::Vrc6;
+use crate::vrc7::Vrc7;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Mirroring {
    VERTICAL,
    HORIZONTAL,
    FOUR_SCREEN,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TvSystem {
+   NTSC,
+   PAL,
+}
+
+// frames per second as exact ratios, NTSC's from the master clock
+pub const NTSC_FRAME_RATE: (u32, u32) = (39375000, 655171);
+pub const PAL_FRAME_RATE: (u32, u32) = (50007, 1000);
+
+pub fn frame_rate_ratio(tv_system: TvSystem) -> (u32, u32) {
+   match tv_system {
+      TvSystem::NTSC => NTSC_FRAME_RATE,
+      TvSystem::PAL => PAL_FRAME_RATE,
+   }
+}
+
+pub fn frame_rate(tv_system: TvSystem) -> f64 {
+   let (num, den) = frame_rate_ratio(tv_system);
+   num as f64 / den as f64
+}
+
 pub struct Rom {
    pub prg_rom: Vec<u8>,
    pub chr_rom: Vec<u8>,
    pub mapper: u8,
    pub screen_mirroring: Mirroring,
+   pub tv_system: TvSystem,
+   // the NES 2.0 default expansion device, 0 when the header doesn't say
+   pub input_device: u8,
 }
 
 impl Rom {
@@ -27,8 +60,16 @@ impl Rom {
 			let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
 
 			let ines_ver = (raw[7] >> 2) & 0b11;
-			if ines_ver != 0 {
-					return Err("NES2.0 format is not supported".to_string());
+			let nes2 = ines_ver == 2;
+			if ines_ver != 0 && !nes2 {
+					return Err("Unknown iNES header version".to_string());
+			}
+			// NES 2.0 is read only as far as the iNES fields plus the input device go
+			if nes2 && raw[8] & 0b1111 != 0 {
+					return Err(format!("Mapper {} is not supported", ((raw[8] as u16 & 0b1111) << 8) | mapper as u16));
+			}
+			if nes2 && (raw[9] & 0b1111 == 0b1111 || raw[9] >> 4 == 0b1111) {
+					return Err("NES2.0 exponent ROM sizes are not supported".to_string());
 			}
 
 			let four_screen = raw[6] & 0b1000 != 0;
@@ -39,8 +80,19 @@ impl Rom {
 					(false, false) => Mirroring::HORIZONTAL,
 			};
 
-			let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-			let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+			// NES 2.0 moves the TV system to byte 12, multi-region ROMs run as NTSC
+			let pal = if nes2 { raw[12] & 0b11 == 1 } else { raw[9] & 0b1 != 0 };
+			let tv_system = if pal { TvSystem::PAL } else { TvSystem::NTSC };
+
+			// NES 2.0 keeps the upper bits of the page counts in byte 9
+			let (prg_pages, chr_pages) = if nes2 {
+					(((raw[9] as usize & 0b1111) << 8) | raw[4] as usize, ((raw[9] as usize >> 4) << 8) | raw[5] as usize)
+			} else {
+					(raw[4] as usize, raw[5] as usize)
+			};
+			let prg_rom_size = prg_pages * PRG_ROM_PAGE_SIZE;
+			let chr_rom_size = chr_pages * CHR_ROM_PAGE_SIZE;
+			let input_device = if nes2 { raw[15] & 0b0011_1111 } else { 0 };
 
 			let skip_trainer = raw[6] & 0b100 != 0;
 
@@ -52,8 +104,26 @@ impl Rom {
 					chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
 					mapper: mapper,
 					screen_mirroring: screen_mirroring,
+					tv_system: tv_system,
+					input_device: input_device,
 			})
 	}
+
+	// the sound chip on the cart, picked by mapper number. Its registers reach it through
+	// the bus even though the mapper's banking isn't emulated
+	pub fn expansion_audio(&self) -> Vec<Box<dyn ExpansionAudio>> {
+			match self.mapper {
+					5 => vec![Box::new(Mmc5Audio::new())],
+					19 => vec![Box::new(N163::new())],
+					// iNES keeps mapper 20 for the Famicom Disk System
+					20 => vec![Box::new(Fds::new())],
+					24 => vec![Box::new(Vrc6::new(false))],
+					26 => vec![Box::new(Vrc6::new(true))],
+					69 => vec![Box::new(Sunsoft5B::new())],
+					85 => vec![Box::new(Vrc7::new())],
+					_ => vec![],
+			}
+	}
 }
 
 pub mod test {
@@ -99,4 +169,40 @@ pub mod test {
 
         Rom::new(&test_rom).unwrap()
     }
+
+    #[test]
+    fn test_nes2_header() {
+        let header = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x00, 0b0000_1000, 0, 0, 0, 0, 0x01, 0, 0, 0x02];
+        let rom = create_rom(test_rom {
+            header: header,
+            trainer: None,
+            pgp_rom: vec![0; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![0; CHR_ROM_PAGE_SIZE],
+        });
+        let parsed = Rom::new(&rom).unwrap();
+        assert_eq!(parsed.input_device, 0x02);
+        assert_eq!(parsed.tv_system, TvSystem::PAL);
+        assert_eq!(parsed.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+
+        // version bits of 1 are neither iNES nor NES 2.0
+        let mut rom = rom;
+        rom[7] = 0b0000_0100;
+        assert!(Rom::new(&rom).is_err());
+    }
+
+    #[test]
+    fn test_expansion_audio_by_mapper() {
+        let mut rom = test_rom(vec![]);
+        assert!(rom.expansion_audio().is_empty());
+        for (mapper, name) in [(5, "MMC5"), (19, "N163"), (20, "FDS"), (24, "VRC6"), (26, "VRC6"), (69, "5B"), (85, "VRC7")] {
+            rom.mapper = mapper;
+            assert_eq!(rom.expansion_audio()[0].name(), name);
+        }
+    }
+
+    #[test]
+    fn test_frame_rates() {
+        assert!((frame_rate(TvSystem::NTSC) - 60.0988).abs() < 0.0001);
+        assert!((frame_rate(TvSystem::PAL) - 50.007).abs() < 0.0001);
+    }
 }
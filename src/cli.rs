@@ -0,0 +1,297 @@
+use crate::display::*;
+use crate::controller::InputSetup;
+use crate::bindings::DEFAULT_BINDINGS_PATH;
+use crate::pad_input::TurboRate;
+use crate::rewind::{DEFAULT_REWIND_BUDGET, DEFAULT_REWIND_INTERVAL};
+use crate::playback::{DEFAULT_FAST_FORWARD, DEFAULT_SLOW_MOTION};
+
+pub struct Options {
+    pub rom_path: String,
+    pub palette: Option<String>,
+    pub display: DisplaySettings,
+    pub record_video: Option<String>,
+    pub record_audio: Option<String>,
+    pub record_channels: bool,
+    // run without a window or audio device for this many frames
+    pub headless_frames: Option<usize>,
+    // 1-based NSF track to start on
+    pub track: Option<usize>,
+    // overrides the input devices the ROM header asks for
+    pub input: Option<InputSetup>,
+    // pad bindings, read at startup and written by the rebinding screen
+    pub bindings: String,
+    pub turbo_rate: TurboRate,
+    // keep Left+Right and Up+Down from reaching the game together
+    pub block_opposites: bool,
+    // FM2 movies: record from power-on, or play one back
+    pub record_movie: Option<String>,
+    pub play_movie: Option<String>,
+    // a played movie ignores the host unless this is set, then any input takes over recording
+    pub read_write: bool,
+    // frames between rewind snapshots and the memory they may take, in megabytes; 0 turns rewinding off
+    pub rewind_interval: usize,
+    pub rewind_budget: usize,
+    // times normal speed while fast-forward is held, None for uncapped
+    pub fast_forward: Option<u32>,
+    // times slower in slow motion
+    pub slow_motion: u32,
+    // run blargg test ROMs headless and report them; the path may be a directory of them
+    pub test_rom: bool,
+}
+
+const DEFAULT_ROM: &str = "src/TestRoms/pacman.nes";
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            rom_path: DEFAULT_ROM.to_string(),
+            palette: None,
+            display: DisplaySettings::new(),
+            record_video: None,
+            record_audio: None,
+            record_channels: false,
+            headless_frames: None,
+            track: None,
+            input: None,
+            bindings: DEFAULT_BINDINGS_PATH.to_string(),
+            turbo_rate: TurboRate::DEFAULT,
+            block_opposites: false,
+            record_movie: None,
+            play_movie: None,
+            read_write: false,
+            rewind_interval: DEFAULT_REWIND_INTERVAL,
+            rewind_budget: DEFAULT_REWIND_BUDGET,
+            fast_forward: DEFAULT_FAST_FORWARD,
+            slow_motion: DEFAULT_SLOW_MOTION,
+            test_rom: false,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--palette" => {
+                    options.palette = Some(next_value(&mut args, arg)?);
+                }
+                "--overscan" => {
+                    options.display.overscan = Overscan::parse(&next_value(&mut args, arg)?)?;
+                }
+                "--aspect" => {
+                    options.display.aspect_correction = true;
+                }
+                "--record-video" => {
+                    options.record_video = Some(next_value(&mut args, arg)?);
+                }
+                "--record-audio" => {
+                    options.record_audio = Some(next_value(&mut args, arg)?);
+                }
+                "--record-channels" => {
+                    options.record_channels = true;
+                }
+                "--track" => {
+                    let value = next_value(&mut args, arg)?;
+                    match value.parse::<usize>() {
+                        Ok(track) if track > 0 => options.track = Some(track),
+                        _ => return Err(format!("bad track {}", value)),
+                    }
+                }
+                "--input" => {
+                    options.input = Some(InputSetup::parse(&next_value(&mut args, arg)?)?);
+                }
+                "--bindings" => {
+                    options.bindings = next_value(&mut args, arg)?;
+                }
+                "--turbo-rate" => {
+                    options.turbo_rate = TurboRate::parse(&next_value(&mut args, arg)?)?;
+                }
+                "--block-opposites" => {
+                    options.block_opposites = true;
+                }
+                "--record-movie" => {
+                    options.record_movie = Some(next_value(&mut args, arg)?);
+                }
+                "--play-movie" => {
+                    options.play_movie = Some(next_value(&mut args, arg)?);
+                }
+                "--read-write" => {
+                    options.read_write = true;
+                }
+                "--rewind-interval" => {
+                    let value = next_value(&mut args, arg)?;
+                    match value.parse::<usize>() {
+                        Ok(frames) if frames > 0 => options.rewind_interval = frames,
+                        _ => return Err(format!("bad rewind interval {}", value)),
+                    }
+                }
+                "--rewind-budget" => {
+                    let value = next_value(&mut args, arg)?;
+                    options.rewind_budget = value.parse::<usize>().map_err(|_| format!("bad rewind budget {}", value))?;
+                }
+                "--fast-forward" => {
+                    let value = next_value(&mut args, arg)?;
+                    options.fast_forward = match value.as_str() {
+                        "max" => None,
+                        _ => Some(speed_factor(&value).map_err(|_| format!("bad fast-forward speed {}, a number above 1 or max", value))?),
+                    };
+                }
+                "--slow-motion" => {
+                    let value = next_value(&mut args, arg)?;
+                    options.slow_motion = speed_factor(&value).map_err(|_| format!("bad slow motion factor {}", value))?;
+                }
+                "--headless" => {
+                    let value = next_value(&mut args, arg)?;
+                    let frames = value.parse::<usize>().map_err(|_| format!("bad frame count {}", value))?;
+                    options.headless_frames = Some(frames);
+                }
+                "--test-rom" => {
+                    options.test_rom = true;
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown option {}", flag));
+                }
+                path => {
+                    options.rom_path = path.to_string();
+                }
+            }
+        }
+        if options.record_channels && options.record_audio.is_none() {
+            return Err("--record-channels needs --record-audio".to_string());
+        }
+        if options.record_movie.is_some() && options.play_movie.is_some() {
+            return Err("--record-movie and --play-movie can't be used together".to_string());
+        }
+        if options.read_write && options.play_movie.is_none() {
+            return Err("--read-write needs --play-movie".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn next_value(args: &mut std::slice::Iter<String>, flag: &str) -> Result<String, String> {
+    args.next().cloned().ok_or(format!("{} needs a value", flag))
+}
+
+fn speed_factor(value: &str) -> Result<u32, ()> {
+    value.parse::<u32>().ok().filter(|factor| *factor > 1).ok_or(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let options = Options::parse(&args(&[])).unwrap();
+        assert_eq!(options.rom_path, DEFAULT_ROM);
+        assert_eq!(options.palette, None);
+    }
+
+    #[test]
+    fn test_rom_and_palette() {
+        let options = Options::parse(&args(&["mario.nes", "--palette", "fceux"])).unwrap();
+        assert_eq!(options.rom_path, "mario.nes");
+        assert_eq!(options.palette, Some("fceux".to_string()));
+    }
+
+    #[test]
+    fn test_display_options() {
+        let options = Options::parse(&args(&["--overscan", "8", "--aspect", "--record-video", "out.y4m"])).unwrap();
+        assert_eq!(options.display.overscan, Overscan::NTSC);
+        assert!(options.display.aspect_correction);
+        assert_eq!(options.record_video, Some("out.y4m".to_string()));
+    }
+
+    #[test]
+    fn test_audio_recording_options() {
+        let options = Options::parse(&args(&["game.nes", "--headless", "600", "--record-audio", "out.wav", "--record-channels"])).unwrap();
+        assert_eq!(options.headless_frames, Some(600));
+        assert_eq!(options.record_audio, Some("out.wav".to_string()));
+        assert!(options.record_channels);
+        assert!(Options::parse(&args(&["--headless", "many"])).is_err());
+        assert!(Options::parse(&args(&["--record-channels"])).is_err());
+    }
+
+    #[test]
+    fn test_test_rom_option() {
+        let options = Options::parse(&args(&["--test-rom", "ppu_vbl_nmi/rom_singles"])).unwrap();
+        assert!(options.test_rom);
+        assert_eq!(options.rom_path, "ppu_vbl_nmi/rom_singles");
+    }
+
+    #[test]
+    fn test_track_option() {
+        let options = Options::parse(&args(&["music.nsf", "--track", "3"])).unwrap();
+        assert_eq!(options.track, Some(3));
+        assert!(Options::parse(&args(&["--track", "0"])).is_err());
+    }
+
+    #[test]
+    fn test_input_option() {
+        let options = Options::parse(&args(&["party.nes", "--input", "fourscore"])).unwrap();
+        assert_eq!(options.input, Some(InputSetup::FourScore));
+        assert_eq!(Options::parse(&args(&[])).unwrap().input, None);
+        assert!(Options::parse(&args(&["--input", "lightgun"])).is_err());
+    }
+
+    #[test]
+    fn test_bindings_option() {
+        assert_eq!(Options::parse(&args(&[])).unwrap().bindings, DEFAULT_BINDINGS_PATH);
+        let options = Options::parse(&args(&["--bindings", "pads.toml"])).unwrap();
+        assert_eq!(options.bindings, "pads.toml");
+    }
+
+    #[test]
+    fn test_turbo_and_opposites_options() {
+        let options = Options::parse(&args(&["--turbo-rate", "1,1", "--block-opposites"])).unwrap();
+        assert_eq!(options.turbo_rate, TurboRate { on: 1, off: 1 });
+        assert!(options.block_opposites);
+        let defaults = Options::parse(&args(&[])).unwrap();
+        assert_eq!(defaults.turbo_rate, TurboRate::DEFAULT);
+        assert!(!defaults.block_opposites);
+        assert!(Options::parse(&args(&["--turbo-rate", "fast"])).is_err());
+    }
+
+    #[test]
+    fn test_movie_options() {
+        let options = Options::parse(&args(&["--play-movie", "run.fm2", "--read-write"])).unwrap();
+        assert_eq!(options.play_movie, Some("run.fm2".to_string()));
+        assert!(options.read_write);
+        let options = Options::parse(&args(&["--record-movie", "new.fm2"])).unwrap();
+        assert_eq!(options.record_movie, Some("new.fm2".to_string()));
+        assert!(!options.read_write);
+        assert!(Options::parse(&args(&["--record-movie", "a.fm2", "--play-movie", "b.fm2"])).is_err());
+        assert!(Options::parse(&args(&["--read-write"])).is_err());
+    }
+
+    #[test]
+    fn test_rewind_options() {
+        let options = Options::parse(&args(&["--rewind-interval", "5", "--rewind-budget", "0"])).unwrap();
+        assert_eq!((options.rewind_interval, options.rewind_budget), (5, 0));
+        let defaults = Options::parse(&args(&[])).unwrap();
+        assert_eq!((defaults.rewind_interval, defaults.rewind_budget), (DEFAULT_REWIND_INTERVAL, DEFAULT_REWIND_BUDGET));
+        assert!(Options::parse(&args(&["--rewind-interval", "0"])).is_err());
+        assert!(Options::parse(&args(&["--rewind-budget", "lots"])).is_err());
+    }
+
+    #[test]
+    fn test_speed_options() {
+        let options = Options::parse(&args(&["--fast-forward", "max", "--slow-motion", "4"])).unwrap();
+        assert_eq!((options.fast_forward, options.slow_motion), (None, 4));
+        let options = Options::parse(&args(&["--fast-forward", "8"])).unwrap();
+        assert_eq!(options.fast_forward, Some(8));
+        let defaults = Options::parse(&args(&[])).unwrap();
+        assert_eq!((defaults.fast_forward, defaults.slow_motion), (DEFAULT_FAST_FORWARD, DEFAULT_SLOW_MOTION));
+        assert!(Options::parse(&args(&["--fast-forward", "1"])).is_err());
+        assert!(Options::parse(&args(&["--slow-motion", "half"])).is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Options::parse(&args(&["--palette"])).is_err());
+        assert!(Options::parse(&args(&["--bogus"])).is_err());
+        assert!(Options::parse(&args(&["--overscan", "1,2"])).is_err());
+    }
+}
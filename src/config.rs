@@ -0,0 +1,199 @@
+// a small subset of TOML: [sections], key = value lines and # comments, where a value is
+// a quoted string, a number, true/false or a one-line [list] of those
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigValue {
+    Text(String),
+    Number(f64),
+    Bool(bool),
+    List(Vec<ConfigValue>),
+}
+
+impl ConfigValue {
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            ConfigValue::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            ConfigValue::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    // a single string counts as a list of one
+    pub fn as_text_list(&self) -> Option<Vec<&str>> {
+        match self {
+            ConfigValue::Text(text) => Some(vec![text.as_str()]),
+            ConfigValue::List(items) => items.iter().map(|item| item.as_text()).collect(),
+            _ => None,
+        }
+    }
+}
+
+pub struct ConfigEntry {
+    pub section: String,
+    pub key: String,
+    pub value: ConfigValue,
+    pub line: usize,
+}
+
+pub struct Config {
+    pub entries: Vec<ConfigEntry>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut entries = vec![];
+        let mut section = String::new();
+        for (i, raw) in text.lines().enumerate() {
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| format!("line {}: {}", i + 1, message);
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(error("unclosed section header"));
+                }
+                section = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+            let equals = line.find('=').ok_or_else(|| error("expected key = value"))?;
+            let key = line[..equals].trim();
+            if key.is_empty() {
+                return Err(error("missing key"));
+            }
+            let (value, rest) = parse_value(line[equals + 1..].trim()).map_err(|e| error(&e))?;
+            if !rest.trim().is_empty() {
+                return Err(error("unexpected text after value"));
+            }
+            entries.push(ConfigEntry { section: section.clone(), key: key.to_string(), value: value, line: i + 1 });
+        }
+        Ok(Config { entries: entries })
+    }
+}
+
+// quotes a string the way parse reads it back
+pub fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if in_string && !escaped => {
+                escaped = true;
+                continue;
+            }
+            '"' if !escaped => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+// the value at the start of text, and whatever follows it
+fn parse_value(text: &str) -> Result<(ConfigValue, &str), String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((ConfigValue::Text(value), &rest[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, escaped)) => value.push(escaped),
+                    None => break,
+                },
+                _ => value.push(c),
+            }
+        }
+        return Err("unclosed string".to_string());
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = vec![];
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((ConfigValue::List(items), after));
+            }
+            if rest.is_empty() {
+                return Err("unclosed list".to_string());
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("expected , or ] in list".to_string());
+            }
+        }
+    }
+    let end = text.find(|c: char| c == ',' || c == ']' || c.is_whitespace()).unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => ConfigValue::Bool(true),
+        "false" => ConfigValue::Bool(false),
+        _ => ConfigValue::Number(word.parse::<f64>().map_err(|_| format!("bad value {}", word))?),
+    };
+    Ok((value, rest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn get<'a>(config: &'a Config, section: &str, key: &str) -> Option<&'a ConfigValue> {
+        config.entries.iter().find(|e| e.section == section && e.key == key).map(|e| &e.value)
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            "# bindings\n\
+             top = 1\n\
+             [player1]\n\
+             up = [\"key:Up\", \"axis:lefty-\"] # the stick too\n\
+             threshold = 0.25\n\
+             \n\
+             [ player2 ]\n\
+             enabled = false\n\
+             name = \"a \\\"quoted\\\" # name\"\n",
+        )
+        .unwrap();
+        assert_eq!(get(&config, "", "top"), Some(&ConfigValue::Number(1.0)));
+        assert_eq!(get(&config, "player1", "up").unwrap().as_text_list(), Some(vec!["key:Up", "axis:lefty-"]));
+        assert_eq!(get(&config, "player1", "threshold").unwrap().as_number(), Some(0.25));
+        assert_eq!(get(&config, "player2", "enabled"), Some(&ConfigValue::Bool(false)));
+        assert_eq!(get(&config, "player2", "name").unwrap().as_text(), Some("a \"quoted\" # name"));
+        assert_eq!(get(&config, "player2", "up"), None);
+        assert_eq!(config.entries[1].line, 4);
+    }
+
+    #[test]
+    fn test_quote_round_trips() {
+        let text = format!("name = {}", quote("back\\slash \"and\" quote"));
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(get(&config, "", "name").unwrap().as_text(), Some("back\\slash \"and\" quote"));
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        assert_eq!(Config::parse("[player1\n").err(), Some("line 1: unclosed section header".to_string()));
+        assert!(Config::parse("\nup\n").err().unwrap().starts_with("line 2"));
+        assert!(Config::parse("up = \"open").is_err());
+        assert!(Config::parse("up = [\"a\" \"b\"]").is_err());
+        assert!(Config::parse("up = [\"a\",").is_err());
+        assert!(Config::parse("up = maybe").is_err());
+        assert!(Config::parse("up = 1 2").is_err());
+    }
+}
@@ -0,0 +1,551 @@
+use crate::joypad::*;
+use crate::zapper::*;
+use crate::paddle::*;
+use crate::power_pad::*;
+use crate::family_keyboard::*;
+use crate::pad_input::*;
+
+// data lines the controller ports drive on $4016/$4017 reads, the rest is open bus
+pub const PORT_DATA_MASK: u8 = 0b0001_1111;
+
+// anything that plugs into a controller port or the Famicom expansion port
+pub trait PortDevice {
+    fn name(&self) -> &'static str;
+
+    // every $4016 write reaches every device: bit 0 is the strobe (OUT0), bits 1-2 only
+    // reach the Famicom expansion port
+    fn write(&mut self, data: u8);
+
+    // the data lines this device drives, already in position, on a read of $4016
+    // (port 0) or $4017 (port 1); devices in a controller port only see their own port
+    fn read(&mut self, port: usize) -> u8;
+
+    // light guns look at the screen right before each read
+    fn needs_light(&self) -> bool {
+        false
+    }
+
+    fn sense_light(&mut self, _light: &LightSense) {}
+
+    // for frontends feeding button state
+    fn joypad(&mut self, _player: usize) -> Option<&mut Joypad> {
+        None
+    }
+
+    fn zapper(&mut self) -> Option<&mut Zapper> {
+        None
+    }
+
+    fn paddle(&mut self) -> Option<&mut Paddle> {
+        None
+    }
+
+    fn power_pad(&mut self) -> Option<&mut PowerPad> {
+        None
+    }
+
+    fn keyboard(&mut self) -> Option<&mut FamilyKeyboard> {
+        None
+    }
+}
+
+impl PortDevice for Joypad {
+    fn name(&self) -> &'static str {
+        "joypad"
+    }
+
+    fn write(&mut self, data: u8) {
+        self.write_joypad(data);
+    }
+
+    fn read(&mut self, _port: usize) -> u8 {
+        self.read_joypad()
+    }
+
+    fn joypad(&mut self, player: usize) -> Option<&mut Joypad> {
+        if player == 0 { Some(self) } else { None }
+    }
+}
+
+// an empty port, nothing pulls the data lines
+pub struct Unplugged;
+
+impl PortDevice for Unplugged {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&mut self, _port: usize) -> u8 {
+        0
+    }
+}
+
+// the Four Score's ID byte after the two pads, read MSB first, one for each port
+const FOUR_SCORE_SIGNATURES: [u8; 2] = [0b0001_0000, 0b0010_0000];
+
+// one port's side of the NES Four Score: its two pads shifted out back to back, then
+// the signature byte
+pub struct FourScore {
+    pads: [Joypad; 2],
+    signature: u8,
+    strobe: bool,
+    bit: u8,
+}
+
+impl FourScore {
+    pub fn new(port: usize) -> Self {
+        FourScore { pads: [Joypad::new(), Joypad::new()], signature: FOUR_SCORE_SIGNATURES[port], strobe: false, bit: 0 }
+    }
+}
+
+impl PortDevice for FourScore {
+    fn name(&self) -> &'static str {
+        "four score"
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.bit = 0;
+        }
+        for pad in self.pads.iter_mut() {
+            pad.write_joypad(data);
+        }
+    }
+
+    fn read(&mut self, _port: usize) -> u8 {
+        let data = match self.bit {
+            0..=7 => self.pads[0].read_joypad(),
+            8..=15 => self.pads[1].read_joypad(),
+            16..=23 => (self.signature >> (23 - self.bit)) & 1,
+            _ => 1,
+        };
+        if !self.strobe && self.bit < 24 {
+            self.bit += 1;
+        }
+        data
+    }
+
+    fn joypad(&mut self, player: usize) -> Option<&mut Joypad> {
+        self.pads.get_mut(player)
+    }
+}
+
+// the Famicom's four-player adapters: players 3 and 4 on D1 of $4016 and $4017
+pub struct FamicomFourPlayer {
+    pads: [Joypad; 2],
+}
+
+impl FamicomFourPlayer {
+    pub fn new() -> Self {
+        FamicomFourPlayer { pads: [Joypad::new(), Joypad::new()] }
+    }
+}
+
+impl PortDevice for FamicomFourPlayer {
+    fn name(&self) -> &'static str {
+        "famicom 4 player"
+    }
+
+    fn write(&mut self, data: u8) {
+        for pad in self.pads.iter_mut() {
+            pad.write_joypad(data);
+        }
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        self.pads[port].read_joypad() << 1
+    }
+
+    fn joypad(&mut self, player: usize) -> Option<&mut Joypad> {
+        self.pads.get_mut(player)
+    }
+}
+
+// what's plugged in, picked on the command line or by the ROM's NES 2.0 header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputSetup {
+    Standard,
+    FourScore,
+    FamicomFourPlayer,
+    // a pad in port 1 and the Zapper in port 2
+    Zapper,
+    // a pad in port 1 and the Arkanoid controller in port 2
+    Vaus,
+    // pads in both ports and the Arkanoid controller on the expansion port
+    FamicomVaus,
+    // a pad in port 1 and the mat in port 2
+    PowerPad,
+    // pads in both ports and the Family BASIC keyboard on the expansion port
+    Keyboard,
+}
+
+impl InputSetup {
+    pub fn parse(name: &str) -> Result<InputSetup, String> {
+        match name {
+            "standard" => Ok(InputSetup::Standard),
+            "fourscore" => Ok(InputSetup::FourScore),
+            "famicom4p" => Ok(InputSetup::FamicomFourPlayer),
+            "zapper" => Ok(InputSetup::Zapper),
+            "vaus" => Ok(InputSetup::Vaus),
+            "famicomvaus" => Ok(InputSetup::FamicomVaus),
+            "powerpad" => Ok(InputSetup::PowerPad),
+            "keyboard" => Ok(InputSetup::Keyboard),
+            _ => Err(format!("unknown input setup {}", name)),
+        }
+    }
+
+    // the NES 2.0 default expansion device numbers
+    pub fn from_header(device: u8) -> Option<InputSetup> {
+        match device {
+            0x01 => Some(InputSetup::Standard),
+            0x02 => Some(InputSetup::FourScore),
+            0x03 => Some(InputSetup::FamicomFourPlayer),
+            0x08 => Some(InputSetup::Zapper),
+            // sides A and B are the same mat
+            0x0B | 0x0C => Some(InputSetup::PowerPad),
+            0x0F => Some(InputSetup::Vaus),
+            0x10 => Some(InputSetup::FamicomVaus),
+            0x23 => Some(InputSetup::Keyboard),
+            _ => None,
+        }
+    }
+
+    pub fn players(&self) -> usize {
+        match self {
+            InputSetup::Zapper | InputSetup::Vaus | InputSetup::PowerPad => 1,
+            InputSetup::Standard | InputSetup::FamicomVaus | InputSetup::Keyboard => 2,
+            InputSetup::FourScore | InputSetup::FamicomFourPlayer => 4,
+        }
+    }
+}
+
+// when host input reaches the pads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostInput {
+    // as it happens
+    Live,
+    // at frame boundaries only, so a movie records exactly what the game saw
+    PerFrame,
+    // not at all while a movie plays
+    Blocked,
+}
+
+// the two controller ports plus the Famicom expansion port
+pub struct ControllerPorts {
+    ports: [Box<dyn PortDevice>; 2],
+    expansion: Box<dyn PortDevice>,
+    // host input for players 1-4 on its way to their pads
+    inputs: Vec<PadInput>,
+    pub turbo_rate: TurboRate,
+    pub block_opposites: bool,
+    host_input: HostInput,
+    // a button went down while host input was blocked
+    host_pressed: bool,
+    setup: InputSetup,
+}
+
+impl ControllerPorts {
+    // a standard pad in each port
+    pub fn new() -> Self {
+        ControllerPorts {
+            ports: [Box::new(Joypad::new()), Box::new(Joypad::new())],
+            expansion: Box::new(Unplugged),
+            inputs: (0..4).map(|_| PadInput::new()).collect(),
+            turbo_rate: TurboRate::DEFAULT,
+            block_opposites: false,
+            host_input: HostInput::Live,
+            host_pressed: false,
+            setup: InputSetup::Standard,
+        }
+    }
+
+    pub fn connect(&mut self, setup: InputSetup) {
+        let (port1, port2, expansion): (Box<dyn PortDevice>, Box<dyn PortDevice>, Box<dyn PortDevice>) = match setup {
+            InputSetup::Standard => (Box::new(Joypad::new()), Box::new(Joypad::new()), Box::new(Unplugged)),
+            InputSetup::FourScore => (Box::new(FourScore::new(0)), Box::new(FourScore::new(1)), Box::new(Unplugged)),
+            InputSetup::FamicomFourPlayer => (Box::new(Joypad::new()), Box::new(Joypad::new()), Box::new(FamicomFourPlayer::new())),
+            InputSetup::Zapper => (Box::new(Joypad::new()), Box::new(Zapper::new()), Box::new(Unplugged)),
+            InputSetup::Vaus => (Box::new(Joypad::new()), Box::new(Paddle::new(false)), Box::new(Unplugged)),
+            InputSetup::FamicomVaus => (Box::new(Joypad::new()), Box::new(Joypad::new()), Box::new(Paddle::new(true))),
+            InputSetup::PowerPad => (Box::new(Joypad::new()), Box::new(PowerPad::new()), Box::new(Unplugged)),
+            InputSetup::Keyboard => (Box::new(Joypad::new()), Box::new(Joypad::new()), Box::new(FamilyKeyboard::new())),
+        };
+        self.ports = [port1, port2];
+        self.expansion = expansion;
+        self.setup = setup;
+    }
+
+    pub fn setup(&self) -> InputSetup {
+        self.setup
+    }
+
+    pub fn plug(&mut self, port: usize, device: Box<dyn PortDevice>) {
+        self.ports[port] = device;
+    }
+
+    pub fn plug_expansion(&mut self, device: Box<dyn PortDevice>) {
+        self.expansion = device;
+    }
+
+    pub fn device(&mut self, port: usize) -> &mut dyn PortDevice {
+        self.ports[port].as_mut()
+    }
+
+    pub fn expansion(&mut self) -> &mut dyn PortDevice {
+        self.expansion.as_mut()
+    }
+
+    pub fn write(&mut self, data: u8) {
+        for device in self.ports.iter_mut() {
+            device.write(data);
+        }
+        self.expansion.write(data);
+    }
+
+    pub fn needs_light(&self) -> bool {
+        self.ports.iter().any(|device| device.needs_light()) || self.expansion.needs_light()
+    }
+
+    pub fn sense_light(&mut self, light: &LightSense) {
+        for device in self.ports.iter_mut() {
+            device.sense_light(light);
+        }
+        self.expansion.sense_light(light);
+    }
+
+    // port 0 is $4016, port 1 is $4017; the upper bits keep whatever was last on the bus
+    pub fn read(&mut self, port: usize, open_bus: u8) -> u8 {
+        let data = self.ports[port].read(port) | self.expansion.read(port);
+        (open_bus & !PORT_DATA_MASK) | (data & PORT_DATA_MASK)
+    }
+
+    // player 0 is the pad in port 1, player 1 the one in port 2; players 2 and 3 sit
+    // behind a Four Score's ports or on the expansion port
+    pub fn joypad(&mut self, player: usize) -> Option<&mut Joypad> {
+        if let Some(pad) = self.ports[player % 2].joypad(player / 2) {
+            return Some(pad);
+        }
+        if player >= 2 { self.expansion.joypad(player - 2) } else { None }
+    }
+
+    // host input for a player's pad, through turbo and the opposite-direction filter;
+    // frontends should press buttons this way rather than on the Joypad itself
+    pub fn press(&mut self, player: usize, button: PadButton, pressed: bool) {
+        if let Some(input) = self.inputs.get_mut(player) {
+            input.set(button, pressed);
+            match self.host_input {
+                HostInput::Live => self.apply(player),
+                HostInput::PerFrame => {}
+                HostInput::Blocked => self.host_pressed |= pressed,
+            }
+        }
+    }
+
+    // once a frame, to pulse the turbo buttons and hand over input held back till now
+    pub fn frame(&mut self) {
+        for player in 0..self.inputs.len() {
+            self.inputs[player].frame();
+            let due = match self.host_input {
+                HostInput::Live => self.inputs[player].has_turbo(),
+                HostInput::PerFrame => true,
+                HostInput::Blocked => false,
+            };
+            if due {
+                self.apply(player);
+            }
+        }
+    }
+
+    pub fn host_input(&self) -> HostInput {
+        self.host_input
+    }
+
+    // the pads take the host's current state whenever its input stops being blocked
+    pub fn set_host_input(&mut self, host_input: HostInput) {
+        self.host_input = host_input;
+        self.host_pressed = false;
+        if host_input != HostInput::Blocked {
+            for player in 0..self.inputs.len() {
+                self.apply(player);
+            }
+        }
+    }
+
+    // whether the host pressed anything since the last call while its input was blocked
+    pub fn take_host_pressed(&mut self) -> bool {
+        std::mem::replace(&mut self.host_pressed, false)
+    }
+
+    fn apply(&mut self, player: usize) {
+        let buttons = self.inputs[player].buttons(self.turbo_rate, self.block_opposites);
+        if let Some(joypad) = self.joypad(player) {
+            joypad.set_button_pressed_status(JoypadButtons::all(), false);
+            joypad.set_button_pressed_status(buttons, true);
+        }
+    }
+
+    pub fn zapper(&mut self) -> Option<&mut Zapper> {
+        self.find(|device| device.zapper())
+    }
+
+    pub fn paddle(&mut self) -> Option<&mut Paddle> {
+        self.find(|device| device.paddle())
+    }
+
+    pub fn power_pad(&mut self) -> Option<&mut PowerPad> {
+        self.find(|device| device.power_pad())
+    }
+
+    pub fn keyboard(&mut self) -> Option<&mut FamilyKeyboard> {
+        self.find(|device| device.keyboard())
+    }
+
+    // the first device, port 2 before port 1 then the expansion port, that has one
+    fn find<T>(&mut self, get: impl for<'a> Fn(&'a mut (dyn PortDevice + 'static)) -> Option<&'a mut T>) -> Option<&mut T> {
+        let [port1, port2] = &mut self.ports;
+        for device in [port2, port1, &mut self.expansion] {
+            if let Some(found) = get(device.as_mut()) {
+                return Some(found);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strobe(ports: &mut ControllerPorts) {
+        ports.write(1);
+        ports.write(0);
+    }
+
+    fn read_byte(ports: &mut ControllerPorts, port: usize) -> u8 {
+        (0..8).map(|i| (ports.read(port, 0) & 1) << i).sum()
+    }
+
+    #[test]
+    fn test_two_pads_read_independently() {
+        let mut ports = ControllerPorts::new();
+        ports.joypad(0).unwrap().set_button_pressed_status(JoypadButtons::START, true);
+        ports.joypad(1).unwrap().set_button_pressed_status(JoypadButtons::BUTTON_A | JoypadButtons::LEFT, true);
+        strobe(&mut ports);
+        assert_eq!(read_byte(&mut ports, 1), 0b0100_0001);
+        assert_eq!(read_byte(&mut ports, 0), 0b0000_1000);
+        // official pads report 1 once all 8 buttons are out
+        assert_eq!(ports.read(1, 0), 1);
+    }
+
+    #[test]
+    fn test_press_goes_through_turbo_and_the_filter() {
+        let mut ports = ControllerPorts::new();
+        ports.connect(InputSetup::FourScore);
+        ports.block_opposites = true;
+        ports.turbo_rate = TurboRate { on: 1, off: 1 };
+        ports.press(3, PadButton::Normal(JoypadButtons::LEFT), true);
+        ports.press(3, PadButton::Normal(JoypadButtons::RIGHT), true);
+        ports.press(3, PadButton::Turbo(JoypadButtons::BUTTON_A), true);
+        let mut frames = vec![];
+        for _ in 0..4 {
+            strobe(&mut ports);
+            frames.push((read_bits(&mut ports, 1) >> 8) as u8);
+            ports.frame();
+        }
+        assert_eq!(frames, vec![0b1000_0001, 0b1000_0000, 0b1000_0001, 0b1000_0000]);
+        // players past the adapter's pads go nowhere
+        ports.press(5, PadButton::Normal(JoypadButtons::START), true);
+    }
+
+    #[test]
+    fn test_open_bus_upper_bits() {
+        let mut ports = ControllerPorts::new();
+        ports.joypad(0).unwrap().set_button_pressed_status(JoypadButtons::BUTTON_A, true);
+        strobe(&mut ports);
+        assert_eq!(ports.read(0, 0x40), 0x41);
+        assert_eq!(ports.read(1, 0x40), 0x40);
+        // the driven data lines are never open bus
+        assert_eq!(ports.read(1, 0xFF), 0xE0);
+    }
+
+    // the 24 bits a Four Score shifts out of one port
+    fn read_bits(ports: &mut ControllerPorts, port: usize) -> u32 {
+        (0..24).map(|i| ((ports.read(port, 0) & 1) as u32) << i).sum()
+    }
+
+    #[test]
+    fn test_four_score() {
+        let mut ports = ControllerPorts::new();
+        ports.connect(InputSetup::FourScore);
+        for (player, button) in [JoypadButtons::BUTTON_A, JoypadButtons::BUTTON_B, JoypadButtons::SELECT, JoypadButtons::START].iter().enumerate() {
+            ports.joypad(player).unwrap().set_button_pressed_status(*button, true);
+        }
+        assert!(ports.joypad(4).is_none());
+        strobe(&mut ports);
+        // players 1 and 3, then the signature read as %00010000
+        assert_eq!(read_bits(&mut ports, 0), 0b0000_1000_0000_0100_0000_0001);
+        // players 2 and 4, then %00100000
+        assert_eq!(read_bits(&mut ports, 1), 0b0000_0100_0000_1000_0000_0010);
+        assert_eq!(ports.read(0, 0), 1);
+
+        // while strobed the first pad's A button keeps coming back
+        ports.write(1);
+        assert_eq!(ports.read(0, 0), 1);
+        assert_eq!(ports.read(0, 0), 1);
+    }
+
+    #[test]
+    fn test_famicom_four_player_adapter() {
+        let mut ports = ControllerPorts::new();
+        ports.connect(InputSetup::FamicomFourPlayer);
+        ports.joypad(0).unwrap().set_button_pressed_status(JoypadButtons::UP, true);
+        ports.joypad(2).unwrap().set_button_pressed_status(JoypadButtons::BUTTON_A, true);
+        ports.joypad(3).unwrap().set_button_pressed_status(JoypadButtons::BUTTON_B, true);
+        strobe(&mut ports);
+        assert_eq!(ports.read(0, 0), 0b10);
+        assert_eq!(ports.read(1, 0), 0b00);
+        assert_eq!(ports.read(1, 0), 0b10);
+        for _ in 0..3 {
+            assert_eq!(ports.read(0, 0), 0b00);
+        }
+        assert_eq!(ports.read(0, 0), 0b01);
+    }
+
+    #[test]
+    fn test_devices_found_where_plugged() {
+        let mut ports = ControllerPorts::new();
+        ports.connect(InputSetup::Vaus);
+        assert!(ports.paddle().is_some() && ports.joypad(1).is_none());
+        ports.connect(InputSetup::FamicomVaus);
+        assert!(ports.paddle().is_some() && ports.joypad(1).is_some());
+        ports.connect(InputSetup::PowerPad);
+        assert_eq!(ports.device(1).name(), "power pad");
+        assert!(ports.power_pad().is_some() && ports.keyboard().is_none());
+        ports.connect(InputSetup::Keyboard);
+        assert_eq!(ports.expansion().name(), "family basic keyboard");
+        assert!(ports.keyboard().is_some() && ports.zapper().is_none());
+    }
+
+    #[test]
+    fn test_input_setup() {
+        assert_eq!(InputSetup::parse("fourscore"), Ok(InputSetup::FourScore));
+        assert!(InputSetup::parse("sixscore").is_err());
+        assert_eq!(InputSetup::from_header(0x03), Some(InputSetup::FamicomFourPlayer));
+        assert_eq!(InputSetup::from_header(0x08), Some(InputSetup::Zapper));
+        assert_eq!(InputSetup::from_header(0x00), None);
+        assert_eq!(InputSetup::FourScore.players(), 4);
+    }
+
+    #[test]
+    fn test_unplugged_port() {
+        let mut ports = ControllerPorts::new();
+        ports.plug(1, Box::new(Unplugged));
+        strobe(&mut ports);
+        assert_eq!(ports.read(1, 0x40), 0x40);
+        assert!(ports.joypad(1).is_none());
+        assert_eq!(ports.device(1).name(), "none");
+    }
+}
@@ -3,6 +3,8 @@ use lazy_static::lazy_static;
 use std::collections::HashMap;
 use crate::opcodes::*;
 use crate::bus::*;
+use crate::rewind::RewindBuffer;
+use crate::savestate::{Savestate, StateBuffer};
     ///
     ///  7 6 5 4 3 2 1 0
     ///  N V _ B D I Z C
@@ -26,6 +28,12 @@ pub struct CPU<'a> {
     pub status: u8,
     pub bus: Bus<'a>,
     additional_cycles: u8,
+    // the running instruction's cycles before any extra ones, 0 between instructions
+    instruction_cycles: u8,
+    // snapshots to step back through, if rewinding is on
+    pub rewind: Option<RewindBuffer>,
+    // makes execute return before the next instruction
+    stopping: bool,
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -89,6 +97,7 @@ impl opCode {
     pub enum InterruptType {
         NMI,
         BRK,
+        IRQ,
     }
 
     #[derive(PartialEq, Eq)]
@@ -106,6 +115,13 @@ impl opCode {
         cpu_cycles: 2,
     };
 
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b00100000,
+        cpu_cycles: 2,
+    };
+
     pub(super) const BRK: Interrupt = Interrupt {
         itype: InterruptType::BRK,
         vector_addr: 0xfffe,
@@ -456,6 +472,11 @@ lazy_static! {
 
 impl Mem for CPU<'_> {
     fn memory_read(&mut self, address: u16) -> u8 {
+        // a $2002 read happens on its instruction's last cycle, so the PPU runs up to it
+        // first: vblank's flag and NMI race a read within a dot or two
+        if self.instruction_cycles > 0 && (0x2000..=0x3FFF).contains(&address) && address & 7 == 2 {
+            self.bus.catch_up(self.instruction_cycles + self.additional_cycles - 1);
+        }
         // return self.memory[address as usize];
         return self.bus.memory_read(address)
     }
@@ -476,6 +497,19 @@ impl Mem for CPU<'_> {
 
 const stack_reset: u8 = 0xFD;
 
+impl Savestate for CPU<'_> {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.u8(&mut self.register_a);
+        s.u8(&mut self.register_x);
+        s.u8(&mut self.register_y);
+        s.u16(&mut self.program_counter);
+        s.u8(&mut self.stack_pointer);
+        s.u8(&mut self.status);
+        s.u8(&mut self.additional_cycles);
+        self.bus.state(s);
+    }
+}
+
 impl <'a>CPU<'a> {
     pub fn new<'b>(bus: Bus<'b>) -> CPU<'b> {
         CPU {
@@ -487,9 +521,17 @@ impl <'a>CPU<'a> {
             status: 0,
             bus: bus,
             additional_cycles: 0,
+            instruction_cycles: 0,
+            rewind: None,
+            stopping: false,
         }
     }
 
+    // for the execute callback, to end the run cleanly
+    pub fn stop(&mut self) {
+        self.stopping = true;
+    }
+
     pub fn reset(&mut self) {
         self.register_a = 0;
         self.register_x = 0;
@@ -503,6 +545,73 @@ impl <'a>CPU<'a> {
         self.program_counter = if pc == 0 { 0x8000 } else {pc};
     }
 
+    // pressing reset leaves A, X and Y alone, moves the stack pointer down three as an
+    // interrupt would and masks IRQs; power-on starts from scratch
+    pub fn reset_with(&mut self, kind: ResetKind) {
+        self.bus.reset(kind);
+        match kind {
+            ResetKind::Soft => {
+                self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+                self.status |= 0b0000_0100;
+                let pc = self.memory_read_u16(0xFFFC);
+                self.program_counter = if pc == 0 { 0x8000 } else { pc };
+            }
+            ResetKind::Power => self.reset(),
+        }
+    }
+
+    pub fn save_state(&mut self) -> Vec<u8> {
+        let mut s = StateBuffer::saving();
+        self.state(&mut s);
+        s.into_data()
+    }
+
+    pub fn load_state(&mut self, data: Vec<u8>) {
+        self.state(&mut StateBuffer::loading(data));
+    }
+
+    // goes back to the newest snapshot at least this many frames old, or the oldest
+    // one kept; returns how many frames back that was
+    pub fn rewind(&mut self, frames: usize) -> usize {
+        let mut buffer = match self.rewind.take() {
+            Some(buffer) => buffer,
+            None => return 0,
+        };
+        let back = self.restore(&mut buffer, frames as u64);
+        self.rewind = Some(buffer);
+        back as usize
+    }
+
+    fn restore(&mut self, buffer: &mut RewindBuffer, frames: u64) -> u64 {
+        let (data, movie_frame, back) = match buffer.rewind(frames) {
+            Some((snapshot, back)) => (snapshot.data.clone(), snapshot.movie_frame, back),
+            None => return 0,
+        };
+        self.load_state(data);
+        if let (Some(movie), Some(frame)) = (self.bus.movie.as_mut(), movie_frame) {
+            movie.rewind_to(frame, &mut self.bus.ports);
+        }
+        back
+    }
+
+    // at each frame boundary: a snapshot every so many frames, or a step back past the
+    // last one while the frontend holds rewind
+    fn end_frame(&mut self) {
+        let mut buffer = match self.rewind.take() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let due = buffer.end_frame();
+        if buffer.held {
+            let step = buffer.interval as u64 + 1;
+            self.restore(&mut buffer, step);
+        } else if due {
+            let movie_frame = self.bus.movie.as_ref().map(|movie| movie.frame);
+            buffer.push(movie_frame, self.save_state());
+        }
+        self.rewind = Some(buffer);
+    }
+
     pub fn stack_push(&mut self, value: u8) {
         self.memory_write(0x100 + (self.stack_pointer as u16), value);
         //println!("{:x}", self.memory_read(0x100 + (self.stack_pointer as u16)));
@@ -517,7 +626,7 @@ impl <'a>CPU<'a> {
         return val;
     }
 
-    fn stack_push_u16(&mut self, data: u16) {
+    pub fn stack_push_u16(&mut self, data: u16) {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
         self.stack_push(hi);
@@ -999,7 +1108,7 @@ impl <'a>CPU<'a> {
 
     fn interrupt(&mut self,  interrupt: Interrupt){
         self.stack_push_u16(self.program_counter);
-        if(interrupt.itype == InterruptType::NMI){
+        if(interrupt.itype == InterruptType::NMI || interrupt.itype == InterruptType::IRQ){
             self.stack_push((self.status & 0b1110_1111) | 0b0010_0000);
         } else if(interrupt.itype == InterruptType::BRK){
             self.stack_push(self.status | 0b0011_0000);
@@ -1013,15 +1122,28 @@ impl <'a>CPU<'a> {
     pub fn execute<F>(&mut self, mut callback: F)
     where F: FnMut(&mut CPU) {
         loop {
+            if let Some(kind) = self.bus.take_reset() {
+                self.reset_with(kind);
+            }
+            if self.bus.take_frame_end() {
+                self.end_frame();
+            }
             if let Some(_nmi) = self.bus.poll_nmi_status() {
                 self.interrupt(NMI);
+            } else if self.bus.poll_irq_status() && self.status & 0b0000_0100 == 0 {
+                self.interrupt(IRQ);
             }
             callback(self);
+            if std::mem::take(&mut self.stopping) {
+                return;
+            }
             self.additional_cycles = 0;
+            self.instruction_cycles = 0;
             //println!("{}", self.status);
             // let opcode = self.memory[self.program_counter as usize];
             let opcode = self.memory_read(self.program_counter);
             self.program_counter += 1;
+            self.instruction_cycles = opcode_map.get(&opcode).map_or(0, |o| o.cycles);
             //println!("{:04x}", ((self.memory_read((self.stack_pointer + 1) as u16 + 0x100) as u16) << 8) | (self.memory_read((self.stack_pointer + 2) as u16 + 0x100))as u16);
             //println!("op code {:#x}", opcode);
 
@@ -1586,6 +1708,7 @@ impl <'a>CPU<'a> {
                 _ => {}//todo!("Unimplemented opcode: {:02X}", opcode),
             }
             let opcode_cycles = opcode_map[&opcode].cycles;
+            self.instruction_cycles = 0;
             self.bus.tick(opcode_cycles + self.additional_cycles);
         }
     }
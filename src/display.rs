@@ -0,0 +1,231 @@
+use std::fmt;
+
+use crate::filters::*;
+use crate::frame::*;
+
+// NES pixels are 8:7 on an NTSC TV
+pub const PIXEL_ASPECT: (usize, usize) = (8, 7);
+
+// lines/columns hidden on each edge, in NES pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    pub const NONE: Overscan = Overscan { top: 0, bottom: 0, left: 0, right: 0 };
+    pub const NTSC: Overscan = Overscan { top: 8, bottom: 8, left: 0, right: 0 };
+
+    // "top,bottom,left,right" or a single number for the top and bottom
+    pub fn parse(text: &str) -> Result<Overscan, String> {
+        let values: Result<Vec<usize>, _> = text.split(',').map(|v| v.trim().parse::<usize>()).collect();
+        let values = values.map_err(|_| format!("bad overscan {}", text))?;
+        let overscan = match values.as_slice() {
+            [vertical] => Overscan { top: *vertical, bottom: *vertical, left: 0, right: 0 },
+            [top, bottom, left, right] => Overscan { top: *top, bottom: *bottom, left: *left, right: *right },
+            _ => return Err(format!("overscan needs 1 or 4 values, got {}", text)),
+        };
+        if overscan.top + overscan.bottom >= Frame::HIGHT || overscan.left + overscan.right >= Frame::WIDTH {
+            return Err(format!("overscan {} crops the whole picture", text));
+        }
+        Ok(overscan)
+    }
+}
+
+// the part of the 256x240 picture that is kept, in NES pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CropRegion {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl fmt::Display for CropRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplaySettings {
+    pub overscan: Overscan,
+    pub aspect_correction: bool,
+}
+
+impl DisplaySettings {
+    pub fn new() -> Self {
+        DisplaySettings {
+            overscan: Overscan::NONE,
+            aspect_correction: false,
+        }
+    }
+
+    pub fn crop_region(&self) -> CropRegion {
+        CropRegion {
+            x: self.overscan.left,
+            y: self.overscan.top,
+            width: Frame::WIDTH - self.overscan.left - self.overscan.right,
+            height: Frame::HIGHT - self.overscan.top - self.overscan.bottom,
+        }
+    }
+
+    // works on frames that were already widened (e.g. by the NTSC filter) by scaling the region
+    pub fn crop(&self, image: &Image) -> Image {
+        let region = self.crop_region();
+        let scale_x = image.width / Frame::WIDTH;
+        let scale_y = image.height / Frame::HIGHT;
+        let mut out = Image::new(region.width * scale_x, region.height * scale_y);
+        for y in 0..out.height {
+            let src = ((region.y * scale_y + y) * image.width + region.x * scale_x) * 3;
+            let dst = y * out.width * 3;
+            out.data[dst..dst + out.width * 3].copy_from_slice(&image.data[src..src + out.width * 3]);
+        }
+        out
+    }
+
+    // size the cropped picture should be shown at, in NES pixel rows/columns
+    pub fn display_size(&self) -> (usize, usize) {
+        let region = self.crop_region();
+        if self.aspect_correction {
+            (corrected_width(region.width), region.height)
+        } else {
+            (region.width, region.height)
+        }
+    }
+
+    pub fn correct_aspect(&self, image: &Image) -> Image {
+        if !self.aspect_correction {
+            return image.clone();
+        }
+        stretch_horizontal(image, corrected_width(image.width))
+    }
+
+    // the frame pixel under a point of a window showing the cropped picture stretched to fit
+    pub fn frame_position(&self, x: i32, y: i32, window_width: u32, window_height: u32) -> Option<(usize, usize)> {
+        if x < 0 || y < 0 || x as u32 >= window_width || y as u32 >= window_height {
+            return None;
+        }
+        let region = self.crop_region();
+        Some((
+            region.x + x as usize * region.width / window_width as usize,
+            region.y + y as usize * region.height / window_height as usize,
+        ))
+    }
+
+    // cropping followed by aspect correction, what screenshots save
+    pub fn present(&self, image: &Image) -> Image {
+        self.correct_aspect(&self.crop(image))
+    }
+}
+
+pub fn corrected_width(width: usize) -> usize {
+    (width * PIXEL_ASPECT.0 + PIXEL_ASPECT.1 / 2) / PIXEL_ASPECT.1
+}
+
+// linear resample of each row to the new width
+fn stretch_horizontal(image: &Image, width: usize) -> Image {
+    let mut out = Image::new(width, image.height);
+    for y in 0..image.height {
+        for x in 0..width {
+            let pos = (x as f32 + 0.5) * image.width as f32 / width as f32 - 0.5;
+            let left = pos.floor();
+            let t = pos - left;
+            let a = image.get(left as isize, y as isize);
+            let b = image.get(left as isize + 1, y as isize);
+            let mix = |a: u8, b: u8| (a as f32 * (1.0 - t) + b as f32 * t).round() as u8;
+            out.set(x, y, (mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2)));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn numbered_frame() -> Image {
+        let mut image = Image::new(Frame::WIDTH, Frame::HIGHT);
+        for y in 0..Frame::HIGHT {
+            for x in 0..Frame::WIDTH {
+                image.set(x, y, (x as u8, y as u8, 0));
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn test_parse_overscan() {
+        assert_eq!(Overscan::parse("8").unwrap(), Overscan::NTSC);
+        assert_eq!(Overscan::parse("1,2,3,4").unwrap(), Overscan { top: 1, bottom: 2, left: 3, right: 4 });
+        assert!(Overscan::parse("1,2").is_err());
+        assert!(Overscan::parse("x").is_err());
+        assert!(Overscan::parse("120").is_err());
+    }
+
+    #[test]
+    fn test_crop_region_reports_offsets() {
+        let mut settings = DisplaySettings::new();
+        settings.overscan = Overscan { top: 8, bottom: 8, left: 4, right: 2 };
+        let region = settings.crop_region();
+        assert_eq!(region, CropRegion { x: 4, y: 8, width: 250, height: 224 });
+        assert_eq!(region.to_string(), "4,8,250,224");
+    }
+
+    #[test]
+    fn test_crop_picks_region() {
+        let mut settings = DisplaySettings::new();
+        settings.overscan = Overscan { top: 8, bottom: 8, left: 4, right: 2 };
+        let out = settings.crop(&numbered_frame());
+        assert_eq!((out.width, out.height), (250, 224));
+        assert_eq!(out.get(0, 0), (4, 8, 0));
+        assert_eq!(out.get(249, 223), (253, 231, 0));
+    }
+
+    #[test]
+    fn test_crop_scales_with_wide_frames() {
+        let mut settings = DisplaySettings::new();
+        settings.overscan = Overscan::NTSC;
+        let mut wide = Image::new(Frame::WIDTH * 2, Frame::HIGHT);
+        wide.set(0, 8, (9, 9, 9));
+        let out = settings.crop(&wide);
+        assert_eq!((out.width, out.height), (512, 224));
+        assert_eq!(out.get(0, 0), (9, 9, 9));
+    }
+
+    #[test]
+    fn test_aspect_correction_widens() {
+        let mut settings = DisplaySettings::new();
+        assert_eq!(settings.display_size(), (256, 240));
+        settings.aspect_correction = true;
+        settings.overscan = Overscan::NTSC;
+        assert_eq!(settings.display_size(), (293, 224));
+
+        let out = settings.present(&numbered_frame());
+        assert_eq!((out.width, out.height), (293, 224));
+        assert_eq!(out.get(0, 0), (0, 8, 0));
+        assert_eq!(out.get(292, 0).0, 255);
+    }
+
+    #[test]
+    fn test_no_correction_is_identity() {
+        let settings = DisplaySettings::new();
+        let frame = numbered_frame();
+        assert_eq!(settings.present(&frame), frame);
+    }
+
+    #[test]
+    fn test_frame_position() {
+        let mut settings = DisplaySettings::new();
+        settings.overscan = Overscan::NTSC;
+        settings.aspect_correction = true;
+        // 293x224 shown at 3x
+        assert_eq!(settings.frame_position(0, 0, 879, 672), Some((0, 8)));
+        assert_eq!(settings.frame_position(878, 671, 879, 672), Some((255, 231)));
+        assert_eq!(settings.frame_position(440, -1, 879, 672), None);
+        assert_eq!(settings.frame_position(879, 0, 879, 672), None);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::fds::Fds;
+use crate::mmc5_audio::Mmc5Audio;
+use crate::n163::N163;
+use crate::savestate::Savestate;
+use crate::sunsoft5b::Sunsoft5B;
+use crate::vrc6::Vrc6;
+use crate::vrc7::Vrc7;
+
+// NSF header expansion bits
+pub const NSF_VRC6: u8 = 0b0000_0001;
+pub const NSF_VRC7: u8 = 0b0000_0010;
+pub const NSF_FDS: u8 = 0b0000_0100;
+pub const NSF_MMC5: u8 = 0b0000_1000;
+pub const NSF_N163: u8 = 0b0001_0000;
+pub const NSF_5B: u8 = 0b0010_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExpansionChannelInfo {
+    pub period: u16,
+    pub frequency: f64,
+    pub volume: u8,
+    pub max_volume: u8,
+    pub active: bool,
+}
+
+// sound hardware on the cartridge. The APU clocks it every CPU cycle and mixes its
+// channels in with its own; the bus hands it the register accesses it decodes. The
+// cartridge brings its chip by mapper number, an NSF by its header bits.
+// kept in savestates and rewind snapshots with the APU
+pub trait ExpansionAudio: Savestate {
+    fn name(&self) -> &'static str;
+    fn channel_names(&self) -> Vec<String>;
+    fn channel_count(&self) -> usize;
+
+    // whether the chip decodes this CPU address
+    fn handles(&self, addr: u16) -> bool;
+    fn write(&mut self, addr: u16, data: u8);
+    fn read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    fn clock(&mut self);
+
+    // a channel's contribution to the mix, in the same units as the 2A03 mixer
+    fn output(&self, channel: usize) -> f32;
+    // raw level for oscilloscopes, 0..=max_level
+    fn level(&self, channel: usize) -> u8;
+    fn max_level(&self, channel: usize) -> u8;
+    fn info(&self, channel: usize, cpu_clock: f64) -> ExpansionChannelInfo;
+}
+
+pub fn expansion_for_nsf(bits: u8) -> Vec<Box<dyn ExpansionAudio>> {
+    let mut chips: Vec<Box<dyn ExpansionAudio>> = vec![];
+    if bits & NSF_VRC6 != 0 {
+        chips.push(Box::new(Vrc6::new(false)));
+    }
+    if bits & NSF_VRC7 != 0 {
+        chips.push(Box::new(Vrc7::new()));
+    }
+    if bits & NSF_FDS != 0 {
+        chips.push(Box::new(Fds::new()));
+    }
+    if bits & NSF_MMC5 != 0 {
+        chips.push(Box::new(Mmc5Audio::new()));
+    }
+    if bits & NSF_N163 != 0 {
+        chips.push(Box::new(N163::new()));
+    }
+    if bits & NSF_5B != 0 {
+        chips.push(Box::new(Sunsoft5B::new()));
+    }
+    chips
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::savestate::StateBuffer;
+
+    // for the chips' tests: a state saved while a tone plays brings the same sound back
+    pub fn assert_state_round_trips(chip: &mut dyn ExpansionAudio) {
+        (0..5000).for_each(|_| chip.clock());
+        let mut s = StateBuffer::saving();
+        chip.state(&mut s);
+        let saved = s.into_data();
+        let run = |chip: &mut dyn ExpansionAudio| -> Vec<f32> {
+            (0..5000)
+                .map(|_| {
+                    chip.clock();
+                    (0..chip.channel_count()).map(|channel| chip.output(channel)).sum()
+                })
+                .collect()
+        };
+        let played = run(chip);
+        assert!(played.iter().any(|output| *output != played[0]), "{} is silent", chip.name());
+        chip.state(&mut StateBuffer::loading(saved));
+        assert_eq!(run(chip), played, "{}", chip.name());
+    }
+
+    #[test]
+    fn test_chips_for_nsf_bits() {
+        let names: Vec<&str> = expansion_for_nsf(NSF_VRC6 | NSF_N163 | NSF_5B).iter().map(|c| c.name()).collect();
+        assert_eq!(names, vec!["VRC6", "N163", "5B"]);
+        assert_eq!(expansion_for_nsf(0b0011_1111).len(), 6);
+        assert!(expansion_for_nsf(0).is_empty());
+    }
+}
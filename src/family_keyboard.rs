@@ -0,0 +1,146 @@
+use crate::controller::*;
+
+pub const KEYBOARD_ROWS: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FamilyKey {
+    F1, F2, F3, F4, F5, F6, F7, F8,
+    Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0,
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    Minus, Caret, Yen, Stop, Escape, At, LeftBracket, RightBracket, Return,
+    Ctr, Semicolon, Colon, Kana, LeftShift, RightShift, Comma, Period, Slash, Underscore,
+    Grph, Space, ClrHome, Insert, Delete, Up, Down, Left, Right,
+}
+
+// the keyboard's matrix, [row][column][bit] with bit 0 read on D1 of $4017 and bit 3 on D4
+const MATRIX: [[[FamilyKey; 4]; 2]; KEYBOARD_ROWS] = {
+    use FamilyKey::*;
+    [
+        [[F8, Return, LeftBracket, RightBracket], [Kana, RightShift, Yen, Stop]],
+        [[F7, At, Colon, Semicolon], [Underscore, Slash, Minus, Caret]],
+        [[F6, O, L, K], [Period, Comma, P, Key0]],
+        [[F5, I, U, J], [M, N, Key9, Key8]],
+        [[F4, Y, G, H], [B, V, Key7, Key6]],
+        [[F3, T, R, D], [F, C, Key5, Key4]],
+        [[F2, W, S, A], [X, Z, E, Key3]],
+        [[F1, Escape, Q, Ctr], [LeftShift, Grph, Key1, Key2]],
+        [[ClrHome, Up, Right, Left], [Down, Space, Delete, Insert]],
+    ]
+};
+
+// the Family BASIC keyboard on the expansion port. $4016 writes: D0 resets the scan to
+// row 0, D1 picks the column and going from column 1 back to 0 moves on a row, D2
+// enables the matrix. The selected row and column's four keys read on D1-D4 of $4017,
+// 0 meaning pressed
+pub struct FamilyKeyboard {
+    // a bit per key, laid out like the reads
+    pressed: [[u8; 2]; KEYBOARD_ROWS],
+    row: usize,
+    column: usize,
+    enabled: bool,
+}
+
+impl FamilyKeyboard {
+    pub fn new() -> Self {
+        FamilyKeyboard { pressed: [[0; 2]; KEYBOARD_ROWS], row: 0, column: 0, enabled: false }
+    }
+
+    pub fn set_key(&mut self, key: FamilyKey, pressed: bool) {
+        for (row, columns) in MATRIX.iter().enumerate() {
+            for (column, keys) in columns.iter().enumerate() {
+                if let Some(bit) = keys.iter().position(|k| *k == key) {
+                    if pressed {
+                        self.pressed[row][column] |= 1 << bit;
+                    } else {
+                        self.pressed[row][column] &= !(1 << bit);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl PortDevice for FamilyKeyboard {
+    fn name(&self) -> &'static str {
+        "family basic keyboard"
+    }
+
+    fn write(&mut self, data: u8) {
+        let column = ((data >> 1) & 1) as usize;
+        self.enabled = data & 0b100 != 0;
+        if data & 1 != 0 {
+            self.row = 0;
+        } else if self.column == 1 && column == 0 {
+            // past the last row nothing is pressed
+            self.row = (self.row + 1).min(KEYBOARD_ROWS);
+        }
+        self.column = column;
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        if port == 0 || !self.enabled {
+            return 0;
+        }
+        let pressed = self.pressed.get(self.row).map_or(0, |columns| columns[self.column]);
+        (!pressed & 0b1111) << 1
+    }
+
+    fn keyboard(&mut self) -> Option<&mut FamilyKeyboard> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // what Family BASIC's scan loop does: reset, then both columns of every row
+    fn scan(keyboard: &mut FamilyKeyboard) -> Vec<u8> {
+        let mut reads = vec![];
+        keyboard.write(0b101);
+        for _ in 0..KEYBOARD_ROWS {
+            keyboard.write(0b100);
+            reads.push((keyboard.read(1) >> 1) & 0b1111);
+            keyboard.write(0b110);
+            reads.push((keyboard.read(1) >> 1) & 0b1111);
+        }
+        reads
+    }
+
+    #[test]
+    fn test_scan_finds_keys() {
+        let mut keyboard = FamilyKeyboard::new();
+        keyboard.set_key(FamilyKey::Return, true);
+        keyboard.set_key(FamilyKey::Key2, true);
+        keyboard.set_key(FamilyKey::Space, true);
+        let reads = scan(&mut keyboard);
+        assert_eq!(reads.len(), 18);
+        assert_eq!(reads[0], 0b1101);
+        assert_eq!(reads[7 * 2 + 1], 0b0111);
+        assert_eq!(reads[8 * 2 + 1], 0b1101);
+        assert_eq!(reads.iter().filter(|r| **r == 0b1111).count(), 15);
+
+        keyboard.set_key(FamilyKey::Return, false);
+        assert_eq!(scan(&mut keyboard)[0], 0b1111);
+    }
+
+    #[test]
+    fn test_disabled_and_past_the_last_row() {
+        let mut keyboard = FamilyKeyboard::new();
+        keyboard.set_key(FamilyKey::A, true);
+        keyboard.write(0b001);
+        assert_eq!(keyboard.read(1), 0);
+        scan(&mut keyboard);
+        keyboard.write(0b100);
+        assert_eq!(keyboard.read(1), 0b1_1110);
+    }
+
+    #[test]
+    fn test_every_key_is_in_the_matrix_once() {
+        let keys: Vec<FamilyKey> = MATRIX.iter().flat_map(|columns| columns.iter().flat_map(|keys| keys.iter().cloned())).collect();
+        assert_eq!(keys.len(), 72);
+        for key in keys.iter() {
+            assert_eq!(keys.iter().filter(|k| *k == key).count(), 1);
+        }
+    }
+}
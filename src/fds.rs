@@ -0,0 +1,400 @@
+use crate::expansion::*;
+use crate::savestate::*;
+
+// wave (0-63) times the clamped volume gain (0-32) tops out at 2016
+const FDS_SCALE: f32 = 0.35 / 2016.0;
+// master volume 2/2, 2/3, 2/4 and 2/5
+const MASTER_VOLUMES: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+// the modulation table's 3-bit entries, 4 resets the counter
+const MOD_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+// the volume and modulation envelopes share one layout
+struct FdsEnvelope {
+    disabled: bool,
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    counter: u32,
+}
+
+impl FdsEnvelope {
+    fn new() -> Self {
+        FdsEnvelope { disabled: true, increase: false, speed: 0, gain: 0, counter: 0 }
+    }
+
+    // $4080/$4084: direct gain when the envelope is off, otherwise its speed
+    fn write(&mut self, data: u8) {
+        self.disabled = data & 0b1000_0000 != 0;
+        self.increase = data & 0b0100_0000 != 0;
+        self.speed = data & 0b0011_1111;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.counter = 0;
+    }
+
+    // ticks every 8 * (master speed + 1) * (speed + 1) CPU cycles
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled || master_speed == 0 {
+            return;
+        }
+        self.counter += 1;
+        if self.counter < 8 * (master_speed as u32 + 1) * (self.speed as u32 + 1) {
+            return;
+        }
+        self.counter = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+// Famicom Disk System audio: one 64-step wavetable channel whose pitch is bent by a
+// second, modulation wavetable
+pub struct Fds {
+    wave: [u8; 64],
+    wave_write: bool,
+    wave_frequency: u16,
+    wave_halt: bool,
+    wave_accumulator: u32,
+    envelopes_halted: bool,
+    master_volume: usize,
+    master_speed: u8,
+    volume: FdsEnvelope,
+    modulation: FdsEnvelope,
+    mod_table: [u8; 64],
+    mod_position: usize,
+    mod_frequency: u16,
+    mod_halt: bool,
+    mod_accumulator: u32,
+    // 7-bit signed
+    mod_counter: i8,
+    sound_enabled: bool,
+}
+
+impl Fds {
+    pub fn new() -> Self {
+        Fds {
+            wave: [0; 64],
+            wave_write: false,
+            wave_frequency: 0,
+            wave_halt: true,
+            wave_accumulator: 0,
+            envelopes_halted: false,
+            master_volume: 0,
+            master_speed: 0xE8,
+            volume: FdsEnvelope::new(),
+            modulation: FdsEnvelope::new(),
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_frequency: 0,
+            mod_halt: true,
+            mod_accumulator: 0,
+            mod_counter: 0,
+            sound_enabled: true,
+        }
+    }
+
+    fn wave_position(&self) -> usize {
+        (self.wave_accumulator >> 16) as usize & 0b11_1111
+    }
+
+    // the wave frequency after modulation
+    fn pitch(&self) -> u32 {
+        let frequency = self.wave_frequency as i32;
+        if self.mod_halt {
+            return frequency as u32;
+        }
+        let mut temp = self.mod_counter as i32 * self.modulation.gain as i32;
+        let remainder = temp & 0xF;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            if self.mod_counter < 0 {
+                temp -= 1;
+            } else {
+                temp += 2;
+            }
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        let mut adjust = frequency * temp;
+        let remainder = adjust & 0x3F;
+        adjust >>= 6;
+        if remainder >= 32 {
+            adjust += 1;
+        }
+        (frequency + adjust).max(0) as u32
+    }
+
+    fn step_modulator(&mut self) {
+        let step = self.mod_table[self.mod_position];
+        self.mod_counter = if step == 4 {
+            0
+        } else {
+            // wrap within 7 bits
+            (((self.mod_counter as i32 + MOD_STEPS[step as usize] as i32 + 64) & 0x7F) - 64) as i8
+        };
+        self.mod_position = (self.mod_position + 1) & 0b11_1111;
+    }
+
+    fn raw_output(&self) -> u32 {
+        if self.wave_write {
+            return 0;
+        }
+        self.wave[self.wave_position()] as u32 * self.volume.gain.min(32) as u32
+    }
+}
+
+impl ExpansionAudio for Fds {
+    fn name(&self) -> &'static str {
+        "FDS"
+    }
+
+    fn channel_names(&self) -> Vec<String> {
+        vec!["fds".to_string()]
+    }
+
+    fn channel_count(&self) -> usize {
+        1
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        matches!(addr, 0x4023 | 0x4040..=0x408A | 0x4090 | 0x4092)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if addr == 0x4023 {
+            self.sound_enabled = data & 0b10 != 0;
+            return;
+        }
+        if !self.sound_enabled {
+            return;
+        }
+        match addr {
+            0x4040..=0x407F if self.wave_write => self.wave[addr as usize - 0x4040] = data & 0b11_1111,
+            0x4080 => self.volume.write(data),
+            0x4082 => self.wave_frequency = (self.wave_frequency & 0x0F00) | data as u16,
+            0x4083 => {
+                self.wave_frequency = (self.wave_frequency & 0x00FF) | (((data & 0b1111) as u16) << 8);
+                self.wave_halt = data & 0b1000_0000 != 0;
+                self.envelopes_halted = data & 0b0100_0000 != 0;
+                if self.wave_halt {
+                    self.wave_accumulator = 0;
+                }
+            }
+            0x4084 => self.modulation.write(data),
+            0x4085 => self.mod_counter = (((data & 0x7F) as i32 ^ 0x40) - 0x40) as i8,
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | data as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | (((data & 0b1111) as u16) << 8);
+                self.mod_halt = data & 0b1000_0000 != 0;
+                if self.mod_halt {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // each write fills two table entries, only while the modulator is halted
+            0x4088 if self.mod_halt => {
+                self.mod_table[self.mod_position] = data & 0b111;
+                self.mod_table[(self.mod_position + 1) & 0b11_1111] = data & 0b111;
+                self.mod_position = (self.mod_position + 2) & 0b11_1111;
+            }
+            0x4089 => {
+                self.wave_write = data & 0b1000_0000 != 0;
+                self.master_volume = (data & 0b11) as usize;
+            }
+            0x408A => self.master_speed = data,
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407F => Some(self.wave[addr as usize - 0x4040] | 0b0100_0000),
+            0x4090 => Some(self.volume.gain | 0b0100_0000),
+            0x4092 => Some(self.modulation.gain | 0b0100_0000),
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.wave_halt && !self.envelopes_halted {
+            self.volume.clock(self.master_speed);
+            self.modulation.clock(self.master_speed);
+        }
+
+        if !self.mod_halt {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if self.mod_accumulator >= 0x10000 {
+                self.mod_accumulator &= 0xFFFF;
+                self.step_modulator();
+            }
+        }
+
+        if !self.wave_halt && !self.wave_write {
+            self.wave_accumulator = (self.wave_accumulator + self.pitch()) & 0x3F_FFFF;
+        }
+    }
+
+    fn output(&self, _channel: usize) -> f32 {
+        self.raw_output() as f32 * MASTER_VOLUMES[self.master_volume] * FDS_SCALE
+    }
+
+    fn level(&self, _channel: usize) -> u8 {
+        if self.wave_write { 0 } else { self.wave[self.wave_position()] }
+    }
+
+    fn max_level(&self, _channel: usize) -> u8 {
+        63
+    }
+
+    fn info(&self, _channel: usize, cpu_clock: f64) -> ExpansionChannelInfo {
+        ExpansionChannelInfo {
+            period: self.wave_frequency,
+            frequency: cpu_clock * self.wave_frequency as f64 / (65536.0 * 64.0),
+            volume: self.volume.gain.min(32),
+            max_volume: 32,
+            active: !self.wave_halt && self.wave_frequency > 0 && self.volume.gain > 0,
+        }
+    }
+}
+
+impl Savestate for FdsEnvelope {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bool(&mut self.disabled);
+        s.bool(&mut self.increase);
+        s.u8(&mut self.speed);
+        s.u8(&mut self.gain);
+        s.u32(&mut self.counter);
+    }
+}
+
+impl Savestate for Fds {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bytes(&mut self.wave);
+        s.bool(&mut self.wave_write);
+        s.u16(&mut self.wave_frequency);
+        s.bool(&mut self.wave_halt);
+        s.u32(&mut self.wave_accumulator);
+        s.bool(&mut self.envelopes_halted);
+        s.usize(&mut self.master_volume);
+        s.u8(&mut self.master_speed);
+        self.volume.state(s);
+        self.modulation.state(s);
+        s.bytes(&mut self.mod_table);
+        s.usize(&mut self.mod_position);
+        s.u16(&mut self.mod_frequency);
+        s.bool(&mut self.mod_halt);
+        s.u32(&mut self.mod_accumulator);
+        s.i8(&mut self.mod_counter);
+        s.bool(&mut self.sound_enabled);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::expansion::test::assert_state_round_trips;
+
+    fn square_wave() -> Fds {
+        let mut fds = Fds::new();
+        fds.write(0x4089, 0b1000_0000);
+        for i in 0..64 {
+            fds.write(0x4040 + i, if i < 32 { 63 } else { 0 });
+        }
+        fds.write(0x4089, 0);
+        fds.write(0x4080, 0b1010_0000); // direct gain 32
+        fds
+    }
+
+    fn count_cycles(fds: &mut Fds, cpu_cycles: usize) -> usize {
+        let mut edges = 0;
+        let mut last = fds.level(0);
+        for _ in 0..cpu_cycles {
+            fds.clock();
+            let level = fds.level(0);
+            if level > last {
+                edges += 1;
+            }
+            last = level;
+        }
+        edges
+    }
+
+    #[test]
+    fn test_wave_ram_only_writable_when_enabled() {
+        let mut fds = square_wave();
+        fds.write(0x4040, 5);
+        assert_eq!(fds.read(0x4040), Some(63 | 0b0100_0000));
+        assert_eq!(fds.read(0x4090), Some(32 | 0b0100_0000));
+    }
+
+    #[test]
+    fn test_wave_frequency() {
+        let mut fds = square_wave();
+        // 64 steps of 65536 at $400 a cycle: one wave every 4096 CPU cycles
+        fds.write(0x4082, 0x00);
+        fds.write(0x4083, 0x04);
+        assert_eq!(count_cycles(&mut fds, 4096 * 10 + 1), 10);
+        assert!(fds.output(0) >= 0.0);
+        assert!((fds.info(0, 1789772.7272).frequency - 1789772.7272 / 4096.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_modulation_bends_pitch() {
+        let mut fds = square_wave();
+        fds.write(0x4082, 0x00);
+        fds.write(0x4083, 0x04);
+        fds.write(0x4084, 0b1010_0000); // modulation gain 32
+        fds.write(0x4085, 0x20); // counter +32
+        assert_eq!(fds.pitch(), 0x400);
+        fds.write(0x4087, 0x00); // running, but at frequency 0 it never steps
+        assert!(fds.pitch() > 0x400);
+        fds.write(0x4085, 0x60); // counter -32
+        assert!(fds.pitch() < 0x400);
+
+        // a table of +4 steps raises the counter each time the modulator wraps
+        fds.write(0x4087, 0x80);
+        for _ in 0..32 {
+            fds.write(0x4088, 3);
+        }
+        fds.write(0x4085, 0);
+        fds.write(0x4086, 0x00);
+        fds.write(0x4087, 0x08); // one step every 32 CPU cycles
+        for _ in 0..32 * 4 {
+            fds.clock();
+        }
+        assert_eq!(fds.mod_counter, 16);
+    }
+
+    #[test]
+    fn test_master_volume() {
+        let mut fds = square_wave();
+        fds.write(0x4083, 0x04);
+        fds.clock();
+        let full = fds.output(0);
+        assert!(full > 0.0);
+        fds.write(0x4089, 0b11);
+        assert!((fds.output(0) / full - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_state_round_trips() {
+        let mut fds = square_wave();
+        fds.write(0x4082, 0x00);
+        fds.write(0x4083, 0x04);
+        // with the modulator bending it
+        fds.write(0x4087, 0b1000_0000);
+        for _ in 0..32 {
+            fds.write(0x4088, 1);
+        }
+        fds.write(0x4084, 0b1000_0100);
+        fds.write(0x4086, 0x40);
+        fds.write(0x4087, 0x00);
+        assert_state_round_trips(&mut fds);
+    }
+}
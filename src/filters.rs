@@ -0,0 +1,634 @@
+use crate::frame::*;
+
+// RGB24 pixels of any size, so filters can be chained and tested on small buffers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Image {
+            width: width,
+            height: height,
+            data: vec![0; width * height * 3],
+        }
+    }
+
+    pub fn from_rgb(width: usize, height: usize, data: &[u8]) -> Self {
+        assert_eq!(data.len(), width * height * 3);
+        Image {
+            width: width,
+            height: height,
+            data: data.to_vec(),
+        }
+    }
+
+    pub fn from_frame(frame: &Frame) -> Self {
+        Image::from_rgb(Frame::WIDTH, Frame::HIGHT, &frame.data)
+    }
+
+    // reads past the edges repeat the border pixel
+    pub fn get(&self, x: isize, y: isize) -> (u8, u8, u8) {
+        let x = x.max(0).min(self.width as isize - 1) as usize;
+        let y = y.max(0).min(self.height as isize - 1) as usize;
+        let base = (y * self.width + x) * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        let base = (y * self.width + x) * 3;
+        self.data[base] = rgb.0;
+        self.data[base + 1] = rgb.1;
+        self.data[base + 2] = rgb.2;
+    }
+}
+
+const SCANLINE_BRIGHTNESS: u32 = 50; // percent kept on the dark rows
+const APERTURE_DIM: u32 = 40; // percent kept on the two channels a mask column doesn't pass
+
+// hqx treats two colors as different once they pass any of these YUV thresholds
+const Y_THRESHOLD: i32 = 48;
+const U_THRESHOLD: i32 = 7;
+const V_THRESHOLD: i32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleFilter {
+    Nearest(usize),
+    Scanlines(usize),
+    Hq2x,
+    Hq3x,
+    Xbr,
+    Crt,
+}
+
+impl ScaleFilter {
+    // the presets cycled through by the frontend
+    pub const PRESETS: [ScaleFilter; 7] = [
+        ScaleFilter::Nearest(1),
+        ScaleFilter::Nearest(3),
+        ScaleFilter::Scanlines(3),
+        ScaleFilter::Hq2x,
+        ScaleFilter::Hq3x,
+        ScaleFilter::Xbr,
+        ScaleFilter::Crt,
+    ];
+
+    pub fn name(&self) -> String {
+        match self {
+            ScaleFilter::Nearest(scale) => format!("nearest {}x", scale),
+            ScaleFilter::Scanlines(scale) => format!("scanlines {}x", scale),
+            ScaleFilter::Hq2x => "hq2x".to_string(),
+            ScaleFilter::Hq3x => "hq3x".to_string(),
+            ScaleFilter::Xbr => "xbr 2x".to_string(),
+            ScaleFilter::Crt => "crt aperture 3x".to_string(),
+        }
+    }
+
+    pub fn apply(&self, src: &Image) -> Image {
+        match *self {
+            ScaleFilter::Nearest(scale) => nearest(src, scale),
+            ScaleFilter::Scanlines(scale) => scanlines(src, scale),
+            ScaleFilter::Hq2x => hqx(src, 2),
+            ScaleFilter::Hq3x => hqx(src, 3),
+            ScaleFilter::Xbr => xbr(src),
+            ScaleFilter::Crt => crt(src),
+        }
+    }
+}
+
+fn nearest(src: &Image, scale: usize) -> Image {
+    let mut out = Image::new(src.width * scale, src.height * scale);
+    for y in 0..out.height {
+        for x in 0..out.width {
+            out.set(x, y, src.get((x / scale) as isize, (y / scale) as isize));
+        }
+    }
+    out
+}
+
+// darkens the last output row of every source row
+fn scanlines(src: &Image, scale: usize) -> Image {
+    let mut out = nearest(src, scale);
+    if scale < 2 {
+        return out;
+    }
+    for y in (scale - 1..out.height).step_by(scale) {
+        for x in 0..out.width {
+            let (r, g, b) = out.get(x as isize, y as isize);
+            out.set(x, y, (dim(r, SCANLINE_BRIGHTNESS), dim(g, SCANLINE_BRIGHTNESS), dim(b, SCANLINE_BRIGHTNESS)));
+        }
+    }
+    out
+}
+
+// an RGB phosphor triad per source pixel, with a dark gap between source rows
+fn crt(src: &Image) -> Image {
+    let mut out = Image::new(src.width * 3, src.height * 3);
+    for y in 0..out.height {
+        for x in 0..out.width {
+            let (r, g, b) = src.get((x / 3) as isize, (y / 3) as isize);
+            let mut rgb = match x % 3 {
+                0 => (r, dim(g, APERTURE_DIM), dim(b, APERTURE_DIM)),
+                1 => (dim(r, APERTURE_DIM), g, dim(b, APERTURE_DIM)),
+                _ => (dim(r, APERTURE_DIM), dim(g, APERTURE_DIM), b),
+            };
+            if y % 3 == 2 {
+                rgb = (dim(rgb.0, SCANLINE_BRIGHTNESS), dim(rgb.1, SCANLINE_BRIGHTNESS), dim(rgb.2, SCANLINE_BRIGHTNESS));
+            }
+            out.set(x, y, rgb);
+        }
+    }
+    out
+}
+
+fn dim(value: u8, percent: u32) -> u8 {
+    (value as u32 * percent / 100) as u8
+}
+
+fn yuv(rgb: (u8, u8, u8)) -> (i32, i32, i32) {
+    let (r, g, b) = (rgb.0 as i32, rgb.1 as i32, rgb.2 as i32);
+    let y = (r * 299 + g * 587 + b * 114) / 1000;
+    let u = (-r * 169 - g * 331 + b * 500) / 1000 + 128;
+    let v = (r * 500 - g * 419 - b * 81) / 1000 + 128;
+    (y, u, v)
+}
+
+fn colors_differ(a: (u8, u8, u8), b: (u8, u8, u8)) -> bool {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    (ya - yb).abs() > Y_THRESHOLD || (ua - ub).abs() > U_THRESHOLD || (va - vb).abs() > V_THRESHOLD
+}
+
+// weighted average of colors, weights are relative
+fn blend(colors: &[((u8, u8, u8), u32)]) -> (u8, u8, u8) {
+    let total: u32 = colors.iter().map(|c| c.1).sum();
+    let channel = |f: fn((u8, u8, u8)) -> u8| {
+        (colors.iter().map(|&(c, w)| f(c) as u32 * w).sum::<u32>() / total) as u8
+    };
+    (channel(|c| c.0), channel(|c| c.1), channel(|c| c.2))
+}
+
+// the 3x3 neighborhood in reading order, w[4] is the pixel itself
+fn neighborhood(src: &Image, x: usize, y: usize) -> [(u8, u8, u8); 9] {
+    let (x, y) = (x as isize, y as isize);
+    let mut w = [(0, 0, 0); 9];
+    for (n, pixel) in w.iter_mut().enumerate() {
+        *pixel = src.get(x + n as isize % 3 - 1, y + n as isize / 3 - 1);
+    }
+    w
+}
+
+// hqx's rules are written for the top-left output pixel. The other corners use the same
+// rules on the neighborhood turned so their corner is top-left: position n of the turned
+// neighborhood holds w[order[n]]
+const TURN_NONE: [usize; 9] = [0, 1, 2, 3, 4, 5, 6, 7, 8];
+const MIRROR_X: [usize; 9] = [2, 1, 0, 5, 4, 3, 8, 7, 6];
+const MIRROR_Y: [usize; 9] = [6, 7, 8, 3, 4, 5, 0, 1, 2];
+const MIRROR_XY: [usize; 9] = [8, 7, 6, 5, 4, 3, 2, 1, 0];
+const TURN_RIGHT: [usize; 9] = [2, 5, 8, 1, 4, 7, 0, 3, 6];
+const TURN_LEFT: [usize; 9] = [6, 3, 0, 7, 4, 1, 8, 5, 2];
+
+// a turned neighborhood and its pattern: bit n is set when the nth neighbor, counting in
+// reading order and skipping the center, differs from the center
+struct Turned {
+    w: [(u8, u8, u8); 9],
+    pattern: u8,
+}
+
+impl Turned {
+    fn new(w: &[(u8, u8, u8); 9], differs: &[bool; 9], order: [usize; 9]) -> Self {
+        let mut turned = Turned { w: [(0, 0, 0); 9], pattern: 0 };
+        for (n, &from) in order.iter().enumerate() {
+            turned.w[n] = w[from];
+            if differs[from] {
+                turned.pattern |= 1 << if n > 4 { n - 1 } else { n };
+            }
+        }
+        turned
+    }
+
+    // whether the pattern masked with any of these masks gives its value
+    fn matches(&self, patterns: &[(u8, u8)]) -> bool {
+        patterns.iter().any(|&(mask, value)| self.pattern & mask == value)
+    }
+
+    fn differ(&self, a: usize, b: usize) -> bool {
+        colors_differ(self.w[a], self.w[b])
+    }
+}
+
+// Maxim Stepin's hq2x and hq3x. Each pixel's neighbors are compared with it using the YUV
+// thresholds, and the resulting 8-bit pattern picks the blend for every output pixel from
+// hqx's lookup table, here folded into the pattern rules of the top-left pixels
+fn hqx(src: &Image, scale: usize) -> Image {
+    let mut out = Image::new(src.width * scale, src.height * scale);
+    for y in 0..src.height {
+        for x in 0..src.width {
+            let w = neighborhood(src, x, y);
+            let mut differs = [false; 9];
+            for n in 0..9 {
+                differs[n] = colors_differ(w[4], w[n]);
+            }
+            let (ox, oy) = (x * scale, y * scale);
+            if scale == 2 {
+                out.set(ox, oy, hq2x_top_left(&Turned::new(&w, &differs, TURN_NONE)));
+                out.set(ox + 1, oy, hq2x_top_left(&Turned::new(&w, &differs, MIRROR_X)));
+                out.set(ox, oy + 1, hq2x_top_left(&Turned::new(&w, &differs, MIRROR_Y)));
+                out.set(ox + 1, oy + 1, hq2x_top_left(&Turned::new(&w, &differs, MIRROR_XY)));
+            } else {
+                // each turn gives a corner and the edge pixel after it, going clockwise
+                for (order, corner, edge) in [
+                    (TURN_NONE, (0, 0), (1, 0)),
+                    (TURN_RIGHT, (2, 0), (2, 1)),
+                    (MIRROR_XY, (2, 2), (1, 2)),
+                    (TURN_LEFT, (0, 2), (0, 1)),
+                ] {
+                    let turned = Turned::new(&w, &differs, order);
+                    out.set(ox + corner.0, oy + corner.1, hq3x_top_left(&turned));
+                    out.set(ox + edge.0, oy + edge.1, hq3x_top_middle(&turned));
+                }
+                out.set(ox + 1, oy + 1, w[4]);
+            }
+        }
+    }
+    out
+}
+
+fn hq2x_top_left(t: &Turned) -> (u8, u8, u8) {
+    let w = &t.w;
+    if t.matches(&[(0xbf, 0x37), (0xdb, 0x13)]) && t.differ(1, 5) {
+        return blend(&[(w[4], 3), (w[3], 1)]);
+    }
+    if t.matches(&[(0xdb, 0x49), (0xef, 0x6d)]) && t.differ(7, 3) {
+        return blend(&[(w[4], 3), (w[1], 1)]);
+    }
+    if t.matches(&[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]) && t.differ(3, 1) {
+        return w[4];
+    }
+    if t.matches(&[
+        (0x6f, 0x2a), (0x5b, 0x0a), (0xbf, 0x3a), (0xdf, 0x5a), (0x9f, 0x8a), (0xcf, 0x8a), (0xef, 0x4e),
+        (0x3f, 0x0e), (0xfb, 0x5a), (0xbb, 0x8a), (0x7f, 0x5a), (0xaf, 0x8a), (0xeb, 0x8a),
+    ]) && t.differ(3, 1)
+    {
+        return blend(&[(w[4], 3), (w[0], 1)]);
+    }
+    if t.matches(&[(0x0b, 0x08)]) {
+        return blend(&[(w[4], 2), (w[0], 1), (w[1], 1)]);
+    }
+    if t.matches(&[(0x0b, 0x02)]) {
+        return blend(&[(w[4], 2), (w[0], 1), (w[3], 1)]);
+    }
+    if t.matches(&[(0x2f, 0x2f)]) {
+        return blend(&[(w[4], 14), (w[3], 1), (w[1], 1)]);
+    }
+    if t.matches(&[(0xbf, 0x37), (0xdb, 0x13)]) {
+        return blend(&[(w[4], 5), (w[1], 2), (w[3], 1)]);
+    }
+    if t.matches(&[(0xdb, 0x49), (0xef, 0x6d)]) {
+        return blend(&[(w[4], 5), (w[3], 2), (w[1], 1)]);
+    }
+    if t.matches(&[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)]) {
+        return blend(&[(w[4], 3), (w[3], 1)]);
+    }
+    if t.matches(&[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)]) {
+        return blend(&[(w[4], 3), (w[1], 1)]);
+    }
+    if t.matches(&[(0x7e, 0x2a), (0xef, 0xab), (0xbf, 0x8f), (0x7e, 0x0e)]) {
+        return blend(&[(w[4], 2), (w[3], 3), (w[1], 3)]);
+    }
+    if t.matches(&[(0xfb, 0x6a), (0x6f, 0x6e), (0x3f, 0x3e), (0xfb, 0xfa), (0xdf, 0xde), (0xdf, 0x1e)]) {
+        return blend(&[(w[4], 3), (w[0], 1)]);
+    }
+    if t.matches(&[
+        (0x0a, 0x00), (0x4f, 0x4b), (0x9f, 0x1b), (0x2f, 0x0b), (0xbe, 0x0a), (0xee, 0x0a), (0x7e, 0x0a),
+        (0xeb, 0x4b), (0x3b, 0x1b),
+    ]) {
+        return blend(&[(w[4], 2), (w[3], 1), (w[1], 1)]);
+    }
+    blend(&[(w[4], 6), (w[3], 1), (w[1], 1)])
+}
+
+fn hq3x_top_left(t: &Turned) -> (u8, u8, u8) {
+    let w = &t.w;
+    if t.matches(&[(0xbf, 0x37), (0xdb, 0x13)]) && t.differ(1, 5) {
+        return blend(&[(w[4], 3), (w[3], 1)]);
+    }
+    if t.matches(&[(0xdb, 0x49), (0xef, 0x6d)]) && t.differ(7, 3) {
+        return blend(&[(w[4], 3), (w[1], 1)]);
+    }
+    if t.matches(&[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]) && t.differ(3, 1) {
+        return w[4];
+    }
+    if t.matches(&[
+        (0x6f, 0x2a), (0x5b, 0x0a), (0xbf, 0x3a), (0xdf, 0x5a), (0x9f, 0x8a), (0xcf, 0x8a), (0xef, 0x4e),
+        (0x3f, 0x0e), (0xfb, 0x5a), (0xbb, 0x8a), (0x7f, 0x5a), (0xaf, 0x8a), (0xeb, 0x8a),
+    ]) && t.differ(3, 1)
+    {
+        return blend(&[(w[4], 3), (w[0], 1)]);
+    }
+    if t.matches(&[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)]) {
+        return blend(&[(w[4], 3), (w[1], 1)]);
+    }
+    if t.matches(&[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)]) {
+        return blend(&[(w[4], 3), (w[3], 1)]);
+    }
+    if t.matches(&[(0x7e, 0x2a), (0xef, 0xab), (0xbf, 0x8f), (0x7e, 0x0e)]) {
+        return blend(&[(w[3], 1), (w[1], 1)]);
+    }
+    if t.matches(&[(0x4f, 0x4b), (0x9f, 0x1b), (0x2f, 0x0b), (0xbe, 0x0a), (0xee, 0x0a), (0x7e, 0x0a), (0xeb, 0x4b), (0x3b, 0x1b)]) {
+        return blend(&[(w[4], 2), (w[3], 7), (w[1], 7)]);
+    }
+    if t.matches(&[
+        (0x0b, 0x08), (0xf9, 0x68), (0xf3, 0x62), (0x6d, 0x6c), (0x67, 0x66), (0x3d, 0x3c), (0x37, 0x36),
+        (0xf9, 0xf8), (0xdd, 0xdc), (0xf3, 0xf2), (0xd7, 0xd6), (0xdd, 0x1c), (0xd7, 0x16), (0x0b, 0x02),
+    ]) {
+        return blend(&[(w[4], 3), (w[0], 1)]);
+    }
+    blend(&[(w[4], 2), (w[3], 1), (w[1], 1)])
+}
+
+fn hq3x_top_middle(t: &Turned) -> (u8, u8, u8) {
+    let w = &t.w;
+    if t.matches(&[(0xfe, 0xde), (0x9e, 0x16), (0xda, 0x12), (0x17, 0x16), (0x5b, 0x12), (0xbb, 0x12)]) && t.differ(1, 5) {
+        return w[4];
+    }
+    if t.matches(&[(0x0f, 0x0b), (0x5e, 0x0a), (0xfb, 0x7b), (0x3b, 0x0b), (0xbe, 0x0a), (0x7a, 0x0a)]) && t.differ(3, 1) {
+        return w[4];
+    }
+    if t.matches(&[(0xbf, 0x8f), (0x7e, 0x0e), (0xbf, 0x37), (0xdb, 0x13)]) {
+        return blend(&[(w[1], 3), (w[4], 1)]);
+    }
+    if t.matches(&[(0x02, 0x00), (0x7c, 0x28), (0xed, 0xa9), (0xf5, 0xb4), (0xd9, 0x90)]) {
+        return blend(&[(w[4], 3), (w[1], 1)]);
+    }
+    if t.matches(&[
+        (0x4f, 0x4b), (0xfb, 0x7b), (0xfe, 0x7e), (0x9f, 0x1b), (0x2f, 0x0b), (0xbe, 0x0a), (0x7e, 0x0a), (0xfb, 0x4b),
+        (0xfb, 0xdb), (0xfe, 0xde), (0xfe, 0x56), (0x57, 0x56), (0x97, 0x16), (0x3f, 0x1e), (0xdb, 0x12), (0xbb, 0x12),
+    ]) {
+        return blend(&[(w[4], 7), (w[1], 1)]);
+    }
+    w[4]
+}
+
+// xBR's distance: the YUV differences summed
+fn color_distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    ((ya - yb).abs() + (ua - ub).abs() + (va - vb).abs()) as u32
+}
+
+// colors xBR counts as the same
+fn close_colors(a: (u8, u8, u8), b: (u8, u8, u8)) -> bool {
+    color_distance(a, b) < 155
+}
+
+// moves each channel of a towards b by weight / 2^shift
+fn alpha_blend(a: (u8, u8, u8), b: (u8, u8, u8), weight: i32, shift: u32) -> (u8, u8, u8) {
+    let channel = |a: u8, b: u8| (a as i32 + (((b as i32 - a as i32) * weight) >> shift)) as u8;
+    (channel(a.0, b.0), channel(a.1, b.1), channel(a.2, b.2))
+}
+
+fn half_blend(a: (u8, u8, u8), b: (u8, u8, u8)) -> (u8, u8, u8) {
+    ((a.0 >> 1) + (b.0 >> 1), (a.1 >> 1) + (b.1 >> 1), (a.2 >> 1) + (b.2 >> 1))
+}
+
+// Hyllian's 2xBR. For each corner the edge strength along the two diagonals through it is
+// weighed from the 5x5 neighborhood; where the edge runs across the corner the output pixel
+// there takes in the closer of the two neighbors along it, and shallow or steep edges
+// (the left and up cases) reach into the next output pixel as well
+fn xbr(src: &Image) -> Image {
+    let mut out = nearest(src, 2);
+    for y in 0..src.height {
+        for x in 0..src.width {
+            let (xi, yi) = (x as isize, y as isize);
+            // bottom-right, top-right, top-left, bottom-left
+            for (dx, dy) in [(1isize, 1isize), (1, -1), (-1, -1), (-1, 1)] {
+                // the pixel names follow the usual xBR layout mirrored so the corner is bottom-right
+                let p = |u: isize, v: isize| src.get(xi + u * dx, yi + v * dy);
+                let e = p(0, 0);
+                let (b, c, d, f) = (p(0, -1), p(1, -1), p(-1, 0), p(1, 0));
+                let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+                let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+                if e == h || e == f {
+                    continue;
+                }
+
+                let across = color_distance(e, c) + color_distance(e, g) + color_distance(i, h5) + color_distance(i, f4) + 4 * color_distance(h, f);
+                let along = color_distance(h, d) + color_distance(h, i5) + color_distance(f, i4) + color_distance(f, b) + 4 * color_distance(e, i);
+                if across > along {
+                    continue;
+                }
+
+                // the output pixels: the corner, the one beside it and the one above it
+                let sub = |u: usize, v: usize| {
+                    let sx = if dx > 0 { u } else { 1 - u };
+                    let sy = if dy > 0 { v } else { 1 - v };
+                    (x * 2 + sx, y * 2 + sy)
+                };
+                let (n3, n2, n1) = (sub(1, 1), sub(0, 1), sub(1, 0));
+                let get = |out: &Image, (x, y): (usize, usize)| out.get(x as isize, y as isize);
+
+                let px = if color_distance(e, f) <= color_distance(e, h) { f } else { h };
+                let sharp = (!close_colors(f, b) && !close_colors(h, d))
+                    || (close_colors(e, i) && !close_colors(f, i4) && !close_colors(h, i5))
+                    || close_colors(e, g)
+                    || close_colors(e, c);
+                if across < along && sharp {
+                    let ke = color_distance(f, g);
+                    let ki = color_distance(h, c);
+                    let left = ke * 2 <= ki && e != g && d != g;
+                    let up = ke >= ki * 2 && e != c && b != c;
+                    if left && up {
+                        out.set(n3.0, n3.1, alpha_blend(get(&out, n3), px, 7, 3));
+                        let side = alpha_blend(get(&out, n2), px, 1, 2);
+                        out.set(n2.0, n2.1, side);
+                        out.set(n1.0, n1.1, side);
+                    } else if left {
+                        out.set(n3.0, n3.1, alpha_blend(get(&out, n3), px, 3, 2));
+                        out.set(n2.0, n2.1, alpha_blend(get(&out, n2), px, 1, 2));
+                    } else if up {
+                        out.set(n3.0, n3.1, alpha_blend(get(&out, n3), px, 3, 2));
+                        out.set(n1.0, n1.1, alpha_blend(get(&out, n1), px, 1, 2));
+                    } else {
+                        out.set(n3.0, n3.1, half_blend(get(&out, n3), px));
+                    }
+                } else {
+                    out.set(n3.0, n3.1, half_blend(get(&out, n3), px));
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BLACK: (u8, u8, u8) = (0, 0, 0);
+    const WHITE: (u8, u8, u8) = (255, 255, 255);
+
+    fn solid(width: usize, height: usize, rgb: (u8, u8, u8)) -> Image {
+        let mut image = Image::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                image.set(x, y, rgb);
+            }
+        }
+        image
+    }
+
+    // white below the diagonal running from top-left to bottom-right
+    fn diagonal(size: usize) -> Image {
+        let mut image = solid(size, size, BLACK);
+        for y in 0..size {
+            for x in 0..=y {
+                image.set(x, y, WHITE);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn test_output_sizes() {
+        let src = solid(5, 4, WHITE);
+        for (filter, scale) in [
+            (ScaleFilter::Nearest(4), 4),
+            (ScaleFilter::Scanlines(2), 2),
+            (ScaleFilter::Hq2x, 2),
+            (ScaleFilter::Hq3x, 3),
+            (ScaleFilter::Xbr, 2),
+            (ScaleFilter::Crt, 3),
+        ] {
+            let out = filter.apply(&src);
+            assert_eq!((out.width, out.height), (5 * scale, 4 * scale), "{}", filter.name());
+            assert_eq!(out.data.len(), out.width * out.height * 3);
+        }
+    }
+
+    #[test]
+    fn test_nearest_replicates_pixels() {
+        let mut src = solid(2, 1, BLACK);
+        src.set(1, 0, (1, 2, 3));
+        let out = ScaleFilter::Nearest(3).apply(&src);
+        for y in 0..3 {
+            assert_eq!(out.get(2, y), BLACK);
+            assert_eq!(out.get(3, y), (1, 2, 3));
+            assert_eq!(out.get(5, y), (1, 2, 3));
+        }
+    }
+
+    #[test]
+    fn test_scanlines_darken_every_third_row() {
+        let out = ScaleFilter::Scanlines(3).apply(&solid(2, 2, (200, 100, 50)));
+        assert_eq!(out.get(0, 0), (200, 100, 50));
+        assert_eq!(out.get(0, 1), (200, 100, 50));
+        assert_eq!(out.get(0, 2), (100, 50, 25));
+        assert_eq!(out.get(0, 5), (100, 50, 25));
+    }
+
+    #[test]
+    fn test_crt_mask_columns() {
+        let out = ScaleFilter::Crt.apply(&solid(1, 1, WHITE));
+        assert_eq!(out.get(0, 0), (255, 102, 102));
+        assert_eq!(out.get(1, 0), (102, 255, 102));
+        assert_eq!(out.get(2, 0), (102, 102, 255));
+        assert_eq!(out.get(1, 2), (51, 127, 51));
+    }
+
+    #[test]
+    fn test_smoothing_filters_keep_flat_areas() {
+        let src = solid(4, 4, (10, 20, 30));
+        for filter in [ScaleFilter::Hq2x, ScaleFilter::Hq3x, ScaleFilter::Xbr] {
+            let out = filter.apply(&src);
+            assert_eq!(out, solid(out.width, out.height, (10, 20, 30)), "{}", filter.name());
+        }
+    }
+
+    fn lone_pixel() -> Image {
+        let mut src = solid(3, 3, BLACK);
+        src.set(1, 1, WHITE);
+        src
+    }
+
+    #[test]
+    fn test_hq2x_lone_pixel() {
+        // every neighbor differs, so each corner is 14 parts the pixel to one of each side
+        let out = ScaleFilter::Hq2x.apply(&lone_pixel());
+        for y in 0..6 {
+            for x in 0..6 {
+                let expected = if (2..4).contains(&x) && (2..4).contains(&y) { (223, 223, 223) } else { BLACK };
+                assert_eq!(out.get(x, y), expected, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hq3x_lone_pixel() {
+        // the corners round off to half the pixel and half its sides, the rest keeps it
+        let out = ScaleFilter::Hq3x.apply(&lone_pixel());
+        for y in 0..9 {
+            for x in 0..9 {
+                let expected = match (x, y) {
+                    (3 | 5, 3 | 5) => (127, 127, 127),
+                    (3..=5, 3..=5) => WHITE,
+                    _ => BLACK,
+                };
+                assert_eq!(out.get(x, y), expected, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hq2x_staircase() {
+        let out = ScaleFilter::Hq2x.apply(&diagonal(4));
+        // the black pixel right of a stair step gets its bottom-left corner lightened
+        assert_eq!(out.get(2, 1), (191, 191, 191));
+        assert_eq!(out.get(3, 0), BLACK);
+        // and the white one below it has its top-right corner cut in half
+        assert_eq!(out.get(3, 2), (127, 127, 127));
+        assert_eq!(out.get(2, 2), WHITE);
+        assert_eq!(out.get(7, 0), BLACK);
+        assert_eq!(out.get(0, 7), WHITE);
+    }
+
+    #[test]
+    fn test_hqx_is_symmetric() {
+        // the rules for every output pixel come from the same corner, so a mirrored frame
+        // has to scale to the mirrored output
+        let mut src = diagonal(5);
+        src.set(3, 1, (200, 40, 40));
+        src.set(1, 4, (40, 40, 200));
+        let mut mirrored = Image::new(5, 5);
+        for y in 0..5 {
+            for x in 0..5 {
+                mirrored.set(4 - x, y, src.get(x as isize, y as isize));
+            }
+        }
+        for (filter, scale) in [(ScaleFilter::Hq2x, 2), (ScaleFilter::Hq3x, 3)] {
+            let out = filter.apply(&src);
+            let out_mirrored = filter.apply(&mirrored);
+            let width = 5 * scale;
+            for y in 0..width {
+                for x in 0..width {
+                    assert_eq!(out.get(x as isize, y as isize), out_mirrored.get((width - 1 - x) as isize, y as isize), "{}", filter.name());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_xbr_staircase() {
+        let out = ScaleFilter::Xbr.apply(&diagonal(6));
+        // along the stair the corner sub-pixels facing the edge are blended halfway
+        assert_eq!(out.get(5, 4), (127, 127, 127));
+        assert_eq!(out.get(6, 5), (127, 127, 127));
+        assert_eq!(out.get(4, 5), WHITE);
+        // away from the edge things stay untouched
+        assert_eq!(out.get(11, 0), BLACK);
+        assert_eq!(out.get(0, 11), WHITE);
+    }
+}
@@ -1,14 +1,17 @@
 pub struct Frame {
     pub data: Vec<u8>,
+    // 9-bit PPU output per pixel: palette index in bits 0-5, emphasis in bits 6-8
+    pub indices: Vec<u16>,
  }
  
  impl Frame {
-    const WIDTH: usize = 256;
-    const HIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HIGHT: usize = 240;
  
     pub fn new() -> Self {
         Frame {
             data: vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3],
+            indices: vec![0; (Frame::WIDTH) * (Frame::HIGHT)],
         }
     }
  
@@ -20,4 +23,11 @@ pub struct Frame {
             self.data[base + 2] = rgb.2;
         }
     }
- }
\ No newline at end of file
+
+    pub fn set_pixel_indexed(&mut self, x: usize, y: usize, index: u16, rgb: (u8, u8, u8)) {
+        if x < Frame::WIDTH && y < Frame::HIGHT {
+            self.indices[y * Frame::WIDTH + x] = index;
+            self.set_pixel(x, y, rgb);
+        }
+    }
+ }
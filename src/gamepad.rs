@@ -0,0 +1,56 @@
+// connected controllers by slot: a new one takes the lowest free slot and unplugging
+// one leaves the others where they were, so each player keeps reading the same pad
+pub struct GamepadSlots<T> {
+    slots: Vec<Option<(u32, T)>>,
+}
+
+impl<T> GamepadSlots<T> {
+    pub fn new() -> Self {
+        GamepadSlots { slots: vec![] }
+    }
+
+    // id is SDL's joystick instance id, which controller events carry
+    pub fn insert(&mut self, id: u32, pad: T) -> usize {
+        match self.slots.iter().position(|slot| slot.is_none()) {
+            Some(free) => {
+                self.slots[free] = Some((id, pad));
+                free
+            }
+            None => {
+                self.slots.push(Some((id, pad)));
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    // the slot the pad was in
+    pub fn remove(&mut self, id: u32) -> Option<usize> {
+        let slot = self.slot(id)?;
+        self.slots[slot] = None;
+        Some(slot)
+    }
+
+    pub fn slot(&self, id: u32) -> Option<usize> {
+        self.slots.iter().position(|slot| slot.as_ref().map(|(pad_id, _)| *pad_id) == Some(id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_slots_survive_unplugging() {
+        let mut slots = GamepadSlots::new();
+        assert_eq!(slots.insert(10, ()), 0);
+        assert_eq!(slots.insert(11, ()), 1);
+        assert_eq!(slots.insert(12, ()), 2);
+        assert_eq!(slots.remove(11), Some(1));
+        assert_eq!(slots.remove(11), None);
+        assert_eq!(slots.slot(12), Some(2));
+        assert_eq!(slots.slot(11), None);
+        // a new pad fills the hole
+        assert_eq!(slots.insert(13, ()), 1);
+        assert_eq!(slots.insert(14, ()), 3);
+    }
+}
@@ -49,4 +49,8 @@ impl Joypad {
     pub fn set_button_pressed_status(&mut self, button: JoypadButtons, pressed: bool) {
         self.button_status.set(button, pressed);
     }
+
+    pub fn buttons(&self) -> JoypadButtons {
+        self.button_status
+    }
 }
\ No newline at end of file
@@ -0,0 +1,95 @@
+use sdl2::keyboard::Keycode;
+
+use crate::family_keyboard::FamilyKey;
+
+// Power Pad buttons 1-12, a block of keys laid out like the mat
+pub const POWER_PAD_KEYS: [Keycode; 12] = [
+    Keycode::T, Keycode::Y, Keycode::U, Keycode::I,
+    Keycode::G, Keycode::H, Keycode::J, Keycode::K,
+    Keycode::B, Keycode::M, Keycode::Comma, Keycode::Period,
+];
+
+pub fn power_pad_button(key: Keycode) -> Option<usize> {
+    POWER_PAD_KEYS.iter().position(|k| *k == key).map(|i| i + 1)
+}
+
+// the Family BASIC keyboard by position on a US layout. Escape stays with the emulator,
+// so the Famicom's ESC is Tab
+pub fn family_key(key: Keycode) -> Option<FamilyKey> {
+    let family = match key {
+        Keycode::F1 => FamilyKey::F1,
+        Keycode::F2 => FamilyKey::F2,
+        Keycode::F3 => FamilyKey::F3,
+        Keycode::F4 => FamilyKey::F4,
+        Keycode::F5 => FamilyKey::F5,
+        Keycode::F6 => FamilyKey::F6,
+        Keycode::F7 => FamilyKey::F7,
+        Keycode::F8 => FamilyKey::F8,
+        Keycode::F9 => FamilyKey::Stop,
+        Keycode::Num1 => FamilyKey::Key1,
+        Keycode::Num2 => FamilyKey::Key2,
+        Keycode::Num3 => FamilyKey::Key3,
+        Keycode::Num4 => FamilyKey::Key4,
+        Keycode::Num5 => FamilyKey::Key5,
+        Keycode::Num6 => FamilyKey::Key6,
+        Keycode::Num7 => FamilyKey::Key7,
+        Keycode::Num8 => FamilyKey::Key8,
+        Keycode::Num9 => FamilyKey::Key9,
+        Keycode::Num0 => FamilyKey::Key0,
+        Keycode::A => FamilyKey::A,
+        Keycode::B => FamilyKey::B,
+        Keycode::C => FamilyKey::C,
+        Keycode::D => FamilyKey::D,
+        Keycode::E => FamilyKey::E,
+        Keycode::F => FamilyKey::F,
+        Keycode::G => FamilyKey::G,
+        Keycode::H => FamilyKey::H,
+        Keycode::I => FamilyKey::I,
+        Keycode::J => FamilyKey::J,
+        Keycode::K => FamilyKey::K,
+        Keycode::L => FamilyKey::L,
+        Keycode::M => FamilyKey::M,
+        Keycode::N => FamilyKey::N,
+        Keycode::O => FamilyKey::O,
+        Keycode::P => FamilyKey::P,
+        Keycode::Q => FamilyKey::Q,
+        Keycode::R => FamilyKey::R,
+        Keycode::S => FamilyKey::S,
+        Keycode::T => FamilyKey::T,
+        Keycode::U => FamilyKey::U,
+        Keycode::V => FamilyKey::V,
+        Keycode::W => FamilyKey::W,
+        Keycode::X => FamilyKey::X,
+        Keycode::Y => FamilyKey::Y,
+        Keycode::Z => FamilyKey::Z,
+        Keycode::Minus => FamilyKey::Minus,
+        Keycode::Equals => FamilyKey::Caret,
+        Keycode::Backslash => FamilyKey::Yen,
+        Keycode::Tab => FamilyKey::Escape,
+        Keycode::Backquote => FamilyKey::At,
+        Keycode::LeftBracket => FamilyKey::LeftBracket,
+        Keycode::RightBracket => FamilyKey::RightBracket,
+        Keycode::Return => FamilyKey::Return,
+        Keycode::LCtrl => FamilyKey::Ctr,
+        Keycode::Semicolon => FamilyKey::Semicolon,
+        Keycode::Quote => FamilyKey::Colon,
+        Keycode::RAlt => FamilyKey::Kana,
+        Keycode::LShift => FamilyKey::LeftShift,
+        Keycode::RShift => FamilyKey::RightShift,
+        Keycode::Comma => FamilyKey::Comma,
+        Keycode::Period => FamilyKey::Period,
+        Keycode::Slash => FamilyKey::Slash,
+        Keycode::End => FamilyKey::Underscore,
+        Keycode::LAlt => FamilyKey::Grph,
+        Keycode::Space => FamilyKey::Space,
+        Keycode::Home => FamilyKey::ClrHome,
+        Keycode::Insert => FamilyKey::Insert,
+        Keycode::Delete | Keycode::Backspace => FamilyKey::Delete,
+        Keycode::Up => FamilyKey::Up,
+        Keycode::Down => FamilyKey::Down,
+        Keycode::Left => FamilyKey::Left,
+        Keycode::Right => FamilyKey::Right,
+        _ => return None,
+    };
+    Some(family)
+}
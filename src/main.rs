@@ -12,18 +12,57 @@ mod render;
 mod frame;
 mod palette;
 mod joypad;
+mod pad_input;
 mod apu;
+mod audio;
+mod cli;
+mod ntsc;
+mod filters;
+mod display;
+mod capture;
+mod pacing;
+mod wav;
+mod visualizer;
+mod nsf;
+mod expansion;
+mod vrc6;
+mod vrc7;
+mod n163;
+mod sunsoft5b;
+mod mmc5_audio;
+mod fds;
+mod controller;
+mod zapper;
+mod paddle;
+mod power_pad;
+mod family_keyboard;
+mod keymaps;
+mod movie;
+mod savestate;
+mod rewind;
+mod playback;
+mod config;
+mod bindings;
+mod gamepad;
+mod rebind;
+mod blargg;
 use std::collections::HashMap;
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Mod;
 use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::sys::KeyCode;
 use sdl2::video::Window;
 use sdl2::EventPump;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+use sdl2::controller::GameController;
+use sdl2::GameControllerSubsystem;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use std::time::Duration;
+use std::rc::Rc;
+use std::cell::Cell;
 use std::fs::File;
 use std::io::Write;
 use crate::opcodes::*;
@@ -37,82 +76,777 @@ use crate::tile_viewer::*;
 use crate::render::*;
 use crate::frame::*;
 use crate::joypad::*;
+use crate::pad_input::*;
+use crate::movie::*;
+use crate::rewind::RewindBuffer;
+use crate::playback::*;
+use crate::controller::*;
 use crate::apu::*;
+use crate::audio::*;
+use crate::cli::*;
+use crate::palette::*;
+use crate::ntsc::*;
+use crate::filters::*;
+use crate::display::*;
+use crate::capture::*;
+use crate::pacing::*;
+use crate::visualizer::*;
+use crate::nsf::*;
+use crate::keymaps::*;
+use crate::bindings::*;
+use crate::gamepad::*;
+use crate::rebind::*;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if options.test_rom {
+        let passed = run_test_roms(&options.rom_path);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    if is_nsf_path(&options.rom_path) {
+        run_nsf(&options);
+        return;
+    }
+    if let Some(frames) = options.headless_frames {
+        run_headless(&options, frames);
+        return;
+    }
+
+    let mut palettes: Vec<Palette> = BuiltinPalette::ALL.iter().map(|kind| Palette::builtin(*kind)).collect();
+    let mut palette_index = 0;
+    if let Some(name) = &options.palette {
+        match BuiltinPalette::from_name(name) {
+            Some(kind) => palette_index = BuiltinPalette::ALL.iter().position(|p| *p == kind).unwrap(),
+            None => {
+                palettes.push(Palette::load(name).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }));
+                palette_index = palettes.len() - 1;
+            }
+        }
+    }
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
+    let audio_subsystem = sdl_context.audio().unwrap();
+    // connected pads show up as device added events, at startup too
+    let controller_subsystem = sdl_context.game_controller().unwrap();
+    let mut gamepads: GamepadSlots<GameController> = GamepadSlots::new();
+    let mut bindings = InputBindings::load(&options.bindings).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let bindings_path = options.bindings.clone();
+    let mut display = options.display;
+    let (display_width, display_height) = display.display_size();
+    println!("crop region: {}", display.crop_region());
     let window = video_subsystem
-        .window("Game", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
+        .window("Game", (display_width * 3) as u32, (display_height * 3) as u32)
         .position_centered()
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    // no vsync, the frame pacer runs at the console's rate whatever the monitor does
+    let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
     canvas.set_scale(3.0, 3.0).unwrap();
 
     let creator = canvas.texture_creator();
+    let texture_creator = &creator;
     let mut texture = creator
         .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
         .unwrap();
+    let mut texture_size = (256, 240);
+    let mut ntsc_filter = NtscFilter::new();
+    let mut use_ntsc = false;
+    let mut filter_index = 0;
+    let mut visualizer: Option<Canvas<Window>> = None;
 
     //load game
     let mut frame = Frame::new();
 
-    let mut key_map = HashMap::new();
-    key_map.insert(Keycode::Down, joypad::JoypadButtons::DOWN);
-    key_map.insert(Keycode::Up, joypad::JoypadButtons::UP);
-    key_map.insert(Keycode::Right, joypad::JoypadButtons::RIGHT);
-    key_map.insert(Keycode::Left, joypad::JoypadButtons::LEFT);
-    key_map.insert(Keycode::Space, joypad::JoypadButtons::SELECT);
-    key_map.insert(Keycode::Return, joypad::JoypadButtons::START);
-    key_map.insert(Keycode::X, joypad::JoypadButtons::BUTTON_A);
-    key_map.insert(Keycode::Z, joypad::JoypadButtons::BUTTON_B);
-
-    let bytes: Vec<u8> = std::fs::read("src/TestRoms/pacman.nes").unwrap();
+    let bytes: Vec<u8> = std::fs::read(&options.rom_path).unwrap();
     let rom = Rom::new(&bytes).unwrap();
-    let bus = Bus::new(rom, move |ppu: &NesPPU, joypad: &mut Joypad| {
-        render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
-        
-        canvas.copy(&texture, None, None).unwrap();
+    let tv_system = rom.tv_system;
+    let mut recorder = options.record_video.as_ref().map(|path| {
+        VideoRecorder::start(path, display, tv_system).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+    // the reset keys, handed from the frame callback to the CPU loop
+    let reset_key: Rc<Cell<Option<ResetKind>>> = Rc::new(Cell::new(None));
+    let reset_pressed = reset_key.clone();
+    // the rewind hotkey and the speed the playback hotkeys ask for, the same way
+    let mut controls = PlaybackControl::new(options.fast_forward, options.slow_motion);
+    let rewind_key: Rc<Cell<bool>> = Rc::new(Cell::new(false));
+    let rewind_pressed = rewind_key.clone();
+    let speed_key: Rc<Cell<Speed>> = Rc::new(Cell::new(Speed::Normal));
+    let speed_pressed = speed_key.clone();
+    // the window closed, the run ends once the recordings are finished
+    let quit_key: Rc<Cell<bool>> = Rc::new(Cell::new(false));
+    let quit_pressed = quit_key.clone();
+    let mut bus = Bus::new(rom, move |ppu: &NesPPU, apu: &mut Apu, ports: &mut ControllerPorts| {
+        // fast-forward skips drawing most frames, a video recording still gets them all
+        let draw = controls.should_draw();
+        if draw || recorder.is_some() {
+            render::render(ppu, &mut frame, &palettes[palette_index]);
+        }
+        if let Some(video) = recorder.as_mut() {
+            if let Err(e) = video.write_frame(&Image::from_frame(&frame)) {
+                eprintln!("video recording stopped: {}", e);
+                finish_video(recorder.take().unwrap());
+            }
+        }
+        if draw {
+            let image = if use_ntsc {
+                Image::from_rgb(NTSC_WIDTH, NTSC_HEIGHT, ntsc_filter.apply(&frame, ppu.odd_frame(), ppu.mask_register.rendering_enabled()))
+            } else {
+                Image::from_frame(&frame)
+            };
+            let image = ScaleFilter::PRESETS[filter_index].apply(&display.crop(&image));
+            if texture_size != (image.width, image.height) {
+                texture = texture_creator
+                    .create_texture_target(PixelFormatEnum::RGB24, image.width as u32, image.height as u32)
+                    .unwrap();
+                texture_size = (image.width, image.height);
+            }
+            texture.update(None, &image.data, image.width * 3).unwrap();
+            canvas.copy(&texture, None, None).unwrap();
 
-        canvas.present();
-        for event in event_pump.poll_iter() {
-            match event {
-              Event::Quit { .. }
-              | Event::KeyDown {
-                  keycode: Some(Keycode::Escape),
-                  ..
-              } => std::process::exit(0),
-
-              Event::KeyDown { keycode, .. } => {
-                if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                    joypad.set_button_pressed_status(*key, true);
-                    //println!("{:?}", *key);
-                }
+            canvas.present();
+            if let Some(window) = visualizer.as_mut() {
+                draw_visualizer(window, apu);
             }
-            Event::KeyUp { keycode, .. } => {
-                if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                    joypad.set_button_pressed_status(*key, false);
+        }
+        // a pause holds the game here, still taking input, until it's lifted or a frame
+        // advanced
+        loop {
+            let mut rebinding = false;
+            let mut quitting = false;
+            for event in event_pump.poll_iter() {
+                if keyboard_device_input(ports, &event) {
+                    continue;
                 }
-            }
+                let hotkeys = hotkey_input(&event, &bindings);
+                if !hotkeys.is_empty() {
+                    // shift with a channel's hotkey solos it instead of muting it
+                    let shift = matches!(event, Event::KeyDown { keymod, .. } if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD));
+                    for (hotkey, pressed) in hotkeys {
+                        if !controls.press(hotkey, pressed) {
+                            continue;
+                        }
+                        match hotkey {
+                            Hotkey::Palette => {
+                                palette_index = (palette_index + 1) % palettes.len();
+                                println!("palette: {}", palettes[palette_index].name);
+                            }
+                            Hotkey::Ntsc => use_ntsc = !use_ntsc,
+                            Hotkey::Overscan => {
+                                display.overscan = if display.overscan == Overscan::NONE { Overscan::NTSC } else { Overscan::NONE };
+                                resize_window(&mut canvas, &display);
+                            }
+                            Hotkey::Aspect => {
+                                display.aspect_correction = !display.aspect_correction;
+                                resize_window(&mut canvas, &display);
+                            }
+                            Hotkey::Screenshot => {
+                                let path = format!("screenshot-{}.bmp", timestamp());
+                                match save_screenshot(&path, &Image::from_frame(&frame), &display) {
+                                    Ok(region) => println!("saved {} (crop {})", path, region),
+                                    Err(e) => println!("{}", e),
+                                }
+                            }
+                            Hotkey::RecordVideo => match recorder.take() {
+                                Some(video) => finish_video(video),
+                                None => {
+                                    let path = format!("capture-{}.y4m", timestamp());
+                                    match VideoRecorder::start(&path, display, tv_system) {
+                                        Ok(video) => {
+                                            println!("recording {} (crop {})", path, display.crop_region());
+                                            recorder = Some(video);
+                                        }
+                                        Err(e) => eprintln!("{}", e),
+                                    }
+                                }
+                            },
+                            Hotkey::RecordAudio => {
+                                if apu.audio.recording() {
+                                    match apu.audio.stop_recording() {
+                                        Ok(samples) => println!("recorded {} audio samples", samples),
+                                        Err(e) => println!("{}", e),
+                                    }
+                                } else {
+                                    let path = format!("audio-{}.wav", timestamp());
+                                    match apu.audio.start_recording(&path, false) {
+                                        Ok(()) => println!("recording {}", path),
+                                        Err(e) => println!("{}", e),
+                                    }
+                                }
+                            }
+                            Hotkey::Visualizer => {
+                                if visualizer.take().is_none() {
+                                    visualizer = Some(open_visualizer(&video_subsystem, apu.channels().len()));
+                                }
+                                apu.set_scope(visualizer.is_some());
+                            }
+                            Hotkey::Channel(index) => {
+                                if let Some(channel) = apu.channels().get(index).cloned() {
+                                    if shift {
+                                        apu.set_solo(channel, !apu.is_solo(channel));
+                                    } else {
+                                        apu.set_muted(channel, !apu.is_muted(channel));
+                                    }
+                                }
+                            }
+                            Hotkey::Filter => {
+                                filter_index = (filter_index + 1) % ScaleFilter::PRESETS.len();
+                                println!("filter: {}", ScaleFilter::PRESETS[filter_index].name());
+                            }
+                            Hotkey::Rebind => rebinding = true,
+                            Hotkey::SoftReset => reset_pressed.set(Some(ResetKind::Soft)),
+                            Hotkey::PowerCycle => reset_pressed.set(Some(ResetKind::Power)),
+                            // the playback hotkeys are PlaybackControl's
+                            _ => {}
+                        }
+                    }
+                    continue;
+                }
+                if gamepad_input(&event, &controller_subsystem, &mut gamepads, &bindings, ports) {
+                    continue;
+                }
+                match event {
+                  Event::Window { window_id, win_event: WindowEvent::Close, .. } => {
+                    if visualizer.as_ref().map(|w| w.window().id()) == Some(window_id) {
+                        visualizer = None;
+                        apu.set_scope(false);
+                    } else {
+                        quitting = true;
+                    }
+                  }
+
+                  Event::Quit { .. }
+                  | Event::KeyDown {
+                      keycode: Some(Keycode::Escape),
+                      ..
+                  } => quitting = true,
+
+                  // the mouse aims the Zapper, left button pulls the trigger at the pointer and
+                  // right button fires away from the screen; it also turns the Vaus knob
+                  Event::MouseMotion { window_id, x, y, .. } if window_id == canvas.window().id() => {
+                    let (width, height) = canvas.window().size();
+                    let position = display.frame_position(x, y, width, height);
+                    if let Some(zapper) = ports.zapper() {
+                        zapper.aim = position;
+                    }
+                    if let (Some(paddle), Some((x, _))) = (ports.paddle(), position) {
+                        paddle.set_pointer(x);
+                    }
+                  }
+                  Event::MouseButtonDown { window_id, mouse_btn, x, y, .. } if window_id == canvas.window().id() => {
+                    let (width, height) = canvas.window().size();
+                    if let Some(zapper) = ports.zapper() {
+                        zapper.aim = match mouse_btn {
+                            MouseButton::Right => None,
+                            _ => display.frame_position(x, y, width, height),
+                        };
+                        zapper.trigger = true;
+                    }
+                    if let Some(paddle) = ports.paddle() {
+                        paddle.fire = true;
+                    }
+                  }
+                  Event::MouseButtonUp { .. } => {
+                    if let Some(zapper) = ports.zapper() {
+                        zapper.trigger = false;
+                    }
+                    if let Some(paddle) = ports.paddle() {
+                        paddle.fire = false;
+                    }
+                  }
+                  Event::Window { window_id, win_event: WindowEvent::Leave, .. } if window_id == canvas.window().id() => {
+                    if let Some(zapper) = ports.zapper() {
+                        zapper.aim = None;
+                    }
+                  }
 
-              _ => { /* do nothing */ }
+                  Event::KeyDown { keycode: Some(key), .. } => set_buttons(ports, &bindings.key(key), true),
+                  Event::KeyUp { keycode: Some(key), .. } => set_buttons(ports, &bindings.key(key), false),
+
+                  _ => { /* do nothing */ }
+                }
+             }
+            if rebinding {
+                quitting |= run_rebinding(&mut canvas, &mut event_pump, &controller_subsystem, &mut gamepads, &mut bindings, &bindings_path, ports);
+                // the screen took the key's release, so it opens again next press
+                controls.press(Hotkey::Rebind, false);
+            }
+            if quitting {
+                finish_recordings(&mut recorder, apu);
+                quit_pressed.set(true);
+                break;
             }
-         }
+            if !controls.waiting() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        rewind_pressed.set(controls.rewinding());
+        speed_pressed.set(controls.speed());
     });
+    if let Some(setup) = options.input {
+        bus.ports.connect(setup);
+    }
+    bus.ports.turbo_rate = options.turbo_rate;
+    bus.ports.block_opposites = options.block_opposites;
+    start_movie(&options, tv_system, &mut bus);
+    let mut sample_rate = DEFAULT_SAMPLE_RATE;
+    match QueueSink::open(&audio_subsystem, DEFAULT_SAMPLE_RATE) {
+        Ok(sink) => {
+            sample_rate = sink.sample_rate();
+            bus.set_audio_sink(Box::new(sink));
+        }
+        Err(e) => println!("no audio: {}", e),
+    }
+    bus.set_frame_pacer(FramePacer::new(tv_system, sample_rate));
+    if let Some(path) = &options.record_audio {
+        bus.apu.audio.start_recording(path, options.record_channels).unwrap();
+    }
     let mut cpu = CPU::new(bus);
+    if options.rewind_budget > 0 {
+        cpu.rewind = Some(RewindBuffer::new(options.rewind_interval, options.rewind_budget * 1024 * 1024));
+    }
     cpu.reset();
     //cpu.program_counter = 0xc000;
     cpu.execute(move |cpu| {
         //println!("{}", trace(cpu));
+        if let Some(kind) = reset_key.take() {
+            cpu.bus.request_reset(kind);
+        }
+        if let Some(rewind) = cpu.rewind.as_mut() {
+            rewind.held = rewind_key.get();
+        }
+        cpu.bus.set_speed(speed_key.get());
+        if quit_key.get() {
+            cpu.stop();
+        }
+    });
+
+}
+
+
+// runs a blargg test ROM, or every ROM in a directory such as rom_singles, and reports
+// each one; true when they all passed
+fn run_test_roms(path: &str) -> bool {
+    let mut paths = vec![];
+    match std::fs::read_dir(path) {
+        Ok(entries) => {
+            paths = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("nes")))
+                .collect();
+            paths.sort();
+        }
+        Err(_) => paths.push(std::path::PathBuf::from(path)),
+    }
+
+    let mut passed = 0;
+    for rom_path in paths.iter() {
+        let name = rom_path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().to_string());
+        let result = std::fs::read(rom_path)
+            .map_err(|e| format!("could not read {}: {}", rom_path.display(), e))
+            .and_then(|bytes| Rom::new(&bytes))
+            .and_then(|rom| blargg::run_test_rom(rom, blargg::DEFAULT_TIMEOUT_FRAMES));
+        match result {
+            Ok(report) if report.passed() => {
+                passed += 1;
+                println!("passed {}", name);
+            }
+            Ok(report) => println!("FAILED {} (#{}): {}", name, report.status, report.summary()),
+            Err(e) => println!("FAILED {}: {}", name, e),
+        }
+    }
+    println!("{} of {} passed", passed, paths.len());
+    passed == paths.len()
+}
+
+// runs the ROM for a number of frames as fast as possible, for dumping audio
+fn run_headless(options: &Options, frames: usize) {
+    let bytes: Vec<u8> = std::fs::read(&options.rom_path).unwrap();
+    let rom = Rom::new(&bytes).unwrap();
+    let tv_system = rom.tv_system;
+    // PPU frames, so a game that never turns on NMI still gets to the end
+    let frame_count = Rc::new(Cell::new(0));
+    let counted = frame_count.clone();
+    let mut bus = Bus::new(rom, move |_ppu: &NesPPU, _apu: &mut Apu, _ports: &mut ControllerPorts| {
+        counted.set(counted.get() + 1);
+    });
+    if let Some(setup) = options.input {
+        bus.ports.connect(setup);
+    }
+    bus.ports.turbo_rate = options.turbo_rate;
+    bus.ports.block_opposites = options.block_opposites;
+    start_movie(options, tv_system, &mut bus);
+    if let Some(path) = &options.record_audio {
+        if let Err(e) = bus.apu.audio.start_recording(path, options.record_channels) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    cpu.execute(|cpu| {
+        if frame_count.get() >= frames {
+            cpu.stop();
+        }
     });
+    if cpu.bus.apu.audio.recording() {
+        match cpu.bus.apu.audio.stop_recording() {
+            Ok(samples) => println!("wrote {} samples", samples),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
 
+// --record-movie or --play-movie, once the input setup is known
+fn start_movie(options: &Options, tv_system: TvSystem, bus: &mut Bus) {
+    let session = if let Some(path) = &options.record_movie {
+        let name = std::path::Path::new(&options.rom_path).file_stem().map_or(String::new(), |stem| stem.to_string_lossy().to_string());
+        Movie::new(&name, tv_system, bus.ports.setup()).and_then(|movie| MovieSession::record(movie, Some(path), &mut bus.ports))
+    } else if let Some(path) = &options.play_movie {
+        Movie::load(path).map(|movie| MovieSession::play(movie, Some(path), !options.read_write, &mut bus.ports))
+    } else {
+        return;
+    };
+    match session {
+        Ok(session) => bus.movie = Some(session),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
+// NSF player: Up/Down pick a track, Enter plays it, Left/Right skip, 1-5 mute channels
+fn run_nsf(options: &Options) {
+    let nsf = Nsf::load(&options.rom_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let tv_system = nsf.tv_system;
+    let mut player = NsfPlayer::new(nsf);
+    let track = options.track.map(|t| t - 1).unwrap_or(player.nsf.starting_song);
+
+    if let Some(frames) = options.headless_frames {
+        let path = options.record_audio.clone().unwrap_or_else(|| {
+            eprintln!("--headless with an NSF needs --record-audio");
+            std::process::exit(1);
+        });
+        match player.render_to_wav(track, &path, frames, options.record_channels) {
+            Ok(samples) => println!("wrote {} samples", samples),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let width = VISUALIZER_WIDTH;
+    let preview = draw_player(&player, 0);
+    let window = video_subsystem
+        .window(&player.nsf.name, (width * 2) as u32, (preview.height * 2) as u32)
+        .position_centered()
+        .build()
+        .unwrap();
+    let mut canvas = window.into_canvas().build().unwrap();
+    canvas.set_scale(2.0, 2.0).unwrap();
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_target(PixelFormatEnum::RGB24, preview.width as u32, preview.height as u32)
+        .unwrap();
+    let mut event_pump = sdl_context.event_pump().unwrap();
+
+    let mut sample_rate = DEFAULT_SAMPLE_RATE;
+    match QueueSink::open(&audio_subsystem, DEFAULT_SAMPLE_RATE) {
+        Ok(sink) => {
+            sample_rate = sink.sample_rate();
+            player.cpu.bus.set_audio_sink(Box::new(sink));
+        }
+        Err(e) => println!("no audio: {}", e),
+    }
+    player.cpu.bus.set_frame_pacer(FramePacer::new(tv_system, sample_rate));
+    player.cpu.bus.apu.set_scope(true);
+    if let Some(path) = &options.record_audio {
+        player.cpu.bus.apu.audio.start_recording(path, options.record_channels).unwrap();
+    }
 
+    let mut cursor = track;
+    player.start_track(track);
+    loop {
+        let last = player.nsf.total_songs.max(1) - 1;
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    if player.cpu.bus.apu.audio.recording() {
+                        player.cpu.bus.apu.audio.stop_recording().unwrap();
+                    }
+                    return;
+                }
+                Event::KeyDown { keycode: Some(Keycode::Up), .. } => cursor = cursor.saturating_sub(1),
+                Event::KeyDown { keycode: Some(Keycode::Down), .. } => cursor = (cursor + 1).min(last),
+                Event::KeyDown { keycode: Some(Keycode::Return), .. } => player.start_track(cursor),
+                Event::KeyDown { keycode: Some(Keycode::Left), .. } => {
+                    cursor = player.track.saturating_sub(1);
+                    player.start_track(cursor);
+                }
+                Event::KeyDown { keycode: Some(Keycode::Right), .. } => {
+                    cursor = (player.track + 1).min(last);
+                    player.start_track(cursor);
+                }
+                Event::KeyDown { keycode: Some(key), keymod, .. } if channel_for_key(key, &player.cpu.bus.apu).is_some() => {
+                    let apu = &mut player.cpu.bus.apu;
+                    let channel = channel_for_key(key, apu).unwrap();
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        apu.set_solo(channel, !apu.is_solo(channel));
+                    } else {
+                        apu.set_muted(channel, !apu.is_muted(channel));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        player.play_frame();
+        let image = draw_player(&player, cursor);
+        texture.update(None, &image.data, image.width * 3).unwrap();
+        canvas.copy(&texture, None, None).unwrap();
+        canvas.present();
+    }
+}
+
+fn set_buttons(ports: &mut ControllerPorts, buttons: &[(usize, PadButton)], pressed: bool) {
+    for (player, button) in buttons.iter() {
+        ports.press(*player, *button, pressed);
+    }
+}
+
+// controller hot-plugging, buttons and sticks; true if the event was a controller's
+fn gamepad_input(
+    event: &Event,
+    subsystem: &GameControllerSubsystem,
+    gamepads: &mut GamepadSlots<GameController>,
+    bindings: &InputBindings,
+    ports: &mut ControllerPorts,
+) -> bool {
+    match event {
+        Event::ControllerDeviceAdded { which, .. } => match subsystem.open(*which) {
+            Ok(pad) if gamepads.slot(pad.instance_id()).is_none() => {
+                let name = pad.name();
+                let slot = gamepads.insert(pad.instance_id(), pad);
+                println!("gamepad {}: {}", slot + 1, name);
+            }
+            Ok(_) => {}
+            Err(e) => println!("could not open gamepad: {}", e),
+        },
+        Event::ControllerDeviceRemoved { which, .. } => {
+            if let Some(slot) = gamepads.remove(*which) {
+                println!("gamepad {} unplugged", slot + 1);
+                set_buttons(ports, &bindings.gamepad_buttons(slot), false);
+            }
+        }
+        Event::ControllerButtonDown { which, button, .. } => {
+            if let Some(slot) = gamepads.slot(*which) {
+                set_buttons(ports, &bindings.button(slot, *button), true);
+            }
+        }
+        Event::ControllerButtonUp { which, button, .. } => {
+            if let Some(slot) = gamepads.slot(*which) {
+                set_buttons(ports, &bindings.button(slot, *button), false);
+            }
+        }
+        Event::ControllerAxisMotion { which, axis, value, .. } => {
+            if let Some(slot) = gamepads.slot(*which) {
+                for (player, button, held) in bindings.axis(slot, *axis, *value) {
+                    set_buttons(ports, &[(player, button)], held);
+                }
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+// the rebinding screen, shown in place of the game, which waits, until it's closed; true
+// if the window was closed from it
+fn run_rebinding(
+    canvas: &mut Canvas<Window>,
+    event_pump: &mut EventPump,
+    subsystem: &GameControllerSubsystem,
+    gamepads: &mut GamepadSlots<GameController>,
+    bindings: &mut InputBindings,
+    path: &str,
+    ports: &mut ControllerPorts,
+) -> bool {
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_target(PixelFormatEnum::RGB24, Frame::WIDTH as u32, Frame::HIGHT as u32)
+        .unwrap();
+    let mut screen = RebindScreen::new();
+    loop {
+        for event in event_pump.poll_iter() {
+            let threshold = bindings.players[screen.player].axis_threshold;
+            match event {
+                Event::Quit { .. } => return true,
+                Event::ControllerDeviceAdded { .. } | Event::ControllerDeviceRemoved { .. } => {
+                    gamepad_input(&event, subsystem, gamepads, bindings, ports);
+                }
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } if screen.capturing => screen.capture(None, bindings),
+                Event::KeyDown { keycode: Some(key), repeat: false, .. } if screen.capturing => {
+                    screen.capture(Some(Binding::Key(key)), bindings);
+                }
+                Event::ControllerButtonDown { button, .. } if screen.capturing => {
+                    screen.capture(Some(Binding::Button(button)), bindings);
+                }
+                Event::ControllerAxisMotion { axis, value, .. }
+                    if screen.capturing && (value as f32 / i16::MAX as f32).abs() > threshold =>
+                {
+                    screen.capture(Some(Binding::Axis(axis, value > 0)), bindings);
+                }
+                Event::KeyDown { keycode: Some(key), .. } if !screen.capturing => match screen.navigate(key, bindings) {
+                    RebindAction::Save => {
+                        screen.message = match bindings.save(path) {
+                            Ok(()) => format!("saved {}", path),
+                            Err(e) => e,
+                        };
+                    }
+                    RebindAction::Close => return false,
+                    RebindAction::Stay => {}
+                },
+                _ => {}
+            }
+        }
+        let image = screen.draw(bindings);
+        texture.update(None, &image.data, image.width * 3).unwrap();
+        canvas.copy(&texture, None, None).unwrap();
+        canvas.present();
+        std::thread::sleep(std::time::Duration::from_millis(16));
+    }
+}
+
+// the hotkeys an event presses or lets go of, none if it isn't bound to any
+fn hotkey_input(event: &Event, bindings: &InputBindings) -> Vec<(Hotkey, bool)> {
+    match event {
+        Event::KeyDown { keycode: Some(key), .. } => bindings.key_hotkeys(*key).into_iter().map(|hotkey| (hotkey, true)).collect(),
+        Event::KeyUp { keycode: Some(key), .. } => bindings.key_hotkeys(*key).into_iter().map(|hotkey| (hotkey, false)).collect(),
+        Event::ControllerButtonDown { button, .. } => bindings.button_hotkeys(*button).into_iter().map(|hotkey| (hotkey, true)).collect(),
+        Event::ControllerButtonUp { button, .. } => bindings.button_hotkeys(*button).into_iter().map(|hotkey| (hotkey, false)).collect(),
+        Event::ControllerAxisMotion { axis, value, .. } => bindings.axis_hotkeys(*axis, *value),
+        _ => vec![],
+    }
+}
+
+// stops the recordings that are running, so their files are complete
+fn finish_recordings(recorder: &mut Option<VideoRecorder>, apu: &mut Apu) {
+    if let Some(video) = recorder.take() {
+        finish_video(video);
+    }
+    if apu.audio.recording() {
+        match apu.audio.stop_recording() {
+            Ok(samples) => println!("recorded {} audio samples", samples),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+fn finish_video(video: VideoRecorder) {
+    match video.finish() {
+        Ok(frames) => println!("recorded {} frames", frames),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+// keys for the Family BASIC keyboard or the Power Pad, when one is plugged in; true if the
+// event was theirs and shouldn't reach the pads or hotkeys
+fn keyboard_device_input(ports: &mut ControllerPorts, event: &Event) -> bool {
+    let (key, pressed) = match event {
+        Event::KeyDown { keycode: Some(key), .. } => (*key, true),
+        Event::KeyUp { keycode: Some(key), .. } => (*key, false),
+        _ => return false,
+    };
+    if let Some(keyboard) = ports.keyboard() {
+        if let Some(family) = family_key(key) {
+            keyboard.set_key(family, pressed);
+            return true;
+        }
+    }
+    if let Some(pad) = ports.power_pad() {
+        if let Some(button) = power_pad_button(key) {
+            pad.set_button(button, pressed);
+            return true;
+        }
+    }
+    false
+}
+
+// in the NSF player, 1-5 are the 2A03 channels, 6-9 and 0 the first expansion channels
+fn channel_for_key(key: Keycode, apu: &Apu) -> Option<Channel> {
+    let keys = [
+        Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4, Keycode::Num5,
+        Keycode::Num6, Keycode::Num7, Keycode::Num8, Keycode::Num9, Keycode::Num0,
+    ];
+    keys.iter().position(|k| *k == key).and_then(|i| apu.channels().get(i).cloned())
+}
+
+fn open_visualizer(video_subsystem: &sdl2::VideoSubsystem, channels: usize) -> Canvas<Window> {
+    let (width, height) = visualizer_size(channels);
+    let window = video_subsystem
+        .window("APU channels", (width * 2) as u32, (height * 2) as u32)
+        .build()
+        .unwrap();
+    let mut canvas = window.into_canvas().build().unwrap();
+    canvas.set_scale(2.0, 2.0).unwrap();
+    canvas
+}
+
+fn draw_visualizer(canvas: &mut Canvas<Window>, apu: &Apu) {
+    let image = draw_channels(apu);
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_target(PixelFormatEnum::RGB24, image.width as u32, image.height as u32)
+        .unwrap();
+    texture.update(None, &image.data, image.width * 3).unwrap();
+    canvas.copy(&texture, None, None).unwrap();
+    canvas.present();
+}
+
+fn resize_window(canvas: &mut Canvas<Window>, display: &DisplaySettings) {
+    let (width, height) = display.display_size();
+    canvas.window_mut().set_size((width * 3) as u32, (height * 3) as u32).unwrap();
+    println!("crop region: {}", display.crop_region());
+}
+
+fn timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
 
 // fn render_memory_dump(canvas: &mut Canvas<Window>, cpu: &CPU) {
 //     // Example rendering logic for memory dump
@@ -0,0 +1,219 @@
+use crate::apu::{Pulse, PulseChannel};
+use crate::audio::mix;
+use crate::expansion::*;
+use crate::savestate::*;
+
+// the MMC5 has no frame counter; envelopes and length counters both run at about 240 Hz
+const FRAME_PERIOD: u32 = 7457;
+const PCM_SCALE: f32 = 0.002;
+
+// MMC5 audio: two 2A03 pulses without sweep units, and an 8-bit PCM register
+pub struct Mmc5Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    pcm: u8,
+    // reading PCM from the $8000-$BFFF bus isn't modelled, only raw writes
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    frame_timer: u32,
+    odd_cycle: bool,
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Self {
+        Mmc5Audio {
+            pulse1: Pulse::new(PulseChannel::Two),
+            pulse2: Pulse::new(PulseChannel::Two),
+            pcm: 0,
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            frame_timer: 0,
+            odd_cycle: false,
+        }
+    }
+
+    fn pulse(&self, channel: usize) -> &Pulse {
+        if channel == 0 { &self.pulse1 } else { &self.pulse2 }
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn name(&self) -> &'static str {
+        "MMC5"
+    }
+
+    fn channel_names(&self) -> Vec<String> {
+        vec!["mmc5 p1".to_string(), "mmc5 p2".to_string(), "mmc5 pcm".to_string()]
+    }
+
+    fn channel_count(&self) -> usize {
+        3
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        matches!(addr, 0x5000..=0x5007 | 0x5010 | 0x5011 | 0x5015)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            // $5001 and $5005 would be the sweep registers, which the MMC5 doesn't have
+            0x5001 | 0x5005 => {}
+            0x5000..=0x5003 => self.pulse1.write_register(addr - 0x5000, data),
+            0x5004..=0x5007 => self.pulse2.write_register(addr - 0x5004, data),
+            0x5010 => {
+                self.pcm_read_mode = data & 0b0000_0001 != 0;
+                self.pcm_irq_enabled = data & 0b1000_0000 != 0;
+            }
+            // writes of 0 are ignored
+            0x5011 if !self.pcm_read_mode && data != 0 => self.pcm = data,
+            0x5015 => {
+                self.pulse1.length.set_enabled(data & 0b01 != 0);
+                self.pulse2.length.set_enabled(data & 0b10 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5015 => {
+                let mut status = 0;
+                if self.pulse1.length.active() {
+                    status |= 0b01;
+                }
+                if self.pulse2.length.active() {
+                    status |= 0b10;
+                }
+                Some(status)
+            }
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        self.frame_timer += 1;
+        if self.frame_timer == FRAME_PERIOD {
+            self.frame_timer = 0;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.clock_quarter_frame();
+                pulse.length.clock();
+            }
+        }
+    }
+
+    fn output(&self, channel: usize) -> f32 {
+        match channel {
+            0 => mix(self.pulse1.output(), 0, 0, 0, 0),
+            1 => mix(0, self.pulse2.output(), 0, 0, 0),
+            _ => self.pcm as f32 * PCM_SCALE,
+        }
+    }
+
+    fn level(&self, channel: usize) -> u8 {
+        match channel {
+            0 | 1 => self.pulse(channel).output(),
+            _ => self.pcm,
+        }
+    }
+
+    fn max_level(&self, channel: usize) -> u8 {
+        if channel == 2 { 255 } else { 15 }
+    }
+
+    fn info(&self, channel: usize, cpu_clock: f64) -> ExpansionChannelInfo {
+        match channel {
+            0 | 1 => {
+                let pulse = self.pulse(channel);
+                let volume = pulse.envelope.volume();
+                ExpansionChannelInfo {
+                    period: pulse.timer_period(),
+                    frequency: cpu_clock / (16.0 * (pulse.timer_period() as f64 + 1.0)),
+                    volume: volume,
+                    max_volume: 15,
+                    active: pulse.length.active() && volume > 0,
+                }
+            }
+            _ => ExpansionChannelInfo {
+                period: 0,
+                frequency: 0.0,
+                volume: self.pcm,
+                max_volume: 255,
+                active: self.pcm != 0,
+            },
+        }
+    }
+}
+
+impl Savestate for Mmc5Audio {
+    fn state(&mut self, s: &mut StateBuffer) {
+        self.pulse1.state(s);
+        self.pulse2.state(s);
+        s.u8(&mut self.pcm);
+        s.bool(&mut self.pcm_read_mode);
+        s.bool(&mut self.pcm_irq_enabled);
+        s.u32(&mut self.frame_timer);
+        s.bool(&mut self.odd_cycle);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::expansion::test::assert_state_round_trips;
+
+    #[test]
+    fn test_pulse_plays_and_reports_status() {
+        let mut mmc5 = Mmc5Audio::new();
+        mmc5.write(0x5015, 0b01);
+        mmc5.write(0x5000, 0b1011_1100); // 50% duty, constant volume 12
+        mmc5.write(0x5002, 253);
+        mmc5.write(0x5003, 0b0000_1000);
+        assert_eq!(mmc5.read(0x5015), Some(0b01));
+        let mut levels = vec![];
+        for _ in 0..4096 {
+            mmc5.clock();
+            levels.push(mmc5.level(0));
+        }
+        assert!(levels.contains(&12) && levels.contains(&0));
+        assert_eq!(mmc5.output(0), mix(mmc5.level(0), 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_length_counter_runs_at_240hz() {
+        let mut mmc5 = Mmc5Audio::new();
+        mmc5.write(0x5015, 0b10);
+        mmc5.write(0x5004, 0b1001_1111); // not halted
+        mmc5.write(0x5007, 0b0001_1000); // length index 3 = 2
+        for _ in 0..FRAME_PERIOD * 2 - 1 {
+            mmc5.clock();
+        }
+        assert_eq!(mmc5.read(0x5015), Some(0b10));
+        mmc5.clock();
+        assert_eq!(mmc5.read(0x5015), Some(0));
+    }
+
+    #[test]
+    fn test_pcm_ignores_zero_writes() {
+        let mut mmc5 = Mmc5Audio::new();
+        mmc5.write(0x5011, 0x80);
+        mmc5.write(0x5011, 0);
+        assert_eq!(mmc5.level(2), 0x80);
+        assert!((mmc5.output(2) - 0x80 as f32 * PCM_SCALE).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_state_round_trips() {
+        let mut mmc5 = Mmc5Audio::new();
+        mmc5.write(0x5015, 0b01);
+        mmc5.write(0x5000, 0b1000_0111); // 50% duty, decaying
+        mmc5.write(0x5002, 253);
+        mmc5.write(0x5003, 0b0000_1000);
+        assert_state_round_trips(&mut mmc5);
+    }
+}
@@ -0,0 +1,579 @@
+use std::fs::File;
+use std::io::Write;
+
+use crate::bus::ResetKind;
+use crate::cartridge::TvSystem;
+use crate::controller::*;
+use crate::joypad::*;
+
+// FM2 command bits; the FDS and VS System ones are kept but do nothing here
+pub const SOFT_RESET: u8 = 0x01;
+pub const POWER: u8 = 0x02;
+
+// pad characters in FM2 order, the first is bit 7 of JoypadButtons
+const PAD_CHARS: &str = "RLDUTSBA";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovieFrame {
+    pub commands: u8,
+    pub pads: [JoypadButtons; 4],
+}
+
+impl MovieFrame {
+    pub fn new() -> Self {
+        MovieFrame { commands: 0, pads: [JoypadButtons::empty(); 4] }
+    }
+
+    // power wins if a frame asks for both
+    pub fn reset(&self) -> Option<ResetKind> {
+        if self.commands & POWER != 0 {
+            Some(ResetKind::Power)
+        } else if self.commands & SOFT_RESET != 0 {
+            Some(ResetKind::Soft)
+        } else {
+            None
+        }
+    }
+}
+
+// a run's input from power-on, frame by frame, as FCEUX's text .fm2 files hold it
+pub struct Movie {
+    pub rom_filename: String,
+    pub pal: bool,
+    pub fourscore: bool,
+    // whether a pad is plugged into each port, without a Four Score
+    pub ports: [bool; 2],
+    pub rerecord_count: u32,
+    // header lines this emulator has no use for, written back out as they came
+    pub other: Vec<(String, String)>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    // an empty movie for the ROM and what's plugged in; only pads can be recorded
+    pub fn new(rom_filename: &str, tv_system: TvSystem, setup: InputSetup) -> Result<Movie, String> {
+        let fourscore = match setup {
+            InputSetup::Standard => false,
+            InputSetup::FourScore | InputSetup::FamicomFourPlayer => true,
+            _ => return Err(format!("movies record pads only, not the {:?} setup", setup)),
+        };
+        Ok(Movie {
+            rom_filename: rom_filename.to_string(),
+            pal: tv_system == TvSystem::PAL,
+            fourscore: fourscore,
+            ports: [true, true],
+            rerecord_count: 0,
+            other: vec![],
+            frames: vec![],
+        })
+    }
+
+    pub fn players(&self) -> usize {
+        if self.fourscore { 4 } else { 2 }
+    }
+
+    pub fn parse_fm2(text: &str) -> Result<Movie, String> {
+        let mut movie = Movie::new("", TvSystem::NTSC, InputSetup::Standard).unwrap();
+        let mut version = None;
+        for (i, raw) in text.lines().enumerate() {
+            let line = raw.trim_end();
+            let error = |message: String| format!("line {}: {}", i + 1, message);
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('|') {
+                let frame = movie.parse_frame(line).map_err(error)?;
+                movie.frames.push(frame);
+                continue;
+            }
+            if !movie.frames.is_empty() {
+                return Err(error("header line after the input log".to_string()));
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "version" => version = Some(value.to_string()),
+                "binary" if parse_flag(value).map_err(error)? => {
+                    return Err(error("binary FM2 input isn't supported".to_string()));
+                }
+                "rerecordCount" => {
+                    movie.rerecord_count = value.parse().map_err(|_| error(format!("bad rerecord count {}", value)))?;
+                }
+                "palFlag" => movie.pal = parse_flag(value).map_err(error)?,
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "fourscore" => movie.fourscore = parse_flag(value).map_err(error)?,
+                "port0" | "port1" => {
+                    let port = if key == "port0" { 0 } else { 1 };
+                    movie.ports[port] = match value {
+                        "0" => false,
+                        "1" => true,
+                        "2" => return Err(error("Zapper input isn't supported".to_string())),
+                        _ => return Err(error(format!("bad {} device {}", key, value))),
+                    };
+                }
+                "port2" if value != "0" => {
+                    return Err(error("expansion port input isn't supported".to_string()));
+                }
+                "binary" | "port2" => {}
+                _ => movie.other.push((key.to_string(), value.to_string())),
+            }
+        }
+        match version.as_deref() {
+            Some("3") => Ok(movie),
+            Some(other) => Err(format!("unsupported FM2 version {}", other)),
+            None => Err("not an FM2 movie, no version line".to_string()),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Movie, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        Movie::parse_fm2(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn header(&self) -> String {
+        let mut text = String::from("version 3\n");
+        text.push_str(&format!("rerecordCount {}\n", self.rerecord_count));
+        text.push_str(&format!("palFlag {}\n", self.pal as u8));
+        text.push_str(&format!("romFilename {}\n", self.rom_filename));
+        text.push_str(&format!("fourscore {}\n", self.fourscore as u8));
+        text.push_str(&format!("port0 {}\nport1 {}\nport2 0\n", self.ports[0] as u8, self.ports[1] as u8));
+        for (key, value) in self.other.iter() {
+            text.push_str(&format!("{} {}\n", key, value));
+        }
+        text
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut text = self.header();
+        for frame in self.frames.iter() {
+            text.push_str(&self.frame_line(frame));
+        }
+        text
+    }
+
+    // |commands|pad 1|pad 2|expansion| or, with a Four Score, four pads before the expansion
+    pub fn frame_line(&self, frame: &MovieFrame) -> String {
+        let mut line = format!("|{}|", frame.commands);
+        for player in 0..self.players() {
+            if self.fourscore || self.ports[player] {
+                line.extend(PAD_CHARS.chars().enumerate().map(|(i, c)| {
+                    if frame.pads[player].bits() & (0x80 >> i) != 0 { c } else { '.' }
+                }));
+            }
+            line.push('|');
+        }
+        line.push_str("|\n");
+        line
+    }
+
+    fn parse_frame(&self, line: &str) -> Result<MovieFrame, String> {
+        let fields: Vec<&str> = line[1..].split('|').collect();
+        // the commands, each pad and the expansion port
+        if fields.len() < self.players() + 2 {
+            return Err(format!("expected {} pads in {}", self.players(), line));
+        }
+        let mut frame = MovieFrame::new();
+        frame.commands = fields[0].trim().parse().map_err(|_| format!("bad commands {}", fields[0]))?;
+        for (player, field) in fields[1..=self.players()].iter().enumerate() {
+            if field.is_empty() {
+                continue;
+            }
+            if field.chars().count() != PAD_CHARS.len() {
+                return Err(format!("pad input {} should be {} characters", field, PAD_CHARS.len()));
+            }
+            // anything but a space or a dot is a pressed button
+            for (i, c) in field.chars().enumerate() {
+                if c != '.' && c != ' ' {
+                    frame.pads[player] |= JoypadButtons::from_bits_truncate(0x80 >> i);
+                }
+            }
+        }
+        Ok(frame)
+    }
+}
+
+fn parse_flag(value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(format!("bad flag {}", value)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MovieMode {
+    Recording,
+    Playing,
+    Finished,
+}
+
+// a movie being recorded or played, stepped once a frame by the bus. Recording holds
+// host input back to frame boundaries so the movie has exactly what the game read; a
+// read-only playback ignores the host, a read-write one hands over to recording from
+// the current frame as soon as the host presses anything
+pub struct MovieSession {
+    pub movie: Movie,
+    pub mode: MovieMode,
+    pub read_only: bool,
+    // frames recorded or played so far
+    pub frame: usize,
+    path: Option<String>,
+    // open for appending while recording
+    file: Option<File>,
+}
+
+impl MovieSession {
+    // a new movie, written frame by frame to path if there is one
+    pub fn record(movie: Movie, path: Option<&str>, ports: &mut ControllerPorts) -> Result<MovieSession, String> {
+        let mut session = MovieSession {
+            frame: movie.frames.len(),
+            movie: movie,
+            mode: MovieMode::Recording,
+            read_only: false,
+            path: path.map(|p| p.to_string()),
+            file: None,
+        };
+        session.rewrite()?;
+        ports.set_host_input(HostInput::PerFrame);
+        Ok(session)
+    }
+
+    // plugs in the pads the movie was made with
+    pub fn play(movie: Movie, path: Option<&str>, read_only: bool, ports: &mut ControllerPorts) -> MovieSession {
+        if movie.fourscore {
+            if ports.setup() != InputSetup::FamicomFourPlayer {
+                ports.connect(InputSetup::FourScore);
+            }
+        } else {
+            ports.connect(InputSetup::Standard);
+            for port in 0..2 {
+                if !movie.ports[port] {
+                    ports.plug(port, Box::new(Unplugged));
+                }
+            }
+        }
+        ports.set_host_input(HostInput::Blocked);
+        MovieSession {
+            movie: movie,
+            mode: MovieMode::Playing,
+            read_only: read_only,
+            frame: 0,
+            path: path.map(|p| p.to_string()),
+            file: None,
+        }
+    }
+
+    // at each frame boundary: the reset to do now, the host's own request included
+    pub fn frame(&mut self, ports: &mut ControllerPorts, requested: Option<ResetKind>) -> Option<ResetKind> {
+        if self.mode == MovieMode::Playing {
+            let host_input = ports.take_host_pressed() || requested.is_some();
+            if host_input && !self.read_only {
+                self.take_over(ports);
+            } else if self.frame < self.movie.frames.len() {
+                let frame = self.movie.frames[self.frame];
+                self.frame += 1;
+                for player in 0..self.movie.players() {
+                    if let Some(joypad) = ports.joypad(player) {
+                        joypad.set_button_pressed_status(JoypadButtons::all(), false);
+                        joypad.set_button_pressed_status(frame.pads[player], true);
+                    }
+                }
+                return frame.reset();
+            } else if self.read_only {
+                println!("movie finished after {} frames", self.frame);
+                self.mode = MovieMode::Finished;
+                ports.set_host_input(HostInput::Live);
+            } else {
+                // read-write carries on recording past the end
+                self.take_over(ports);
+            }
+        }
+        if self.mode == MovieMode::Recording {
+            let mut frame = MovieFrame::new();
+            frame.commands = match requested {
+                Some(ResetKind::Soft) => SOFT_RESET,
+                Some(ResetKind::Power) => POWER,
+                None => 0,
+            };
+            for player in 0..self.movie.players() {
+                if let Some(joypad) = ports.joypad(player) {
+                    frame.pads[player] = joypad.buttons();
+                }
+            }
+            self.append(&frame);
+        }
+        requested
+    }
+
+    // recording from the current frame on, dropping the rest of the movie
+    fn take_over(&mut self, ports: &mut ControllerPorts) {
+        println!("movie recording from frame {}", self.frame);
+        self.movie.frames.truncate(self.frame);
+        self.movie.rerecord_count += 1;
+        self.mode = MovieMode::Recording;
+        ports.set_host_input(HostInput::PerFrame);
+        if let Err(e) = self.rewrite() {
+            eprintln!("{}", e);
+        }
+    }
+
+    // the machine went back to this frame: a recording drops what came after it, a
+    // playback carries on from there
+    pub fn rewind_to(&mut self, frame: usize, ports: &mut ControllerPorts) {
+        if frame >= self.frame {
+            return;
+        }
+        self.frame = frame;
+        match self.mode {
+            MovieMode::Recording => {
+                self.movie.frames.truncate(frame);
+                self.movie.rerecord_count += 1;
+                if let Err(e) = self.rewrite() {
+                    eprintln!("{}", e);
+                }
+            }
+            MovieMode::Finished => {
+                self.mode = MovieMode::Playing;
+                ports.set_host_input(HostInput::Blocked);
+            }
+            MovieMode::Playing => {}
+        }
+    }
+
+    // the whole movie so far, then open for appending
+    fn rewrite(&mut self) -> Result<(), String> {
+        self.file = None;
+        if let Some(path) = &self.path {
+            let error = |e: std::io::Error| format!("could not write {}: {}", path, e);
+            let mut file = File::create(path).map_err(error)?;
+            file.write_all(self.movie.to_fm2().as_bytes()).map_err(error)?;
+            self.file = Some(file);
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, frame: &MovieFrame) {
+        let line = self.movie.frame_line(frame);
+        self.movie.frames.push(*frame);
+        self.frame += 1;
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                eprintln!("movie recording stopped: {}", e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::{Mem, CPU};
+    use crate::pad_input::PadButton;
+
+    const FM2: &str = "version 3\n\
+                       emuVersion 22020\n\
+                       rerecordCount 7\n\
+                       palFlag 0\n\
+                       romFilename smb\n\
+                       guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\n\
+                       fourscore 0\n\
+                       port0 1\n\
+                       port1 1\n\
+                       port2 0\n\
+                       comment author someone\n\
+                       |2|........|........||\n\
+                       |0|R......A|...U.S..||\n\
+                       |1|........|........||\n";
+
+    #[test]
+    fn test_parse_fm2() {
+        let movie = Movie::parse_fm2(FM2).unwrap();
+        assert_eq!(movie.rom_filename, "smb");
+        assert_eq!(movie.rerecord_count, 7);
+        assert!(!movie.pal && !movie.fourscore);
+        assert_eq!(movie.frames.len(), 3);
+        assert_eq!(movie.frames[0].reset(), Some(ResetKind::Power));
+        assert_eq!(movie.frames[1].pads[0], JoypadButtons::RIGHT | JoypadButtons::BUTTON_A);
+        assert_eq!(movie.frames[1].pads[1], JoypadButtons::UP | JoypadButtons::SELECT);
+        assert_eq!(movie.frames[2].reset(), Some(ResetKind::Soft));
+        assert_eq!(movie.other[1], ("guid".to_string(), "452DE2C3-EF43-2FA9-77AC-0677FC51543B".to_string()));
+    }
+
+    #[test]
+    fn test_fm2_round_trips() {
+        let movie = Movie::parse_fm2(FM2).unwrap();
+        let text = movie.to_fm2();
+        assert!(text.contains("comment author someone\n"));
+        assert!(text.ends_with("|0|R......A|...U.S..||\n|1|........|........||\n"));
+        let again = Movie::parse_fm2(&text).unwrap();
+        assert_eq!(again.frames, movie.frames);
+        assert_eq!(again.to_fm2(), text);
+    }
+
+    #[test]
+    fn test_fourscore_and_unplugged_ports() {
+        let movie = Movie::parse_fm2("version 3\nfourscore 1\n|0|.......A|......B.|.....S..|....T...||\n").unwrap();
+        assert_eq!(movie.players(), 4);
+        assert_eq!(movie.frames[0].pads[3], JoypadButtons::START);
+        let movie = Movie::parse_fm2("version 3\nport1 0\n|0|.......A|||\n").unwrap();
+        assert_eq!(movie.frame_line(&movie.frames[0]), "|0|.......A|||\n");
+    }
+
+    #[test]
+    fn test_fm2_errors() {
+        assert!(Movie::parse_fm2("|0|........|........||\n").is_err());
+        assert!(Movie::parse_fm2("version 2\n").is_err());
+        assert!(Movie::parse_fm2("version 3\nport0 2\n").err().unwrap().contains("Zapper"));
+        assert!(Movie::parse_fm2("version 3\nbinary 1\n").is_err());
+        assert!(Movie::parse_fm2("version 3\n|0|.....|........||\n").err().unwrap().starts_with("line 2"));
+        assert!(Movie::parse_fm2("version 3\n|x|........|........||\n").is_err());
+        assert!(Movie::parse_fm2("version 3\n|0|........\n").is_err());
+        assert!(Movie::parse_fm2("version 3\n|0|........|........||\nport0 1\n").is_err());
+    }
+
+    #[test]
+    fn test_read_only_playback_ignores_the_host() {
+        let mut ports = ControllerPorts::new();
+        let movie = Movie::parse_fm2(FM2).unwrap();
+        let mut session = MovieSession::play(movie, None, true, &mut ports);
+        assert_eq!(session.frame(&mut ports, None), Some(ResetKind::Power));
+        ports.press(0, PadButton::Normal(JoypadButtons::START), true);
+        assert_eq!(session.frame(&mut ports, Some(ResetKind::Soft)), None);
+        assert_eq!(ports.joypad(0).unwrap().buttons(), JoypadButtons::RIGHT | JoypadButtons::BUTTON_A);
+        assert_eq!(session.frame(&mut ports, None), Some(ResetKind::Soft));
+        assert_eq!(session.mode, MovieMode::Playing);
+        // past the end the host gets its pads back
+        session.frame(&mut ports, None);
+        assert_eq!(session.mode, MovieMode::Finished);
+        assert_eq!(ports.joypad(0).unwrap().buttons(), JoypadButtons::START);
+    }
+
+    #[test]
+    fn test_read_write_playback_hands_over_to_recording() {
+        let mut ports = ControllerPorts::new();
+        let movie = Movie::parse_fm2(FM2).unwrap();
+        let mut session = MovieSession::play(movie, None, false, &mut ports);
+        session.frame(&mut ports, None);
+        ports.press(1, PadButton::Normal(JoypadButtons::BUTTON_B), true);
+        assert_eq!(session.frame(&mut ports, None), None);
+        assert_eq!(session.mode, MovieMode::Recording);
+        assert_eq!(session.movie.rerecord_count, 8);
+        assert_eq!(session.movie.frames.len(), 2);
+        assert_eq!(session.movie.frames[1].pads[1], JoypadButtons::BUTTON_B);
+        // resets get recorded too
+        assert_eq!(session.frame(&mut ports, Some(ResetKind::Soft)), Some(ResetKind::Soft));
+        assert_eq!(session.movie.frames[2].commands, SOFT_RESET);
+    }
+
+    #[test]
+    fn test_recording_writes_as_it_goes() {
+        let path = std::env::temp_dir().join(format!("nes-movie-{}.fm2", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut ports = ControllerPorts::new();
+        let movie = Movie::new("game", TvSystem::PAL, InputSetup::Standard).unwrap();
+        let mut session = MovieSession::record(movie, Some(path), &mut ports).unwrap();
+        // held back until the frame boundary
+        ports.press(0, PadButton::Normal(JoypadButtons::UP), true);
+        assert!(ports.joypad(0).unwrap().buttons().is_empty());
+        ports.frame();
+        session.frame(&mut ports, None);
+        session.frame(&mut ports, Some(ResetKind::Power));
+        let loaded = Movie::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(loaded.pal);
+        assert_eq!(loaded.frames, session.movie.frames);
+        assert_eq!(loaded.frames[0].pads[0], JoypadButtons::UP);
+        assert_eq!(loaded.frames[1].reset(), Some(ResetKind::Power));
+        assert!(Movie::new("game", TvSystem::NTSC, InputSetup::Zapper).is_err());
+    }
+
+    // reset: turn on NMI and spin. NMI: read pad 1 into $10+frame, after FRAMES frames BRK
+    const FRAMES: u8 = 12;
+
+    fn pad_logger() -> Vec<u8> {
+        let mut program = vec![
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // LDA #$80, STA $2000
+            0x4C, 0x05, 0x80, // JMP $8005
+            0xA9, 0x01, 0x8D, 0x16, 0x40, // LDA #1, STA $4016
+            0xA9, 0x00, 0x8D, 0x16, 0x40, // LDA #0, STA $4016
+            0xA2, 0x08, // LDX #8
+            0xAD, 0x16, 0x40, 0x4A, 0x26, 0x00, // LDA $4016, LSR A, ROL $00
+            0xCA, 0xD0, 0xF7, // DEX, BNE back to the LDA
+            0xA6, 0x01, 0xA5, 0x00, 0x95, 0x10, // LDX $01, LDA $00, STA $10,X
+            0xE6, 0x01, 0xA5, 0x01, 0xC9, FRAMES, // INC $01, LDA $01, CMP #FRAMES
+            0xD0, 0x01, 0x00, 0x40, // BNE to the RTI, BRK, RTI
+        ];
+        program.resize(0x8000, 0);
+        program[0x7FFA..].copy_from_slice(&[0x08, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        program
+    }
+
+    // reset: wait out three vblanks polling $2002 with NMI off, then the same as pad_logger
+    fn polling_pad_logger() -> Vec<u8> {
+        let mut program = vec![
+            0xA0, 0x03, // LDY #3
+            0x2C, 0x02, 0x20, 0x10, 0xFB, // BIT $2002, BPL back to the BIT
+            0x88, 0xD0, 0xF8, // DEY, BNE back to the BIT
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // LDA #$80, STA $2000
+            0x4C, 0x0F, 0x80, // JMP $800F
+        ];
+        program.extend_from_slice(&pad_logger()[0x08..0x2D]);
+        program.resize(0x8000, 0);
+        program[0x7FFA..].copy_from_slice(&[0x12, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        program
+    }
+
+    // the pads the program logged, and the movie session
+    fn run(bus: Bus) -> (Vec<u8>, MovieSession) {
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.execute(|_| {});
+        let pads = (0..FRAMES as u16).map(|i| cpu.bus.memory_read(0x10 + i)).collect();
+        (pads, cpu.bus.movie.take().unwrap())
+    }
+
+    #[test]
+    fn test_playback_reproduces_the_run() {
+        let mut frame = 0;
+        let mut bus = Bus::new(test_rom(pad_logger()), move |_, _, ports: &mut ControllerPorts| {
+            frame += 1;
+            let buttons = [JoypadButtons::BUTTON_A, JoypadButtons::LEFT, JoypadButtons::START];
+            for (i, button) in buttons.iter().enumerate() {
+                ports.press(0, PadButton::Normal(*button), (frame / (i + 1)) % 2 == 1);
+            }
+        });
+        let movie = Movie::new("logger", TvSystem::NTSC, InputSetup::Standard).unwrap();
+        bus.movie = Some(MovieSession::record(movie, None, &mut bus.ports).unwrap());
+        let (recorded, session) = run(bus);
+        assert!(recorded.iter().any(|pad| *pad != recorded[0]));
+
+        let mut bus = Bus::new(test_rom(pad_logger()), |_, _, _| {});
+        bus.movie = Some(MovieSession::play(session.movie, None, true, &mut bus.ports));
+        let (played, session) = run(bus);
+        assert_eq!(played, recorded);
+        assert_eq!(session.mode, MovieMode::Playing);
+    }
+
+    #[test]
+    fn test_frames_without_nmi_are_recorded() {
+        let mut frame = 0;
+        let mut bus = Bus::new(test_rom(polling_pad_logger()), move |_, _, ports: &mut ControllerPorts| {
+            frame += 1;
+            ports.press(0, PadButton::Normal(JoypadButtons::BUTTON_A), frame % 2 == 1);
+        });
+        let movie = Movie::new("logger", TvSystem::NTSC, InputSetup::Standard).unwrap();
+        bus.movie = Some(MovieSession::record(movie, None, &mut bus.ports).unwrap());
+        let (recorded, session) = run(bus);
+        // one line for every PPU frame, the ones before NMI was turned on too
+        assert!(session.movie.frames.len() >= FRAMES as usize + 3, "{} frames", session.movie.frames.len());
+        assert_eq!(session.movie.frames[0].pads[0], JoypadButtons::BUTTON_A);
+        assert_eq!(session.movie.frames[1].pads[0], JoypadButtons::empty());
+
+        let mut bus = Bus::new(test_rom(polling_pad_logger()), |_, _, _| {});
+        bus.movie = Some(MovieSession::play(session.movie, None, true, &mut bus.ports));
+        let (played, _) = run(bus);
+        assert_eq!(played, recorded);
+    }
+}
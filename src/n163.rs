@@ -0,0 +1,238 @@
+use crate::expansion::*;
+use crate::savestate::*;
+
+// one channel updates every 15 CPU cycles
+const UPDATE_PERIOD: u8 = 15;
+const N163_SCALE: f32 = 0.00125;
+
+// Namco 163: up to 8 wavetable channels. Waveforms and channel registers share 128
+// bytes of internal RAM, channel n (1-8) has its registers at $38 + 8n.
+pub struct N163 {
+    ram: [u8; 128],
+    address: u8,
+    auto_increment: bool,
+    timer: u8,
+    // the channel the next update goes to, counting down from 7
+    current: usize,
+    // the last computed (sample - 8) * volume of each channel
+    outputs: [i16; 8],
+}
+
+impl N163 {
+    pub fn new() -> Self {
+        N163 { ram: [0; 128], address: 0, auto_increment: false, timer: 0, current: 7, outputs: [0; 8] }
+    }
+
+    fn enabled_channels(&self) -> usize {
+        ((self.ram[0x7F] >> 4) & 0b111) as usize + 1
+    }
+
+    fn base(channel: usize) -> usize {
+        0x40 + 8 * channel
+    }
+
+    fn frequency(&self, channel: usize) -> u32 {
+        let base = N163::base(channel);
+        self.ram[base] as u32 | (self.ram[base + 2] as u32) << 8 | ((self.ram[base + 4] & 0b11) as u32) << 16
+    }
+
+    fn phase(&self, channel: usize) -> u32 {
+        let base = N163::base(channel);
+        self.ram[base + 1] as u32 | (self.ram[base + 3] as u32) << 8 | (self.ram[base + 5] as u32) << 16
+    }
+
+    fn set_phase(&mut self, channel: usize, phase: u32) {
+        let base = N163::base(channel);
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+    }
+
+    // in 4-bit samples
+    fn length(&self, channel: usize) -> u32 {
+        256 - (self.ram[N163::base(channel) + 4] & 0b1111_1100) as u32
+    }
+
+    fn volume(&self, channel: usize) -> u8 {
+        self.ram[N163::base(channel) + 7] & 0b1111
+    }
+
+    fn sample(&self, index: u8) -> u8 {
+        (self.ram[index as usize >> 1] >> ((index & 1) * 4)) & 0b1111
+    }
+
+    fn update_channel(&mut self, channel: usize) {
+        let length = self.length(channel) << 16;
+        let phase = (self.phase(channel) + self.frequency(channel)) % length;
+        self.set_phase(channel, phase);
+        let offset = self.ram[N163::base(channel) + 6];
+        let sample = self.sample(offset.wrapping_add((phase >> 16) as u8));
+        self.outputs[channel] = (sample as i16 - 8) * self.volume(channel) as i16;
+    }
+
+    fn channel_enabled(&self, channel: usize) -> bool {
+        channel >= 8 - self.enabled_channels()
+    }
+}
+
+impl ExpansionAudio for N163 {
+    fn name(&self) -> &'static str {
+        "N163"
+    }
+
+    fn channel_names(&self) -> Vec<String> {
+        (1..=8).map(|n| format!("n163 {}", n)).collect()
+    }
+
+    fn channel_count(&self) -> usize {
+        8
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        matches!(addr, 0x4800..=0x4FFF | 0xF800..=0xFFFF)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xF800..=0xFFFF => {
+                self.address = data & 0b0111_1111;
+                self.auto_increment = data & 0b1000_0000 != 0;
+            }
+            _ => {
+                self.ram[self.address as usize] = data;
+                if self.auto_increment {
+                    self.address = (self.address + 1) & 0b0111_1111;
+                }
+            }
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4FFF => {
+                let data = self.ram[self.address as usize];
+                if self.auto_increment {
+                    self.address = (self.address + 1) & 0b0111_1111;
+                }
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        self.timer += 1;
+        if self.timer < UPDATE_PERIOD {
+            return;
+        }
+        self.timer = 0;
+        let channel = self.current;
+        self.update_channel(channel);
+        self.current = if channel == 8 - self.enabled_channels() { 7 } else { channel - 1 };
+    }
+
+    // the hardware time-multiplexes the channels, so each is heard for 1/n of the time
+    fn output(&self, channel: usize) -> f32 {
+        if !self.channel_enabled(channel) {
+            return 0.0;
+        }
+        self.outputs[channel] as f32 * N163_SCALE / self.enabled_channels() as f32
+    }
+
+    fn level(&self, channel: usize) -> u8 {
+        if self.volume(channel) == 0 {
+            return 8;
+        }
+        (self.outputs[channel] / self.volume(channel) as i16 + 8) as u8
+    }
+
+    fn max_level(&self, _channel: usize) -> u8 {
+        15
+    }
+
+    fn info(&self, channel: usize, cpu_clock: f64) -> ExpansionChannelInfo {
+        let frequency = self.frequency(channel);
+        let steps = UPDATE_PERIOD as f64 * self.enabled_channels() as f64 * 65536.0 * self.length(channel) as f64;
+        ExpansionChannelInfo {
+            period: (frequency >> 2) as u16,
+            frequency: cpu_clock * frequency as f64 / steps,
+            volume: self.volume(channel),
+            max_volume: 15,
+            active: self.channel_enabled(channel) && frequency > 0 && self.volume(channel) > 0,
+        }
+    }
+}
+
+impl Savestate for N163 {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bytes(&mut self.ram);
+        s.u8(&mut self.address);
+        s.bool(&mut self.auto_increment);
+        s.u8(&mut self.timer);
+        s.usize(&mut self.current);
+        for output in self.outputs.iter_mut() {
+            s.i16(output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::expansion::test::assert_state_round_trips;
+
+    fn poke(n163: &mut N163, addr: u8, data: &[u8]) {
+        n163.write(0xF800, 0b1000_0000 | addr);
+        for byte in data {
+            n163.write(0x4800, *byte);
+        }
+    }
+
+    #[test]
+    fn test_ram_port_auto_increments() {
+        let mut n163 = N163::new();
+        poke(&mut n163, 0x10, &[1, 2, 3]);
+        n163.write(0xF800, 0b1000_0000 | 0x10);
+        assert_eq!(n163.read(0x4800), Some(1));
+        assert_eq!(n163.read(0x4800), Some(2));
+        n163.write(0xF800, 0x12);
+        assert_eq!(n163.read(0x4800), Some(3));
+        assert_eq!(n163.read(0x4800), Some(3));
+    }
+
+    #[test]
+    fn test_wavetable_playback() {
+        let mut n163 = N163::new();
+        // a 4-sample waveform 0, 15, 0, 15 at $00
+        poke(&mut n163, 0x00, &[0xF0, 0xF0]);
+        // channel 8 only: frequency $10000 steps one sample per update, length 4, volume 15
+        poke(&mut n163, 0x78, &[0x00, 0x00, 0x00, 0x00, 0xFD, 0x00, 0x00, 0x0F]);
+        let mut levels = vec![];
+        for _ in 0..15 * 8 {
+            n163.clock();
+            levels.push(n163.level(7));
+        }
+        assert!(levels.contains(&15) && levels.contains(&0));
+        assert!(n163.output(7).abs() > 0.0);
+        assert_eq!(n163.output(0), 0.0);
+    }
+
+    #[test]
+    fn test_frequency_depends_on_channel_count() {
+        let mut n163 = N163::new();
+        poke(&mut n163, 0x78, &[0x00, 0x00, 0x10, 0x00, 0xE0, 0x00, 0x00, 0x0F]);
+        let one = n163.info(7, 1789772.7272).frequency;
+        poke(&mut n163, 0x7F, &[0x3F]); // 4 channels
+        let four = n163.info(7, 1789772.7272).frequency;
+        assert!((one / four - 4.0).abs() < 1e-9);
+        assert!(n163.info(4, 1789772.7272).active == false);
+    }
+
+    #[test]
+    fn test_state_round_trips() {
+        let mut n163 = N163::new();
+        poke(&mut n163, 0x00, &[0xF0, 0xF0]);
+        poke(&mut n163, 0x78, &[0x00, 0x00, 0x00, 0x00, 0xFD, 0x00, 0x00, 0x0F]);
+        assert_state_round_trips(&mut n163);
+    }
+}
@@ -0,0 +1,777 @@
+use crate::bus::Bus;
+use crate::cartridge::*;
+use crate::cpu::*;
+use crate::expansion::*;
+use crate::filters::Image;
+use crate::savestate::*;
+use crate::visualizer::*;
+
+const NSF_HEADER_SIZE: usize = 0x80;
+const BANK_SIZE: usize = 0x1000;
+// the driver returns INIT/PLAY calls to a BRK here, which hands control back to us
+pub const RETURN_ADDRESS: u16 = 0x5FF0;
+const DEFAULT_PLAY_SPEED_NTSC: u16 = 16639;
+const DEFAULT_PLAY_SPEED_PAL: u16 = 19997;
+
+// an NSF or NSFE file, NSFE chunks end up in the same fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nsf {
+    pub total_songs: usize,
+    // 0-based
+    pub starting_song: usize,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub name: String,
+    pub artist: String,
+    pub copyright: String,
+    // microseconds between PLAY calls
+    pub play_speed_ntsc: u16,
+    pub play_speed_pal: u16,
+    pub bankswitch: [u8; 8],
+    pub tv_system: TvSystem,
+    // expansion audio chips the tune expects, bits as in the NSF header
+    pub expansion: u8,
+    pub track_labels: Vec<Option<String>>,
+    // milliseconds, from NSFE "time" chunks
+    pub track_times: Vec<Option<u32>>,
+    pub data: Vec<u8>,
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+// fixed-size, zero padded header string
+fn header_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+// zero terminated strings packed back to back
+fn string_list(bytes: &[u8]) -> Vec<String> {
+    let mut list: Vec<String> = bytes.split(|b| *b == 0).map(|s| String::from_utf8_lossy(s).to_string()).collect();
+    if bytes.last() == Some(&0) {
+        list.pop();
+    }
+    list
+}
+
+fn tv_system_from_flags(flags: u8) -> TvSystem {
+    // bit 1 is dual PAL/NTSC, which we play as NTSC
+    if flags & 0b11 == 0b01 { TvSystem::PAL } else { TvSystem::NTSC }
+}
+
+impl Nsf {
+    pub fn load(path: &str) -> Result<Nsf, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        Nsf::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Nsf, String> {
+        if bytes.starts_with(b"NESM\x1A") {
+            Nsf::parse_nsf(bytes)
+        } else if bytes.starts_with(b"NSFE") {
+            Nsf::parse_nsfe(bytes)
+        } else {
+            Err("not an NSF or NSFE file".to_string())
+        }
+    }
+
+    fn parse_nsf(bytes: &[u8]) -> Result<Nsf, String> {
+        if bytes.len() <= NSF_HEADER_SIZE {
+            return Err("NSF file is truncated".to_string());
+        }
+        let total_songs = bytes[0x06] as usize;
+        let mut bankswitch = [0; 8];
+        bankswitch.copy_from_slice(&bytes[0x70..0x78]);
+        Ok(Nsf {
+            total_songs: total_songs,
+            starting_song: (bytes[0x07] as usize).max(1) - 1,
+            load_address: le_u16(bytes, 0x08),
+            init_address: le_u16(bytes, 0x0A),
+            play_address: le_u16(bytes, 0x0C),
+            name: header_string(&bytes[0x0E..0x2E]),
+            artist: header_string(&bytes[0x2E..0x4E]),
+            copyright: header_string(&bytes[0x4E..0x6E]),
+            play_speed_ntsc: le_u16(bytes, 0x6E),
+            play_speed_pal: le_u16(bytes, 0x78),
+            bankswitch: bankswitch,
+            tv_system: tv_system_from_flags(bytes[0x7A]),
+            expansion: bytes[0x7B],
+            track_labels: vec![None; total_songs],
+            track_times: vec![None; total_songs],
+            data: bytes[NSF_HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    // "NSFE" then chunks of [length u32][id 4 bytes][data], up to NEND
+    fn parse_nsfe(bytes: &[u8]) -> Result<Nsf, String> {
+        let mut nsf = Nsf {
+            total_songs: 0,
+            starting_song: 0,
+            load_address: 0,
+            init_address: 0,
+            play_address: 0,
+            name: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            play_speed_ntsc: DEFAULT_PLAY_SPEED_NTSC,
+            play_speed_pal: DEFAULT_PLAY_SPEED_PAL,
+            bankswitch: [0; 8],
+            tv_system: TvSystem::NTSC,
+            expansion: 0,
+            track_labels: vec![],
+            track_times: vec![],
+            data: vec![],
+        };
+        let mut has_info = false;
+        let mut pos = 4;
+        while pos + 8 <= bytes.len() {
+            let length = u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize;
+            let id = &bytes[pos + 4..pos + 8];
+            let start = pos + 8;
+            let chunk = bytes.get(start..start + length).ok_or(format!("NSFE chunk {} is truncated", String::from_utf8_lossy(id)))?;
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 9 {
+                        return Err("NSFE INFO chunk is too short".to_string());
+                    }
+                    nsf.load_address = le_u16(chunk, 0);
+                    nsf.init_address = le_u16(chunk, 2);
+                    nsf.play_address = le_u16(chunk, 4);
+                    nsf.tv_system = tv_system_from_flags(chunk[6]);
+                    nsf.expansion = chunk[7];
+                    nsf.total_songs = chunk[8] as usize;
+                    nsf.starting_song = chunk.get(9).cloned().unwrap_or(0) as usize;
+                    has_info = true;
+                }
+                b"DATA" => nsf.data = chunk.to_vec(),
+                b"BANK" => {
+                    for (i, bank) in chunk.iter().take(8).enumerate() {
+                        nsf.bankswitch[i] = *bank;
+                    }
+                }
+                b"RATE" => {
+                    if chunk.len() >= 2 {
+                        nsf.play_speed_ntsc = le_u16(chunk, 0);
+                    }
+                    if chunk.len() >= 4 {
+                        nsf.play_speed_pal = le_u16(chunk, 2);
+                    }
+                }
+                b"auth" => {
+                    let mut strings = string_list(chunk).into_iter();
+                    nsf.name = strings.next().unwrap_or_default();
+                    nsf.artist = strings.next().unwrap_or_default();
+                    nsf.copyright = strings.next().unwrap_or_default();
+                }
+                b"tlbl" => nsf.track_labels = string_list(chunk).into_iter().map(Some).collect(),
+                b"time" => {
+                    nsf.track_times = chunk
+                        .chunks_exact(4)
+                        .map(|t| i32::from_le_bytes([t[0], t[1], t[2], t[3]]))
+                        .map(|ms| if ms < 0 { None } else { Some(ms as u32) })
+                        .collect();
+                }
+                b"NEND" => break,
+                // chunks starting with a capital letter must be understood
+                _ if id[0].is_ascii_uppercase() => {
+                    return Err(format!("unsupported NSFE chunk {}", String::from_utf8_lossy(id)));
+                }
+                _ => {}
+            }
+            pos = start + length;
+        }
+        if !has_info || nsf.data.is_empty() {
+            return Err("NSFE file has no INFO or DATA chunk".to_string());
+        }
+        nsf.track_labels.resize(nsf.total_songs, None);
+        nsf.track_times.resize(nsf.total_songs, None);
+        Ok(nsf)
+    }
+
+    pub fn bankswitched(&self) -> bool {
+        self.bankswitch.iter().any(|b| *b != 0)
+    }
+
+    pub fn play_speed(&self) -> u16 {
+        let speed = match self.tv_system {
+            TvSystem::NTSC => self.play_speed_ntsc,
+            TvSystem::PAL => self.play_speed_pal,
+        };
+        if speed == 0 {
+            return match self.tv_system {
+                TvSystem::NTSC => DEFAULT_PLAY_SPEED_NTSC,
+                TvSystem::PAL => DEFAULT_PLAY_SPEED_PAL,
+            };
+        }
+        speed
+    }
+
+    pub fn track_title(&self, track: usize) -> String {
+        match self.track_labels.get(track).cloned().flatten() {
+            Some(label) => format!("{} {}", track + 1, label),
+            None => format!("{} track {}", track + 1, track + 1),
+        }
+    }
+}
+
+// what the CPU sees at $4020-$FFFF while an NSF is loaded
+pub struct NsfMemory {
+    data: Vec<u8>,
+    banks: [u8; 8],
+    initial_banks: [u8; 8],
+    ram: [u8; 0x2000],
+    // FDS tunes may also write to $8000-$DFFF, which is RAM on the disk system;
+    // the loaded data is kept to restore it between tracks
+    fds_data: Option<Vec<u8>>,
+    // bankswitched FDS tunes also page their data into $6000-$7FFF, through $5FF6/$5FF7
+    fds_banks: Option<[u8; 2]>,
+}
+
+impl NsfMemory {
+    pub fn new(nsf: &Nsf) -> Self {
+        // bankswitched data is laid out from the start of the 4K bank the load address is in
+        let (data, banks) = if nsf.bankswitched() {
+            let mut data = vec![0; (nsf.load_address as usize & 0xFFF) + nsf.data.len()];
+            let padding = nsf.load_address as usize & 0xFFF;
+            data[padding..].copy_from_slice(&nsf.data);
+            (data, nsf.bankswitch)
+        } else {
+            let mut data = vec![0; 0x8000];
+            let start = (nsf.load_address as usize).max(0x8000) - 0x8000;
+            let len = nsf.data.len().min(0x8000 - start);
+            data[start..start + len].copy_from_slice(&nsf.data[..len]);
+            (data, [0, 1, 2, 3, 4, 5, 6, 7])
+        };
+        let fds = nsf.expansion & NSF_FDS != 0;
+        let fds_data = if fds { Some(data.clone()) } else { None };
+        // starting out as the two at $E000-$FFFF do
+        let fds_banks = if fds && nsf.bankswitched() { Some([banks[6], banks[7]]) } else { None };
+        NsfMemory { data: data, banks: banks, initial_banks: banks, ram: [0; 0x2000], fds_data: fds_data, fds_banks: fds_banks }
+    }
+
+    pub fn reset(&mut self) {
+        self.banks = self.initial_banks;
+        if self.fds_banks.is_some() {
+            self.fds_banks = Some([self.initial_banks[6], self.initial_banks[7]]);
+        }
+        self.ram = [0; 0x2000];
+        if let Some(data) = &self.fds_data {
+            self.data = data.clone();
+        }
+    }
+
+    fn offset(&self, addr: u16) -> usize {
+        let slot = (addr as usize - 0x8000) / BANK_SIZE;
+        self.banks[slot] as usize * BANK_SIZE + (addr as usize & 0xFFF)
+    }
+
+    fn fds_offset(&self, addr: u16) -> Option<usize> {
+        let slot = (addr as usize - 0x6000) / BANK_SIZE;
+        self.fds_banks.map(|banks| banks[slot] as usize * BANK_SIZE + (addr as usize & 0xFFF))
+    }
+
+    // the disk system's RAM, written through to the loaded data
+    fn write_fds_data(&mut self, offset: usize, data: u8) {
+        if offset >= self.data.len() {
+            self.data.resize(offset + 1, 0);
+        }
+        self.data[offset] = data;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            RETURN_ADDRESS => 0x00, // BRK
+            0x6000..=0x7FFF => match self.fds_offset(addr) {
+                Some(offset) => self.data.get(offset).cloned().unwrap_or(0),
+                None => self.ram[addr as usize - 0x6000],
+            },
+            0x8000..=0xFFFF => self.data.get(self.offset(addr)).cloned().unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5FF6..=0x5FF7 => {
+                if let Some(banks) = self.fds_banks.as_mut() {
+                    banks[addr as usize - 0x5FF6] = data;
+                }
+            }
+            0x5FF8..=0x5FFF => self.banks[addr as usize - 0x5FF8] = data,
+            0x6000..=0x7FFF => match self.fds_offset(addr) {
+                Some(offset) => self.write_fds_data(offset, data),
+                None => self.ram[addr as usize - 0x6000] = data,
+            },
+            0x8000..=0xDFFF if self.fds_data.is_some() => self.write_fds_data(self.offset(addr), data),
+            _ => {}
+        }
+    }
+
+    pub fn banks(&self) -> [u8; 8] {
+        self.banks
+    }
+}
+
+// the tune's data is ROM, except on the disk system
+impl Savestate for NsfMemory {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bytes(&mut self.banks);
+        s.bytes(&mut self.ram);
+        if let Some(banks) = self.fds_banks.as_mut() {
+            s.bytes(banks);
+        }
+        if self.fds_data.is_some() {
+            let mut len = self.data.len();
+            s.usize(&mut len);
+            self.data.resize(len, 0);
+            s.bytes(&mut self.data);
+        }
+    }
+}
+
+// drives INIT and PLAY on the real CPU and APU, the PPU is never turned on
+pub struct NsfPlayer<'a> {
+    pub cpu: CPU<'a>,
+    pub nsf: Nsf,
+    pub track: usize,
+    pub frames: usize,
+    cycles_per_play: f64,
+    // fractional cycles carried between PLAY periods
+    cycle_error: f64,
+    // an INIT or PLAY still running when its period ran out, carried on with next period
+    // in place of a new PLAY
+    busy: bool,
+}
+
+impl<'a> NsfPlayer<'a> {
+    pub fn new(nsf: Nsf) -> NsfPlayer<'a> {
+        let rom = Rom {
+            prg_rom: vec![],
+            chr_rom: vec![0; 0x2000],
+            mapper: 0,
+            screen_mirroring: Mirroring::HORIZONTAL,
+            tv_system: nsf.tv_system,
+            input_device: 0,
+        };
+        let mut bus = Bus::new(rom, |_, _, _| {});
+        bus.load_nsf(NsfMemory::new(&nsf));
+        bus.apu.set_expansion(expansion_for_nsf(nsf.expansion));
+        let clock = crate::audio::cpu_clock(nsf.tv_system);
+        NsfPlayer {
+            cpu: CPU::new(bus),
+            cycles_per_play: clock * nsf.play_speed() as f64 / 1_000_000.0,
+            track: nsf.starting_song,
+            nsf: nsf,
+            frames: 0,
+            cycle_error: 0.0,
+            busy: false,
+        }
+    }
+
+    // starts the routine and runs it for up to the given cycles
+    fn call(&mut self, addr: u16, a: u8, x: u8, cycles: usize) {
+        self.cpu.register_a = a;
+        self.cpu.register_x = x;
+        self.cpu.register_y = 0;
+        self.cpu.status = 0b0010_0100;
+        self.cpu.stack_pointer = 0xFD;
+        self.cpu.stack_push_u16(RETURN_ADDRESS - 1);
+        self.cpu.program_counter = addr;
+        self.resume(cycles);
+    }
+
+    // runs the current routine until it returns to RETURN_ADDRESS, or until the cycles
+    // are used up, which leaves it busy
+    fn resume(&mut self, cycles: usize) {
+        let end = self.cpu.bus.cycles() + cycles;
+        let mut out_of_time = false;
+        self.cpu.execute(|cpu| {
+            if cpu.bus.cycles() >= end {
+                out_of_time = true;
+                cpu.stop();
+            }
+        });
+        self.busy = out_of_time;
+        if !self.busy {
+            self.cpu.stack_pointer = 0xFD;
+        }
+    }
+
+    pub fn start_track(&mut self, track: usize) {
+        self.track = track.min(self.nsf.total_songs.max(1) - 1);
+        self.frames = 0;
+        let bus = &mut self.cpu.bus;
+        for addr in 0..0x0800 {
+            bus.memory_write(addr, 0);
+        }
+        bus.reset_nsf();
+        for addr in 0x4000..=0x4013 {
+            bus.memory_write(addr, 0);
+        }
+        bus.memory_write(0x4015, 0);
+        bus.memory_write(0x4015, 0x0F);
+        bus.memory_write(0x4017, 0x40);
+        // fresh expansion chips, FDS sound enabled with its default envelope speed
+        bus.apu.set_expansion(expansion_for_nsf(self.nsf.expansion));
+        if self.nsf.expansion & NSF_FDS != 0 {
+            bus.memory_write(0x4023, 0b10);
+            bus.memory_write(0x4089, 0x80);
+            bus.memory_write(0x408A, 0xE8);
+        }
+        let region = if self.nsf.tv_system == TvSystem::PAL { 1 } else { 0 };
+        self.call(self.nsf.init_address, self.track as u8, region, self.cycles_per_play as usize);
+    }
+
+    // one PLAY call and the idle time up to the next one, then the audio goes out. A call
+    // that runs past the period goes on in the next one instead
+    pub fn play_frame(&mut self) {
+        let start = self.cpu.bus.cycles();
+        let target = self.cycles_per_play + self.cycle_error;
+        if self.busy {
+            self.resume(target as usize);
+        } else {
+            self.call(self.nsf.play_address, 0, 0, target as usize);
+        }
+        let used = (self.cpu.bus.cycles() - start) as f64;
+        let idle = (target - used).max(0.0) as usize;
+        self.cycle_error = target - used - idle as f64;
+        for _ in 0..idle / 100 {
+            self.cpu.bus.tick(100);
+        }
+        self.cpu.bus.tick((idle % 100) as u8);
+        self.cpu.bus.flush_audio();
+        self.frames += 1;
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        self.frames as u64 * self.nsf.play_speed() as u64 / 1000
+    }
+
+    // renders a track to a WAV file, headless
+    pub fn render_to_wav(&mut self, track: usize, path: &str, frames: usize, per_channel: bool) -> Result<usize, String> {
+        self.cpu.bus.apu.audio.start_recording(path, per_channel)?;
+        self.start_track(track);
+        for _ in 0..frames {
+            self.play_frame();
+        }
+        self.cpu.bus.apu.audio.stop_recording()
+    }
+}
+
+const LINE_HEIGHT: usize = 12;
+const VISIBLE_TRACKS: usize = 12;
+const HEADER_LINES: usize = 5;
+
+fn format_time(ms: u64) -> String {
+    format!("{}:{:02}", ms / 60000, ms / 1000 % 60)
+}
+
+// metadata, a scrolling track list with the cursor and the playing track marked, and the
+// channel visualizer underneath
+pub fn draw_player(player: &NsfPlayer, cursor: usize) -> Image {
+    let nsf = &player.nsf;
+    let (width, channels_height) = visualizer_size(player.cpu.bus.apu.channels().len());
+    let list_height = (HEADER_LINES + VISIBLE_TRACKS) * LINE_HEIGHT;
+    let mut image = Image::new(width, list_height + channels_height);
+
+    let text = (220, 220, 220);
+    let dim = (120, 120, 120);
+    let highlight = (255, 208, 64);
+    draw_text(&mut image, 2, 2, &nsf.name, highlight);
+    draw_text(&mut image, 2, 2 + LINE_HEIGHT, &nsf.artist, text);
+    draw_text(&mut image, 2, 2 + LINE_HEIGHT * 2, &nsf.copyright, dim);
+    let mut status = format!("{}/{} {}", player.track + 1, nsf.total_songs, format_time(player.elapsed_ms()));
+    if let Some(Some(length)) = nsf.track_times.get(player.track) {
+        status.push_str(&format!(" / {}", format_time(*length as u64)));
+    }
+    draw_text(&mut image, 2, 2 + LINE_HEIGHT * 3, &status, text);
+
+    let first = cursor.saturating_sub(VISIBLE_TRACKS / 2).min(nsf.total_songs.saturating_sub(VISIBLE_TRACKS));
+    for (line, track) in (first..nsf.total_songs).take(VISIBLE_TRACKS).enumerate() {
+        let marker = if track == cursor { ">" } else { " " };
+        let color = if track == player.track { highlight } else { text };
+        let y = 2 + LINE_HEIGHT * (HEADER_LINES + line);
+        draw_text(&mut image, 2, y, &format!("{} {}", marker, nsf.track_title(track)), color);
+    }
+
+    let channels = draw_channels(&player.cpu.bus.apu);
+    for y in 0..channels.height {
+        for x in 0..channels.width {
+            image.set(x, list_height + y, channels.get(x as isize, y as isize));
+        }
+    }
+    image
+}
+
+pub fn is_nsf_path(path: &str) -> bool {
+    let lower = path.to_lowercase();
+    lower.ends_with(".nsf") || lower.ends_with(".nsfe")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu::Channel;
+    use crate::audio::mix;
+
+    // INIT stores the track in $00 and starts pulse 1, PLAY counts calls in $01
+    fn test_nsf_bytes() -> Vec<u8> {
+        let mut bytes = vec![0; NSF_HEADER_SIZE];
+        bytes[0..5].copy_from_slice(b"NESM\x1A");
+        bytes[5] = 1;
+        bytes[6] = 3;
+        bytes[7] = 2;
+        bytes[0x08..0x0A].copy_from_slice(&0x8000u16.to_le_bytes());
+        bytes[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+        bytes[0x0C..0x0E].copy_from_slice(&0x8020u16.to_le_bytes());
+        bytes[0x0E..0x13].copy_from_slice(b"Tune!");
+        bytes[0x2E..0x31].copy_from_slice(b"Me!");
+        bytes[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        let mut code = vec![0xEA; 0x30];
+        let init = [
+            0x85, 0x00, // STA $00
+            0xA9, 0xBF, 0x8D, 0x00, 0x40, // LDA #$BF, STA $4000
+            0xA9, 0xFD, 0x8D, 0x02, 0x40, // LDA #$FD, STA $4002
+            0xA9, 0x08, 0x8D, 0x03, 0x40, // LDA #$08, STA $4003
+            0x60, // RTS
+        ];
+        code[..init.len()].copy_from_slice(&init);
+        let play = [0xE6, 0x01, 0x60]; // INC $01, RTS
+        code[0x20..0x23].copy_from_slice(&play);
+        bytes.extend(code);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let nsf = Nsf::parse(&test_nsf_bytes()).unwrap();
+        assert_eq!(nsf.total_songs, 3);
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!(nsf.init_address, 0x8000);
+        assert_eq!(nsf.play_address, 0x8020);
+        assert_eq!(nsf.name, "Tune!");
+        assert_eq!(nsf.artist, "Me!");
+        assert_eq!(nsf.tv_system, TvSystem::NTSC);
+        assert!(!nsf.bankswitched());
+        assert_eq!(nsf.track_title(0), "1 track 1");
+        assert!(Nsf::parse(b"NES\x1A").is_err());
+    }
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u32).to_le_bytes().to_vec();
+        out.extend_from_slice(id);
+        out.extend_from_slice(data);
+        out
+    }
+
+    #[test]
+    fn test_parse_nsfe() {
+        let mut bytes = b"NSFE".to_vec();
+        let mut info = vec![];
+        info.extend_from_slice(&0x8000u16.to_le_bytes());
+        info.extend_from_slice(&0x8000u16.to_le_bytes());
+        info.extend_from_slice(&0x8010u16.to_le_bytes());
+        info.extend_from_slice(&[0x01, 0x00, 2, 1]);
+        bytes.extend(chunk(b"INFO", &info));
+        bytes.extend(chunk(b"DATA", &[0x60; 16]));
+        bytes.extend(chunk(b"BANK", &[0, 1]));
+        bytes.extend(chunk(b"auth", b"Song\0Artist\0(c)\0Ripper\0"));
+        bytes.extend(chunk(b"tlbl", b"Intro\0Boss\0"));
+        let mut times = 90000i32.to_le_bytes().to_vec();
+        times.extend_from_slice(&(-1i32).to_le_bytes());
+        bytes.extend(chunk(b"time", &times));
+        bytes.extend(chunk(b"psfx", &[1, 2, 3]));
+        bytes.extend(chunk(b"NEND", &[]));
+
+        let nsf = Nsf::parse(&bytes).unwrap();
+        assert_eq!(nsf.total_songs, 2);
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!(nsf.tv_system, TvSystem::PAL);
+        assert_eq!(nsf.name, "Song");
+        assert_eq!(nsf.copyright, "(c)");
+        assert_eq!(nsf.track_title(1), "2 Boss");
+        assert_eq!(nsf.track_times, vec![Some(90000), None]);
+        assert_eq!(nsf.bankswitch[1], 1);
+        assert!(nsf.bankswitched());
+
+        let mut unknown = b"NSFE".to_vec();
+        unknown.extend(chunk(b"INFO", &info));
+        unknown.extend(chunk(b"ZZZZ", &[]));
+        assert!(Nsf::parse(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        let mut nsf = Nsf::parse(&test_nsf_bytes()).unwrap();
+        nsf.load_address = 0x8100;
+        nsf.data = vec![0; 0x2000];
+        nsf.data[0x0EFF] = 0xAA; // last byte of bank 0 once padded
+        nsf.data[0x1000] = 0xBB;
+        nsf.bankswitch = [0, 1, 0, 0, 0, 0, 0, 0];
+        let mut memory = NsfMemory::new(&nsf);
+        assert_eq!(memory.read(0x8FFF), 0xAA);
+        assert_eq!(memory.read(0x9000 + 0x100), 0xBB);
+        memory.write(0x5FF8, 1);
+        assert_eq!(memory.read(0x8100), 0xBB);
+        memory.write(0x6123, 7);
+        assert_eq!(memory.read(0x6123), 7);
+        memory.reset();
+        assert_eq!(memory.banks(), [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(memory.read(0x6123), 0);
+    }
+
+    #[test]
+    fn test_init_and_play() {
+        let nsf = Nsf::parse(&test_nsf_bytes()).unwrap();
+        let mut player = NsfPlayer::new(nsf);
+        player.start_track(2);
+        assert_eq!(player.cpu.bus.memory_read(0x00), 2);
+        assert!(player.cpu.bus.apu.channel_state(Channel::Pulse1).active);
+
+        let start = player.cpu.bus.cycles();
+        for _ in 0..60 {
+            player.play_frame();
+        }
+        assert_eq!(player.cpu.bus.memory_read(0x01), 60);
+        // 60 PLAY periods of 16639us
+        let cycles = (player.cpu.bus.cycles() - start) as f64;
+        assert!((cycles - 60.0 * 29780.5).abs() < 60.0, "{} cycles", cycles);
+        assert_eq!(player.elapsed_ms(), 998);
+    }
+
+    #[test]
+    fn test_play_that_runs_over_is_resumed() {
+        let mut bytes = test_nsf_bytes();
+        // PLAY: INC $01, then spin forever
+        bytes[NSF_HEADER_SIZE + 0x20..NSF_HEADER_SIZE + 0x25].copy_from_slice(&[0xE6, 0x01, 0x4C, 0x22, 0x80]);
+        let mut player = NsfPlayer::new(Nsf::parse(&bytes).unwrap());
+        player.start_track(0);
+        let start = player.cpu.bus.cycles();
+        for _ in 0..3 {
+            player.play_frame();
+        }
+        // each frame gave up after its period, and no new PLAY started over the running one
+        let cycles = (player.cpu.bus.cycles() - start) as f64;
+        assert!((cycles - 3.0 * 29780.5).abs() < 20.0, "{} cycles", cycles);
+        assert_eq!(player.cpu.bus.memory_read(0x01), 1);
+        assert!(player.busy);
+        // a new track starts afresh
+        player.start_track(0);
+        assert!(!player.busy);
+    }
+
+    #[test]
+    fn test_render_to_wav() {
+        let path = std::env::temp_dir().join(format!("nes-nsf-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut player = NsfPlayer::new(Nsf::parse(&test_nsf_bytes()).unwrap());
+        let samples = player.render_to_wav(0, path, 60, false).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        // 60 PLAY periods of 16639us
+        assert!((samples as i32 - 44027).abs() <= 2, "{} samples", samples);
+        assert_eq!(bytes.len(), 44 + samples * 2);
+        assert!(bytes[44..].chunks(2).any(|s| i16::from_le_bytes([s[0], s[1]]).abs() > 1000));
+    }
+
+    #[test]
+    fn test_fds_ram() {
+        let mut nsf = Nsf::parse(&test_nsf_bytes()).unwrap();
+        nsf.expansion = NSF_FDS;
+        let mut memory = NsfMemory::new(&nsf);
+        memory.write(0x8018, 0x42);
+        assert_eq!(memory.read(0x8018), 0x42);
+        memory.write(0xE000, 0x42);
+        assert_eq!(memory.read(0xE000), 0x00);
+        memory.reset();
+        assert_eq!(memory.read(0x8018), 0xEA);
+
+        // the written data goes into savestates along with the RAM and banks
+        memory.write(0x8018, 0x42);
+        memory.write(0x6000, 0x24);
+        let mut s = StateBuffer::saving();
+        memory.state(&mut s);
+        let saved = s.into_data();
+        memory.reset();
+        memory.state(&mut StateBuffer::loading(saved));
+        assert_eq!((memory.read(0x8018), memory.read(0x6000)), (0x42, 0x24));
+
+        nsf.expansion = 0;
+        let mut memory = NsfMemory::new(&nsf);
+        memory.write(0x8018, 0x42);
+        assert_eq!(memory.read(0x8018), 0xEA);
+    }
+
+    #[test]
+    fn test_fds_banks_at_6000() {
+        let mut nsf = Nsf::parse(&test_nsf_bytes()).unwrap();
+        nsf.expansion = NSF_FDS;
+        nsf.data = vec![0; 0x3000];
+        nsf.data[0x1000] = 0xBB;
+        nsf.data[0x2000] = 0xCC;
+        nsf.bankswitch = [0, 1, 2, 0, 0, 0, 1, 2];
+        let mut memory = NsfMemory::new(&nsf);
+        // $6000 and $7000 start out as $E000 and $F000
+        assert_eq!((memory.read(0x6000), memory.read(0x7000)), (0xBB, 0xCC));
+        memory.write(0x5FF6, 2);
+        assert_eq!(memory.read(0x6000), 0xCC);
+        // it's all RAM on the disk system
+        memory.write(0x6001, 0x42);
+        assert_eq!(memory.read(0xA001), 0x42);
+        memory.reset();
+        assert_eq!((memory.read(0x6000), memory.read(0xA001)), (0xBB, 0x00));
+
+        // $5FF6/$5FF7 are nothing without the FDS
+        nsf.expansion = 0;
+        let mut memory = NsfMemory::new(&nsf);
+        memory.write(0x5FF6, 2);
+        memory.write(0x6000, 0x42);
+        assert_eq!(memory.read(0x6000), 0x42);
+    }
+
+    #[test]
+    fn test_expansion_audio() {
+        let mut nsf = Nsf::parse(&test_nsf_bytes()).unwrap();
+        nsf.expansion = NSF_VRC6 | NSF_5B;
+        let mut player = NsfPlayer::new(nsf);
+        assert_eq!(player.cpu.bus.apu.channels().len(), 5 + 3 + 3);
+        player.start_track(0);
+        player.cpu.bus.apu.set_muted(Channel::Pulse1, true);
+        // the VRC6 saw through the bus, and into the mix
+        player.cpu.bus.memory_write(0xB000, 0x20);
+        player.cpu.bus.memory_write(0xB002, 0b1000_0001);
+        player.play_frame();
+        let state = player.cpu.bus.apu.channel_state(Channel::Expansion(2));
+        assert_eq!(state.name, "vrc6 saw");
+        assert!(state.active);
+        let mut peak: f32 = 0.0;
+        for _ in 0..1000 {
+            player.cpu.bus.tick(1);
+            peak = peak.max(player.cpu.bus.apu.output());
+        }
+        assert!(peak > mix(0, 0, 15, 0, 0));
+        // mutes survive a track change
+        player.start_track(1);
+        assert!(player.cpu.bus.apu.is_muted(Channel::Pulse1));
+    }
+
+    #[test]
+    fn test_draw_player() {
+        let mut player = NsfPlayer::new(Nsf::parse(&test_nsf_bytes()).unwrap());
+        player.start_track(1);
+        let image = draw_player(&player, 2);
+        assert_eq!(image.width, VISUALIZER_WIDTH);
+        assert_eq!(image.height, (HEADER_LINES + VISIBLE_TRACKS) * LINE_HEIGHT + visualizer_size(5).1);
+        assert_eq!(format_time(83500), "1:23");
+    }
+
+    #[test]
+    fn test_nsf_paths() {
+        assert!(is_nsf_path("music/SMB.NSF"));
+        assert!(is_nsf_path("zelda.nsfe"));
+        assert!(!is_nsf_path("zelda.nes"));
+    }
+}
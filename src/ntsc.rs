@@ -0,0 +1,219 @@
+use std::f32::consts::PI;
+
+use crate::frame::*;
+use crate::palette::*;
+
+// the PPU puts out 8 composite samples per pixel, 12 samples make up one color subcarrier cycle
+pub const SAMPLES_PER_PIXEL: usize = 8;
+pub const PHASES: usize = 12;
+pub const OUTPUT_PIXELS_PER_PIXEL: usize = 2;
+pub const NTSC_WIDTH: usize = Frame::WIDTH * OUTPUT_PIXELS_PER_PIXEL;
+pub const NTSC_HEIGHT: usize = Frame::HIGHT;
+
+const LINE_SAMPLES: usize = Frame::WIDTH * SAMPLES_PER_PIXEL;
+const SAMPLES_PER_OUTPUT: usize = SAMPLES_PER_PIXEL / OUTPUT_PIXELS_PER_PIXEL;
+// 341 dots * 8 samples leaves the subcarrier 4 phases further along at the start of every line
+const LINE_PHASE_STEP: usize = (341 * SAMPLES_PER_PIXEL) % PHASES;
+// luma gets one subcarrier cycle of averaging, chroma two, so sharp luma edges bleed into color (fringing)
+const LUMA_WINDOW: usize = PHASES;
+const CHROMA_WINDOW: usize = PHASES * 2;
+
+// decodes the composite signal the PPU would have sent to a TV, which reproduces
+// artifact colors, dot crawl and color fringing around sharp edges
+pub struct NtscFilter {
+    pub data: Vec<u8>,
+    levels: Vec<[f32; PHASES]>,
+    cos_table: [f32; PHASES],
+    sin_table: [f32; PHASES],
+    line: Vec<f32>,
+}
+
+impl NtscFilter {
+    pub fn new() -> Self {
+        let levels = (0..512u16)
+            .map(|index| {
+                let mut phases = [0.0; PHASES];
+                for phase in 0..PHASES {
+                    phases[phase] = ntsc_signal((index & 0x3F) as u8, (index >> 6) as u8, phase);
+                }
+                phases
+            })
+            .collect();
+
+        let mut cos_table = [0.0; PHASES];
+        let mut sin_table = [0.0; PHASES];
+        for phase in 0..PHASES {
+            let angle = PI * (phase as f32 + COLORBURST_PHASE) / 6.0;
+            cos_table[phase] = angle.cos();
+            sin_table[phase] = angle.sin();
+        }
+
+        NtscFilter {
+            data: vec![0; NTSC_WIDTH * NTSC_HEIGHT * 3],
+            levels: levels,
+            cos_table: cos_table,
+            sin_table: sin_table,
+            line: vec![0.0; LINE_SAMPLES],
+        }
+    }
+
+    // the frame parity and rendering state come from the PPU, so the crawl stays right
+    // across frames that never reach the filter
+    pub fn apply(&mut self, frame: &Frame, odd_frame: bool, rendering_enabled: bool) -> &[u8] {
+        let frame_phase = frame_phase(odd_frame, rendering_enabled);
+        for y in 0..Frame::HIGHT {
+            let line_phase = (frame_phase + y * LINE_PHASE_STEP) % PHASES;
+            self.encode_line(frame, y, line_phase);
+            self.decode_line(y, line_phase);
+        }
+        &self.data
+    }
+
+    fn encode_line(&mut self, frame: &Frame, y: usize, line_phase: usize) {
+        for x in 0..Frame::WIDTH {
+            let levels = &self.levels[(frame.indices[y * Frame::WIDTH + x] & 0x1FF) as usize];
+            for s in 0..SAMPLES_PER_PIXEL {
+                let sample = x * SAMPLES_PER_PIXEL + s;
+                self.line[sample] = levels[(line_phase + sample) % PHASES];
+            }
+        }
+    }
+
+    fn decode_line(&mut self, y: usize, line_phase: usize) {
+        for x in 0..NTSC_WIDTH {
+            let center = (x * SAMPLES_PER_OUTPUT + SAMPLES_PER_OUTPUT / 2) as isize;
+
+            let mut luma = 0.0;
+            for sample in window(center, LUMA_WINDOW) {
+                luma += self.sample(sample);
+            }
+
+            let (mut i, mut q) = (0.0, 0.0);
+            for sample in window(center, CHROMA_WINDOW) {
+                let signal = self.sample(sample);
+                let phase = (line_phase as isize + sample).rem_euclid(PHASES as isize) as usize;
+                i += signal * self.cos_table[phase];
+                q += signal * self.sin_table[phase];
+            }
+
+            let rgb = yiq_to_rgb(luma / LUMA_WINDOW as f32, i / CHROMA_WINDOW as f32, q / CHROMA_WINDOW as f32);
+            let base = (y * NTSC_WIDTH + x) * 3;
+            self.data[base] = rgb.0;
+            self.data[base + 1] = rgb.1;
+            self.data[base + 2] = rgb.2;
+        }
+    }
+
+    // samples past either edge of the line repeat the edge pixel
+    fn sample(&self, sample: isize) -> f32 {
+        self.line[sample.max(0).min(LINE_SAMPLES as isize - 1) as usize]
+    }
+}
+
+// a full frame is 262 * 341 dots and moves the subcarrier 4 phases on, an odd frame with
+// rendering on drops a dot and moves it 8, so even frames start on phase 0 and odd ones on 4.
+// Without the dropped dot the even frames start 4 further along instead
+fn frame_phase(odd_frame: bool, rendering_enabled: bool) -> usize {
+    let phase = if odd_frame { 4 } else { 0 };
+    if rendering_enabled { phase } else { (phase + 8) % PHASES }
+}
+
+fn window(center: isize, width: usize) -> std::ops::Range<isize> {
+    let half = (width / 2) as isize;
+    (center - half)..(center + half)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filled_frame(index: u16) -> Frame {
+        let mut frame = Frame::new();
+        for i in frame.indices.iter_mut() {
+            *i = index;
+        }
+        frame
+    }
+
+    fn pixel(data: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * NTSC_WIDTH + x) * 3;
+        (data[base], data[base + 1], data[base + 2])
+    }
+
+    fn close(a: (u8, u8, u8), b: (u8, u8, u8)) -> bool {
+        let diff = |a: u8, b: u8| (a as i16 - b as i16).abs() <= 3;
+        diff(a.0, b.0) && diff(a.1, b.1) && diff(a.2, b.2)
+    }
+
+    #[test]
+    fn test_output_is_wider() {
+        let mut filter = NtscFilter::new();
+        let data = filter.apply(&Frame::new(), false, true);
+        assert_eq!(data.len(), NTSC_WIDTH * NTSC_HEIGHT * 3);
+        assert!(NTSC_WIDTH > Frame::WIDTH);
+    }
+
+    #[test]
+    fn test_flat_colors_match_2c02_palette() {
+        let palette = Palette::builtin(BuiltinPalette::Ntsc2C02);
+        for index in [0x0Fu16, 0x16, 0x1A, 0x12, 0x20, 0x2D, 0x16 | (0b001 << 6)] {
+            let mut filter = NtscFilter::new();
+            let data = filter.apply(&filled_frame(index), false, true);
+            let expected = palette.color((index & 0x3F) as u8, (index >> 6) as u8);
+            for &(x, y) in [(100, 10), (300, 120), (256, 239)].iter() {
+                assert!(close(pixel(data, x, y), expected), "{:x} at {},{}: {:?} vs {:?}", index, x, y, pixel(data, x, y), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fine_grey_pattern_produces_artifact_color() {
+        let mut frame = filled_frame(0x0F);
+        for y in 0..Frame::HIGHT {
+            for x in (0..Frame::WIDTH).step_by(2) {
+                frame.indices[y * Frame::WIDTH + x] = 0x30;
+            }
+        }
+        let mut filter = NtscFilter::new();
+        let data = filter.apply(&frame, false, true);
+        let (r, g, b) = pixel(data, 200, 50);
+        let spread = r.max(g).max(b) - r.min(g).min(b);
+        assert!(spread > 20, "expected a tinted pixel, got {:?}", (r, g, b));
+    }
+
+    #[test]
+    fn test_edges_fringe() {
+        let mut frame = filled_frame(0x0F);
+        for y in 0..Frame::HIGHT {
+            for x in 128..Frame::WIDTH {
+                frame.indices[y * Frame::WIDTH + x] = 0x30;
+            }
+        }
+        let mut filter = NtscFilter::new();
+        let data = filter.apply(&frame, false, true);
+        // right on the black to white edge the luma is half way and the chroma leaks in
+        let (r, g, b) = pixel(data, 128 * OUTPUT_PIXELS_PER_PIXEL, 40);
+        assert!(r > 20 && r < 235 && g > 20 && g < 235 && b > 20 && b < 235);
+        assert!(close(pixel(data, 100, 40), (0, 0, 0)));
+        assert!(close(pixel(data, 400, 40), (255, 255, 255)));
+    }
+
+    #[test]
+    fn test_dot_crawl_changes_between_frames() {
+        let mut frame = filled_frame(0x0F);
+        for y in 0..Frame::HIGHT {
+            for x in (0..Frame::WIDTH).step_by(2) {
+                frame.indices[y * Frame::WIDTH + x] = 0x30;
+            }
+        }
+        let mut filter = NtscFilter::new();
+        let even = filter.apply(&frame, false, true).to_vec();
+        let odd = filter.apply(&frame, true, true).to_vec();
+        assert_ne!(even, odd);
+        // the phase follows the parity it's given, frames that were never filtered don't shift it
+        assert_eq!(filter.apply(&frame, false, true), &even[..]);
+        assert_eq!(filter.apply(&frame, false, true), &even[..]);
+        // no dropped dot, so the even frame lands somewhere else
+        assert_ne!(filter.apply(&frame, false, false), &even[..]);
+    }
+}
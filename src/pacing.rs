@@ -0,0 +1,237 @@
+use std::time::{Duration, Instant};
+
+use crate::audio::*;
+use crate::cartridge::{frame_rate, TvSystem};
+
+// how far the resampling rate may be bent to keep the audio queue level, half a
+// percent of pitch is not audible
+const MAX_RATE_DELTA: f64 = 0.005;
+// frames of audio we try to keep queued on the device
+const TARGET_LATENCY_FRAMES: f64 = 3.0;
+// past this many frames behind, catch up by dropping the schedule instead of racing
+const MAX_LAG_FRAMES: u32 = 4;
+
+// how fast frames go by next to the console
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    Normal,
+    // this many frames in the time of one, or as many as the host manages
+    Fast(Option<u32>),
+    // each frame takes this many times as long
+    Slow(u32),
+}
+
+impl Speed {
+    pub fn factor(&self) -> f64 {
+        match self {
+            Speed::Normal => 1.0,
+            Speed::Fast(Some(speed)) => *speed as f64,
+            Speed::Fast(None) => f64::INFINITY,
+            Speed::Slow(factor) => 1.0 / *factor as f64,
+        }
+    }
+}
+
+// keeps emulation at the console's frame rate instead of the host's refresh rate. With an
+// audio device the queue fill is the clock: we block while it holds more than the target
+// and bend the resampling rate to hold it there, so neither side drifts. Without one a
+// plain timer does the pacing. Running faster or slower bends the audio's pitch by as
+// much, so it keeps the time still; uncapped there's nothing to wait for and no audio.
+pub struct FramePacer {
+    frame_time: Duration,
+    next_frame: Option<Instant>,
+    target_queue: usize,
+    pub speed: Speed,
+}
+
+impl FramePacer {
+    pub fn new(tv_system: TvSystem, sample_rate: u32) -> Self {
+        let rate = frame_rate(tv_system);
+        FramePacer {
+            frame_time: Duration::from_secs_f64(1.0 / rate),
+            next_frame: None,
+            target_queue: (sample_rate as f64 / rate * TARGET_LATENCY_FRAMES) as usize,
+            speed: Speed::Normal,
+        }
+    }
+
+    pub fn frame_time(&self) -> Duration {
+        self.frame_time
+    }
+
+    // how long a frame lasts at the current speed
+    fn scaled_frame_time(&self) -> Duration {
+        self.frame_time.div_f64(self.speed.factor())
+    }
+
+    pub fn mutes_audio(&self) -> bool {
+        self.speed == Speed::Fast(None)
+    }
+
+    pub fn target_queue(&self) -> usize {
+        self.target_queue
+    }
+
+    // resampling ratio for the next frame, above 1 when the queue runs low
+    pub fn rate_adjust(&self, queued: usize) -> f64 {
+        let target = self.target_queue as f64;
+        let error = ((queued as f64 - target) / target).max(-1.0).min(1.0);
+        1.0 - MAX_RATE_DELTA * error
+    }
+
+    pub fn wait_for_timer(&mut self) {
+        let frame_time = self.scaled_frame_time();
+        let now = Instant::now();
+        let next = self.next_frame.unwrap_or(now) + frame_time;
+        if next > now {
+            std::thread::sleep(next - now);
+            self.next_frame = Some(next);
+        } else if now - next > frame_time * MAX_LAG_FRAMES {
+            self.next_frame = Some(now);
+        } else {
+            self.next_frame = Some(next);
+        }
+    }
+
+    pub fn wait_for_audio(&mut self, sink: &dyn AudioSink) {
+        let start = Instant::now();
+        while let Some(queued) = sink.queued_samples() {
+            // a stalled device must not freeze the emulator
+            if queued <= self.target_queue || start.elapsed() > self.scaled_frame_time() * 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        self.next_frame = Some(Instant::now());
+    }
+
+    // called once per frame after the frame's samples were queued
+    pub fn end_frame(&mut self, sink: Option<&dyn AudioSink>, audio: &mut AudioPipeline) {
+        if self.mutes_audio() {
+            self.next_frame = None;
+            return;
+        }
+        match sink.and_then(|sink| sink.queued_samples().map(|queued| (sink, queued))) {
+            Some((sink, queued)) => {
+                audio.set_rate_adjust(self.rate_adjust(queued) / self.speed.factor());
+                self.wait_for_audio(sink);
+            }
+            None => self.wait_for_timer(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    // drains a fixed number of samples every time it is polled
+    struct DrainingSink {
+        queued: Cell<usize>,
+        per_poll: usize,
+    }
+
+    impl AudioSink for DrainingSink {
+        fn sample_rate(&self) -> u32 {
+            44100
+        }
+
+        fn push_samples(&mut self, samples: &[f32]) {
+            self.queued.set(self.queued.get() + samples.len());
+        }
+
+        fn queued_samples(&self) -> Option<usize> {
+            let queued = self.queued.get();
+            self.queued.set(queued.saturating_sub(self.per_poll));
+            Some(queued)
+        }
+    }
+
+    #[test]
+    fn test_frame_time() {
+        let pacer = FramePacer::new(TvSystem::NTSC, 44100);
+        assert_eq!(pacer.frame_time().as_micros(), 16639);
+        assert_eq!(pacer.target_queue(), 2201);
+    }
+
+    #[test]
+    fn test_rate_adjust_follows_queue_fill() {
+        let pacer = FramePacer::new(TvSystem::NTSC, 44100);
+        assert_eq!(pacer.rate_adjust(pacer.target_queue()), 1.0);
+        assert!(pacer.rate_adjust(0) > 1.0);
+        assert!(pacer.rate_adjust(pacer.target_queue() * 3 / 2) < 1.0);
+        // clamped so the pitch never moves more than the limit
+        assert_eq!(pacer.rate_adjust(0), 1.0 + MAX_RATE_DELTA);
+        assert_eq!(pacer.rate_adjust(100000), 1.0 - MAX_RATE_DELTA);
+    }
+
+    #[test]
+    fn test_timer_paces_frames() {
+        let mut pacer = FramePacer::new(TvSystem::PAL, 44100);
+        let start = Instant::now();
+        for _ in 0..4 {
+            pacer.wait_for_timer();
+        }
+        // 4 frames at 50 Hz, give or take scheduler slop
+        assert!(start.elapsed() >= Duration::from_millis(78));
+    }
+
+    #[test]
+    fn test_audio_wait_blocks_until_queue_drains() {
+        let mut pacer = FramePacer::new(TvSystem::NTSC, 44100);
+        let sink = DrainingSink { queued: Cell::new(pacer.target_queue() + 500), per_poll: 100 };
+        pacer.wait_for_audio(&sink);
+        assert!(sink.queued.get() <= pacer.target_queue());
+    }
+
+    #[test]
+    fn test_end_frame_bends_rate() {
+        let mut pacer = FramePacer::new(TvSystem::NTSC, 44100);
+        let mut audio = AudioPipeline::new(NTSC_CPU_CLOCK, 44100);
+        let mut sink = BufferSink::new(44100);
+        let starved = DrainingSink { queued: Cell::new(0), per_poll: 0 };
+        pacer.end_frame(Some(&starved), &mut audio);
+        for _ in 0..29780 {
+            audio.add_level(0.0);
+        }
+        audio.end_frame();
+        audio.drain(&mut sink);
+        // a starved queue gets half a percent more samples than the nominal 733
+        assert_eq!(sink.samples.len(), 737);
+    }
+
+    #[test]
+    fn test_speed_bends_pitch() {
+        let samples = |speed: Speed| -> usize {
+            let mut pacer = FramePacer::new(TvSystem::NTSC, 44100);
+            pacer.speed = speed;
+            let mut audio = AudioPipeline::new(NTSC_CPU_CLOCK, 44100);
+            let mut sink = BufferSink::new(44100);
+            let level = DrainingSink { queued: Cell::new(pacer.target_queue()), per_poll: 10000 };
+            pacer.end_frame(Some(&level), &mut audio);
+            for _ in 0..29780 {
+                audio.add_level(0.0);
+            }
+            audio.end_frame();
+            audio.drain(&mut sink);
+            sink.samples.len()
+        };
+        assert_eq!(samples(Speed::Normal), 733);
+        assert_eq!(samples(Speed::Slow(2)), 1467);
+        assert_eq!(samples(Speed::Fast(Some(4))), 183);
+    }
+
+    #[test]
+    fn test_uncapped_does_not_wait() {
+        let mut pacer = FramePacer::new(TvSystem::NTSC, 44100);
+        pacer.speed = Speed::Fast(None);
+        assert!(pacer.mutes_audio());
+        let mut audio = AudioPipeline::new(NTSC_CPU_CLOCK, 44100);
+        let start = Instant::now();
+        for _ in 0..100 {
+            pacer.end_frame(None, &mut audio);
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
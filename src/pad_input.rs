@@ -0,0 +1,162 @@
+use crate::joypad::JoypadButtons;
+
+// a button as the host holds it: the pad's own, or a turbo one that keeps pressing and
+// releasing it while held
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PadButton {
+    Normal(JoypadButtons),
+    Turbo(JoypadButtons),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TurboRate {
+    // frames pressed, then frames released
+    pub on: u32,
+    pub off: u32,
+}
+
+impl TurboRate {
+    // 15 presses a second
+    pub const DEFAULT: TurboRate = TurboRate { on: 2, off: 2 };
+
+    // "on,off" in frames
+    pub fn parse(text: &str) -> Result<TurboRate, String> {
+        let error = || format!("bad turbo rate {}, expected <frames on>,<frames off>", text);
+        let (on, off) = text.split_once(',').ok_or_else(error)?;
+        let on = on.trim().parse::<u32>().map_err(|_| error())?;
+        let off = off.trim().parse::<u32>().map_err(|_| error())?;
+        if on == 0 || off == 0 {
+            return Err(error());
+        }
+        Ok(TurboRate { on: on, off: off })
+    }
+}
+
+// what the host holds for one pad, turned into the buttons the pad reports: turbo
+// buttons pulse with the frame count, and with opposites blocked Left+Right and Up+Down,
+// which a real d-pad can't press, keep only the direction pressed last
+pub struct PadInput {
+    held: JoypadButtons,
+    turbo: JoypadButtons,
+    // frames since the first turbo button went down, so a press always starts pressed
+    turbo_frame: u32,
+    // the direction of each axis pressed most recently
+    last_horizontal: JoypadButtons,
+    last_vertical: JoypadButtons,
+}
+
+impl PadInput {
+    pub fn new() -> Self {
+        PadInput {
+            held: JoypadButtons::empty(),
+            turbo: JoypadButtons::empty(),
+            turbo_frame: 0,
+            last_horizontal: JoypadButtons::empty(),
+            last_vertical: JoypadButtons::empty(),
+        }
+    }
+
+    pub fn set(&mut self, button: PadButton, pressed: bool) {
+        match button {
+            PadButton::Normal(button) => {
+                if pressed {
+                    let horizontal = button & (JoypadButtons::LEFT | JoypadButtons::RIGHT);
+                    let vertical = button & (JoypadButtons::UP | JoypadButtons::DOWN);
+                    if !horizontal.is_empty() {
+                        self.last_horizontal = horizontal;
+                    }
+                    if !vertical.is_empty() {
+                        self.last_vertical = vertical;
+                    }
+                }
+                self.held.set(button, pressed);
+            }
+            PadButton::Turbo(button) => {
+                if pressed && self.turbo.is_empty() {
+                    self.turbo_frame = 0;
+                }
+                self.turbo.set(button, pressed);
+            }
+        }
+    }
+
+    pub fn has_turbo(&self) -> bool {
+        !self.turbo.is_empty()
+    }
+
+    pub fn frame(&mut self) {
+        self.turbo_frame = self.turbo_frame.wrapping_add(1);
+    }
+
+    pub fn buttons(&self, rate: TurboRate, block_opposites: bool) -> JoypadButtons {
+        let mut buttons = self.held;
+        if self.turbo_frame % (rate.on + rate.off) < rate.on {
+            buttons |= self.turbo;
+        }
+        if block_opposites {
+            for (pair, last) in [
+                (JoypadButtons::LEFT | JoypadButtons::RIGHT, self.last_horizontal),
+                (JoypadButtons::UP | JoypadButtons::DOWN, self.last_vertical),
+            ] {
+                if buttons.contains(pair) {
+                    buttons.remove(pair - last);
+                }
+            }
+        }
+        buttons
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_turbo_pulses_from_the_press() {
+        let mut pad = PadInput::new();
+        let rate = TurboRate { on: 2, off: 1 };
+        pad.frame();
+        pad.set(PadButton::Turbo(JoypadButtons::BUTTON_A), true);
+        pad.set(PadButton::Normal(JoypadButtons::BUTTON_B), true);
+        let mut frames = vec![];
+        for _ in 0..6 {
+            frames.push(pad.buttons(rate, false).contains(JoypadButtons::BUTTON_A));
+            pad.frame();
+        }
+        assert_eq!(frames, vec![true, true, false, true, true, false]);
+        assert!(pad.buttons(rate, false).contains(JoypadButtons::BUTTON_B));
+
+        // a normal A press holds through the off frames
+        pad.set(PadButton::Normal(JoypadButtons::BUTTON_A), true);
+        pad.frame();
+        pad.frame();
+        assert!(pad.buttons(rate, false).contains(JoypadButtons::BUTTON_A));
+        pad.set(PadButton::Turbo(JoypadButtons::BUTTON_A), false);
+        pad.set(PadButton::Normal(JoypadButtons::BUTTON_A), false);
+        assert_eq!(pad.buttons(rate, false), JoypadButtons::BUTTON_B);
+    }
+
+    #[test]
+    fn test_opposites_keep_the_last_pressed() {
+        let mut pad = PadInput::new();
+        let rate = TurboRate::DEFAULT;
+        pad.set(PadButton::Normal(JoypadButtons::LEFT), true);
+        pad.set(PadButton::Normal(JoypadButtons::UP), true);
+        pad.set(PadButton::Normal(JoypadButtons::RIGHT), true);
+        assert_eq!(pad.buttons(rate, true), JoypadButtons::RIGHT | JoypadButtons::UP);
+        assert_eq!(pad.buttons(rate, false), JoypadButtons::LEFT | JoypadButtons::RIGHT | JoypadButtons::UP);
+        // letting go of the newer one brings the older back
+        pad.set(PadButton::Normal(JoypadButtons::RIGHT), false);
+        assert_eq!(pad.buttons(rate, true), JoypadButtons::LEFT | JoypadButtons::UP);
+        pad.set(PadButton::Normal(JoypadButtons::DOWN), true);
+        assert_eq!(pad.buttons(rate, true), JoypadButtons::LEFT | JoypadButtons::DOWN);
+    }
+
+    #[test]
+    fn test_turbo_rate_parse() {
+        assert_eq!(TurboRate::parse("1,3"), Ok(TurboRate { on: 1, off: 3 }));
+        assert!(TurboRate::parse("2").is_err());
+        assert!(TurboRate::parse("0,2").is_err());
+        assert!(TurboRate::parse("a,b").is_err());
+    }
+}
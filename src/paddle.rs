@@ -0,0 +1,105 @@
+use crate::controller::*;
+
+// the knob's useful range as Arkanoid sees it
+pub const PADDLE_MIN: u8 = 0x62;
+pub const PADDLE_MAX: u8 = 0xF2;
+
+// the Arkanoid Vaus controller: a potentiometer read through an 8-bit shift register,
+// latched on the strobe and shifted out MSB first, inverted. The NES version sits in
+// port 2 with the data on D3 and the button on D4, the Famicom one in the expansion
+// port with the button on D1 of $4016 and the data on D1 of $4017
+pub struct Paddle {
+    pub position: u8,
+    pub fire: bool,
+    famicom: bool,
+    strobe: bool,
+    shift: u8,
+}
+
+impl Paddle {
+    pub fn new(famicom: bool) -> Self {
+        Paddle { position: PADDLE_MIN, fire: false, famicom: famicom, strobe: false, shift: 0 }
+    }
+
+    // turns the knob to match a pointer at frame column x
+    pub fn set_pointer(&mut self, x: usize) {
+        let span = (PADDLE_MAX - PADDLE_MIN) as usize;
+        self.position = PADDLE_MIN + (x.min(255) * span / 255) as u8;
+    }
+
+    fn data_bit(&mut self) -> u8 {
+        let bit = !self.shift >> 7;
+        if !self.strobe {
+            self.shift <<= 1;
+        }
+        bit
+    }
+}
+
+impl PortDevice for Paddle {
+    fn name(&self) -> &'static str {
+        "vaus"
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.shift = self.position;
+        }
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        let fire = self.fire as u8;
+        match (self.famicom, port) {
+            (false, _) => (self.data_bit() << 3) | (fire << 4),
+            (true, 0) => fire << 1,
+            (true, _) => self.data_bit() << 1,
+        }
+    }
+
+    fn paddle(&mut self) -> Option<&mut Paddle> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_position(paddle: &mut Paddle, port: usize, shift: u8) -> u8 {
+        paddle.write(1);
+        paddle.write(0);
+        (0..8).fold(0, |value, _| (value << 1) | ((paddle.read(port) >> shift) & 1))
+    }
+
+    #[test]
+    fn test_position_shifts_out_inverted() {
+        let mut paddle = Paddle::new(false);
+        paddle.position = 0xA5;
+        assert_eq!(read_position(&mut paddle, 1, 3), !0xA5);
+        // emptied, the register reads as all ones
+        assert_eq!(paddle.read(1) & 0b1000, 0b1000);
+        paddle.fire = true;
+        assert_eq!(paddle.read(1) & 0b1_0000, 0b1_0000);
+    }
+
+    #[test]
+    fn test_famicom_wiring() {
+        let mut paddle = Paddle::new(true);
+        paddle.position = 0x80;
+        paddle.fire = true;
+        assert_eq!(paddle.read(0), 0b10);
+        assert_eq!(read_position(&mut paddle, 1, 1), 0x7F);
+    }
+
+    #[test]
+    fn test_pointer_range() {
+        let mut paddle = Paddle::new(false);
+        paddle.set_pointer(0);
+        assert_eq!(paddle.position, PADDLE_MIN);
+        paddle.set_pointer(255);
+        assert_eq!(paddle.position, PADDLE_MAX);
+        paddle.set_pointer(1000);
+        assert_eq!(paddle.position, PADDLE_MAX);
+    }
+}
@@ -1,5 +1,6 @@
-#[rustfmt::skip]
+use std::f32::consts::PI;
 
+#[rustfmt::skip]
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E), 
     (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00), 
@@ -14,4 +15,282 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0), 
     (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA), 
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
-];
\ No newline at end of file
+];
+
+// FCEUX's default palette
+#[rustfmt::skip]
+pub static FCEUX_PALLETE: [(u8,u8,u8); 64] = [
+    (0x74, 0x74, 0x74), (0x24, 0x18, 0x8C), (0x00, 0x00, 0xA8), (0x44, 0x00, 0x9C), (0x8C, 0x00, 0x74),
+    (0xA8, 0x00, 0x10), (0xA4, 0x00, 0x00), (0x7C, 0x08, 0x00), (0x40, 0x2C, 0x00), (0x00, 0x44, 0x00),
+    (0x00, 0x50, 0x00), (0x00, 0x3C, 0x14), (0x18, 0x3C, 0x5C), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0x00, 0x00, 0x00), (0xBC, 0xBC, 0xBC), (0x00, 0x70, 0xEC), (0x20, 0x38, 0xEC), (0x80, 0x00, 0xF0),
+    (0xBC, 0x00, 0xBC), (0xE4, 0x00, 0x58), (0xD8, 0x28, 0x00), (0xC8, 0x4C, 0x0C), (0x88, 0x70, 0x00),
+    (0x00, 0x94, 0x00), (0x00, 0xA8, 0x00), (0x00, 0x90, 0x38), (0x00, 0x80, 0x88), (0x00, 0x00, 0x00),
+    (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0xFC, 0xFC, 0xFC), (0x3C, 0xBC, 0xFC), (0x5C, 0x94, 0xFC),
+    (0xCC, 0x88, 0xFC), (0xF4, 0x78, 0xFC), (0xFC, 0x74, 0xB4), (0xFC, 0x74, 0x60), (0xFC, 0x98, 0x38),
+    (0xF0, 0xBC, 0x3C), (0x80, 0xD0, 0x10), (0x4C, 0xDC, 0x48), (0x58, 0xF8, 0x98), (0x00, 0xE8, 0xD8),
+    (0x78, 0x78, 0x78), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0xFC, 0xFC, 0xFC), (0xA8, 0xE4, 0xFC),
+    (0xC4, 0xD4, 0xFC), (0xD4, 0xC8, 0xFC), (0xFC, 0xC4, 0xFC), (0xFC, 0xC4, 0xD8), (0xFC, 0xBC, 0xB0),
+    (0xFC, 0xD8, 0xA8), (0xFC, 0xE4, 0xA0), (0xE0, 0xFC, 0xA0), (0xA8, 0xF0, 0xBC), (0xB0, 0xFC, 0xCC),
+    (0x9C, 0xFC, 0xF0), (0xC4, 0xC4, 0xC4), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00)
+];
+
+// 2C03 RGB PPU, one octal digit (0-7) per channel
+#[rustfmt::skip]
+static RGB_2C03_LEVELS: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420, 0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630, 0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750, 0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772, 0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+// composite output levels measured on a 2C02, in volts
+pub const SIGNAL_LOW: [f32; 4] = [0.228, 0.312, 0.552, 0.880];
+pub const SIGNAL_HIGH: [f32; 4] = [0.616, 0.840, 1.100, 1.100];
+pub const SIGNAL_BLACK: f32 = 0.312;
+pub const SIGNAL_WHITE: f32 = 1.100;
+pub const EMPHASIS_ATTENUATION: f32 = 0.746;
+// colorburst offset in subcarrier phases (30 degrees each) used when decoding hue
+pub const COLORBURST_PHASE: f32 = 4.0;
+
+pub const PAL_FILE_SIZE: usize = 64 * 3;
+pub const PAL_FILE_SIZE_WITH_EMPHASIS: usize = 512 * 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuiltinPalette {
+    Classic,
+    Ntsc2C02,
+    Rgb2C03,
+    Fceux,
+}
+
+impl BuiltinPalette {
+    pub const ALL: [BuiltinPalette; 4] = [
+        BuiltinPalette::Classic,
+        BuiltinPalette::Ntsc2C02,
+        BuiltinPalette::Rgb2C03,
+        BuiltinPalette::Fceux,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuiltinPalette::Classic => "classic",
+            BuiltinPalette::Ntsc2C02 => "2c02",
+            BuiltinPalette::Rgb2C03 => "2c03",
+            BuiltinPalette::Fceux => "fceux",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BuiltinPalette> {
+        BuiltinPalette::ALL.iter().copied().find(|p| p.name().eq_ignore_ascii_case(name))
+    }
+}
+
+// 64 colors for each of the 8 emphasis combinations, indexed by (emphasis << 6) | color
+pub struct Palette {
+    pub name: String,
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+    pub fn builtin(kind: BuiltinPalette) -> Self {
+        let name = kind.name().to_string();
+        match kind {
+            BuiltinPalette::Classic => Palette::from_base_colors(name, &SYSTEM_PALLETE),
+            BuiltinPalette::Fceux => Palette::from_base_colors(name, &FCEUX_PALLETE),
+            BuiltinPalette::Rgb2C03 => Palette::rgb_2c03(name),
+            BuiltinPalette::Ntsc2C02 => {
+                let colors = (0..512).map(|i| decode_ntsc_color((i & 0x3F) as u8, (i >> 6) as u8)).collect();
+                Palette { name: name, colors: colors }
+            }
+        }
+    }
+
+    // accepts both plain 64 color files and ones that carry all 8 emphasis variants
+    pub fn from_pal_bytes(name: &str, raw: &[u8]) -> Result<Palette, String> {
+        let colors: Vec<(u8, u8, u8)> = raw.chunks_exact(3).map(|c| (c[0], c[1], c[2])).collect();
+        match raw.len() {
+            PAL_FILE_SIZE => Ok(Palette::from_base_colors(name.to_string(), &colors)),
+            PAL_FILE_SIZE_WITH_EMPHASIS => Ok(Palette { name: name.to_string(), colors: colors }),
+            len => Err(format!("palette file must be {} or {} bytes, got {}", PAL_FILE_SIZE, PAL_FILE_SIZE_WITH_EMPHASIS, len)),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Palette, String> {
+        let raw = std::fs::read(path).map_err(|e| format!("could not read palette {}: {}", path, e))?;
+        let name = std::path::Path::new(path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or(path.to_string());
+        Palette::from_pal_bytes(&name, &raw)
+    }
+
+    // emphasis is the top 3 bits of PPUMASK shifted down (bit 0 red, bit 1 green, bit 2 blue)
+    pub fn color(&self, index: u8, emphasis: u8) -> (u8, u8, u8) {
+        self.colors[(((emphasis & 0b111) as usize) << 6) | (index & 0x3F) as usize]
+    }
+
+    fn from_base_colors(name: String, base: &[(u8, u8, u8)]) -> Self {
+        let mut colors = Vec::with_capacity(512);
+        for emphasis in 0..8u8 {
+            for index in 0..64 {
+                colors.push(attenuate(base[index], index as u8, emphasis));
+            }
+        }
+        Palette { name: name, colors: colors }
+    }
+
+    fn rgb_2c03(name: String) -> Self {
+        let mut colors = Vec::with_capacity(512);
+        for emphasis in 0..8u8 {
+            for index in 0..64 {
+                let level = RGB_2C03_LEVELS[index];
+                let mut channels = [(level >> 6) & 7, (level >> 3) & 7, level & 7];
+                // the RGB PPU drives emphasized channels to full instead of dimming the others
+                for bit in 0..3 {
+                    if emphasis & (1 << bit) != 0 {
+                        channels[bit] = 7;
+                    }
+                }
+                let scale = |v: u16| (v * 255 / 7) as u8;
+                colors.push((scale(channels[0]), scale(channels[1]), scale(channels[2])));
+            }
+        }
+        Palette { name: name, colors: colors }
+    }
+}
+
+// approximates emphasis for palettes that only ship the 64 base colors
+fn attenuate(rgb: (u8, u8, u8), index: u8, emphasis: u8) -> (u8, u8, u8) {
+    if emphasis == 0 || index & 0x0F >= 0x0E {
+        return rgb;
+    }
+    let dim = |v: u8, emphasized: bool| {
+        if emphasized { v } else { (v as f32 * EMPHASIS_ATTENUATION) as u8 }
+    };
+    let all = emphasis == 0b111;
+    (
+        dim(rgb.0, emphasis & 0b001 != 0 && !all),
+        dim(rgb.1, emphasis & 0b010 != 0 && !all),
+        dim(rgb.2, emphasis & 0b100 != 0 && !all),
+    )
+}
+
+// true while the color's square wave is high during one of the 12 subcarrier phases
+pub fn in_color_phase(color: u8, phase: usize) -> bool {
+    (color as usize + phase) % 12 < 6
+}
+
+// composite voltage for a pixel at one subcarrier phase, normalized so black is 0 and white is 1
+pub fn ntsc_signal(index: u8, emphasis: u8, phase: usize) -> f32 {
+    let color = index & 0x0F;
+    let level = if color >= 0x0E { 1 } else { ((index >> 4) & 3) as usize };
+
+    let mut low = SIGNAL_LOW[level];
+    let mut high = SIGNAL_HIGH[level];
+    if color == 0 {
+        low = high;
+    }
+    if color > 0x0C {
+        high = low;
+    }
+
+    let mut signal = if in_color_phase(color, phase) { high } else { low };
+
+    let emphasized = (emphasis & 0b001 != 0 && in_color_phase(0x0C, phase))
+        || (emphasis & 0b010 != 0 && in_color_phase(0x04, phase))
+        || (emphasis & 0b100 != 0 && in_color_phase(0x08, phase));
+    if emphasized && color < 0x0E {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32) -> (u8, u8, u8) {
+    let clamp = |v: f32| (v.max(0.0).min(1.0) * 255.0).round() as u8;
+    (
+        clamp(y + 0.946882 * i + 0.623557 * q),
+        clamp(y - 0.274788 * i - 0.635691 * q),
+        clamp(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+// averages one full subcarrier cycle of the composite signal and decodes it as YIQ
+fn decode_ntsc_color(index: u8, emphasis: u8) -> (u8, u8, u8) {
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let signal = ntsc_signal(index, emphasis, phase);
+        let angle = PI * (phase as f32 + COLORBURST_PHASE) / 6.0;
+        y += signal;
+        i += signal * angle.cos();
+        q += signal * angle.sin();
+    }
+    yiq_to_rgb(y / 12.0, i / 12.0, q / 12.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builtin_names_round_trip() {
+        for kind in BuiltinPalette::ALL {
+            assert_eq!(BuiltinPalette::from_name(kind.name()), Some(kind));
+        }
+        assert_eq!(BuiltinPalette::from_name("FCEUX"), Some(BuiltinPalette::Fceux));
+        assert_eq!(BuiltinPalette::from_name("nope"), None);
+    }
+
+    #[test]
+    fn test_load_64_color_pal() {
+        let raw: Vec<u8> = (0..PAL_FILE_SIZE).map(|i| i as u8).collect();
+        let palette = Palette::from_pal_bytes("test", &raw).unwrap();
+        assert_eq!(palette.color(0x01, 0), (3, 4, 5));
+        assert_eq!(palette.color(0x3F, 0), (189, 190, 191));
+        // synthesized emphasis dims the other channels
+        let (r, g, b) = palette.color(0x01, 0b001);
+        assert_eq!(r, 3);
+        assert!(g < 4 && b < 5);
+    }
+
+    #[test]
+    fn test_load_512_color_pal() {
+        let mut raw = vec![0u8; PAL_FILE_SIZE_WITH_EMPHASIS];
+        raw[(5 * 64 + 0x21) * 3] = 0xAB;
+        let palette = Palette::from_pal_bytes("test", &raw).unwrap();
+        assert_eq!(palette.color(0x21, 5), (0xAB, 0, 0));
+        for i in 0..512 {
+            assert_eq!(palette.color((i % 64) as u8, (i / 64) as u8), (raw[i * 3], raw[i * 3 + 1], raw[i * 3 + 2]));
+        }
+    }
+
+    #[test]
+    fn test_reject_bad_pal_size() {
+        assert!(Palette::from_pal_bytes("bad", &[0; 100]).is_err());
+    }
+
+    #[test]
+    fn test_2c03_levels() {
+        let palette = Palette::builtin(BuiltinPalette::Rgb2C03);
+        assert_eq!(palette.color(0x30, 0), (255, 255, 255));
+        assert_eq!(palette.color(0x0F, 0), (0, 0, 0));
+        assert_eq!(palette.color(0x16, 0), (255, 0, 0));
+        assert_eq!(palette.color(0x0F, 0b100), (0, 0, 255));
+    }
+
+    #[test]
+    fn test_2c02_hues() {
+        let palette = Palette::builtin(BuiltinPalette::Ntsc2C02);
+        let (r, g, b) = palette.color(0x16, 0);
+        assert!(r > g && r > b, "0x16 should be red, got {:?}", (r, g, b));
+        let (r, g, b) = palette.color(0x1A, 0);
+        assert!(g > r && g > b, "0x1A should be green, got {:?}", (r, g, b));
+        let (r, g, b) = palette.color(0x12, 0);
+        assert!(b > r && b > g, "0x12 should be blue, got {:?}", (r, g, b));
+        assert_eq!(palette.color(0x0F, 0), (0, 0, 0));
+        assert_eq!(palette.color(0x20, 0), (255, 255, 255));
+        // emphasis darkens
+        assert!(palette.color(0x20, 0b110).0 < 255);
+    }
+}
@@ -0,0 +1,213 @@
+use std::time::{Duration, Instant};
+
+use crate::pacing::Speed;
+
+pub const DEFAULT_FAST_FORWARD: Option<u32> = Some(4);
+pub const DEFAULT_SLOW_MOTION: u32 = 2;
+// an uncapped fast-forward still shows about this often
+const DRAW_INTERVAL: Duration = Duration::from_micros(16_667);
+
+// the emulator's own controls, bindable like the pad buttons
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    Pause,
+    FrameAdvance,
+    FastForward,
+    SlowMotion,
+    Rewind,
+    SoftReset,
+    PowerCycle,
+    Rebind,
+    RecordAudio,
+    RecordVideo,
+    Screenshot,
+    Palette,
+    Ntsc,
+    Overscan,
+    Aspect,
+    Visualizer,
+    Filter,
+    // mutes an APU or expansion channel by number, or solos it with shift
+    Channel(usize),
+}
+
+// what the hotkeys have asked for. Pause and slow motion toggle, fast-forward and rewind
+// last while held, and frame advance lets one frame through a pause, pausing first if
+// the game is running
+pub struct PlaybackControl {
+    pub paused: bool,
+    advance: bool,
+    fast_forward: bool,
+    slow_motion: bool,
+    rewinding: bool,
+    // frames run in the time of one, None for as fast as the host can
+    pub fast_forward_speed: Option<u32>,
+    // how many times as long a frame takes
+    pub slow_motion_factor: u32,
+    // so a held key's repeats and a stick's small moves don't count as presses
+    held: Vec<Hotkey>,
+    // frames run since the last one drawn while fast-forwarding
+    skipped: u32,
+    last_drawn: Option<Instant>,
+}
+
+impl PlaybackControl {
+    pub fn new(fast_forward_speed: Option<u32>, slow_motion_factor: u32) -> Self {
+        PlaybackControl {
+            paused: false,
+            advance: false,
+            fast_forward: false,
+            slow_motion: false,
+            rewinding: false,
+            fast_forward_speed: fast_forward_speed,
+            slow_motion_factor: slow_motion_factor,
+            held: Vec::new(),
+            skipped: 0,
+            last_drawn: None,
+        }
+    }
+
+    // true for a new press, for the hotkeys handled outside playback
+    pub fn press(&mut self, hotkey: Hotkey, pressed: bool) -> bool {
+        if self.held.contains(&hotkey) == pressed {
+            return false;
+        }
+        if pressed {
+            self.held.push(hotkey);
+        } else {
+            self.held.retain(|held| *held != hotkey);
+        }
+        match hotkey {
+            Hotkey::Pause if pressed => {
+                self.paused = !self.paused;
+                println!("{}", if self.paused { "paused" } else { "resumed" });
+            }
+            Hotkey::FrameAdvance if pressed => {
+                if self.paused {
+                    self.advance = true;
+                } else {
+                    self.paused = true;
+                    println!("paused");
+                }
+            }
+            Hotkey::SlowMotion if pressed => {
+                self.slow_motion = !self.slow_motion;
+                println!("slow motion {}", if self.slow_motion { "on" } else { "off" });
+            }
+            Hotkey::FastForward => self.fast_forward = pressed,
+            Hotkey::Rewind => self.rewinding = pressed,
+            _ => {}
+        }
+        pressed
+    }
+
+    // checked at each frame boundary: true to hold the game there, until it's unpaused or
+    // frame advance lets one through
+    pub fn waiting(&mut self) -> bool {
+        self.paused && !std::mem::take(&mut self.advance)
+    }
+
+    // fast-forward wins over slow motion while it's held
+    pub fn speed(&self) -> Speed {
+        if self.fast_forward {
+            Speed::Fast(self.fast_forward_speed)
+        } else if self.slow_motion {
+            Speed::Slow(self.slow_motion_factor)
+        } else {
+            Speed::Normal
+        }
+    }
+
+    pub fn rewinding(&self) -> bool {
+        self.rewinding
+    }
+
+    // whether to show this frame: while fast-forwarding, one in every so many at a fixed
+    // speed, or one a host frame's time at most uncapped
+    pub fn should_draw(&mut self) -> bool {
+        if !self.fast_forward {
+            self.skipped = 0;
+            return true;
+        }
+        match self.fast_forward_speed {
+            Some(speed) => {
+                self.skipped += 1;
+                if self.skipped < speed {
+                    return false;
+                }
+                self.skipped = 0;
+                true
+            }
+            None => {
+                let now = Instant::now();
+                if self.last_drawn.map_or(false, |drawn| now - drawn < DRAW_INTERVAL) {
+                    return false;
+                }
+                self.last_drawn = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pause_and_frame_advance() {
+        let mut controls = PlaybackControl::new(DEFAULT_FAST_FORWARD, DEFAULT_SLOW_MOTION);
+        assert!(!controls.waiting());
+        controls.press(Hotkey::FrameAdvance, true);
+        assert!(controls.waiting());
+        controls.press(Hotkey::FrameAdvance, false);
+        controls.press(Hotkey::FrameAdvance, true);
+        // one frame through, then held again
+        assert!(!controls.waiting());
+        assert!(controls.waiting());
+        // a repeat without a release is not another press
+        controls.press(Hotkey::FrameAdvance, true);
+        assert!(controls.waiting());
+        controls.press(Hotkey::Pause, true);
+        assert!(!controls.waiting());
+        controls.press(Hotkey::Pause, true);
+        assert!(!controls.paused);
+        // other hotkeys only report their presses
+        assert!(controls.press(Hotkey::Channel(2), true));
+        assert!(!controls.press(Hotkey::Channel(2), true));
+        assert!(controls.press(Hotkey::Channel(3), true));
+        assert!(!controls.press(Hotkey::Channel(2), false));
+        assert!(controls.press(Hotkey::Channel(2), true));
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut controls = PlaybackControl::new(None, 3);
+        assert_eq!(controls.speed(), Speed::Normal);
+        controls.press(Hotkey::SlowMotion, true);
+        controls.press(Hotkey::SlowMotion, false);
+        assert_eq!(controls.speed(), Speed::Slow(3));
+        controls.press(Hotkey::FastForward, true);
+        assert_eq!(controls.speed(), Speed::Fast(None));
+        controls.press(Hotkey::FastForward, false);
+        assert_eq!(controls.speed(), Speed::Slow(3));
+        controls.press(Hotkey::Rewind, true);
+        assert!(controls.rewinding());
+        controls.press(Hotkey::Rewind, false);
+        assert!(!controls.rewinding());
+    }
+
+    #[test]
+    fn test_fast_forward_skips_drawing() {
+        let mut controls = PlaybackControl::new(Some(4), DEFAULT_SLOW_MOTION);
+        assert!((0..4).all(|_| controls.should_draw()));
+        controls.press(Hotkey::FastForward, true);
+        let drawn: Vec<bool> = (0..8).map(|_| controls.should_draw()).collect();
+        assert_eq!(drawn, vec![false, false, false, true, false, false, false, true]);
+
+        let mut controls = PlaybackControl::new(None, DEFAULT_SLOW_MOTION);
+        controls.press(Hotkey::FastForward, true);
+        assert!(controls.should_draw());
+        assert!(!controls.should_draw());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::controller::*;
+
+// the order side B's numbered buttons come out of the two shift registers
+const D3_ORDER: [usize; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const D4_ORDER: [usize; 4] = [4, 3, 12, 8];
+
+// the Power Pad mat in port 2: 12 buttons, numbered on side B as
+//   1  2  3  4
+//   5  6  7  8
+//   9 10 11 12
+// latched on the strobe into two shift registers read on D3 and D4, 1 meaning pressed
+pub struct PowerPad {
+    // bit n - 1 for button n
+    buttons: u16,
+    strobe: bool,
+    d3: u8,
+    d4: u8,
+}
+
+impl PowerPad {
+    pub fn new() -> Self {
+        PowerPad { buttons: 0, strobe: false, d3: 0, d4: 0 }
+    }
+
+    pub fn set_button(&mut self, button: usize, pressed: bool) {
+        if (1..=12).contains(&button) {
+            if pressed {
+                self.buttons |= 1 << (button - 1);
+            } else {
+                self.buttons &= !(1 << (button - 1));
+            }
+        }
+    }
+
+    fn pressed(&self, button: usize) -> u8 {
+        ((self.buttons >> (button - 1)) & 1) as u8
+    }
+
+    fn latch(&mut self) {
+        self.d3 = D3_ORDER.iter().enumerate().map(|(i, button)| self.pressed(*button) << i).sum();
+        // once the four buttons are out the register shifts in ones
+        self.d4 = D4_ORDER.iter().enumerate().map(|(i, button)| self.pressed(*button) << i).sum::<u8>() | 0xF0;
+    }
+}
+
+impl PortDevice for PowerPad {
+    fn name(&self) -> &'static str {
+        "power pad"
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, _port: usize) -> u8 {
+        if self.strobe {
+            self.latch();
+        }
+        let data = ((self.d3 & 1) << 3) | ((self.d4 & 1) << 4);
+        if !self.strobe {
+            // ones shift in behind the buttons
+            self.d3 = (self.d3 >> 1) | 0x80;
+            self.d4 = (self.d4 >> 1) | 0x80;
+        }
+        data
+    }
+
+    fn power_pad(&mut self) -> Option<&mut PowerPad> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_streams(pad: &mut PowerPad) -> (Vec<u8>, Vec<u8>) {
+        pad.write(1);
+        pad.write(0);
+        (0..10).map(|_| pad.read(1)).map(|data| ((data >> 3) & 1, (data >> 4) & 1)).unzip()
+    }
+
+    #[test]
+    fn test_button_order() {
+        let mut pad = PowerPad::new();
+        pad.set_button(1, true);
+        pad.set_button(12, true);
+        let (d3, d4) = read_streams(&mut pad);
+        assert_eq!(d3, vec![0, 1, 0, 0, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(d4, vec![0, 0, 1, 0, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_release_and_bad_buttons() {
+        let mut pad = PowerPad::new();
+        pad.set_button(7, true);
+        pad.set_button(7, false);
+        pad.set_button(0, true);
+        pad.set_button(13, true);
+        let (d3, _) = read_streams(&mut pad);
+        assert_eq!(&d3[..8], &[0; 8]);
+    }
+}
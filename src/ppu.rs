@@ -1,9 +1,12 @@
 use crate::Mirroring;
 use bitflags::bitflags;
 use crate::cartridge::*;
+use crate::savestate::*;
 
 pub struct ppu {
     pub chr_rom: Vec<u8>,
+    // carts without CHR ROM have 8KB of RAM there instead
+    pub chr_ram: bool,
     pub palette_table: [u8; 32],
     pub vram: [u8; 2048],
     pub oam_data: [u8; 256],
@@ -17,13 +20,32 @@ pub struct ppu {
     pub scroll_register: ScrollRegister,
     scanline: u16,
     cycles: usize,
+    odd_frame: bool,
+    suppress_vblank: bool,
     pub nmi_interrupt: Option<u8>,
 }
 
+const DOTS_PER_SCANLINE: usize = 341;
+const VBLANK_SCANLINE: u16 = 241;
+const PRE_RENDER_SCANLINE: u16 = 261;
+
+bitflags!{
+    // what happened during a tick
+    pub struct PpuEvents: u8 {
+        // dot 0 of scanline 0, the last picture is gone
+        const FRAME_STARTED = 0b0000_0001;
+        // dot 1 of scanline 241, the picture is done; this happens whether or not NMI is
+        // enabled or a $2002 read kept the vblank flag from being set
+        const FRAME_COMPLETE = 0b0000_0010;
+    }
+}
+
 impl ppu {
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let chr_ram = chr_rom.is_empty();
         ppu {
-            chr_rom: chr_rom,
+            chr_rom: if chr_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_ram: chr_ram,
             mirroring: mirroring,
             vram: [0; 2048],
             oam_data: [0; 256],
@@ -37,46 +59,76 @@ impl ppu {
             scroll_register: ScrollRegister::new(),
             scanline: 0,
             cycles: 0,
+            odd_frame: false,
+            suppress_vblank: false,
             nmi_interrupt: None,
         }
     }
 
-    pub fn tick(&mut self, ticks: u8) -> bool{
-        self.cycles += ticks as usize;
-        if(self.cycles >= 341){
-            self.cycles -= 341;
-            self.scanline += 1;
+    pub fn tick(&mut self, ticks: u8) -> PpuEvents{
+        let mut events = PpuEvents::empty();
+        for _ in 0..ticks {
+            events |= self.step_dot();
+        }
+        return events;
+    }
 
+    // advances the ppu by a single dot
+    fn step_dot(&mut self) -> PpuEvents{
+        let mut events = PpuEvents::empty();
+        self.cycles += 1;
 
-            // println!("1");
+        // odd frames drop the last dot of the pre-render line when rendering is enabled
+        if self.scanline == PRE_RENDER_SCANLINE && self.cycles == DOTS_PER_SCANLINE - 1
+            && self.odd_frame && self.mask_register.rendering_enabled() {
+            self.cycles = DOTS_PER_SCANLINE;
+        }
 
-            // for i in 0..4{
-            //     println!("{:0b}", self.oam_data[i]);
-            // }
+        if self.cycles >= DOTS_PER_SCANLINE {
+            self.cycles = 0;
+            self.scanline += 1;
 
             if self.sprite_0_hit(self.cycles) {
                 self.status_register.set_sprite_zero_hit(true);
             }
 
-            if self.scanline == 241 {
-                self.status_register.set_vblank(true);
-                self.status_register.set_sprite_zero_hit(false);
-                //self.status.set_sprite_zero_hit(false);
-                if self.control_register.generate_nmi() {
-                    self.nmi_interrupt = Some(1);
+            if self.scanline > PRE_RENDER_SCANLINE {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+                events |= PpuEvents::FRAME_STARTED;
+            }
+        }
+
+        if self.cycles == 1 {
+            if self.scanline == VBLANK_SCANLINE {
+                events |= PpuEvents::FRAME_COMPLETE;
+                if !self.suppress_vblank {
+                    self.status_register.set_vblank(true);
+                    if self.control_register.generate_nmi() {
+                        self.nmi_interrupt = Some(1);
+                    }
                 }
+                self.suppress_vblank = false;
             }
 
-            if(self.scanline >= 262){
-                self.scanline = 0;
+            if self.scanline == PRE_RENDER_SCANLINE {
                 self.nmi_interrupt = None;
                 self.status_register.clear_vblank();
                 self.status_register.set_sprite_zero_hit(false);
-                return true;
+                self.status_register.set_sprite_overflow(false);
             }
         }
-        return false;
+        return events;
+    }
 
+    // the reset line clears PPUCTRL, PPUMASK and the write latch, the frame timing carries on
+    pub fn reset(&mut self) {
+        self.control_register = ControlRegister::new();
+        self.mask_register = MaskRegister::new();
+        self.address.reset_ptr();
+        self.internal_buffer = 0;
+        self.odd_frame = false;
+        self.nmi_interrupt = None;
     }
 
     pub fn write_ppu_address(&mut self, data: u8){
@@ -89,6 +141,10 @@ impl ppu {
         if !before_nmi_status && self.control_register.generate_nmi() && self.status_register.check_vblank() {
             self.nmi_interrupt = Some(1);
         }
+        // turning nmi off right as vblank starts cancels the pending interrupt
+        if before_nmi_status && !self.control_register.generate_nmi() && self.in_vblank_race_window() {
+            self.nmi_interrupt = None;
+        }
     }
 
     pub fn write_mask_register(&mut self, data: u8){
@@ -96,6 +152,14 @@ impl ppu {
     }
 
     pub fn read_status_register(&mut self) -> u8{
+        // reading one dot before vblank starts means the flag never gets set this frame
+        if self.scanline == VBLANK_SCANLINE && self.cycles == 0 {
+            self.suppress_vblank = true;
+        }
+        // reading on the same dot (or the one after) still sees the flag but no nmi happens
+        if self.in_vblank_race_window() {
+            self.nmi_interrupt = None;
+        }
         let data = self.status_register.get();
         self.status_register.clear_vblank();
         self.address.reset_ptr();
@@ -131,6 +195,22 @@ impl ppu {
         }
     }
 
+    fn in_vblank_race_window(&self) -> bool{
+        return self.scanline == VBLANK_SCANLINE && (self.cycles == 1 || self.cycles == 2);
+    }
+
+    pub fn scanline(&self) -> u16{
+        return self.scanline;
+    }
+
+    pub fn dot(&self) -> usize{
+        return self.cycles;
+    }
+
+    pub fn odd_frame(&self) -> bool{
+        return self.odd_frame;
+    }
+
     fn sprite_0_hit(&mut self, cycle: usize) -> bool{
         let y = self.oam_data[0] as usize;
         let x = self.oam_data[3] as usize;
@@ -163,39 +243,53 @@ impl ppu {
         }
     }
 
+    // $3F10/$3F14/$3F18/$3F1C share memory with $3F00/$3F04/$3F08/$3F0C and
+    // $3F20-$3FFF repeat the 32 bytes at $3F00-$3F1F
+    pub fn mirror_palette_address(&self, addr: u16) -> usize {
+        let index = (addr & 0x1F) as usize;
+        match index {
+            0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+            _ => index,
+        }
+    }
+
+    pub fn palette_entry(&self, index: usize) -> u8 {
+        let value = self.palette_table[self.mirror_palette_address(index as u16)];
+        if self.mask_register.contains(MaskRegister::GREYSCALE) {
+            return value & 0x30;
+        }
+        return value;
+    }
+
+    pub fn backdrop_color(&self) -> u8 {
+        return self.palette_entry(0);
+    }
+
     pub fn read_data(&mut self) -> u8{
         let address = self.address.get();
         self.increment_vram_address();
 
         match address {
-            0..0x1FFF => {
+            0..=0x1FFF => {
                 let old_result = self.internal_buffer;
                 self.internal_buffer = self.chr_rom[address as usize];
                 return old_result;
             }
 
-            0x2000..0x2FFF => {
+            0x2000..=0x3EFF => {
                 let old_result = self.internal_buffer;
                 self.internal_buffer = self.vram[self.mirror_vram_address(address) as usize];
                 return old_result;
             }
 
-            0x3000..0x3EFF => {
-                panic!("writing to mirror address space");
-            }
-
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = address - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize]
-            }
-
-            0x3f00..=0x3fff =>
-            {
-                self.palette_table[(address - 0x3f00) as usize]
+            0x3F00..=0x3FFF => {
+                // palette reads skip the buffer, which picks up the nametable byte underneath instead
+                self.internal_buffer = self.vram[self.mirror_vram_address(address - 0x1000) as usize];
+                return self.palette_entry(address as usize);
             }
 
             _ => {
-                panic!("writing to mirror address spaces");
+                panic!("unexpected access to mirrored space {:x}", address);
             }
         }
     }
@@ -205,36 +299,66 @@ impl ppu {
         
 
         match address {
-            0..0x1FFF => {
-                panic!("attemp to write to chr_rom space");
-            }
-
-            0x2000..0x2FFF => {
-                self.vram[self.mirror_vram_address(address) as usize] = data; 
+            0..=0x1FFF if self.chr_ram => {
+                self.chr_rom[address as usize] = data;
             }
 
-            0x3000..0x3EFF => {
-                panic!("writing to mirror address space");
+            0..=0x1FFF => {
+                panic!("attemp to write to chr_rom space");
             }
 
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = address - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = data;
+            0x2000..=0x3EFF => {
+                self.vram[self.mirror_vram_address(address) as usize] = data; 
             }
 
-            0x3f00..=0x3fff =>
-            {
-                self.palette_table[(address - 0x3f00) as usize] = data;
+            0x3F00..=0x3FFF => {
+                // palette entries are only 6 bits wide
+                let index = self.mirror_palette_address(address);
+                self.palette_table[index] = data & 0x3F;
             }
 
             _ => {
-                panic!("writing to mirror address spaces");
+                panic!("unexpected access to mirrored space {:x}", address);
             }
         }
         self.increment_vram_address();
     }
 }
 
+// CHR ROM and the mirroring are fixed, so neither is part of the state; CHR RAM is
+impl Savestate for ppu {
+    fn state(&mut self, s: &mut StateBuffer) {
+        if self.chr_ram {
+            s.bytes(&mut self.chr_rom);
+        }
+        s.bytes(&mut self.palette_table);
+        s.bytes(&mut self.vram);
+        s.bytes(&mut self.oam_data);
+        s.u8(&mut self.address.value.0);
+        s.u8(&mut self.address.value.1);
+        s.bool(&mut self.address.hi_ptr);
+        let mut control = self.control_register.bits();
+        s.u8(&mut control);
+        self.control_register = ControlRegister::from_bits_truncate(control);
+        s.u8(&mut self.internal_buffer);
+        let mut mask = self.mask_register.bits();
+        s.u8(&mut mask);
+        self.mask_register = MaskRegister::from_bits_truncate(mask);
+        let mut status = self.status_register.bits();
+        s.u8(&mut status);
+        self.status_register = StatusRegister::from_bits_truncate(status);
+        s.u8(&mut self.oam_address);
+        s.u8(&mut self.scroll_register.X_scroll);
+        s.u8(&mut self.scroll_register.Y_scroll);
+        s.bool(&mut self.scroll_register.scroll_ptr);
+        s.u16(&mut self.scanline);
+        s.usize(&mut self.cycles);
+        s.bool(&mut self.odd_frame);
+        s.bool(&mut self.suppress_vblank);
+        s.option_u8(&mut self.nmi_interrupt);
+    }
+}
+
 pub struct address_register {
     value: (u8, u8),
     hi_ptr: bool,
@@ -396,6 +520,15 @@ bitflags!{
             pub fn show_sprites(&mut self) -> bool{
                 return self.contains(MaskRegister::ENABLE_SPRITE_RENDER);
             }
+
+            // bit 0 red, bit 1 green, bit 2 blue
+            pub fn emphasis(&self) -> u8{
+                return self.bits >> 5;
+            }
+
+            pub fn rendering_enabled(&self) -> bool{
+                return self.intersects(MaskRegister::ENABLE_BACKGROUND_RENDER | MaskRegister::ENABLE_SPRITE_RENDER);
+            }
         
             pub fn update(&mut self, data: u8){
                 self.bits = data;
@@ -687,4 +820,245 @@ bitflags!{
             ppu.write_oam_address(0x11);
             ppu.write_oam_address(0x66);
         }
-    }
\ No newline at end of file
+    
+        fn run_to(ppu: &mut ppu, scanline: u16, dot: usize) {
+            while !(ppu.scanline() == scanline && ppu.dot() == dot) {
+                ppu.tick(1);
+            }
+        }
+
+        fn frame_length(ppu: &mut ppu) -> usize {
+            run_to(ppu, 0, 0);
+            let mut dots = 0;
+            loop {
+                dots += 1;
+                if ppu.tick(1).contains(PpuEvents::FRAME_STARTED) {
+                    return dots;
+                }
+            }
+        }
+
+        #[test]
+        fn test_vblank_starts_on_dot_1_of_241() {
+            let mut ppu = ppu::new_empty_rom();
+            ppu.write_control_register(0b1000_0000);
+            run_to(&mut ppu, 241, 0);
+            assert!(!ppu.status_register.check_vblank());
+            assert_eq!(ppu.nmi_interrupt, None);
+
+            ppu.tick(1);
+            assert!(ppu.status_register.check_vblank());
+            assert_eq!(ppu.nmi_interrupt, Some(1));
+        }
+
+        #[test]
+        fn test_frame_completes_without_nmi() {
+            let mut ppu = ppu::new_empty_rom();
+            run_to(&mut ppu, 241, 0);
+            // a read one dot early suppresses the flag but the frame is still done
+            ppu.read_status_register();
+            assert_eq!(ppu.tick(1), PpuEvents::FRAME_COMPLETE);
+            assert!(!ppu.status_register.check_vblank());
+            assert_eq!(ppu.nmi_interrupt, None);
+            assert!((0..341 * 262 - 1).all(|_| !ppu.tick(1).contains(PpuEvents::FRAME_COMPLETE)));
+            assert_eq!(ppu.tick(1), PpuEvents::FRAME_COMPLETE);
+        }
+
+        #[test]
+        fn test_pre_render_line_clears_flags() {
+            let mut ppu = ppu::new_empty_rom();
+            run_to(&mut ppu, 241, 1);
+            ppu.status_register.set_sprite_zero_hit(true);
+            ppu.status_register.set_sprite_overflow(true);
+
+            run_to(&mut ppu, 261, 0);
+            assert!(ppu.status_register.check_vblank());
+
+            ppu.tick(1);
+            assert_eq!(ppu.status_register.get() & 0b1110_0000, 0);
+        }
+
+        #[test]
+        fn test_odd_frame_skips_dot_when_rendering() {
+            let mut ppu = ppu::new_empty_rom();
+            ppu.write_mask_register(0b0000_1000);
+            let even = frame_length(&mut ppu);
+            let odd = frame_length(&mut ppu);
+            assert_eq!(even, 341 * 262);
+            assert_eq!(odd, 341 * 262 - 1);
+        }
+
+        #[test]
+        fn test_odd_frame_full_length_when_not_rendering() {
+            let mut ppu = ppu::new_empty_rom();
+            assert_eq!(frame_length(&mut ppu), 341 * 262);
+            assert_eq!(frame_length(&mut ppu), 341 * 262);
+        }
+
+        #[test]
+        fn test_status_read_before_vblank_suppresses_flag_and_nmi() {
+            let mut ppu = ppu::new_empty_rom();
+            ppu.write_control_register(0b1000_0000);
+            run_to(&mut ppu, 241, 0);
+
+            assert_eq!(ppu.read_status_register() >> 7, 0);
+            ppu.tick(1);
+            assert!(!ppu.status_register.check_vblank());
+            assert_eq!(ppu.nmi_interrupt, None);
+        }
+
+        #[test]
+        fn test_status_read_as_vblank_starts_suppresses_nmi() {
+            for dot in 1..=2 {
+                let mut ppu = ppu::new_empty_rom();
+                ppu.write_control_register(0b1000_0000);
+                run_to(&mut ppu, 241, dot);
+
+                assert_eq!(ppu.read_status_register() >> 7, 1);
+                assert_eq!(ppu.nmi_interrupt, None);
+            }
+        }
+
+        #[test]
+        fn test_status_read_later_in_vblank_keeps_nmi() {
+            let mut ppu = ppu::new_empty_rom();
+            ppu.write_control_register(0b1000_0000);
+            run_to(&mut ppu, 241, 3);
+
+            assert_eq!(ppu.read_status_register() >> 7, 1);
+            assert_eq!(ppu.nmi_interrupt, Some(1));
+        }
+
+        #[test]
+        fn test_enabling_nmi_during_vblank_triggers_nmi() {
+            let mut ppu = ppu::new_empty_rom();
+            run_to(&mut ppu, 245, 0);
+            assert_eq!(ppu.nmi_interrupt, None);
+
+            ppu.write_control_register(0b1000_0000);
+            assert_eq!(ppu.nmi_interrupt, Some(1));
+        }
+
+        #[test]
+        fn test_disabling_nmi_as_vblank_starts_cancels_nmi() {
+            let mut ppu = ppu::new_empty_rom();
+            ppu.write_control_register(0b1000_0000);
+            run_to(&mut ppu, 241, 1);
+            assert_eq!(ppu.nmi_interrupt, Some(1));
+
+            ppu.write_control_register(0);
+            assert_eq!(ppu.nmi_interrupt, None);
+        }
+    
+        fn set_address(ppu: &mut ppu, addr: u16) {
+            ppu.write_ppu_address((addr >> 8) as u8);
+            ppu.write_ppu_address((addr & 0xff) as u8);
+        }
+
+        fn write_palette(ppu: &mut ppu, addr: u16, data: u8) {
+            set_address(ppu, addr);
+            ppu.write_data(data);
+        }
+
+        fn read_palette(ppu: &mut ppu, addr: u16) -> u8 {
+            set_address(ppu, addr);
+            ppu.read_data()
+        }
+
+        #[test]
+        fn test_palette_mirrors_every_32_bytes() {
+            for base in 0x3f00..0x3f20u16 {
+                let mut ppu = ppu::new_empty_rom();
+                write_palette(&mut ppu, base, 0x2a);
+                let mut mirror = base;
+                while mirror <= 0x3fff {
+                    assert_eq!(read_palette(&mut ppu, mirror), 0x2a, "{:x} mirror of {:x}", mirror, base);
+                    mirror += 0x20;
+                }
+            }
+        }
+
+        #[test]
+        fn test_palette_writes_through_upper_mirrors() {
+            for addr in 0x3f20..=0x3fffu16 {
+                let mut ppu = ppu::new_empty_rom();
+                write_palette(&mut ppu, addr, 0x15);
+                assert_eq!(read_palette(&mut ppu, 0x3f00 + (addr & 0x1f)), 0x15, "{:x}", addr);
+            }
+        }
+
+        #[test]
+        fn test_sprite_backdrop_entries_mirror_background() {
+            for (sprite, background) in [(0x3f10u16, 0x3f00u16), (0x3f14, 0x3f04), (0x3f18, 0x3f08), (0x3f1c, 0x3f0c)] {
+                let mut ppu = ppu::new_empty_rom();
+                write_palette(&mut ppu, sprite, 0x11);
+                assert_eq!(read_palette(&mut ppu, background), 0x11);
+
+                write_palette(&mut ppu, background, 0x22);
+                assert_eq!(read_palette(&mut ppu, sprite), 0x22);
+            }
+        }
+
+        #[test]
+        fn test_other_sprite_palette_entries_are_not_shared() {
+            let mut ppu = ppu::new_empty_rom();
+            write_palette(&mut ppu, 0x3f01, 0x01);
+            write_palette(&mut ppu, 0x3f11, 0x02);
+            assert_eq!(read_palette(&mut ppu, 0x3f01), 0x01);
+            assert_eq!(read_palette(&mut ppu, 0x3f11), 0x02);
+        }
+
+        #[test]
+        fn test_palette_read_fills_buffer_with_nametable() {
+            let mut ppu = ppu::new_empty_rom();
+            ppu.vram[ppu.mirror_vram_address(0x2f05) as usize] = 0x66;
+            write_palette(&mut ppu, 0x3f05, 0x07);
+
+            assert_eq!(read_palette(&mut ppu, 0x3f05), 0x07);
+            set_address(&mut ppu, 0x2000);
+            assert_eq!(ppu.read_data(), 0x66);
+        }
+
+        #[test]
+        fn test_palette_stores_six_bits() {
+            let mut ppu = ppu::new_empty_rom();
+            write_palette(&mut ppu, 0x3f03, 0xff);
+            assert_eq!(read_palette(&mut ppu, 0x3f03), 0x3f);
+        }
+
+        #[test]
+        fn test_palette_greyscale_read() {
+            let mut ppu = ppu::new_empty_rom();
+            write_palette(&mut ppu, 0x3f02, 0x2a);
+            ppu.write_mask_register(0b0000_0001);
+            assert_eq!(read_palette(&mut ppu, 0x3f02), 0x20);
+            assert_eq!(ppu.backdrop_color(), 0);
+        }
+
+        #[test]
+        fn test_nametable_mirror_above_3000() {
+            let mut ppu = ppu::new_empty_rom();
+            ppu.vram[0x0305] = 0x66;
+            set_address(&mut ppu, 0x3305);
+            ppu.read_data();
+            assert_eq!(ppu.read_data(), 0x66);
+        }
+
+        #[test]
+        fn test_chr_ram_without_chr_rom() {
+            let mut ppu = ppu::new(vec![], Mirroring::HORIZONTAL);
+            assert!(ppu.chr_ram);
+            set_address(&mut ppu, 0x1234);
+            ppu.write_data(0x5A);
+            set_address(&mut ppu, 0x1234);
+            ppu.read_data();
+            assert_eq!(ppu.read_data(), 0x5A);
+
+            let mut s = StateBuffer::saving();
+            ppu.state(&mut s);
+            let saved = s.into_data();
+            ppu.chr_rom[0x1234] = 0;
+            ppu.state(&mut StateBuffer::loading(saved));
+            assert_eq!(ppu.chr_rom[0x1234], 0x5A);
+        }
+    }
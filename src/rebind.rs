@@ -0,0 +1,191 @@
+use sdl2::keyboard::Keycode;
+
+use crate::bindings::*;
+use crate::filters::Image;
+use crate::frame::Frame;
+use crate::playback::Hotkey;
+use crate::visualizer::draw_text;
+
+const LINE_HEIGHT: usize = 12;
+const BACKGROUND: (u8, u8, u8) = (16, 16, 24);
+const TEXT: (u8, u8, u8) = (220, 220, 220);
+const DIMMED: (u8, u8, u8) = (120, 120, 120);
+const HIGHLIGHT: (u8, u8, u8) = (255, 208, 64);
+// the pad buttons, then the gamepad and the stick threshold
+const ROWS: usize = BUTTONS.len() + 2;
+const GAMEPAD_ROW: usize = BUTTONS.len();
+const THRESHOLD_ROW: usize = BUTTONS.len() + 1;
+const THRESHOLDS: [f32; 4] = [0.25, 0.5, 0.75, 0.9];
+// characters across the 256 pixel screen
+const COLUMNS: usize = 32;
+
+pub enum RebindAction {
+    Stay,
+    Save,
+    Close,
+}
+
+// the in-app rebinding screen: pick a player and a button, press Return and then the
+// key, controller button or stick direction to add to it
+pub struct RebindScreen {
+    pub player: usize,
+    pub row: usize,
+    pub capturing: bool,
+    pub message: String,
+}
+
+impl RebindScreen {
+    pub fn new() -> Self {
+        RebindScreen { player: 0, row: 0, capturing: false, message: String::new() }
+    }
+
+    // keys while not waiting for a binding
+    pub fn navigate(&mut self, key: Keycode, bindings: &mut InputBindings) -> RebindAction {
+        let rebind_key = bindings.key_hotkeys(key).contains(&Hotkey::Rebind);
+        let player = &mut bindings.players[self.player];
+        match key {
+            _ if rebind_key => return RebindAction::Close,
+            Keycode::Escape => return RebindAction::Close,
+            Keycode::S => return RebindAction::Save,
+            Keycode::Up => self.row = (self.row + ROWS - 1) % ROWS,
+            Keycode::Down => self.row = (self.row + 1) % ROWS,
+            Keycode::Left => self.player = (self.player + PLAYERS - 1) % PLAYERS,
+            Keycode::Right => self.player = (self.player + 1) % PLAYERS,
+            Keycode::Return => match self.row {
+                GAMEPAD_ROW => {
+                    // none, then pads 1 to 4
+                    player.gamepad = match player.gamepad {
+                        None => Some(0),
+                        Some(pad) if pad + 1 < PLAYERS => Some(pad + 1),
+                        Some(_) => None,
+                    };
+                }
+                THRESHOLD_ROW => {
+                    let next = THRESHOLDS.iter().position(|t| *t > player.axis_threshold).unwrap_or(0);
+                    player.axis_threshold = THRESHOLDS[next];
+                }
+                _ => {
+                    self.capturing = true;
+                    self.message = "press a key, button or stick - esc cancels".to_string();
+                }
+            },
+            Keycode::Delete | Keycode::Backspace if self.row < GAMEPAD_ROW => player.buttons[self.row].clear(),
+            _ => {}
+        }
+        RebindAction::Stay
+    }
+
+    // the input pressed while capturing, None to cancel
+    pub fn capture(&mut self, binding: Option<Binding>, bindings: &mut InputBindings) {
+        self.capturing = false;
+        self.message.clear();
+        if let Some(binding) = binding {
+            // a hotkey's binding would never reach the pad
+            if let Some(hotkey) = bindings.hotkey_name(&binding) {
+                self.message = format!("{} is the {} hotkey", binding.name(), hotkey);
+                return;
+            }
+            bindings.players[self.player].bind(self.row, binding);
+        }
+    }
+
+    pub fn draw(&self, bindings: &InputBindings) -> Image {
+        let mut image = Image::new(Frame::WIDTH, Frame::HIGHT);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                image.set(x, y, BACKGROUND);
+            }
+        }
+        let player = &bindings.players[self.player];
+        draw_text(&mut image, 2, 2, &format!("< player {} >", self.player + 1), HIGHLIGHT);
+
+        let mut lines: Vec<(String, String)> = BUTTONS
+            .iter()
+            .zip(player.buttons.iter())
+            .map(|((name, _), list)| (name.to_string(), list.iter().map(|b| b.name()).collect::<Vec<_>>().join(" ")))
+            .collect();
+        lines.push(("pad".to_string(), player.gamepad.map_or("none".to_string(), |pad| (pad + 1).to_string())));
+        lines.push(("stick".to_string(), format!("{}", player.axis_threshold)));
+        for (row, (name, value)) in lines.iter().enumerate() {
+            let marker = if row == self.row { ">" } else { " " };
+            let mut line = format!("{} {:<8}{}", marker, name, value);
+            line.truncate(COLUMNS);
+            let color = if row == self.row { HIGHLIGHT } else { TEXT };
+            draw_text(&mut image, 2, 2 + LINE_HEIGHT * (row + 2), &line, color);
+        }
+
+        let help = ["return: bind  del: clear", "left/right: player", "s: save  esc: back"];
+        for (i, text) in help.iter().enumerate() {
+            draw_text(&mut image, 2, 2 + LINE_HEIGHT * (ROWS + 3 + i), text, DIMMED);
+        }
+        draw_text(&mut image, 2, 2 + LINE_HEIGHT * (ROWS + 7), &self.message, HIGHLIGHT);
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rebinding_a_button() {
+        let mut bindings = InputBindings::defaults();
+        let mut screen = RebindScreen::new();
+        screen.navigate(Keycode::Right, &mut bindings);
+        screen.navigate(Keycode::Up, &mut bindings);
+        assert_eq!((screen.player, screen.row), (1, THRESHOLD_ROW));
+        for _ in 0..5 {
+            screen.navigate(Keycode::Up, &mut bindings);
+        }
+        // player 2's B, cleared and given the Home key
+        screen.navigate(Keycode::Delete, &mut bindings);
+        assert!(bindings.players[1].buttons[6].is_empty());
+        screen.navigate(Keycode::Return, &mut bindings);
+        assert!(screen.capturing);
+        screen.capture(Some(Binding::Key(Keycode::Home)), &mut bindings);
+        assert!(!screen.capturing);
+        assert_eq!(bindings.key(Keycode::Home), vec![(1, crate::pad_input::PadButton::Normal(crate::joypad::JoypadButtons::BUTTON_B))]);
+
+        // cancelling leaves things alone
+        screen.navigate(Keycode::Return, &mut bindings);
+        screen.capture(None, &mut bindings);
+        assert_eq!(bindings.players[1].buttons[6], vec![Binding::Key(Keycode::Home)]);
+
+        // and a hotkey's key is refused
+        screen.navigate(Keycode::Return, &mut bindings);
+        screen.capture(Some(Binding::Key(Keycode::N)), &mut bindings);
+        assert!(screen.message.contains("ntsc"));
+        assert_eq!(bindings.key(Keycode::N), vec![]);
+        assert_eq!(bindings.players[1].buttons[6], vec![Binding::Key(Keycode::Home)]);
+    }
+
+    #[test]
+    fn test_gamepad_and_threshold_rows() {
+        let mut bindings = InputBindings::defaults();
+        let mut screen = RebindScreen::new();
+        screen.row = GAMEPAD_ROW;
+        screen.navigate(Keycode::Return, &mut bindings);
+        assert_eq!(bindings.players[0].gamepad, Some(1));
+        for _ in 0..3 {
+            screen.navigate(Keycode::Return, &mut bindings);
+        }
+        assert_eq!(bindings.players[0].gamepad, None);
+        screen.row = THRESHOLD_ROW;
+        screen.navigate(Keycode::Return, &mut bindings);
+        assert_eq!(bindings.players[0].axis_threshold, 0.75);
+        screen.navigate(Keycode::Return, &mut bindings);
+        screen.navigate(Keycode::Return, &mut bindings);
+        assert_eq!(bindings.players[0].axis_threshold, 0.25);
+    }
+
+    #[test]
+    fn test_close_and_save() {
+        let mut bindings = InputBindings::defaults();
+        let mut screen = RebindScreen::new();
+        assert!(matches!(screen.navigate(Keycode::S, &mut bindings), RebindAction::Save));
+        assert!(matches!(screen.navigate(Keycode::Escape, &mut bindings), RebindAction::Close));
+        assert!(matches!(screen.navigate(Keycode::F9, &mut bindings), RebindAction::Close));
+        let image = screen.draw(&bindings);
+        assert_eq!((image.width, image.height), (Frame::WIDTH, Frame::HIGHT));
+    }
+}
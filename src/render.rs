@@ -37,11 +37,12 @@ fn bg_pallette(ppu: &ppu, attribute_table: &[u8], tile_column: usize, tile_row :
     };
  
     let pallete_start: usize = 1 + (pallet_idx as usize)*4;
-    return [ppu.palette_table[0], ppu.palette_table[pallete_start], ppu.palette_table[pallete_start+1], ppu.palette_table[pallete_start+2]];
+    return [ppu.backdrop_color(), ppu.palette_entry(pallete_start), ppu.palette_entry(pallete_start+1), ppu.palette_entry(pallete_start+2)];
  }
 
- fn render_name_table(ppu: &ppu, frame: &mut Frame, name_table: &[u8],
+ fn render_name_table(ppu: &ppu, frame: &mut Frame, colors: &Palette, name_table: &[u8],
     view_port: Rect, shift_x: isize, shift_y: isize) {
+    let emphasis = ppu.mask_register.emphasis();
     let bank = ppu.control_register.background_pattern_addr();
  
     let attribute_table = &name_table[0x3c0.. 0x400];
@@ -61,18 +62,20 @@ fn bg_pallette(ppu: &ppu, attribute_table: &[u8], tile_column: usize, tile_row :
                 let value = (1 & lower) << 1 | (1 & upper);
                 upper = upper >> 1;
                 lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
+                let color = match value {
+                    0 => palette[0],
+                    1 => palette[1],
+                    2 => palette[2],
+                    3 => palette[3],
                     _ => panic!("can't be"),
                 };
+                let rgb = colors.color(color, emphasis);
+                let index = output_index(color, emphasis);
                 let pixel_x = tile_column * 8 + x;
                 let pixel_y = tile_row * 8 + y;
  
                 if pixel_x >= view_port.x1 && pixel_x < view_port.x2 && pixel_y >= view_port.y1 && pixel_y < view_port.y2 {
-                    frame.set_pixel((shift_x + pixel_x as isize) as usize, (shift_y + pixel_y as isize) as usize, rgb);
+                    frame.set_pixel_indexed((shift_x + pixel_x as isize) as usize, (shift_y + pixel_y as isize) as usize, index, rgb);
                 }
             }
         }
@@ -80,7 +83,8 @@ fn bg_pallette(ppu: &ppu, attribute_table: &[u8], tile_column: usize, tile_row :
  }
  
 
- pub fn render(ppu: &ppu, frame: &mut Frame) {
+ pub fn render(ppu: &ppu, frame: &mut Frame, colors: &Palette) {
+    let emphasis = ppu.mask_register.emphasis();
     let scroll_x = (ppu.scroll_register.X_scroll) as usize;
     let scroll_y = (ppu.scroll_register.Y_scroll) as usize;
 
@@ -96,19 +100,19 @@ fn bg_pallette(ppu: &ppu, attribute_table: &[u8], tile_column: usize, tile_row :
         }
     };
 
-    render_name_table(ppu, frame, 
+    render_name_table(ppu, frame, colors,
         main_nametable, 
         Rect::new(scroll_x, scroll_y, 256, 240 ),
         -(scroll_x as isize), -(scroll_y as isize)
     );
     if scroll_x > 0 {
-        render_name_table(ppu, frame, 
+        render_name_table(ppu, frame, colors,
             second_nametable, 
             Rect::new(0, 0, scroll_x, 240),
             (256 - scroll_x) as isize, 0
         );
     } else if scroll_y > 0 {
-        render_name_table(ppu, frame, 
+        render_name_table(ppu, frame, colors,
             second_nametable, 
             Rect::new(0, 0, 256, scroll_y),
             0, (240 - scroll_y) as isize
@@ -144,28 +148,30 @@ fn bg_pallette(ppu: &ppu, attribute_table: &[u8], tile_column: usize, tile_row :
                 let value = (1 & lower) << 1 | (1 & upper);
                 upper = upper >> 1;
                 lower = lower >> 1;
-                let rgb = match value {
+                let color = match value {
                     0 => continue 'ololo, // skip coloring the pixel
-                    1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
+                    1 => sprite_palette[1],
+                    2 => sprite_palette[2],
+                    3 => sprite_palette[3],
                     _ => panic!("can't be"),
                 };
+                let rgb = colors.color(color, emphasis);
+                let index = output_index(color, emphasis);
                 match (flip_horizontal, flip_vertical) {
                     (false, false) => {
-                        frame.set_pixel(tile_x + x , tile_y + y, rgb);
+                        frame.set_pixel_indexed(tile_x + x , tile_y + y, index, rgb);
                         // frame.set_pixel(tile_x + x, tile_y + y +250, rgb);
                     },
                     (true, false) => {
-                        frame.set_pixel(tile_x + 7 - x , tile_y + y , rgb);
+                        frame.set_pixel_indexed(tile_x + 7 - x , tile_y + y , index, rgb);
                         // frame.set_pixel(tile_x + 7 - x , tile_y + y + 250, rgb);
                     }
                     (false, true) => {
-                        frame.set_pixel(tile_x + x  , tile_y + 7 - y, rgb);
+                        frame.set_pixel_indexed(tile_x + x  , tile_y + 7 - y, index, rgb);
                         // frame.set_pixel(tile_x + x, tile_y + 7 - y + 250, rgb);
                     }
                     (true, true) => {
-                        frame.set_pixel(tile_x + 7 - x , tile_y + 7 - y , rgb);
+                        frame.set_pixel_indexed(tile_x + 7 - x , tile_y + 7 - y , index, rgb);
                         // frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y+250, rgb);
                     }
                 }
@@ -174,13 +180,17 @@ fn bg_pallette(ppu: &ppu, attribute_table: &[u8], tile_column: usize, tile_row :
     }
 }
 
+ fn output_index(color: u8, emphasis: u8) -> u16 {
+    ((emphasis as u16) << 6) | color as u16
+ }
+
  fn sprite_palette(ppu: &ppu, pallete_idx: u8) -> [u8; 4] {
     let start = 0x11 + (pallete_idx * 4) as usize;
     [
-        0,
-        ppu.palette_table[start],
-        ppu.palette_table[start + 1],
-        ppu.palette_table[start + 2],
+        ppu.backdrop_color(),
+        ppu.palette_entry(start),
+        ppu.palette_entry(start + 1),
+        ppu.palette_entry(start + 2),
     ]
 }
  
@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+
+pub const DEFAULT_REWIND_INTERVAL: usize = 2;
+// in megabytes
+pub const DEFAULT_REWIND_BUDGET: usize = 32;
+
+pub struct Snapshot {
+    // the frame it was taken at, counting since rewinding was switched on
+    pub frame: u64,
+    // how far a movie had got, to wind it back along with the machine
+    pub movie_frame: Option<usize>,
+    pub data: Vec<u8>,
+}
+
+// machine states taken every few frames. Only the newest is kept whole: each older one
+// is stored as its XOR with the state after it, which is mostly zeros and squeezes
+// down to a few hundred bytes. The oldest go once the memory budget is used up
+pub struct RewindBuffer {
+    // frames between snapshots
+    pub interval: usize,
+    // bytes
+    pub budget: usize,
+    // set by the frontend while its rewind key is down
+    pub held: bool,
+    frame: u64,
+    latest: Option<Snapshot>,
+    // oldest first
+    older: VecDeque<(u64, Option<usize>, Vec<u8>)>,
+    used: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(interval: usize, budget: usize) -> Self {
+        RewindBuffer {
+            interval: interval.max(1),
+            budget: budget,
+            held: false,
+            frame: 0,
+            latest: None,
+            older: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    // counts a frame; true if a snapshot is due
+    pub fn end_frame(&mut self) -> bool {
+        self.frame += 1;
+        match &self.latest {
+            Some(latest) => self.frame - latest.frame >= self.interval as u64,
+            None => true,
+        }
+    }
+
+    pub fn push(&mut self, movie_frame: Option<usize>, data: Vec<u8>) {
+        self.used += data.len();
+        let snapshot = Snapshot { frame: self.frame, movie_frame: movie_frame, data: data };
+        if let Some(previous) = self.latest.replace(snapshot) {
+            let delta = delta(&self.latest.as_ref().unwrap().data, &previous.data);
+            self.used += delta.len();
+            self.used -= previous.data.len();
+            self.older.push_back((previous.frame, previous.movie_frame, delta));
+        }
+        while self.used > self.budget {
+            match self.older.pop_front() {
+                Some((_, _, delta)) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // winds back to the newest snapshot at least this many frames old, or the oldest
+    // one left, and how many frames back that is; the snapshot stays the newest
+    pub fn rewind(&mut self, frames: u64) -> Option<(&Snapshot, u64)> {
+        let target = self.frame.saturating_sub(frames);
+        let latest = self.latest.as_mut()?;
+        while latest.frame > target {
+            match self.older.pop_back() {
+                Some((frame, movie_frame, delta)) => {
+                    apply_delta(&delta, &mut latest.data);
+                    self.used -= delta.len();
+                    latest.frame = frame;
+                    latest.movie_frame = movie_frame;
+                }
+                None => break,
+            }
+        }
+        let back = self.frame - latest.frame;
+        self.frame = latest.frame;
+        Some((latest, back))
+    }
+
+    pub fn len(&self) -> usize {
+        self.older.len() + self.latest.is_some() as usize
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+}
+
+// the XOR of two states of the same size, as pairs of a zero run length and a literal
+// run length, each followed by the literal bytes
+fn delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    assert_eq!(from.len(), to.len());
+    let mut out = vec![];
+    let mut i = 0;
+    while i < from.len() {
+        let start = i;
+        while i < from.len() && from[i] == to[i] {
+            i += 1;
+        }
+        let zeros = i - start;
+        let literal = i;
+        while i < from.len() && from[i] != to[i] {
+            i += 1;
+        }
+        put_varint(&mut out, zeros);
+        put_varint(&mut out, i - literal);
+        out.extend((literal..i).map(|k| from[k] ^ to[k]));
+    }
+    out
+}
+
+fn apply_delta(delta: &[u8], data: &mut [u8]) {
+    let mut position = 0;
+    let mut i = 0;
+    while position < delta.len() {
+        i += get_varint(delta, &mut position);
+        let literals = get_varint(delta, &mut position);
+        for byte in data[i..i + literals].iter_mut() {
+            *byte ^= delta[position];
+            position += 1;
+        }
+        i += literals;
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn get_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::{Mem, CPU};
+
+    #[test]
+    fn test_delta_round_trips() {
+        let from: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let mut to = from.clone();
+        to[3] ^= 0xFF;
+        to[4] = 0;
+        to[999] = 1;
+        for byte in to[200..400].iter_mut() {
+            *byte = 0x55;
+        }
+        let coded = delta(&from, &to);
+        assert!(coded.len() < 220);
+        let mut restored = to.clone();
+        apply_delta(&coded, &mut restored);
+        assert_eq!(restored, from);
+        assert_eq!(delta(&from, &from), vec![0xE8, 0x07, 0x00]);
+    }
+
+    #[test]
+    fn test_budget_drops_the_oldest() {
+        let mut buffer = RewindBuffer::new(1, 1100);
+        for frame in 0..10u8 {
+            buffer.end_frame();
+            let mut data = vec![0; 1000];
+            data[0] = frame;
+            buffer.push(Some(frame as usize), data);
+        }
+        // the whole newest one and a 6 byte delta for each older one
+        assert_eq!(buffer.len(), 10);
+        assert_eq!(buffer.used(), 1000 + 9 * 6);
+        let mut buffer = RewindBuffer::new(1, 1010);
+        for frame in 0..10u8 {
+            buffer.end_frame();
+            buffer.push(None, vec![frame; 1000]);
+        }
+        assert!(buffer.used() <= 1010);
+        let (snapshot, back) = buffer.rewind(100).unwrap();
+        assert_eq!(snapshot.data, vec![9; 1000]);
+        assert_eq!(back, 0);
+    }
+
+    #[test]
+    fn test_rewind_finds_the_snapshot() {
+        let mut buffer = RewindBuffer::new(3, usize::MAX);
+        for frame in 1..=10u64 {
+            if buffer.end_frame() {
+                buffer.push(Some(frame as usize), vec![frame as u8; 16]);
+            }
+        }
+        // taken at 1, 4, 7 and 10
+        assert_eq!(buffer.len(), 4);
+        let (snapshot, back) = buffer.rewind(4).unwrap();
+        assert_eq!((snapshot.frame, snapshot.movie_frame, back), (4, Some(4), 6));
+        assert_eq!(snapshot.data, vec![4; 16]);
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.rewind(0).unwrap().1, 0);
+    }
+
+    // reset: turn on NMI and spin. NMI: count frames in $00 and BRK at FRAMES
+    const FRAMES: u8 = 10;
+
+    fn frame_counter() -> Vec<u8> {
+        let mut program = vec![
+            0xA9, 0x80, 0x8D, 0x00, 0x20, // LDA #$80, STA $2000
+            0x4C, 0x05, 0x80, // JMP $8005
+            0xE6, 0x00, 0xA5, 0x00, 0xC9, FRAMES, // INC $00, LDA $00, CMP #FRAMES
+            0xD0, 0x01, 0x00, 0x40, // BNE to the RTI, BRK, RTI
+        ];
+        program.resize(0x8000, 0);
+        program[0x7FFA..].copy_from_slice(&[0x08, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        program
+    }
+
+    #[test]
+    fn test_cpu_rewind() {
+        let bus = Bus::new(test_rom(frame_counter()), |_, _, _| {});
+        let mut cpu = CPU::new(bus);
+        assert_eq!(cpu.rewind(5), 0);
+        cpu.rewind = Some(RewindBuffer::new(2, usize::MAX));
+        cpu.reset();
+        cpu.execute(|_| {});
+        assert_eq!(cpu.memory_read(0x00), FRAMES);
+
+        // snapshots at frames 1, 3, 5, 7 and 9, each before that frame's NMI handler
+        assert_eq!(cpu.rewind(4), 5);
+        assert_eq!(cpu.memory_read(0x00), 4);
+        cpu.execute(|_| {});
+        assert_eq!(cpu.memory_read(0x00), FRAMES);
+        assert_eq!(cpu.rewind(100), 9);
+        assert_eq!(cpu.memory_read(0x00), 0);
+    }
+}
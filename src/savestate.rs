@@ -0,0 +1,143 @@
+// the machine state as bytes. Each part has one `state` function that walks its fields
+// in a fixed order, writing them out when saving and reading them back when loading,
+// so the two directions can't drift apart
+pub struct StateBuffer {
+    data: Vec<u8>,
+    position: usize,
+    loading: bool,
+}
+
+impl StateBuffer {
+    pub fn saving() -> Self {
+        StateBuffer { data: vec![], position: 0, loading: false }
+    }
+
+    pub fn loading(data: Vec<u8>) -> Self {
+        StateBuffer { data: data, position: 0, loading: true }
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn bytes(&mut self, value: &mut [u8]) {
+        if self.loading {
+            value.copy_from_slice(&self.data[self.position..self.position + value.len()]);
+        } else {
+            self.data.extend_from_slice(value);
+        }
+        self.position += value.len();
+    }
+
+    pub fn u8(&mut self, value: &mut u8) {
+        let mut bytes = [*value];
+        self.bytes(&mut bytes);
+        *value = bytes[0];
+    }
+
+    pub fn u16(&mut self, value: &mut u16) {
+        let mut bytes = value.to_le_bytes();
+        self.bytes(&mut bytes);
+        *value = u16::from_le_bytes(bytes);
+    }
+
+    pub fn u32(&mut self, value: &mut u32) {
+        let mut bytes = value.to_le_bytes();
+        self.bytes(&mut bytes);
+        *value = u32::from_le_bytes(bytes);
+    }
+
+    pub fn u64(&mut self, value: &mut u64) {
+        let mut bytes = value.to_le_bytes();
+        self.bytes(&mut bytes);
+        *value = u64::from_le_bytes(bytes);
+    }
+
+    pub fn i8(&mut self, value: &mut i8) {
+        let mut byte = *value as u8;
+        self.u8(&mut byte);
+        *value = byte as i8;
+    }
+
+    pub fn i16(&mut self, value: &mut i16) {
+        let mut bytes = value.to_le_bytes();
+        self.bytes(&mut bytes);
+        *value = i16::from_le_bytes(bytes);
+    }
+
+    pub fn f64(&mut self, value: &mut f64) {
+        let mut bits = value.to_bits();
+        self.u64(&mut bits);
+        *value = f64::from_bits(bits);
+    }
+
+    pub fn usize(&mut self, value: &mut usize) {
+        let mut wide = *value as u64;
+        self.u64(&mut wide);
+        *value = wide as usize;
+    }
+
+    pub fn bool(&mut self, value: &mut bool) {
+        let mut byte = *value as u8;
+        self.u8(&mut byte);
+        *value = byte != 0;
+    }
+
+    pub fn option_u8(&mut self, value: &mut Option<u8>) {
+        let mut some = value.is_some();
+        let mut inner = value.unwrap_or(0);
+        self.bool(&mut some);
+        self.u8(&mut inner);
+        *value = if some { Some(inner) } else { None };
+    }
+
+    pub fn option_u16(&mut self, value: &mut Option<u16>) {
+        let mut some = value.is_some();
+        let mut inner = value.unwrap_or(0);
+        self.bool(&mut some);
+        self.u16(&mut inner);
+        *value = if some { Some(inner) } else { None };
+    }
+}
+
+pub trait Savestate {
+    fn state(&mut self, s: &mut StateBuffer);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Sample {
+        a: u8,
+        b: u16,
+        c: usize,
+        d: bool,
+        e: Option<u8>,
+        f: [u8; 3],
+    }
+
+    impl Savestate for Sample {
+        fn state(&mut self, s: &mut StateBuffer) {
+            s.u8(&mut self.a);
+            s.u16(&mut self.b);
+            s.usize(&mut self.c);
+            s.bool(&mut self.d);
+            s.option_u8(&mut self.e);
+            s.bytes(&mut self.f);
+        }
+    }
+
+    #[test]
+    fn test_state_round_trips() {
+        let mut saved = Sample { a: 1, b: 0x1234, c: 99999, d: true, e: Some(7), f: [4, 5, 6] };
+        let mut s = StateBuffer::saving();
+        saved.state(&mut s);
+        let data = s.into_data();
+        assert_eq!(data.len(), 1 + 2 + 8 + 1 + 2 + 3);
+
+        let mut loaded = Sample { a: 0, b: 0, c: 0, d: false, e: None, f: [0; 3] };
+        loaded.state(&mut StateBuffer::loading(data));
+        assert_eq!((loaded.a, loaded.b, loaded.c, loaded.d, loaded.e, loaded.f), (1, 0x1234, 99999, true, Some(7), [4, 5, 6]));
+    }
+}
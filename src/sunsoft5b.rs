@@ -0,0 +1,315 @@
+use lazy_static::lazy_static;
+
+use crate::expansion::*;
+use crate::savestate::*;
+
+// tone, noise and envelope counters all run off CPU clock / 16
+const PRESCALER: u8 = 16;
+const SUNSOFT_5B_SCALE: f32 = 0.15;
+
+lazy_static! {
+    // 1.5 dB per step of the 5-bit internal volume, 0 is silent
+    static ref AMPLITUDES: [f32; 32] = {
+        let mut table = [0.0; 32];
+        for i in 1..32 {
+            table[i] = 10f32.powf((i as f32 - 31.0) * 1.5 / 20.0);
+        }
+        table
+    };
+}
+
+// Sunsoft 5B: a YM2149F (AY-3-8910 family) with three square channels, a noise
+// generator and an envelope generator shared between channels
+pub struct Sunsoft5B {
+    registers: [u8; 16],
+    selected: u8,
+    prescaler: u8,
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_counter: u16,
+    lfsr: u32,
+    envelope_counter: u32,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+}
+
+impl Sunsoft5B {
+    pub fn new() -> Self {
+        Sunsoft5B {
+            registers: [0; 16],
+            selected: 0,
+            prescaler: 0,
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            lfsr: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let period = self.registers[channel * 2] as u16 | ((self.registers[channel * 2 + 1] & 0b1111) as u16) << 8;
+        period.max(1)
+    }
+
+    fn noise_period(&self) -> u16 {
+        (self.registers[6] & 0b1_1111).max(1) as u16
+    }
+
+    fn envelope_period(&self) -> u32 {
+        (self.registers[11] as u32 | (self.registers[12] as u32) << 8).max(1)
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack { self.envelope_step } else { 31 - self.envelope_step }
+    }
+
+    fn restart_envelope(&mut self) {
+        self.envelope_step = 0;
+        self.envelope_counter = 0;
+        self.envelope_holding = false;
+        self.envelope_attack = self.registers[13] & 0b0100 != 0;
+    }
+
+    // shape bits: CONTINUE, ATTACK, ALTERNATE, HOLD
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+        let shape = self.registers[13];
+        if shape & 0b1000 == 0 {
+            // one shot, then silence
+            self.envelope_holding = true;
+            self.envelope_attack = false;
+            self.envelope_step = 31;
+        } else if shape & 0b0001 != 0 {
+            // hold the final level, or the opposite one when alternating
+            self.envelope_holding = true;
+            if shape & 0b0010 != 0 {
+                self.envelope_attack = !self.envelope_attack;
+            }
+        } else {
+            if shape & 0b0010 != 0 {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_step = 0;
+        }
+    }
+
+    // 5-bit volume index of a channel, 4-bit register volumes land on the odd steps
+    fn volume_index(&self, channel: usize) -> u8 {
+        let register = self.registers[8 + channel];
+        if register & 0b1_0000 != 0 {
+            self.envelope_level()
+        } else if register & 0b1111 == 0 {
+            0
+        } else {
+            (register & 0b1111) * 2 + 1
+        }
+    }
+
+    fn gate(&self, channel: usize) -> bool {
+        let mixer = self.registers[7];
+        let tone_off = mixer & (1 << channel) != 0;
+        let noise_off = mixer & (1 << (channel + 3)) != 0;
+        (self.tone_outputs[channel] || tone_off) && (self.lfsr & 1 != 0 || noise_off)
+    }
+}
+
+impl ExpansionAudio for Sunsoft5B {
+    fn name(&self) -> &'static str {
+        "5B"
+    }
+
+    fn channel_names(&self) -> Vec<String> {
+        vec!["5b a".to_string(), "5b b".to_string(), "5b c".to_string()]
+    }
+
+    fn channel_count(&self) -> usize {
+        3
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        addr == 0xC000 || addr == 0xE000
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xC000 => self.selected = data & 0b1111,
+            _ => {
+                self.registers[self.selected as usize] = data;
+                if self.selected == 13 {
+                    self.restart_envelope();
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        self.prescaler += 1;
+        if self.prescaler < PRESCALER {
+            return;
+        }
+        self.prescaler = 0;
+
+        // a square toggles every period ticks, so a full cycle is 32 * period CPU cycles
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+        self.noise_counter += 1;
+        if self.noise_counter >= self.noise_period() * 2 {
+            self.noise_counter = 0;
+            let bit = (self.lfsr ^ (self.lfsr >> 3)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 16);
+        }
+        self.envelope_counter += 1;
+        if self.envelope_counter >= self.envelope_period() {
+            self.envelope_counter = 0;
+            self.clock_envelope();
+        }
+    }
+
+    fn output(&self, channel: usize) -> f32 {
+        if !self.gate(channel) {
+            return 0.0;
+        }
+        AMPLITUDES[self.volume_index(channel) as usize] * SUNSOFT_5B_SCALE
+    }
+
+    fn level(&self, channel: usize) -> u8 {
+        if self.gate(channel) { self.volume_index(channel) } else { 0 }
+    }
+
+    fn max_level(&self, _channel: usize) -> u8 {
+        31
+    }
+
+    fn info(&self, channel: usize, cpu_clock: f64) -> ExpansionChannelInfo {
+        let period = self.tone_period(channel);
+        let mixer = self.registers[7];
+        let enabled = mixer & (1 << channel) == 0 || mixer & (1 << (channel + 3)) == 0;
+        let volume = self.volume_index(channel) / 2;
+        ExpansionChannelInfo {
+            period: period,
+            frequency: cpu_clock / (32.0 * period as f64),
+            volume: volume,
+            max_volume: 15,
+            active: enabled && self.volume_index(channel) > 0,
+        }
+    }
+}
+
+impl Savestate for Sunsoft5B {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bytes(&mut self.registers);
+        s.u8(&mut self.selected);
+        s.u8(&mut self.prescaler);
+        for i in 0..3 {
+            s.u16(&mut self.tone_counters[i]);
+            s.bool(&mut self.tone_outputs[i]);
+        }
+        s.u16(&mut self.noise_counter);
+        s.u32(&mut self.lfsr);
+        s.u32(&mut self.envelope_counter);
+        s.u8(&mut self.envelope_step);
+        s.bool(&mut self.envelope_attack);
+        s.bool(&mut self.envelope_holding);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::expansion::test::assert_state_round_trips;
+
+    fn set(chip: &mut Sunsoft5B, register: u8, data: u8) {
+        chip.write(0xC000, register);
+        chip.write(0xE000, data);
+    }
+
+    #[test]
+    fn test_tone_period() {
+        let mut chip = Sunsoft5B::new();
+        set(&mut chip, 0, 4);
+        set(&mut chip, 7, 0b11_1110); // tone A only
+        set(&mut chip, 8, 15);
+        let mut edges = 0;
+        let mut last = chip.level(0);
+        for _ in 0..32 * 4 * 10 {
+            chip.clock();
+            let level = chip.level(0);
+            if level != last && level > 0 {
+                edges += 1;
+            }
+            last = level;
+        }
+        assert_eq!(edges, 10);
+        assert!((chip.info(0, 1789772.7272).frequency - 1789772.7272 / 128.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_logarithmic_volume() {
+        let mut chip = Sunsoft5B::new();
+        set(&mut chip, 7, 0b11_1111); // everything off: a constant level
+        set(&mut chip, 8, 15);
+        let full = chip.output(0);
+        set(&mut chip, 8, 13);
+        // two register steps are 6 dB, about half the amplitude
+        assert!((chip.output(0) / full - 0.501).abs() < 0.01);
+        set(&mut chip, 8, 0);
+        assert_eq!(chip.output(0), 0.0);
+    }
+
+    #[test]
+    fn test_envelope_shapes() {
+        let mut chip = Sunsoft5B::new();
+        set(&mut chip, 7, 0b11_1111);
+        set(&mut chip, 8, 0b1_0000);
+        set(&mut chip, 11, 1);
+        // decay once then silence
+        set(&mut chip, 13, 0b0000);
+        assert_eq!(chip.level(0), 31);
+        for _ in 0..16 * 40 {
+            chip.clock();
+        }
+        assert_eq!(chip.level(0), 0);
+        // sawtooth up, repeating
+        set(&mut chip, 13, 0b1100);
+        let mut levels = vec![];
+        for _ in 0..16 * 64 {
+            chip.clock();
+            levels.push(chip.level(0));
+        }
+        assert_eq!(levels.iter().filter(|l| **l == 31).count(), 16 * 2);
+        // attack and hold at the top
+        set(&mut chip, 13, 0b1101);
+        for _ in 0..16 * 40 {
+            chip.clock();
+        }
+        assert_eq!(chip.level(0), 31);
+    }
+
+    #[test]
+    fn test_state_round_trips() {
+        let mut chip = Sunsoft5B::new();
+        set(&mut chip, 0, 4);
+        set(&mut chip, 6, 3);
+        set(&mut chip, 7, 0b11_0110); // tone A and noise on A
+        set(&mut chip, 8, 0b1_0000); // enveloped
+        set(&mut chip, 11, 0x10);
+        set(&mut chip, 13, 0b1110);
+        assert_state_round_trips(&mut chip);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::apu::*;
+use crate::filters::*;
+
+pub const VISUALIZER_WIDTH: usize = 320;
+const ROW_HEIGHT: usize = 48;
+const TEXT_HEIGHT: usize = 9;
+const SCOPE_HEIGHT: usize = ROW_HEIGHT - TEXT_HEIGHT - 3;
+const VOLUME_BAR_WIDTH: usize = 8;
+
+const BACKGROUND: (u8, u8, u8) = (16, 16, 24);
+const TEXT: (u8, u8, u8) = (220, 220, 220);
+const DIMMED: (u8, u8, u8) = (90, 90, 90);
+const CHANNEL_COLORS: [(u8, u8, u8); 5] = [(255, 96, 96), (255, 176, 64), (96, 200, 255), (200, 200, 200), (160, 255, 120)];
+const EXPANSION_COLOR: (u8, u8, u8) = (216, 144, 255);
+
+// 3x5 glyphs, one row per byte, bit 2 is the left column
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '[' => [0b110, 0b100, 0b100, 0b100, 0b110],
+        ']' => [0b011, 0b001, 0b001, 0b001, 0b011],
+        _ => [0; 5],
+    }
+}
+
+// text at 2x, 8 pixels per character
+pub fn draw_text(image: &mut Image, x: usize, y: usize, text: &str, color: (u8, u8, u8)) {
+    for (i, c) in text.chars().enumerate() {
+        let rows = glyph(c);
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    fill_rect(image, x + i * 8 + col * 2, y + row * 2, 2, 2, color);
+                }
+            }
+        }
+    }
+}
+
+fn fill_rect(image: &mut Image, x: usize, y: usize, width: usize, height: usize, color: (u8, u8, u8)) {
+    for py in y..(y + height).min(image.height) {
+        for px in x..(x + width).min(image.width) {
+            image.set(px, py, color);
+        }
+    }
+}
+
+// one row per channel, expansion channels included
+pub fn visualizer_size(channels: usize) -> (usize, usize) {
+    (VISUALIZER_WIDTH, ROW_HEIGHT * channels)
+}
+
+fn label(state: &ChannelState) -> String {
+    let mut text = format!(
+        "{:<8} {:<4} P:{:03X} V:{:<3}",
+        state.name,
+        state.note.clone().unwrap_or("-".to_string()),
+        state.period,
+        state.volume
+    );
+    if state.muted {
+        text.push_str(" [M]");
+    }
+    if state.solo {
+        text.push_str(" [S]");
+    }
+    text
+}
+
+// one row per channel: name, note, period, volume, then a volume bar and the oscilloscope
+pub fn draw_channels(apu: &Apu) -> Image {
+    let channels = apu.channels();
+    let (width, height) = visualizer_size(channels.len());
+    let mut image = Image::new(width, height);
+    fill_rect(&mut image, 0, 0, width, height, BACKGROUND);
+
+    for (row, channel) in channels.iter().enumerate() {
+        let state = apu.channel_state(*channel);
+        let top = row * ROW_HEIGHT;
+        let color = match channel {
+            _ if !apu.audible(*channel) => DIMMED,
+            Channel::Expansion(_) => EXPANSION_COLOR,
+            _ => CHANNEL_COLORS[row],
+        };
+        draw_text(&mut image, 2, top + 2, &label(&state), if state.active { TEXT } else { DIMMED });
+
+        // volume bar on the left of the scope
+        let bar = state.volume as usize * SCOPE_HEIGHT / state.max_volume.max(1) as usize;
+        let scope_top = top + TEXT_HEIGHT + 2;
+        fill_rect(&mut image, 2, scope_top + SCOPE_HEIGHT - bar, VOLUME_BAR_WIDTH - 2, bar, color);
+
+        let wave = apu.waveform(*channel);
+        let scope_left = VOLUME_BAR_WIDTH + 2;
+        let scope_width = width - scope_left - 2;
+        if wave.is_empty() {
+            continue;
+        }
+        for x in 0..scope_width {
+            let level = wave[x * wave.len() / scope_width] as usize;
+            let y = scope_top + SCOPE_HEIGHT - 1 - level.min(state.max_level as usize) * (SCOPE_HEIGHT - 1) / state.max_level.max(1) as usize;
+            image.set(scope_left + x, y, color);
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::TvSystem;
+
+    #[test]
+    fn test_label_text() {
+        let mut apu = Apu::new(TvSystem::NTSC);
+        apu.write_register(0x4015, 0b1);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 253);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.set_muted(Channel::Pulse1, true);
+        assert_eq!(label(&apu.channel_state(Channel::Pulse1)), "pulse1   A4   P:0FD V:15  [M]");
+        assert_eq!(label(&apu.channel_state(Channel::Noise)), "noise    -    P:004 V:0  ");
+    }
+
+    #[test]
+    fn test_draw_channels_plots_scope() {
+        let mut apu = Apu::new(TvSystem::NTSC);
+        apu.set_scope(true);
+        apu.write_register(0x4015, 0b1);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 253);
+        apu.write_register(0x4003, 0b0000_1000);
+        for _ in 0..400 {
+            apu.tick(100);
+        }
+        let image = draw_channels(&apu);
+        assert_eq!((image.width, image.height), visualizer_size(5));
+        // the pulse scope reaches both the top and the bottom of its strip
+        let scope_top = TEXT_HEIGHT + 2;
+        let row_has = |y: usize| (VOLUME_BAR_WIDTH + 2..VISUALIZER_WIDTH - 2).any(|x| image.get(x as isize, y as isize) == CHANNEL_COLORS[0]);
+        assert!(row_has(scope_top));
+        assert!(row_has(scope_top + SCOPE_HEIGHT - 1));
+    }
+
+    #[test]
+    fn test_expansion_rows() {
+        let mut apu = Apu::new(TvSystem::NTSC);
+        apu.set_expansion(crate::expansion::expansion_for_nsf(crate::expansion::NSF_VRC6));
+        apu.expansion_write(0x9000, 0b0111_1000);
+        apu.expansion_write(0x9001, 0xFD);
+        apu.expansion_write(0x9002, 0b1000_0000);
+        let image = draw_channels(&apu);
+        assert_eq!(image.height, visualizer_size(8).1);
+        assert_eq!(label(&apu.channel_state(Channel::Expansion(0))), "vrc6 p1  A4   P:0FD V:8  ");
+    }
+
+    #[test]
+    fn test_glyphs_cover_labels() {
+        for c in "pulse1 triangle noise dmc vrc6 vrc7 n163 5b mmc5 fds saw pcm A#4 P:0FD V:15 [M] [S]".chars() {
+            assert!(c == ' ' || glyph(c) != [0; 5], "no glyph for {}", c);
+        }
+    }
+}
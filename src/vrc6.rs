@@ -0,0 +1,332 @@
+use crate::expansion::*;
+use crate::savestate::*;
+
+// one step of a VRC6 pulse or saw level is about one step of a 2A03 pulse
+const VRC6_SCALE: f32 = 0.00996;
+
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    // ignore duty, output the volume all the time
+    digitized: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Self {
+        Vrc6Pulse { volume: 0, duty: 0, digitized: false, period: 0, enabled: false, timer: 0, step: 15 }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.digitized = data & 0b1000_0000 != 0;
+                self.duty = (data >> 4) & 0b111;
+                self.volume = data & 0b1111;
+            }
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | (((data & 0b1111) as u16) << 8);
+                self.enabled = data & 0b1000_0000 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = if self.step == 0 { 15 } else { self.step - 1 };
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) { self.volume } else { 0 }
+    }
+}
+
+struct Vrc6Saw {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn new() -> Self {
+        Vrc6Saw { rate: 0, period: 0, enabled: false, timer: 0, step: 0, accumulator: 0 }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0b0011_1111,
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | (((data & 0b1111) as u16) << 8);
+                self.enabled = data & 0b1000_0000 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    // the accumulator grows on every other step and resets after 14 steps (7 adds)
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step % 2 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// Konami VRC6: two pulses with 8 duty settings and a sawtooth
+pub struct Vrc6 {
+    // mapper 26 boards swap the A0 and A1 lines
+    swapped: bool,
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    halt: bool,
+    shift: u8,
+}
+
+impl Vrc6 {
+    pub fn new(swapped: bool) -> Self {
+        Vrc6 {
+            swapped: swapped,
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            saw: Vrc6Saw::new(),
+            halt: false,
+            shift: 0,
+        }
+    }
+
+    fn decode(&self, addr: u16) -> u16 {
+        if self.swapped {
+            (addr & !0b11) | ((addr & 0b01) << 1) | ((addr & 0b10) >> 1)
+        } else {
+            addr
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6 {
+    fn name(&self) -> &'static str {
+        "VRC6"
+    }
+
+    fn channel_names(&self) -> Vec<String> {
+        vec!["vrc6 p1".to_string(), "vrc6 p2".to_string(), "vrc6 saw".to_string()]
+    }
+
+    fn channel_count(&self) -> usize {
+        3
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        matches!(self.decode(addr), 0x9000..=0x9003 | 0xA000..=0xA002 | 0xB000..=0xB002)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        let addr = self.decode(addr);
+        match addr {
+            0x9003 => {
+                self.halt = data & 0b001 != 0;
+                // the x256 bit wins over the x16 bit
+                self.shift = if data & 0b100 != 0 { 8 } else if data & 0b010 != 0 { 4 } else { 0 };
+            }
+            0x9000..=0x9002 => self.pulse1.write(addr - 0x9000, data),
+            0xA000..=0xA002 => self.pulse2.write(addr - 0xA000, data),
+            0xB000..=0xB002 => self.saw.write(addr - 0xB000, data),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    fn output(&self, channel: usize) -> f32 {
+        self.level(channel) as f32 * VRC6_SCALE
+    }
+
+    fn level(&self, channel: usize) -> u8 {
+        match channel {
+            0 => self.pulse1.output(),
+            1 => self.pulse2.output(),
+            _ => self.saw.output(),
+        }
+    }
+
+    fn max_level(&self, channel: usize) -> u8 {
+        if channel == 2 { 31 } else { 15 }
+    }
+
+    fn info(&self, channel: usize, cpu_clock: f64) -> ExpansionChannelInfo {
+        match channel {
+            0 | 1 => {
+                let pulse = if channel == 0 { &self.pulse1 } else { &self.pulse2 };
+                ExpansionChannelInfo {
+                    period: pulse.period,
+                    frequency: cpu_clock / (16.0 * (pulse.period as f64 + 1.0)),
+                    volume: pulse.volume,
+                    max_volume: 15,
+                    active: pulse.enabled && pulse.volume > 0,
+                }
+            }
+            _ => ExpansionChannelInfo {
+                period: self.saw.period,
+                frequency: cpu_clock / (14.0 * (self.saw.period as f64 + 1.0)),
+                volume: self.saw.rate,
+                max_volume: 63,
+                active: self.saw.enabled && self.saw.rate > 0,
+            },
+        }
+    }
+}
+
+impl Savestate for Vrc6Pulse {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.u8(&mut self.volume);
+        s.u8(&mut self.duty);
+        s.bool(&mut self.digitized);
+        s.u16(&mut self.period);
+        s.bool(&mut self.enabled);
+        s.u16(&mut self.timer);
+        s.u8(&mut self.step);
+    }
+}
+
+impl Savestate for Vrc6Saw {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.u8(&mut self.rate);
+        s.u16(&mut self.period);
+        s.bool(&mut self.enabled);
+        s.u16(&mut self.timer);
+        s.u8(&mut self.step);
+        s.u8(&mut self.accumulator);
+    }
+}
+
+// the wiring is the board's, not state
+impl Savestate for Vrc6 {
+    fn state(&mut self, s: &mut StateBuffer) {
+        self.pulse1.state(s);
+        self.pulse2.state(s);
+        self.saw.state(s);
+        s.bool(&mut self.halt);
+        s.u8(&mut self.shift);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::expansion::test::assert_state_round_trips;
+
+    fn run(vrc6: &mut Vrc6, cycles: usize, channel: usize) -> Vec<u8> {
+        (0..cycles)
+            .map(|_| {
+                vrc6.clock();
+                vrc6.level(channel)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut vrc6 = Vrc6::new(false);
+        vrc6.write(0x9000, 0b0011_1010); // duty 3 (4/16), volume 10
+        vrc6.write(0x9001, 0);
+        vrc6.write(0x9002, 0b1000_0000);
+        let levels = run(&mut vrc6, 160, 0);
+        assert_eq!(levels.iter().filter(|l| **l == 10).count(), 40);
+
+        // digitized mode ignores the duty
+        vrc6.write(0x9000, 0b1000_0101);
+        assert!(run(&mut vrc6, 32, 0).iter().all(|l| *l == 5));
+    }
+
+    #[test]
+    fn test_saw_ramp() {
+        let mut vrc6 = Vrc6::new(false);
+        vrc6.write(0xB000, 42);
+        vrc6.write(0xB001, 0);
+        vrc6.write(0xB002, 0b1000_0000);
+        let levels = run(&mut vrc6, 14, 2);
+        // 6 adds of 42 = 252 peak, 252 >> 3 = 31, then back to 0
+        assert_eq!(*levels.iter().max().unwrap(), 31);
+        assert_eq!(levels[13], 0);
+    }
+
+    #[test]
+    fn test_mapper_26_swaps_lines() {
+        let mut vrc6 = Vrc6::new(true);
+        assert!(vrc6.handles(0xB002));
+        vrc6.write(0x9002, 0x55); // A0/A1 swapped: period low of pulse 1
+        assert_eq!(vrc6.pulse1.period, 0x55);
+        let info = vrc6.info(0, 1789772.7272);
+        assert!((info.frequency - 1789772.7272 / (16.0 * 86.0)).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_frequency_scaling() {
+        let mut vrc6 = Vrc6::new(false);
+        vrc6.write(0x9000, 0b1000_1111);
+        vrc6.write(0x9001, 0x10);
+        vrc6.write(0x9002, 0b1000_0000);
+        vrc6.write(0x9003, 0b010); // period >> 4
+        assert_eq!(vrc6.shift, 4);
+        vrc6.write(0x9003, 0b001);
+        let before = vrc6.pulse1.step;
+        run(&mut vrc6, 100, 0);
+        assert_eq!(vrc6.pulse1.step, before);
+    }
+
+    #[test]
+    fn test_state_round_trips() {
+        let mut vrc6 = Vrc6::new(false);
+        vrc6.write(0x9000, 0b0011_1010);
+        vrc6.write(0x9001, 0x40);
+        vrc6.write(0x9002, 0b1000_0000);
+        vrc6.write(0xB000, 42);
+        vrc6.write(0xB001, 0x80);
+        vrc6.write(0xB002, 0b1000_0000);
+        assert_state_round_trips(&mut vrc6);
+    }
+}
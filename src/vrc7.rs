@@ -0,0 +1,510 @@
+use std::f64::consts::PI;
+
+use crate::expansion::*;
+use crate::savestate::*;
+
+// the YM2413-derived core makes one sample every 36 CPU cycles (about 49.7 kHz)
+const SAMPLE_PERIOD: u8 = 36;
+const VRC7_SCALE: f32 = 0.12;
+// envelope attenuation range in dB, anything below it is silent
+const ENVELOPE_RANGE: f64 = 48.0;
+
+// the 15 built-in instruments, 8 bytes each like the custom one at $00-$07
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+// frequency multipliers, MULT 0 is one half
+const MULTIPLIERS: [f64; 16] = [0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0];
+// key scale attenuation in dB at block 7 by the top 4 bits of the F-number
+const KEY_SCALE_LEVELS: [f64; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25, 42.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+// one side of an instrument, decoded from the patch bytes
+#[derive(Clone, Copy)]
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    // sustained tones hold at the sustain level until key off, percussive ones keep decaying
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: f64,
+    key_scale_level: u8,
+    rectified: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+fn decode_patch(patch: &[u8; 8]) -> (OperatorPatch, OperatorPatch, u8, u8) {
+    let operator = |n: usize, rectified: bool, key_scale_level: u8| OperatorPatch {
+        tremolo: patch[n] & 0b1000_0000 != 0,
+        vibrato: patch[n] & 0b0100_0000 != 0,
+        sustained: patch[n] & 0b0010_0000 != 0,
+        key_scale_rate: patch[n] & 0b0001_0000 != 0,
+        multiplier: MULTIPLIERS[(patch[n] & 0b1111) as usize],
+        key_scale_level: key_scale_level,
+        rectified: rectified,
+        attack: patch[4 + n] >> 4,
+        decay: patch[4 + n] & 0b1111,
+        sustain_level: patch[6 + n] >> 4,
+        release: patch[6 + n] & 0b1111,
+    };
+    let modulator = operator(0, patch[3] & 0b0000_1000 != 0, patch[2] >> 6);
+    let carrier = operator(1, patch[3] & 0b0001_0000 != 0, patch[3] >> 6);
+    // modulator total level, feedback
+    (modulator, carrier, patch[2] & 0b0011_1111, patch[3] & 0b111)
+}
+
+struct Operator {
+    phase: f64,
+    state: EnvelopeState,
+    // attenuation in dB
+    envelope: f64,
+}
+
+impl Operator {
+    fn new() -> Self {
+        Operator { phase: 0.0, state: EnvelopeState::Off, envelope: ENVELOPE_RANGE }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.state != EnvelopeState::Off {
+            self.state = EnvelopeState::Release;
+        }
+    }
+
+    // the 4-bit rate plus key scaling gives a 0-63 rate index; every 4 halves the time
+    fn clock_envelope(&mut self, patch: &OperatorPatch, key_rate: u8, channel_sustain: bool) {
+        let scaled = |rate: u8| -> f64 {
+            if rate == 0 {
+                return 0.0;
+            }
+            let index = (rate as u32 * 4 + if patch.key_scale_rate { key_rate } else { key_rate >> 2 } as u32).min(63);
+            index as f64
+        };
+        // dB per sample for a decay at a rate index, 96 dB takes 39 s at index 4
+        let decay_step = |index: f64| -> f64 {
+            if index == 0.0 {
+                return 0.0;
+            }
+            96.0 / 39.28 * 2f64.powf((index - 4.0) / 4.0) / 49716.0
+        };
+
+        match self.state {
+            EnvelopeState::Attack => {
+                let index = scaled(patch.attack);
+                if index >= 60.0 {
+                    self.envelope = 0.0;
+                } else if index > 0.0 {
+                    // exponential approach from 48 dB down to 0.01 dB, 2.8 s at index 4
+                    let seconds = 2.826 / 2f64.powf((index - 4.0) / 4.0);
+                    let k = 1.0 - (0.01 / ENVELOPE_RANGE).powf(1.0 / (seconds * 49716.0));
+                    self.envelope -= self.envelope * k + 0.0001;
+                }
+                if self.envelope <= 0.0 {
+                    self.envelope = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                self.envelope += decay_step(scaled(patch.decay));
+                let sustain = patch.sustain_level as f64 * 3.0;
+                if self.envelope >= sustain {
+                    self.envelope = sustain;
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+            EnvelopeState::Sustain => {
+                if !patch.sustained {
+                    self.envelope += decay_step(scaled(patch.release));
+                }
+            }
+            EnvelopeState::Release => {
+                let rate = if channel_sustain {
+                    5
+                } else if patch.sustained {
+                    patch.release
+                } else {
+                    7
+                };
+                self.envelope += decay_step(scaled(rate));
+            }
+            EnvelopeState::Off => {}
+        }
+        if self.envelope >= ENVELOPE_RANGE {
+            self.envelope = ENVELOPE_RANGE;
+            if self.state != EnvelopeState::Attack {
+                self.state = EnvelopeState::Off;
+            }
+        }
+    }
+
+    // a sine, or its positive half when rectified, scaled by the attenuation in dB
+    fn output(&self, modulation: f64, patch: &OperatorPatch, attenuation: f64) -> f64 {
+        let total = self.envelope + attenuation;
+        if total >= ENVELOPE_RANGE * 2.0 || self.state == EnvelopeState::Off {
+            return 0.0;
+        }
+        let wave = (2.0 * PI * self.phase + modulation).sin();
+        let wave = if patch.rectified && wave < 0.0 { 0.0 } else { wave };
+        wave * 10f64.powf(-total / 20.0)
+    }
+}
+
+struct FmChannel {
+    fnum: u16,
+    block: u8,
+    key: bool,
+    sustain: bool,
+    instrument: u8,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    feedback: [f64; 2],
+    output: f64,
+}
+
+impl FmChannel {
+    fn new() -> Self {
+        FmChannel {
+            fnum: 0,
+            block: 0,
+            key: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: [0.0; 2],
+            output: 0.0,
+        }
+    }
+
+    fn frequency(&self) -> f64 {
+        49716.0 * self.fnum as f64 * 2f64.powi(self.block as i32) / 524288.0
+    }
+
+    // block and F-number MSB, for rate key scaling
+    fn key_rate(&self) -> u8 {
+        (self.block << 1) | (self.fnum >> 8) as u8
+    }
+
+    fn key_scale_attenuation(&self, level: u8) -> f64 {
+        if level == 0 {
+            return 0.0;
+        }
+        let base = (KEY_SCALE_LEVELS[(self.fnum >> 5) as usize] - 6.0 * (7 - self.block) as f64).max(0.0);
+        // 1.5, 3 and 6 dB per octave
+        base / [0.0, 4.0, 2.0, 1.0][level as usize]
+    }
+}
+
+// Konami VRC7: a cut-down YM2413 with six 2-operator FM channels and one custom
+// instrument. The operators are computed in floating point rather than with the
+// chip's log-sine tables.
+pub struct Vrc7 {
+    custom: [u8; 8],
+    selected: u8,
+    channels: Vec<FmChannel>,
+    timer: u8,
+    lfo_phase: f64,
+}
+
+impl Vrc7 {
+    pub fn new() -> Self {
+        Vrc7 { custom: [0; 8], selected: 0, channels: (0..6).map(|_| FmChannel::new()).collect(), timer: 0, lfo_phase: 0.0 }
+    }
+
+    fn patch(&self, instrument: u8) -> [u8; 8] {
+        if instrument == 0 { self.custom } else { PATCHES[instrument as usize - 1] }
+    }
+
+    fn write_register(&mut self, register: u8, data: u8) {
+        match register {
+            0x00..=0x07 => self.custom[register as usize] = data,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[register as usize - 0x10];
+                channel.fnum = (channel.fnum & 0x100) | data as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[register as usize - 0x20];
+                channel.fnum = (channel.fnum & 0xFF) | ((data & 1) as u16) << 8;
+                channel.block = (data >> 1) & 0b111;
+                channel.sustain = data & 0b0010_0000 != 0;
+                let key = data & 0b0001_0000 != 0;
+                if key && !channel.key {
+                    channel.modulator.key_on();
+                    channel.carrier.key_on();
+                } else if !key && channel.key {
+                    channel.modulator.key_off();
+                    channel.carrier.key_off();
+                }
+                channel.key = key;
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[register as usize - 0x30];
+                channel.instrument = data >> 4;
+                channel.volume = data & 0b1111;
+            }
+            _ => {}
+        }
+    }
+
+    fn generate(&mut self) {
+        // tremolo at 3.7 Hz, 4.8 dB deep; vibrato at 6.4 Hz, about 14 cents
+        self.lfo_phase += 1.0 / 49716.0;
+        let tremolo = (1.0 + (2.0 * PI * 3.7 * self.lfo_phase).sin()) * 2.4;
+        let vibrato = 2f64.powf((2.0 * PI * 6.4 * self.lfo_phase).sin() * 14.0 / 1200.0);
+
+        for i in 0..self.channels.len() {
+            let patch = self.patch(self.channels[i].instrument);
+            let (mod_patch, car_patch, total_level, feedback) = decode_patch(&patch);
+            let channel = &mut self.channels[i];
+            let key_rate = channel.key_rate();
+            channel.modulator.clock_envelope(&mod_patch, key_rate, channel.sustain);
+            channel.carrier.clock_envelope(&car_patch, key_rate, channel.sustain);
+
+            let increment = channel.fnum as f64 * 2f64.powi(channel.block as i32) / 524288.0;
+            for (operator, patch) in [(&mut channel.modulator, &mod_patch), (&mut channel.carrier, &car_patch)] {
+                let step = increment * patch.multiplier * if patch.vibrato { vibrato } else { 1.0 };
+                operator.phase = (operator.phase + step).fract();
+            }
+
+            // feedback of 7 is a modulation index of 4 pi
+            let self_modulation = if feedback == 0 {
+                0.0
+            } else {
+                (channel.feedback[0] + channel.feedback[1]) / 2.0 * 4.0 * PI / 2f64.powi(7 - feedback as i32)
+            };
+            let mod_attenuation = total_level as f64 * 0.75
+                + channel.key_scale_attenuation(mod_patch.key_scale_level)
+                + if mod_patch.tremolo { tremolo } else { 0.0 };
+            let modulation = channel.modulator.output(self_modulation, &mod_patch, mod_attenuation);
+            channel.feedback = [channel.feedback[1], modulation];
+
+            let car_attenuation = channel.volume as f64 * 3.0
+                + channel.key_scale_attenuation(car_patch.key_scale_level)
+                + if car_patch.tremolo { tremolo } else { 0.0 };
+            channel.output = channel.carrier.output(modulation * 2.0 * PI, &car_patch, car_attenuation);
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc7 {
+    fn name(&self) -> &'static str {
+        "VRC7"
+    }
+
+    fn channel_names(&self) -> Vec<String> {
+        (1..=6).map(|n| format!("vrc7 {}", n)).collect()
+    }
+
+    fn channel_count(&self) -> usize {
+        6
+    }
+
+    fn handles(&self, addr: u16) -> bool {
+        addr == 0x9010 || addr == 0x9030
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9010 => self.selected = data,
+            _ => self.write_register(self.selected, data),
+        }
+    }
+
+    fn clock(&mut self) {
+        self.timer += 1;
+        if self.timer == SAMPLE_PERIOD {
+            self.timer = 0;
+            self.generate();
+        }
+    }
+
+    fn output(&self, channel: usize) -> f32 {
+        self.channels[channel].output as f32 * VRC7_SCALE
+    }
+
+    fn level(&self, channel: usize) -> u8 {
+        ((self.channels[channel].output + 1.0) * 7.5).round() as u8
+    }
+
+    fn max_level(&self, _channel: usize) -> u8 {
+        15
+    }
+
+    fn info(&self, channel: usize, _cpu_clock: f64) -> ExpansionChannelInfo {
+        let channel = &self.channels[channel];
+        ExpansionChannelInfo {
+            period: channel.fnum,
+            frequency: channel.frequency(),
+            volume: 15 - channel.volume,
+            max_volume: 15,
+            active: channel.carrier.state != EnvelopeState::Off,
+        }
+    }
+}
+
+const ENVELOPE_STATES: [EnvelopeState; 5] =
+    [EnvelopeState::Attack, EnvelopeState::Decay, EnvelopeState::Sustain, EnvelopeState::Release, EnvelopeState::Off];
+
+impl Savestate for Operator {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.f64(&mut self.phase);
+        let mut state = ENVELOPE_STATES.iter().position(|state| *state == self.state).unwrap() as u8;
+        s.u8(&mut state);
+        self.state = ENVELOPE_STATES[state as usize];
+        s.f64(&mut self.envelope);
+    }
+}
+
+impl Savestate for FmChannel {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.u16(&mut self.fnum);
+        s.u8(&mut self.block);
+        s.bool(&mut self.key);
+        s.bool(&mut self.sustain);
+        s.u8(&mut self.instrument);
+        s.u8(&mut self.volume);
+        self.modulator.state(s);
+        self.carrier.state(s);
+        s.f64(&mut self.feedback[0]);
+        s.f64(&mut self.feedback[1]);
+        s.f64(&mut self.output);
+    }
+}
+
+impl Savestate for Vrc7 {
+    fn state(&mut self, s: &mut StateBuffer) {
+        s.bytes(&mut self.custom);
+        s.u8(&mut self.selected);
+        for channel in self.channels.iter_mut() {
+            channel.state(s);
+        }
+        s.u8(&mut self.timer);
+        s.f64(&mut self.lfo_phase);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::expansion::test::assert_state_round_trips;
+
+    fn set(vrc7: &mut Vrc7, register: u8, data: u8) {
+        vrc7.write(0x9010, register);
+        vrc7.write(0x9030, data);
+    }
+
+    // A4 on channel 1: F-number 290 in block 4
+    fn play_a4(vrc7: &mut Vrc7, instrument: u8) {
+        set(vrc7, 0x30, instrument << 4);
+        set(vrc7, 0x10, (290 & 0xFF) as u8);
+        set(vrc7, 0x20, 0b0001_0000 | (4 << 1) | (290 >> 8) as u8);
+    }
+
+    fn zero_crossings(vrc7: &mut Vrc7, cpu_cycles: usize) -> usize {
+        let mut crossings = 0;
+        let mut last = vrc7.output(0);
+        for _ in 0..cpu_cycles {
+            vrc7.clock();
+            let out = vrc7.output(0);
+            if last <= 0.0 && out > 0.0 {
+                crossings += 1;
+            }
+            last = out;
+        }
+        crossings
+    }
+
+    #[test]
+    fn test_note_frequency() {
+        let mut vrc7 = Vrc7::new();
+        // a pure sine: custom patch with the modulator silenced
+        for (register, data) in [0x01u8, 0x01, 0x3F, 0x00, 0xF0, 0xF0, 0x00, 0x00].iter().enumerate() {
+            set(&mut vrc7, register as u8, *data);
+        }
+        play_a4(&mut vrc7, 0);
+        let info = vrc7.info(0, 1789772.7272);
+        assert!((info.frequency - 440.0).abs() < 1.0);
+        assert!(info.active);
+        // one second of CPU cycles
+        let crossings = zero_crossings(&mut vrc7, 1789773);
+        assert!((crossings as i32 - 440).abs() <= 2, "{}", crossings);
+    }
+
+    #[test]
+    fn test_key_off_releases() {
+        let mut vrc7 = Vrc7::new();
+        play_a4(&mut vrc7, 3);
+        zero_crossings(&mut vrc7, 36 * 2000);
+        assert!(vrc7.channels[0].carrier.state != EnvelopeState::Off);
+        set(&mut vrc7, 0x20, (4 << 1) | 1);
+        assert_eq!(vrc7.channels[0].carrier.state, EnvelopeState::Release);
+        // a few seconds later the note is gone
+        for _ in 0..36 * 49716 * 4 {
+            vrc7.clock();
+        }
+        assert_eq!(vrc7.channels[0].carrier.state, EnvelopeState::Off);
+        assert_eq!(vrc7.output(0), 0.0);
+        assert!(!vrc7.info(0, 1789772.7272).active);
+    }
+
+    #[test]
+    fn test_volume_attenuates() {
+        let peak = |volume: u8| {
+            let mut vrc7 = Vrc7::new();
+            set(&mut vrc7, 0x30, (1 << 4) | volume);
+            set(&mut vrc7, 0x10, (290 & 0xFF) as u8);
+            set(&mut vrc7, 0x20, 0b0001_0000 | (4 << 1) | 1);
+            let mut peak: f32 = 0.0;
+            for _ in 0..36 * 2000 {
+                vrc7.clock();
+                peak = peak.max(vrc7.output(0).abs());
+            }
+            peak
+        };
+        // 4 steps are 12 dB, about a quarter
+        let ratio = peak(4) / peak(0);
+        assert!((ratio - 0.25).abs() < 0.05, "{}", ratio);
+    }
+
+    #[test]
+    fn test_state_round_trips() {
+        let mut vrc7 = Vrc7::new();
+        play_a4(&mut vrc7, 3);
+        assert_state_round_trips(&mut vrc7);
+    }
+}
@@ -0,0 +1,172 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+use crate::audio::CHANNEL_NAMES;
+
+const HEADER_SIZE: u32 = 44;
+
+// canonical 44 byte header for mono 16-bit PCM
+pub fn wav_header(sample_rate: u32, data_bytes: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_SIZE as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_bytes).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    out.extend_from_slice(&1u16.to_le_bytes()); // mono
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_bytes.to_le_bytes());
+    out
+}
+
+pub fn to_pcm16(sample: f32) -> i16 {
+    (sample.max(-1.0).min(1.0) * 32767.0).round() as i16
+}
+
+// sizes in the header are patched in by finish()
+pub struct WavWriter {
+    out: BufWriter<File>,
+    sample_rate: u32,
+    pub samples: usize,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, sample_rate: u32) -> Result<WavWriter, String> {
+        let file = File::create(path).map_err(|e| format!("could not create {}: {}", path, e))?;
+        let mut out = BufWriter::new(file);
+        out.write_all(&wav_header(sample_rate, 0)).map_err(|e| e.to_string())?;
+        Ok(WavWriter { out: out, sample_rate: sample_rate, samples: 0 })
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            bytes.extend_from_slice(&to_pcm16(*sample).to_le_bytes());
+        }
+        self.out.write_all(&bytes).map_err(|e| e.to_string())?;
+        self.samples += samples.len();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<usize, String> {
+        let header = wav_header(self.sample_rate, (self.samples * 2) as u32);
+        self.out.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+        self.out.write_all(&header).map_err(|e| e.to_string())?;
+        self.out.flush().map_err(|e| e.to_string())?;
+        Ok(self.samples)
+    }
+}
+
+// "music.wav" -> "music-triangle.wav"
+pub fn channel_path(path: &str, channel: &str) -> String {
+    match path.rfind('.') {
+        Some(dot) if !path[dot..].contains('/') => format!("{}-{}{}", &path[..dot], channel, &path[dot..]),
+        _ => format!("{}-{}", path, channel),
+    }
+}
+
+// the mixed output, plus one file per APU channel and one for expansion audio when asked for
+pub struct WavRecorder {
+    mixed: WavWriter,
+    channels: Vec<WavWriter>,
+}
+
+impl WavRecorder {
+    pub fn start(path: &str, sample_rate: u32, per_channel: bool) -> Result<WavRecorder, String> {
+        let mut channels = vec![];
+        if per_channel {
+            for name in CHANNEL_NAMES.iter() {
+                channels.push(WavWriter::create(&channel_path(path, name), sample_rate)?);
+            }
+        }
+        Ok(WavRecorder { mixed: WavWriter::create(path, sample_rate)?, channels: channels })
+    }
+
+    pub fn per_channel(&self) -> bool {
+        !self.channels.is_empty()
+    }
+
+    pub fn write(&mut self, mixed: &[f32], channels: &[Vec<f32>]) -> Result<(), String> {
+        self.mixed.write(mixed)?;
+        for (writer, samples) in self.channels.iter_mut().zip(channels) {
+            writer.write(samples)?;
+        }
+        Ok(())
+    }
+
+    // returns the number of samples in the mixed file
+    pub fn finish(self) -> Result<usize, String> {
+        for writer in self.channels {
+            writer.finish()?;
+        }
+        self.mixed.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("nes-wav-{}-{}", std::process::id(), name)).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_header_layout() {
+        let header = wav_header(44100, 8);
+        assert_eq!(header.len(), 44);
+        assert_eq!(&header[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([header[4], header[5], header[6], header[7]]), 44);
+        assert_eq!(u32::from_le_bytes([header[24], header[25], header[26], header[27]]), 44100);
+        assert_eq!(u32::from_le_bytes([header[40], header[41], header[42], header[43]]), 8);
+    }
+
+    #[test]
+    fn test_pcm_conversion_clamps() {
+        assert_eq!(to_pcm16(0.0), 0);
+        assert_eq!(to_pcm16(1.0), 32767);
+        assert_eq!(to_pcm16(-2.0), -32767);
+    }
+
+    #[test]
+    fn test_channel_paths() {
+        assert_eq!(channel_path("out/music.wav", "dmc"), "out/music-dmc.wav");
+        assert_eq!(channel_path("music", "noise"), "music-noise");
+        assert_eq!(channel_path("some.dir/music", "noise"), "some.dir/music-noise");
+    }
+
+    #[test]
+    fn test_writer_patches_sizes() {
+        let path = temp_path("sizes.wav");
+        let mut writer = WavWriter::create(&path, 48000).unwrap();
+        writer.write(&[0.5, -0.5, 0.0]).unwrap();
+        assert_eq!(writer.finish().unwrap(), 3);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[..44], wav_header(48000, 6).as_slice());
+        assert_eq!(i16::from_le_bytes([bytes[44], bytes[45]]), 16384);
+        assert_eq!(i16::from_le_bytes([bytes[46], bytes[47]]), -16384);
+    }
+
+    #[test]
+    fn test_recorder_writes_channel_files() {
+        let path = temp_path("channels.wav");
+        let mut recorder = WavRecorder::start(&path, 44100, true).unwrap();
+        let channels: Vec<Vec<f32>> = (0..CHANNEL_NAMES.len()).map(|_| vec![0.1; 4]).collect();
+        recorder.write(&[0.2; 4], &channels).unwrap();
+        assert_eq!(recorder.finish().unwrap(), 4);
+        for name in CHANNEL_NAMES.iter() {
+            let channel = channel_path(&path, name);
+            assert_eq!(std::fs::read(&channel).unwrap().len(), 44 + 8);
+            std::fs::remove_file(&channel).unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+use crate::controller::*;
+use crate::frame::Frame;
+use crate::palette::*;
+use crate::ppu::ppu;
+use crate::render;
+
+// how bright a pixel must be, with black at 0 and white at 1, for the photodiode to fire
+const LIGHT_THRESHOLD: f32 = 0.7;
+// the diode sees a small circle of the screen, taken as a square this many pixels out
+const AIM_RADIUS: usize = 2;
+// the diode stays lit for a while after the beam passes
+const LIGHT_SCANLINES: usize = 16;
+const VISIBLE_SCANLINES: usize = 240;
+
+// what a light gun looks at: the PPU's output for the current frame, rendered the first
+// time a light gun asks for it during that frame
+pub struct LightSense {
+    frame: Frame,
+    colors: Palette,
+    rendered: bool,
+    // per 9-bit PPU output, emphasis included
+    bright: Vec<bool>,
+    scanline: usize,
+    dot: usize,
+}
+
+impl LightSense {
+    pub fn new() -> Self {
+        let bright = (0..512u16)
+            .map(|index| {
+                let luma = (0..12).map(|phase| ntsc_signal((index & 0x3F) as u8, (index >> 6) as u8, phase)).sum::<f32>() / 12.0;
+                luma >= LIGHT_THRESHOLD
+            })
+            .collect();
+        LightSense {
+            frame: Frame::new(),
+            colors: Palette::builtin(BuiltinPalette::Classic),
+            rendered: false,
+            bright: bright,
+            scanline: 0,
+            dot: 0,
+        }
+    }
+
+    // a new frame started, the old picture is gone
+    pub fn invalidate(&mut self) {
+        self.rendered = false;
+    }
+
+    pub fn update(&mut self, ppu: &ppu) {
+        if !self.rendered {
+            render::render(ppu, &mut self.frame, &self.colors);
+            self.rendered = true;
+        }
+        self.scanline = ppu.scanline() as usize;
+        self.dot = ppu.dot();
+    }
+
+    #[cfg(test)]
+    pub fn from_frame(frame: Frame, scanline: usize, dot: usize) -> Self {
+        let mut light = LightSense::new();
+        light.frame = frame;
+        light.rendered = true;
+        light.scanline = scanline;
+        light.dot = dot;
+        light
+    }
+
+    // true if the beam drew a bright pixel here within the last few scanlines
+    pub fn lit(&self, x: usize, y: usize) -> bool {
+        if x >= Frame::WIDTH || y >= VISIBLE_SCANLINES || y > self.scanline {
+            return false;
+        }
+        // dot 1 outputs pixel 0, so pixel x is out once the PPU is past dot x + 1
+        if y == self.scanline && self.dot <= x + 1 {
+            return false;
+        }
+        if self.scanline - y >= LIGHT_SCANLINES {
+            return false;
+        }
+        self.bright[self.frame.indices[y * Frame::WIDTH + x] as usize & 0x1FF]
+    }
+}
+
+// the NES Zapper: trigger on D4 and light sense on D3 of its port, the sense bit low
+// while light is seen
+pub struct Zapper {
+    // in frame pixels, None while pointing away from the screen
+    pub aim: Option<(usize, usize)>,
+    pub trigger: bool,
+    light: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper { aim: None, trigger: false, light: false }
+    }
+
+    fn sees_light(&self, light: &LightSense) -> bool {
+        let (x, y) = match self.aim {
+            Some(aim) => aim,
+            None => return false,
+        };
+        for row in y.saturating_sub(AIM_RADIUS)..=y + AIM_RADIUS {
+            for column in x.saturating_sub(AIM_RADIUS)..=x + AIM_RADIUS {
+                if light.lit(column, row) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl PortDevice for Zapper {
+    fn name(&self) -> &'static str {
+        "zapper"
+    }
+
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&mut self, _port: usize) -> u8 {
+        let mut data = 0;
+        if !self.light {
+            data |= 0b0000_1000;
+        }
+        if self.trigger {
+            data |= 0b0001_0000;
+        }
+        data
+    }
+
+    fn needs_light(&self) -> bool {
+        true
+    }
+
+    fn sense_light(&mut self, light: &LightSense) {
+        self.light = self.sees_light(light);
+    }
+
+    fn zapper(&mut self) -> Option<&mut Zapper> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // a white 8x8 box at (100, 50) on black
+    fn target_frame() -> Frame {
+        let mut frame = Frame::new();
+        for index in frame.indices.iter_mut() {
+            *index = 0x0F;
+        }
+        for y in 50..58 {
+            for x in 100..108 {
+                frame.indices[y * Frame::WIDTH + x] = 0x30;
+            }
+        }
+        frame
+    }
+
+    fn sensed(aim: Option<(usize, usize)>, scanline: usize, dot: usize) -> bool {
+        let mut zapper = Zapper::new();
+        zapper.aim = aim;
+        zapper.sense_light(&LightSense::from_frame(target_frame(), scanline, dot));
+        zapper.read(1) & 0b0000_1000 == 0
+    }
+
+    #[test]
+    fn test_light_follows_the_beam() {
+        // not drawn yet
+        assert!(!sensed(Some((103, 53)), 40, 200));
+        assert!(!sensed(Some((103, 53)), 51, 50));
+        // just drawn, and still glowing a few scanlines later
+        assert!(sensed(Some((103, 53)), 51, 120));
+        assert!(sensed(Some((103, 53)), 60, 10));
+        // long gone
+        assert!(!sensed(Some((103, 53)), 80, 10));
+    }
+
+    #[test]
+    fn test_aim() {
+        // the edge of the box is within the diode's view
+        assert!(sensed(Some((98, 53)), 60, 10));
+        assert!(!sensed(Some((90, 53)), 60, 10));
+        assert!(!sensed(None, 60, 10));
+    }
+
+    #[test]
+    fn test_dark_colors_are_not_light() {
+        let light = LightSense::new();
+        assert!(light.bright[0x30] && light.bright[0x20]);
+        assert!(!light.bright[0x0F] && !light.bright[0x16] && !light.bright[0x12]);
+    }
+
+    #[test]
+    fn test_trigger() {
+        let mut zapper = Zapper::new();
+        assert_eq!(zapper.read(1), 0b0000_1000);
+        zapper.trigger = true;
+        assert_eq!(zapper.read(1), 0b0001_1000);
+    }
+}